@@ -1,16 +1,21 @@
 use std::{
-    io,
+    io::{self, IsTerminal},
     sync::{Arc, Mutex},
 };
 
 use console::Term;
 use dialoguer::{Input, Password, Select};
 use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use tracing::warn;
 use tracing_subscriber::fmt::MakeWriter;
 
+use crate::{dashboard::Dashboard, Error, Result};
+
 pub struct Bar {
     bar: ProgressBar,
     console: Console,
+    /// Set when the bar is displayed by the dashboard.
+    transfer: Option<u64>,
 }
 
 impl Bar {
@@ -25,6 +30,13 @@ impl Bar {
 
 impl Drop for Bar {
     fn drop(&mut self) {
+        if let Some(id) = self.transfer {
+            if let Some(dashboard) = self.console.dashboard() {
+                dashboard.remove_transfer(id);
+            }
+            return;
+        }
+
         self.bar.finish_and_clear();
 
         self.console.progress_bars.remove(&self.bar);
@@ -51,6 +63,11 @@ impl ConsoleWriter {
 
 impl io::Write for ConsoleWriter {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(dashboard) = self.console.dashboard() {
+            dashboard.write_log(buf);
+            return Ok(buf.len());
+        }
+
         self.console.term.write(buf)
     }
 
@@ -91,6 +108,7 @@ impl Drop for BarHideGuard {
     }
 }
 
+#[derive(Clone, Copy)]
 pub enum ProgressType {
     Bytes,
     Percent,
@@ -101,6 +119,22 @@ pub struct Console {
     term: Term,
     progress_bars: MultiProgress,
     state: Arc<Mutex<ConsoleState>>,
+    dashboard: Arc<Mutex<Option<Arc<Dashboard>>>>,
+    interactive: bool,
+}
+
+/// Closes the dashboard when dropped.
+pub struct DashboardGuard {
+    console: Console,
+}
+
+impl Drop for DashboardGuard {
+    fn drop(&mut self) {
+        let dashboard = self.console.dashboard.lock().unwrap().take();
+        if let Some(dashboard) = dashboard {
+            dashboard.stop();
+        }
+    }
 }
 
 impl Default for Console {
@@ -110,11 +144,56 @@ impl Default for Console {
             progress_bars: MultiProgress::with_draw_target(ProgressDrawTarget::hidden()),
             term,
             state: Default::default(),
+            dashboard: Default::default(),
+            interactive: io::stdin().is_terminal(),
         }
     }
 }
 
 impl Console {
+    /// Whether prompts can be shown. Defaults to whether stdin is a terminal.
+    pub fn is_interactive(&self) -> bool {
+        self.interactive
+    }
+
+    pub fn set_interactive(&mut self, interactive: bool) {
+        self.interactive = interactive;
+    }
+
+    fn check_interactive(&self, prompt: &str) -> Result {
+        if self.interactive {
+            Ok(())
+        } else {
+            Err(Error::PromptUnavailable(prompt.to_owned()))
+        }
+    }
+
+    fn dashboard(&self) -> Option<Arc<Dashboard>> {
+        self.dashboard.lock().unwrap().clone()
+    }
+
+    /// Replaces the progress bars and log output with a full screen dashboard
+    /// until the returned guard is dropped. Does nothing unless the output is
+    /// a terminal.
+    pub fn show_dashboard(&self) -> Option<DashboardGuard> {
+        if !self.term.is_term() {
+            return None;
+        }
+
+        match Dashboard::start() {
+            Ok(dashboard) => {
+                *self.dashboard.lock().unwrap() = Some(Arc::new(dashboard));
+                Some(DashboardGuard {
+                    console: self.clone(),
+                })
+            }
+            Err(e) => {
+                warn!(error=?e, "Failed to start the dashboard");
+                None
+            }
+        }
+    }
+
     fn update_draw_target(&self, state: &ConsoleState) {
         let should_be_hidden = state.progress_bar_count == 0 || state.hide_count > 0;
 
@@ -145,6 +224,18 @@ impl Console {
             }
         };
 
+        if let Some(dashboard) = self.dashboard() {
+            let bar = ProgressBar::hidden().with_message(msg.to_owned());
+            bar.set_length(100);
+            let id = dashboard.add_transfer(bar.clone(), progress_type);
+
+            return Bar {
+                bar,
+                console: self.clone(),
+                transfer: Some(id),
+            };
+        }
+
         let inner_bar = ProgressBar::new(100)
             .with_message(msg.to_owned())
             .with_style(style);
@@ -156,6 +247,7 @@ impl Console {
         Bar {
             bar: self.progress_bars.add(inner_bar),
             console: self.clone(),
+            transfer: None,
         }
     }
 
@@ -165,10 +257,26 @@ impl Console {
     {
         let _guard = BarHideGuard::new(self);
 
-        f(&self.term)
+        let dashboard = self.dashboard();
+        if let Some(ref dashboard) = dashboard {
+            dashboard.pause();
+        }
+
+        let result = f(&self.term);
+
+        if let Some(ref dashboard) = dashboard {
+            dashboard.resume();
+        }
+
+        result
     }
 
     fn inner_println<S: AsRef<str>>(&self, msg: S) -> io::Result<()> {
+        if let Some(dashboard) = self.dashboard() {
+            dashboard.message(msg.as_ref());
+            return Ok(());
+        }
+
         self.with_term(|term| term.write_line(msg.as_ref()))
     }
 
@@ -176,33 +284,35 @@ impl Console {
         self.inner_println(msg).unwrap();
     }
 
-    pub fn input<P: Into<String>>(&self, prompt: P) -> String {
-        self.with_term(|term| {
-            Input::new()
+    pub fn input<P: Into<String>>(&self, prompt: P) -> Result<String> {
+        let prompt = prompt.into();
+        self.check_interactive(&prompt)?;
+
+        Ok(self.with_term(|term| {
+            Input::<String>::new()
                 .with_prompt(prompt)
                 .interact_text_on(term)
-                .unwrap()
-        })
+        })?)
     }
 
-    pub fn password<P: Into<String>>(&self, prompt: P) -> String {
-        self.with_term(|term| {
-            Password::new()
-                .with_prompt(prompt)
-                .interact_on(term)
-                .unwrap()
-        })
+    pub fn password<P: Into<String>>(&self, prompt: P) -> Result<String> {
+        let prompt = prompt.into();
+        self.check_interactive(&prompt)?;
+
+        Ok(self.with_term(|term| Password::new().with_prompt(prompt).interact_on(term))?)
     }
 
-    pub fn select<P: Into<String>, S: ToString>(&self, prompt: P, items: &[S]) -> usize {
-        self.with_term(|term| {
+    pub fn select<P: Into<String>, S: ToString>(&self, prompt: P, items: &[S]) -> Result<usize> {
+        let prompt = prompt.into();
+        self.check_interactive(&prompt)?;
+
+        Ok(self.with_term(|term| {
             Select::new()
                 .with_prompt(prompt)
                 .items(items)
                 .default(0)
                 .interact_on(term)
-                .unwrap()
-        })
+        })?)
     }
 }
 
@@ -0,0 +1,300 @@
+//! A full screen view of a sync showing each transfer's progress and speed
+//! above a scrolling log, used instead of the plain progress bars.
+
+use std::{
+    collections::VecDeque,
+    io::{self, Stdout},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use console::strip_ansi_codes;
+use indicatif::{DecimalBytes, ProgressBar};
+use ratatui::{
+    backend::CrosstermBackend,
+    crossterm::{
+        execute,
+        terminal::{EnterAlternateScreen, LeaveAlternateScreen},
+    },
+    layout::{Constraint, Direction, Layout, Rect},
+    style::{Color, Style},
+    widgets::{Block, Borders, Gauge, List, ListItem},
+    Frame, Terminal,
+};
+
+use crate::console::ProgressType;
+
+/// The number of log lines kept for display.
+const LOG_LENGTH: usize = 500;
+/// The height of the log pane including its border.
+const LOG_HEIGHT: u16 = 12;
+const REFRESH: Duration = Duration::from_millis(250);
+
+struct Transfer {
+    id: u64,
+    bar: ProgressBar,
+    progress_type: ProgressType,
+    sample: Option<(Instant, u64)>,
+    /// Bytes per second since the previous refresh.
+    speed: f64,
+}
+
+impl Transfer {
+    fn update_speed(&mut self, now: Instant) {
+        let position = self.bar.position();
+
+        if let Some((time, previous)) = self.sample {
+            let elapsed = now.duration_since(time).as_secs_f64();
+            if elapsed > 0.0 {
+                self.speed = position.saturating_sub(previous) as f64 / elapsed;
+            }
+        }
+
+        self.sample = Some((now, position));
+    }
+
+    fn label(&self) -> String {
+        let message = self.bar.message();
+
+        match self.progress_type {
+            ProgressType::Bytes => format!(
+                "{message}  {}/{}  {}/s",
+                DecimalBytes(self.bar.position()),
+                DecimalBytes(self.bar.length().unwrap_or_default()),
+                DecimalBytes(self.speed as u64)
+            ),
+            ProgressType::Percent => format!("{message}  {}%", self.bar.position()),
+        }
+    }
+
+    fn ratio(&self) -> f64 {
+        match self.bar.length() {
+            Some(length) if length > 0 => (self.bar.position() as f64 / length as f64).min(1.0),
+            _ => 0.0,
+        }
+    }
+}
+
+#[derive(Default)]
+struct DashboardState {
+    next_id: u64,
+    transfers: Vec<Transfer>,
+    log: VecDeque<String>,
+    /// The unfinished last line written to the log.
+    partial: String,
+    /// Messages to print once the dashboard closes.
+    messages: Vec<String>,
+    /// The number of prompts currently displayed in place of the dashboard.
+    paused: usize,
+    /// Set when the screen needs to be fully redrawn.
+    redraw: bool,
+}
+
+impl DashboardState {
+    fn push_line(&mut self, line: &str) {
+        if self.log.len() == LOG_LENGTH {
+            self.log.pop_front();
+        }
+        self.log
+            .push_back(strip_ansi_codes(line).trim_end().to_owned());
+    }
+}
+
+pub struct Dashboard {
+    state: Arc<Mutex<DashboardState>>,
+    running: Arc<AtomicBool>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Dashboard {
+    /// Switches the terminal to the dashboard and starts drawing it.
+    pub fn start() -> io::Result<Self> {
+        let terminal = Terminal::new(CrosstermBackend::new(io::stdout()))?;
+        execute!(io::stdout(), EnterAlternateScreen)?;
+
+        let state: Arc<Mutex<DashboardState>> = Default::default();
+        let running = Arc::new(AtomicBool::new(true));
+
+        let thread = {
+            let state = state.clone();
+            let running = running.clone();
+            thread::spawn(move || draw_loop(terminal, state, running))
+        };
+
+        Ok(Self {
+            state,
+            running,
+            thread: Mutex::new(Some(thread)),
+        })
+    }
+
+    /// Restores the terminal and prints the messages shown while the
+    /// dashboard was open.
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::Release);
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            let _ = thread.join();
+        }
+
+        let state = self.state.lock().unwrap();
+        if state.paused == 0 {
+            let _ = execute!(io::stdout(), LeaveAlternateScreen);
+        }
+
+        for message in state.messages.iter() {
+            println!("{message}");
+        }
+    }
+
+    pub fn add_transfer(&self, bar: ProgressBar, progress_type: ProgressType) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+
+        state.transfers.push(Transfer {
+            id,
+            bar,
+            progress_type,
+            sample: None,
+            speed: 0.0,
+        });
+
+        id
+    }
+
+    pub fn remove_transfer(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.transfers.retain(|transfer| transfer.id != id);
+    }
+
+    /// Adds a message to the log that is also printed when the dashboard
+    /// closes.
+    pub fn message(&self, message: &str) {
+        let mut state = self.state.lock().unwrap();
+        for line in message.lines() {
+            state.push_line(line);
+        }
+        state.messages.push(message.to_owned());
+    }
+
+    /// Adds log output, which may contain partial lines.
+    pub fn write_log(&self, buf: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        let text = String::from_utf8_lossy(buf);
+
+        let mut pending = std::mem::take(&mut state.partial);
+        pending.push_str(&text);
+
+        let mut lines: Vec<&str> = pending.split('\n').collect();
+        let partial = lines.pop().unwrap_or_default().to_owned();
+        for line in lines {
+            if !line.trim().is_empty() {
+                state.push_line(line);
+            }
+        }
+
+        state.partial = partial;
+    }
+
+    /// Returns the terminal to normal so a prompt can be displayed.
+    pub fn pause(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.paused += 1;
+        if state.paused == 1 {
+            let _ = execute!(io::stdout(), LeaveAlternateScreen);
+        }
+    }
+
+    pub fn resume(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.paused -= 1;
+        if state.paused == 0 {
+            let _ = execute!(io::stdout(), EnterAlternateScreen);
+            state.redraw = true;
+        }
+    }
+}
+
+fn draw_loop(
+    mut terminal: Terminal<CrosstermBackend<Stdout>>,
+    state: Arc<Mutex<DashboardState>>,
+    running: Arc<AtomicBool>,
+) {
+    while running.load(Ordering::Acquire) {
+        {
+            let mut state = state.lock().unwrap();
+
+            if state.paused == 0 {
+                if state.redraw {
+                    let _ = terminal.clear();
+                    state.redraw = false;
+                }
+
+                let now = Instant::now();
+                for transfer in state.transfers.iter_mut() {
+                    transfer.update_speed(now);
+                }
+
+                let _ = terminal.draw(|frame| draw(frame, &state));
+            }
+        }
+
+        thread::sleep(REFRESH);
+    }
+}
+
+fn draw(frame: &mut Frame, state: &DashboardState) {
+    let areas = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(3), Constraint::Length(LOG_HEIGHT)])
+        .split(frame.size());
+
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .title(format!("Transfers ({})", state.transfers.len()));
+    let inner = block.inner(areas[0]);
+    frame.render_widget(block, areas[0]);
+
+    for (row, transfer) in state
+        .transfers
+        .iter()
+        .take(inner.height as usize)
+        .enumerate()
+    {
+        let area = Rect {
+            x: inner.x,
+            y: inner.y + row as u16,
+            width: inner.width,
+            height: 1,
+        };
+
+        let color = match transfer.progress_type {
+            ProgressType::Bytes => Color::Cyan,
+            ProgressType::Percent => Color::Magenta,
+        };
+
+        frame.render_widget(
+            Gauge::default()
+                .gauge_style(Style::default().fg(color))
+                .ratio(transfer.ratio())
+                .label(transfer.label()),
+            area,
+        );
+    }
+
+    let visible = areas[1].height.saturating_sub(2) as usize;
+    let lines: Vec<ListItem> = state
+        .log
+        .iter()
+        .skip(state.log.len().saturating_sub(visible))
+        .map(|line| ListItem::new(line.as_str()))
+        .collect();
+    frame.render_widget(
+        List::new(lines).block(Block::default().borders(Borders::ALL).title("Log")),
+        areas[1],
+    );
+}
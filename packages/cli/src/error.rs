@@ -28,6 +28,10 @@ pub enum Error {
     UnknownServer(String),
     #[error("{0}")]
     ErrorMessage(String),
+    #[error(
+        "Unable to prompt for '{0}' when running non-interactively, pass it as an option instead"
+    )]
+    PromptUnavailable(String),
     #[error("Unknown error")]
     Unknown,
 }
@@ -8,22 +8,28 @@ use async_std::{
     stream::StreamExt,
 };
 use async_trait::async_trait;
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueHint};
 use enum_dispatch::enum_dispatch;
 use error::{err, Error};
-use flick_sync::{FlickSync, Server, CONFIG_FILE, STATE_FILE};
-use sync::{Prune, Sync};
+use flick_sync::{
+    lock_store, FlickSync, Server, CONFIG_FILE, LOCK_FILE, STATE_DATABASE, STATE_FILE,
+};
+use sync::{Plan, Prune, Sync, Wait};
 use tracing::{error, trace};
 
 mod console;
+mod dashboard;
 mod error;
+mod select;
 mod server;
 mod sync;
 mod util;
 
 pub use crate::console::Console;
-use server::{Add, Login, Rebuild, Remove};
-use util::{List, Stats};
+use server::{Add, Login, Media, Pin, Rebuild, Redownload, Remove, Rename, Skip, Wizard};
+use util::{
+    Completions, Config, Doctor, Eject, History, List, Schema, Snapshot, Stats, Status, Verify,
+};
 
 pub type Result<T = ()> = std::result::Result<T, Error>;
 
@@ -41,12 +47,78 @@ pub enum Command {
     Prune,
     /// Performs a full sync.
     Sync,
+    /// Waits until no sync is running, and by default until everything has
+    /// downloaded, for use in scripts.
+    Wait,
+    /// Estimates how much a sync would download, grouped by show and
+    /// collection.
+    Plan,
     /// List download statistics.
     Stats,
+    /// Summarises the store for each server without connecting to them.
+    Status,
+    /// Lists the recent syncs of each server.
+    History,
     /// Lists sync items.
     List,
     /// Attempts to rebuild a corrupt state file.
     Rebuild,
+    /// Marks an item to be skipped when downloading.
+    Skip,
+    /// Marks an item to never be evicted when space is short.
+    Pin,
+    /// Lists or chooses the media version synced for a video.
+    Media,
+    /// Sets an explicit path for a video instead of using the naming
+    /// templates.
+    Rename,
+    /// Deletes the downloads for an item so they are fetched again.
+    Redownload,
+    /// Reads or changes a setting in the config file.
+    Config,
+    /// Checks completed downloads for missing or damaged files.
+    Verify,
+    /// Checks for common problems with the store and servers.
+    Doctor,
+    /// Archives the server metadata seen by the last sync.
+    Snapshot,
+    /// Guides choosing whole libraries to sync that fit in the free space.
+    Wizard,
+    /// Flushes the store to disk and unmounts the drive holding it.
+    Eject,
+}
+
+impl Command {
+    /// Whether the command changes the state, config or downloads. Only one
+    /// of these can use the store at a time, commands that only read from it
+    /// can run while a sync is in progress.
+    fn writes(&self) -> bool {
+        match self {
+            Command::Plan(_)
+            | Command::Stats(_)
+            | Command::Status(_)
+            | Command::History(_)
+            | Command::List(_)
+            | Command::Doctor(_)
+            // Waiting and ejecting take the lock themselves.
+            | Command::Wait(_)
+            | Command::Eject(_) => false,
+            Command::Config(config) => config.writes(),
+            Command::Verify(verify) => verify.writes(),
+            _ => true,
+        }
+    }
+}
+
+/// Most commands use a store, the rest are run before one is opened.
+#[derive(Subcommand)]
+enum Action {
+    #[clap(flatten)]
+    Store(Command),
+    /// Prints a JSON Schema describing the config or state file format.
+    Schema(Schema),
+    /// Prints a completion script for a shell.
+    Completions(Completions),
 }
 
 #[async_trait]
@@ -78,11 +150,20 @@ pub async fn select_servers(flick_sync: &FlickSync, ids: &Vec<String>) -> Result
 #[clap(author, version)]
 struct Args {
     /// The storage location to use.
-    #[clap(short, long, env)]
+    #[clap(short, long, env, value_hint = ValueHint::DirPath)]
     store: Option<PathBuf>,
 
+    /// Wait for another process using the store to finish instead of failing.
+    #[clap(long, global = true)]
+    wait_lock: bool,
+
+    /// Fail instead of prompting for anything. The default when stdin is not
+    /// a terminal.
+    #[clap(long, global = true)]
+    non_interactive: bool,
+
     #[clap(subcommand)]
-    command: Command,
+    action: Action,
 }
 
 async fn validate_store(store: Option<PathBuf>) -> Result<PathBuf> {
@@ -110,13 +191,14 @@ async fn validate_store(store: Option<PathBuf>) -> Result<PathBuf> {
         }
     }
 
-    let state = path.join(STATE_FILE);
-    if let Ok(stats) = metadata(&state).await {
-        if stats.is_file() {
-            trace!("Store contained state file");
-            return Ok(path);
-        } else {
-            return err("Store contained a non-file where a state file was expected");
+    for state_file in [STATE_FILE, STATE_DATABASE] {
+        if let Ok(stats) = metadata(path.join(state_file)).await {
+            if stats.is_file() {
+                trace!("Store contained state file");
+                return Ok(path);
+            } else {
+                return err("Store contained a non-file where a state file was expected");
+            }
         }
     }
 
@@ -135,7 +217,7 @@ async fn validate_store(store: Option<PathBuf>) -> Result<PathBuf> {
 
         let typ = entry.file_type().await?;
         if typ.is_file() {
-            if name != CONFIG_FILE {
+            if name != CONFIG_FILE && name != LOCK_FILE {
                 error!("{} exists in a potential new store", name);
                 return err("New store is not empty");
             }
@@ -149,17 +231,40 @@ async fn validate_store(store: Option<PathBuf>) -> Result<PathBuf> {
 }
 
 async fn wrapped_main(args: Args, console: Console) -> Result {
+    let command = match args.action {
+        Action::Store(command) => command,
+        Action::Schema(schema) => return schema.print(console),
+        Action::Completions(completions) => {
+            completions.generate();
+            return Ok(());
+        }
+    };
+
     let store = validate_store(args.store).await?;
+    // Diagnostics should still run while another process holds the lock or
+    // when the store cannot be opened.
+    if let Command::Doctor(doctor) = command {
+        return doctor.diagnose(&store, console).await;
+    }
+
+    let _lock = if command.writes() {
+        Some(lock_store(&store, args.wait_lock).await?)
+    } else {
+        None
+    };
     let flick_sync = FlickSync::new(&store).await?;
 
-    args.command.run(flick_sync, console).await
+    command.run(flick_sync, console).await
 }
 
 #[async_std::main]
 async fn main() -> Result {
     let args: Args = Args::parse();
 
-    let console = Console::default();
+    let mut console = Console::default();
+    if args.non_interactive {
+        console.set_interactive(false);
+    }
 
     let log_filter = env::var("RUST_LOG").unwrap_or_else(|_| "flick_sync=trace,warn".to_string());
 
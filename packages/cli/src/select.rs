@@ -0,0 +1,269 @@
+use std::{collections::HashSet, fmt, str::FromStr};
+
+use clap::{Args, ValueEnum};
+use flick_sync::{FlickSync, ItemType, Server, Video};
+use regex::Regex;
+use tracing::error;
+
+use crate::{error::err, select_servers, Console, Error, Result};
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum SelectType {
+    Movie,
+    Episode,
+    Show,
+    Season,
+    Playlist,
+    Collection,
+}
+
+impl SelectType {
+    fn from_item(item_type: &ItemType) -> Option<Self> {
+        match item_type {
+            ItemType::Movie => Some(Self::Movie),
+            ItemType::Episode => Some(Self::Episode),
+            ItemType::Show => Some(Self::Show),
+            ItemType::Season => Some(Self::Season),
+            ItemType::Playlist => Some(Self::Playlist),
+            ItemType::MovieCollection | ItemType::ShowCollection => Some(Self::Collection),
+            ItemType::Unknown => None,
+        }
+    }
+
+    fn from_video(video: &Video) -> Self {
+        match video {
+            Video::Movie(_) => Self::Movie,
+            Video::Episode(_) => Self::Episode,
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Field {
+    Id,
+    Title,
+}
+
+#[derive(Clone)]
+enum Pattern {
+    Exact(String),
+    Regex(Regex),
+}
+
+/// A single `field="value"` or `field~="regex"` match.
+#[derive(Clone)]
+pub struct Match {
+    field: Field,
+    pattern: Pattern,
+}
+
+impl Match {
+    fn is_match(&self, id: &str, title: &str) -> bool {
+        let value = match self.field {
+            Field::Id => id,
+            Field::Title => title,
+        };
+
+        match self.pattern {
+            Pattern::Exact(ref expected) => value == expected,
+            Pattern::Regex(ref regex) => regex.is_match(value),
+        }
+    }
+}
+
+impl FromStr for Match {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (field, value) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Expected field=value but got '{s}'"))?;
+
+        let (field, is_regex) = match field.strip_suffix('~') {
+            Some(field) => (field.trim(), true),
+            None => (field.trim(), false),
+        };
+
+        let field = match field {
+            "id" => Field::Id,
+            "title" => Field::Title,
+            _ => return Err(format!("Unknown field '{field}'")),
+        };
+
+        let value = value.trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|v| v.strip_suffix('"'))
+            .unwrap_or(value);
+
+        let pattern = if is_regex {
+            Pattern::Regex(Regex::new(value).map_err(|e| e.to_string())?)
+        } else {
+            Pattern::Exact(value.to_owned())
+        };
+
+        Ok(Self { field, pattern })
+    }
+}
+
+/// An item chosen by a [`Selector`].
+#[derive(Clone)]
+pub struct Selected {
+    pub server: Server,
+    pub id: String,
+    pub title: String,
+}
+
+impl fmt::Display for Selected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "'{}' ({}/{})", self.title, self.server.id(), self.id)
+    }
+}
+
+/// Chooses the items a command operates on, either a single item or every
+/// item matching some filters.
+#[derive(Args)]
+pub struct Selector {
+    /// The server the item is on.
+    #[clap(requires = "id")]
+    server: Option<String>,
+    /// The id of the item.
+    id: Option<String>,
+    /// Only select items from this server. Can be repeated.
+    #[clap(short = 's', long = "server", conflicts_with = "id")]
+    servers: Vec<String>,
+    /// Only select items of this type.
+    #[clap(short = 't', long = "type", conflicts_with = "id")]
+    item_type: Option<SelectType>,
+    /// Select items where a field matches, either `field="value"` or
+    /// `field~="regex"`. The `id` and `title` fields are supported. Can be
+    /// repeated.
+    #[clap(short, long = "match", conflicts_with = "id")]
+    matches: Vec<Match>,
+    /// List the items that would be affected without changing anything.
+    #[clap(long)]
+    dry_run: bool,
+}
+
+impl Selector {
+    /// The servers given with --server, or every server.
+    pub async fn servers(&self, flick_sync: &FlickSync) -> Result<Vec<Server>> {
+        select_servers(flick_sync, &self.servers).await
+    }
+
+    async fn selected_servers(&self, flick_sync: &FlickSync) -> Result<Vec<Server>> {
+        if let Some(ref id) = self.server {
+            return Ok(vec![flick_sync
+                .server(id)
+                .await
+                .ok_or_else(|| Error::UnknownServer(id.clone()))?]);
+        }
+
+        if self.servers.is_empty() && self.item_type.is_none() && self.matches.is_empty() {
+            return err("Either an item or a selector must be given");
+        }
+
+        select_servers(flick_sync, &self.servers).await
+    }
+
+    fn is_match(&self, id: &str, title: &str, item_type: Option<SelectType>) -> bool {
+        if let Some(ref expected) = self.id {
+            return id == expected;
+        }
+
+        if self.item_type.is_some() && self.item_type != item_type {
+            return false;
+        }
+
+        self.matches.iter().all(|m| m.is_match(id, title))
+    }
+
+    /// The matching items in the sync lists.
+    pub async fn sync_items(&self, flick_sync: &FlickSync) -> Result<Vec<Selected>> {
+        let mut selected = Vec::new();
+
+        for server in self.selected_servers(flick_sync).await? {
+            let syncs = match server.list_syncs(false).await {
+                Ok(syncs) => syncs,
+                Err(e) => {
+                    error!(server=server.id(), error=?e, "Failed to list sync items");
+                    continue;
+                }
+            };
+
+            for sync in syncs {
+                if self.is_match(
+                    &sync.id,
+                    &sync.title,
+                    SelectType::from_item(&sync.item_type),
+                ) {
+                    selected.push(Selected {
+                        server: server.clone(),
+                        id: sync.id,
+                        title: sync.title,
+                    });
+                }
+            }
+        }
+
+        Ok(selected)
+    }
+
+    /// The matching items from both the sync lists and the synced videos. A
+    /// single item that cannot be found is still returned as-is.
+    pub async fn items(&self, flick_sync: &FlickSync) -> Result<Vec<Selected>> {
+        let mut selected = self.sync_items(flick_sync).await?;
+        let mut seen: HashSet<(String, String)> = selected
+            .iter()
+            .map(|s| (s.server.id().to_owned(), s.id.clone()))
+            .collect();
+
+        for server in self.selected_servers(flick_sync).await? {
+            for video in server.videos().await {
+                let title = video.title().await;
+                if !self.is_match(video.id(), &title, Some(SelectType::from_video(&video))) {
+                    continue;
+                }
+
+                if seen.insert((server.id().to_owned(), video.id().to_owned())) {
+                    selected.push(Selected {
+                        server: server.clone(),
+                        id: video.id().to_owned(),
+                        title,
+                    });
+                }
+            }
+        }
+
+        if let (Some(server), Some(id)) = (&self.server, &self.id) {
+            if selected.is_empty() {
+                if let Some(server) = flick_sync.server(server).await {
+                    selected.push(Selected {
+                        server,
+                        id: id.clone(),
+                        title: id.clone(),
+                    });
+                }
+            }
+        }
+
+        Ok(selected)
+    }
+
+    /// Lists the selected items for a dry run. Returns whether the command
+    /// should go on to make changes.
+    pub fn preview(&self, console: &Console, action: &str, selected: &[Selected]) -> bool {
+        if selected.is_empty() {
+            console.println("No items matched.");
+            return false;
+        }
+
+        if self.dry_run {
+            for item in selected {
+                console.println(format!("Would {action} {item}"));
+            }
+        }
+
+        !self.dry_run
+    }
+}
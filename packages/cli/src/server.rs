@@ -1,3 +1,6 @@
+use std::time::{Duration, Instant};
+
+use async_std::task::sleep;
 use async_trait::async_trait;
 use clap::Args;
 use flick_sync::{
@@ -5,26 +8,197 @@ use flick_sync::{
         self,
         device::{Device, DeviceConnection},
         library::{Item, MetadataItem},
-        HttpClient, MyPlex, MyPlexBuilder, Server as PlexServer,
+        HttpClient, MyPlex, MyPlexBuilder, PinManager, Server as PlexServer,
     },
-    FlickSync, Server, ServerConnection,
+    ErrorClass, Filter, FlickSync, Server, ServerConnection, Video,
 };
+use fs2::available_space;
+use indicatif::DecimalBytes;
 use tracing::{error, warn};
 use url::Url;
 
-use crate::{error::err, Console, Error, Result, Runnable};
+use crate::{
+    error::err,
+    select::{Selected, Selector},
+    select_servers, Console, Error, Result, Runnable,
+};
+
+/// How long to wait for a plex.tv/link code to be entered.
+const LINK_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+const LINK_POLL_INTERVAL: Duration = Duration::from_secs(2);
 
 #[derive(Args)]
 pub struct Login {
     /// An identifier for the server.
-    id: String,
+    #[clap(required_unless_present = "check")]
+    id: Option<String>,
     /// The default transcode profile to use for items.
     #[clap(short, long)]
     transcode_profile: Option<String>,
+    /// Reports whether the stored tokens still work without logging in again.
+    /// Checks every server unless an identifier is given.
+    #[clap(long)]
+    check: bool,
+    #[clap(flatten)]
+    credentials: Credentials,
+}
+
+/// Login details that can be passed instead of being prompted for, needed
+/// when running non-interactively.
+#[derive(Args)]
+struct Credentials {
+    /// Connect directly to the server at this address instead of through a
+    /// Plex account, without contacting plex.tv. Unclaimed servers need no
+    /// token.
+    #[clap(long, conflicts_with_all = ["username", "link"])]
+    url: Option<String>,
+    /// The Plex account to log in with.
+    #[clap(long, env = "PLEX_USERNAME")]
+    username: Option<String>,
+    /// The password of the Plex account.
+    #[clap(long, env = "PLEX_PASSWORD", hide_env_values = true)]
+    password: Option<String>,
+    /// The one time code for accounts using two factor authentication.
+    #[clap(long)]
+    otp: Option<String>,
+    /// The name of the Plex Home user to sync as.
+    #[clap(long)]
+    user: Option<String>,
+    /// The PIN of a protected Plex Home user.
+    #[clap(long, env = "PLEX_PIN", hide_env_values = true)]
+    pin: Option<String>,
+    /// The name of the server to sync when the account can access several.
+    #[clap(long)]
+    server_name: Option<String>,
+    /// The X-Plex-Token to use for a server connected to directly.
+    #[clap(long, env = "PLEX_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+    /// Log in by entering a code at plex.tv/link instead of with a password,
+    /// needed for accounts that sign in through another provider.
+    #[clap(long, conflicts_with_all = ["url", "password", "otp"])]
+    link: bool,
+}
+
+/// Logs in by displaying a code for the user to enter at plex.tv/link and
+/// waiting for it to be linked to their account.
+async fn link_auth(console: &Console, client: &HttpClient) -> Result<MyPlex> {
+    let manager = PinManager::new(client.clone());
+    let pin = manager.pin().await?;
+
+    console.println(format!(
+        "Visit https://plex.tv/link and enter the code {}",
+        pin.code()
+    ));
+
+    let started = Instant::now();
+    let token = loop {
+        match pin.check().await {
+            Ok(info) => {
+                if let Some(token) = info.auth_token {
+                    break token;
+                }
+            }
+            Err(plex_api::Error::PinNotLinked) => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        if started.elapsed() > LINK_TIMEOUT {
+            return err("The code was not entered in time, log in again for a new code");
+        }
+
+        sleep(LINK_POLL_INTERVAL).await;
+    };
+
+    Ok(MyPlexBuilder::default()
+        .set_client(client.clone())
+        .set_token(token)
+        .build()
+        .await?)
 }
 
-async fn myplex_auth(console: &Console, client: &HttpClient, username: &str) -> Result<MyPlex> {
-    let password = console.password("Password");
+/// Completes a server address that is missing the scheme or port.
+fn server_url(url: &str) -> String {
+    if url.contains("://") {
+        url.to_owned()
+    } else if url.contains(':') {
+        format!("http://{url}")
+    } else {
+        format!("http://{url}:32400")
+    }
+}
+
+/// Connects straight to a server without involving plex.tv. Unclaimed servers
+/// accept requests without a token so one is only asked for when the server
+/// rejects the connection.
+async fn connect_direct(
+    flick_sync: &FlickSync,
+    console: &Console,
+    url: &str,
+    token: &Option<String>,
+) -> Result<(PlexServer, String)> {
+    let client = flick_sync.client().await;
+
+    let token = match token {
+        Some(token) => token.clone(),
+        None => match PlexServer::new(url.to_owned(), client.clone()).await {
+            Ok(server) => return Ok((server, String::new())),
+            Err(e) => {
+                let e = flick_sync::Error::from(e);
+                if e.class() != ErrorClass::Auth {
+                    return Err(e.into());
+                }
+
+                console.input("X-Plex-Token")?
+            }
+        },
+    };
+
+    let server = PlexServer::new(url.to_owned(), client.set_x_plex_token(token.clone())).await?;
+    Ok((server, token))
+}
+
+/// Uses the value passed as an option or else prompts for it.
+fn given_or_input(console: &Console, given: &Option<String>, prompt: &str) -> Result<String> {
+    match given {
+        Some(value) => Ok(value.clone()),
+        None => console.input(prompt),
+    }
+}
+
+/// Picks the named choice when a name was passed as an option, otherwise
+/// prompts when there is more than one choice.
+fn given_or_select(
+    console: &Console,
+    given: &Option<String>,
+    prompt: &str,
+    names: &[String],
+) -> Result<usize> {
+    match given {
+        Some(name) => names.iter().position(|n| n == name).ok_or_else(|| {
+            Error::ErrorMessage(format!(
+                "Unknown choice {name}, expected one of {}",
+                names.join(", ")
+            ))
+        }),
+        None if names.len() == 1 => Ok(0),
+        None => console.select(prompt, names),
+    }
+}
+
+async fn myplex_auth(
+    console: &Console,
+    client: &HttpClient,
+    username: &str,
+    credentials: &Credentials,
+) -> Result<MyPlex> {
+    if credentials.link {
+        return link_auth(console, client).await;
+    }
+
+    let password = match credentials.password {
+        Some(ref password) => password.clone(),
+        None => console.password("Password")?,
+    };
 
     let myplex = match MyPlexBuilder::default()
         .set_client(client.clone())
@@ -35,7 +209,7 @@ async fn myplex_auth(console: &Console, client: &HttpClient, username: &str) ->
         Ok(p) => p,
         Err(e) => {
             if matches!(e, plex_api::Error::OtpRequired) {
-                let otp = console.input("OTP");
+                let otp = given_or_input(console, &credentials.otp, "OTP")?;
                 MyPlexBuilder::default()
                     .set_client(client.clone())
                     .set_username_and_password(username, password.clone())
@@ -51,7 +225,22 @@ async fn myplex_auth(console: &Console, client: &HttpClient, username: &str) ->
     Ok(myplex)
 }
 
-async fn reconnect_server(server: &Server, flick_sync: &FlickSync, console: &Console) -> Result {
+async fn reconnect_server(
+    server: &Server,
+    flick_sync: &FlickSync,
+    console: &Console,
+    credentials: &Credentials,
+) -> Result {
+    if let Some(ref url) = credentials.url {
+        let url = server_url(url);
+        let (plex_server, token) =
+            connect_direct(flick_sync, console, &url, &credentials.token).await?;
+        return server
+            .update_direct_connection(url, &token, plex_server)
+            .await
+            .map_err(Into::into);
+    }
+
     let connection = server.connection().await;
 
     match connection {
@@ -63,32 +252,25 @@ async fn reconnect_server(server: &Server, flick_sync: &FlickSync, console: &Con
             let client = flick_sync.client().await;
 
             console.println(format!("Username: {username}"));
-            let myplex = myplex_auth(console, &client, &username).await?;
+            let myplex = myplex_auth(console, &client, &username, credentials).await?;
             let auth_token = myplex.client().x_plex_token().to_owned();
 
             let home = myplex.home()?;
             let users = home.users().await?;
 
             let user = if let Some(user) = users.iter().find(|u| u.uuid == user_id) {
-                console.println(format!("User: {}", user.title));
                 user
             } else {
-                let index = if users.len() == 1 {
-                    console.println(format!("User: {}", users[0].title));
-                    0
-                } else {
-                    let names = users
-                        .iter()
-                        .map(|u| u.title.clone())
-                        .collect::<Vec<String>>();
-                    console.select("Select user", &names)
-                };
-
-                &users[index]
+                let names = users
+                    .iter()
+                    .map(|u| u.title.clone())
+                    .collect::<Vec<String>>();
+                &users[given_or_select(console, &credentials.user, "Select user", &names)?]
             };
+            console.println(format!("User: {}", user.title));
 
             let pin = if user.protected {
-                console.input("Enter PIN")
+                given_or_input(console, &credentials.pin, "Enter PIN")?
             } else {
                 "".to_string()
             };
@@ -117,67 +299,158 @@ async fn reconnect_server(server: &Server, flick_sync: &FlickSync, console: &Con
                 console.println("Expected server no longer exists.")
             }
         }
-        ServerConnection::Direct { url: _ } => {
-            console.println("No need to re-authenticate with direct connection.");
+        ServerConnection::Direct { url } => {
+            if credentials.token.is_none() && server.refresh_token().await? {
+                console.println("Refreshed the token through a Plex account.");
+                return Ok(());
+            }
+
+            let (plex_server, token) =
+                connect_direct(flick_sync, console, &url, &credentials.token).await?;
+
+            server.update_connection(&token, plex_server).await?;
         }
     }
     Ok(())
 }
 
-async fn create_server(args: Login, flick_sync: FlickSync, console: Console) -> Result {
-    let client = flick_sync.client().await;
+async fn check_auth(servers: Vec<Server>, console: &Console) -> Result {
+    let mut failed = false;
 
-    let method = console.select(
-        "Select how to connect to the new server",
-        &["MyPlex", "Direct"],
-    );
+    for (pos, server) in servers.iter().enumerate() {
+        if pos > 0 {
+            console.println("");
+        }
 
-    if method == 1 {
-        let mut url = console.input("Enter the server address (IP:port or URL)");
-        if !url.contains("://") {
-            if !url.contains(':') {
-                url = format!("http://{}:32400", url);
-            } else {
-                url = format!("http://{}", url);
+        console.println(format!("Server {}:", server.id()));
+        let check = match server.check_auth().await {
+            Ok(check) => check,
+            Err(e) => {
+                console.println(format!("  Unable to check: {e}"));
+                failed = true;
+                continue;
             }
+        };
+
+        match check.error {
+            Some(error) => console.println(format!("  Token: invalid ({error})")),
+            None => console.println("  Token: valid"),
+        }
+        if let Some(owner) = check.owner {
+            console.println(format!("  Owner: {owner}"));
         }
+        if let Some(plex_pass) = check.plex_pass {
+            console.println(format!(
+                "  Plex Pass: {}",
+                if plex_pass { "active" } else { "inactive" }
+            ));
+        }
+        if !check.servers.is_empty() {
+            console.println(format!(
+                "  Accessible servers: {}",
+                check.servers.join(", ")
+            ));
+        }
+
+        failed |= !check.valid;
+        if !check.valid {
+            continue;
+        }
+
+        match server.features(false).await {
+            Ok(features) => {
+                let mut limits = Vec::new();
+                if !features.plex_account {
+                    limits.push(
+                        "Plex Home users and automatic address changes need a plex.tv account",
+                    );
+                }
+                if !features.claimed {
+                    limits.push("the server is not signed in to plex.tv");
+                }
+                if !features.downloads {
+                    limits.push("the server does not allow downloads");
+                }
+                if !features.transcoding {
+                    limits.push("the server cannot transcode, use the local transcoder");
+                }
 
-        let server = PlexServer::new(url.clone(), client).await?;
+                for limit in limits {
+                    console.println(format!("  Limitation: {limit}"));
+                }
+            }
+            Err(e) => warn!(server = server.id(), error = ?e, "Failed to detect server features"),
+        }
+    }
 
+    if failed {
+        err("Some servers need to be logged in to again")
+    } else {
+        Ok(())
+    }
+}
+
+async fn create_server(
+    id: String,
+    transcode_profile: Option<String>,
+    credentials: Credentials,
+    flick_sync: FlickSync,
+    console: Console,
+) -> Result {
+    let client = flick_sync.client().await;
+
+    let method = if credentials.url.is_some() {
+        1
+    } else if credentials.username.is_some() || credentials.link {
+        0
+    } else {
+        console.select(
+            "Select how to connect to the new server",
+            &["MyPlex", "Direct"],
+        )?
+    };
+
+    if method == 1 {
+        let url = server_url(&given_or_input(
+            &console,
+            &credentials.url,
+            "Enter the server address (IP:port or URL)",
+        )?);
+
+        let (server, auth_token) =
+            connect_direct(&flick_sync, &console, &url, &credentials.token).await?;
         let connection = ServerConnection::Direct { url };
-        let auth_token = server.client().x_plex_token().to_owned();
 
         flick_sync
-            .add_server(
-                &args.id,
-                server,
-                &auth_token,
-                connection,
-                args.transcode_profile,
-            )
+            .add_server(&id, server, &auth_token, connection, transcode_profile)
             .await?;
     } else {
-        let username = console.input("Username");
-        let myplex = myplex_auth(&console, &client, &username).await?;
+        let (myplex, username) = if credentials.link {
+            let myplex = link_auth(&console, &client).await?;
+            let username = myplex
+                .account()
+                .map(|account| account.username.clone())
+                .unwrap_or_default();
+            (myplex, username)
+        } else {
+            let username = given_or_input(&console, &credentials.username, "Username")?;
+            let myplex = myplex_auth(&console, &client, &username, &credentials).await?;
+            (myplex, username)
+        };
         let auth_token = myplex.client().x_plex_token().to_owned();
 
         let home = myplex.home()?;
         let users = home.users().await?;
 
-        let index = if users.len() == 1 {
-            console.println(format!("User: {}", users[0].title));
-            0
-        } else {
-            let names = users
-                .iter()
-                .map(|u| u.title.clone())
-                .collect::<Vec<String>>();
-            console.select("Select user", &names)
-        };
+        let names = users
+            .iter()
+            .map(|u| u.title.clone())
+            .collect::<Vec<String>>();
+        let user = &users[given_or_select(&console, &credentials.user, "Select user", &names)?];
+        console.println(format!("User: {}", user.title));
 
-        let user = &users[index];
         let pin = if user.protected {
-            console.input("Enter PIN")
+            given_or_input(&console, &credentials.pin, "Enter PIN")?
         } else {
             "".to_string()
         };
@@ -196,12 +469,9 @@ async fn create_server(args: Login, flick_sync: FlickSync, console: Console) ->
 
         let device = if devices.is_empty() {
             return err("No servers found in this account");
-        } else if devices.len() == 1 {
-            &devices[0]
         } else {
             let names: Vec<String> = devices.iter().map(|d| d.name().to_owned()).collect();
-            let index = console.select("Select server", &names);
-            &devices[index]
+            &devices[given_or_select(&console, &credentials.server_name, "Select server", &names)?]
         };
 
         console.println(format!("Got device {}", device.identifier()));
@@ -218,13 +488,7 @@ async fn create_server(args: Login, flick_sync: FlickSync, console: Console) ->
         };
 
         flick_sync
-            .add_server(
-                &args.id,
-                server,
-                &auth_token,
-                connection,
-                args.transcode_profile,
-            )
+            .add_server(&id, server, &auth_token, connection, transcode_profile)
             .await?;
     }
 
@@ -234,56 +498,212 @@ async fn create_server(args: Login, flick_sync: FlickSync, console: Console) ->
 #[async_trait]
 impl Runnable for Login {
     async fn run(self, flick_sync: FlickSync, console: Console) -> Result {
-        match flick_sync.server(&self.id).await {
-            Some(server) => reconnect_server(&server, &flick_sync, &console).await,
-            None => create_server(self, flick_sync, console).await,
+        if self.check {
+            let ids: Vec<String> = self.id.into_iter().collect();
+            let servers = select_servers(&flick_sync, &ids).await?;
+            return check_auth(servers, &console).await;
+        }
+
+        let id = self.id.unwrap_or_default();
+        match flick_sync.server(&id).await {
+            Some(server) => {
+                reconnect_server(&server, &flick_sync, &console, &self.credentials).await
+            }
+            None => {
+                create_server(
+                    id,
+                    self.transcode_profile,
+                    self.credentials,
+                    flick_sync,
+                    console,
+                )
+                .await
+            }
         }
     }
 }
 
 #[derive(Args)]
 pub struct Add {
-    /// The web url of the item to add to the list to sync.
-    url: String,
+    /// The web url of the item to add to the list to sync, or its rating key
+    /// when --server is given. Movies, shows, seasons, episodes, collections
+    /// and playlists can be added.
+    #[clap(required_unless_present_any = ["library", "query"])]
+    url: Option<String>,
+    /// The server to add items from when using a rating key or a filter.
+    #[clap(long)]
+    server: Option<String>,
+    /// The library, by name or id, to add matching items from.
+    #[clap(long, requires = "server", conflicts_with = "url")]
+    library: Option<String>,
+    /// Only add items from the library that match this filter, e.g.
+    /// "genre=Documentary year>=2020". Supports genre, collection, watched,
+    /// year and resolution combined with &, |, ! and parentheses.
+    #[clap(short, long, requires = "library")]
+    filter: Option<Filter>,
+    /// Add a query that is re-evaluated on every sync instead of a fixed
+    /// list of items, e.g. 'library("Movies") & (genre("Comedy") |
+    /// year>=2020) & !watched'.
+    #[clap(long, requires = "server", conflicts_with_all = ["url", "library"])]
+    query: Option<String>,
     /// The transcode profile to use for this item.
     #[clap(short, long)]
     profile: Option<String>,
     /// Only sync unplayed items
     #[clap(short, long)]
     only_unplayed: bool,
+    /// For shows and seasons only sync this many of the next unplayed episodes.
+    #[clap(short, long)]
+    max_episodes: Option<u32>,
+    /// For shows only sync the latest season.
+    #[clap(short, long)]
+    latest_season: bool,
+}
+
+/// Extracts the server's machine identifier and the item's rating key from a
+/// Plex web url.
+fn parse_web_url(url: &str) -> Result<(String, String)> {
+    let unexpected = || Error::ErrorMessage("Unexpected URL format".to_string());
+
+    let url = Url::parse(url)?;
+    let fragment = url.fragment().ok_or_else(unexpected)?;
+    if fragment.get(0..1) != Some("!") {
+        return Err(unexpected());
+    }
+    let fragment = &fragment[1..];
+
+    let url = Url::options()
+        .base_url(Some(&Url::parse("https://nowhere.flick-sync")?))
+        .parse(fragment)?;
+
+    let mut segments = url.path_segments().ok_or_else(unexpected)?;
+    if !matches!(segments.next(), Some("server")) {
+        return Err(unexpected());
+    }
+
+    let id = segments.next().ok_or_else(unexpected)?;
+    let key = url
+        .query_pairs()
+        .find_map(|(k, v)| if k == "key" { Some(v) } else { None })
+        .ok_or_else(unexpected)?;
+
+    // Keys look like `/library/metadata/<rating key>` possibly followed by
+    // `/children` for seasons and shows.
+    let rating_key = key
+        .split('/')
+        .skip_while(|s| *s != "metadata")
+        .nth(1)
+        .filter(|s| !s.is_empty())
+        .ok_or_else(unexpected)?;
+
+    Ok((id.to_owned(), rating_key.to_owned()))
+}
+
+impl Add {
+    async fn add_from_library(self, console: Console, server: Server, library: String) -> Result {
+        let titles = server
+            .add_filtered_syncs(
+                &library,
+                &self.filter.unwrap_or_default(),
+                self.profile,
+                self.only_unplayed,
+                self.max_episodes,
+                self.latest_season,
+            )
+            .await?;
+
+        if titles.is_empty() {
+            console.println("No items matched the filter.");
+        }
+
+        for title in titles {
+            console.println(format!(
+                "Added '{}' to the sync list for {}",
+                title,
+                server.id()
+            ));
+        }
+
+        Ok(())
+    }
+
+    async fn add_item(
+        self,
+        console: Console,
+        server: Server,
+        plex_server: PlexServer,
+        rating_key: &str,
+    ) -> Result {
+        let item = plex_server.item_by_id(rating_key).await?;
+        if matches!(
+            item,
+            Item::Photo(_)
+                | Item::Artist(_)
+                | Item::MusicAlbum(_)
+                | Item::Track(_)
+                | Item::PhotoPlaylist(_)
+                | Item::MusicPlaylist(_)
+                | Item::UnknownItem(_)
+        ) {
+            return Err(Error::UnsupportedType(item.title().to_owned()));
+        }
+
+        console.println(format!(
+            "Adding '{}' to the sync list for {}",
+            item.title(),
+            server.id(),
+        ));
+
+        server
+            .add_sync(
+                rating_key,
+                self.profile,
+                self.only_unplayed,
+                self.max_episodes,
+                self.latest_season,
+            )
+            .await?;
+
+        Ok(())
+    }
 }
 
 #[async_trait]
 impl Runnable for Add {
     async fn run(self, flick_sync: FlickSync, console: Console) -> Result {
-        let unexpected = || Error::ErrorMessage("Unexpected URL format".to_string());
+        if let Some(ref id) = self.server {
+            let server = flick_sync
+                .server(id)
+                .await
+                .ok_or_else(|| Error::UnknownServer(id.clone()))?;
 
-        let url = Url::parse(&self.url)?;
-        let fragment = url.fragment().ok_or_else(unexpected)?;
-        if fragment.get(0..1) != Some("!") {
-            return Err(unexpected());
-        }
-        let fragment = &fragment[1..];
+            if let Some(ref query) = self.query {
+                server
+                    .add_query(
+                        query,
+                        self.profile,
+                        self.only_unplayed,
+                        self.max_episodes,
+                        self.latest_season,
+                    )
+                    .await?;
+
+                console.println(format!("Added query '{query}' to {}", server.id()));
+                return Ok(());
+            }
 
-        let url = Url::options()
-            .base_url(Some(&Url::parse("https://nowhere.flick-sync")?))
-            .parse(fragment)?;
+            if let Some(library) = self.library.clone() {
+                return self.add_from_library(console, server, library).await;
+            }
 
-        let mut segments = url.path_segments().ok_or_else(unexpected)?;
-        if !matches!(segments.next(), Some("server")) {
-            return Err(unexpected());
+            let rating_key = self.url.clone().unwrap_or_default();
+            let plex_server = server.connect().await?;
+            return self
+                .add_item(console, server, plex_server, &rating_key)
+                .await;
         }
 
-        let id = segments.next().ok_or_else(unexpected)?;
-        let key = url
-            .query_pairs()
-            .find_map(|(k, v)| if k == "key" { Some(v) } else { None })
-            .ok_or_else(unexpected)?;
-
-        let rating_key = match key.rfind('/') {
-            Some(idx) => key[idx + 1..].to_owned(),
-            None => return Err(unexpected()),
-        };
+        let (id, rating_key) = parse_web_url(self.url.as_deref().unwrap_or_default())?;
 
         for server in flick_sync.servers().await {
             let plex_server = match server.connect().await {
@@ -298,31 +718,9 @@ impl Runnable for Add {
                 continue;
             }
 
-            let item = plex_server.item_by_id(&rating_key).await?;
-            if matches!(
-                item,
-                Item::Photo(_)
-                    | Item::Artist(_)
-                    | Item::MusicAlbum(_)
-                    | Item::Track(_)
-                    | Item::PhotoPlaylist(_)
-                    | Item::MusicPlaylist(_)
-                    | Item::UnknownItem(_)
-            ) {
-                return Err(Error::UnsupportedType(item.title().to_owned()));
-            }
-
-            console.println(format!(
-                "Adding '{}' to the sync list for {}",
-                item.title(),
-                server.id(),
-            ));
-
-            server
-                .add_sync(&rating_key, self.profile, self.only_unplayed)
-                .await?;
-
-            return Ok(());
+            return self
+                .add_item(console, server, plex_server, &rating_key)
+                .await;
         }
 
         Err(Error::ErrorMessage("No matching server found".to_string()))
@@ -331,31 +729,61 @@ impl Runnable for Add {
 
 #[derive(Args)]
 pub struct Remove {
-    /// The server to remove from.
-    server: String,
-    /// The id item to remove.
-    id: String,
+    #[clap(flatten)]
+    selector: Selector,
+    /// Remove a query added with `add --query` instead of sync items.
+    #[clap(long, conflicts_with_all = ["id", "item_type", "matches"])]
+    query: Option<String>,
+    /// Leave local files in place until the next sync or prune instead of
+    /// deleting them now.
+    #[clap(long)]
+    keep_files: bool,
 }
 
 #[async_trait]
 impl Runnable for Remove {
     async fn run(self, flick_sync: FlickSync, console: Console) -> Result {
-        let server = if let Some(server) = flick_sync.server(&self.server).await {
-            server
+        let mut servers: Vec<Server> = Vec::new();
+
+        if let Some(ref query) = self.query {
+            for server in self.selector.servers(&flick_sync).await? {
+                if server.remove_query(query).await? {
+                    console.println(format!("Removed query '{query}' from {}", server.id()));
+                    servers.push(server);
+                }
+            }
+
+            if servers.is_empty() {
+                return err(format!("No server has the query '{query}'"));
+            }
         } else {
-            console.println(format!("{} is not a known server.", self.server));
+            let selected = self.selector.sync_items(&flick_sync).await?;
+            if !self.selector.preview(&console, "remove", &selected) {
+                return Ok(());
+            }
+
+            for item in selected {
+                if item.server.remove_sync(&item.id).await?
+                    && !servers.iter().any(|s| s.id() == item.server.id())
+                {
+                    servers.push(item.server);
+                }
+            }
+        }
+
+        if self.keep_files {
             return Ok(());
-        };
+        }
 
-        if server.remove_sync(&self.id).await? {
+        for server in servers {
             if let Err(e) = server.update_state().await {
                 error!(server=server.id(), error=?e, "Failed to update server");
-                return Ok(());
+                continue;
             }
 
             if let Err(e) = server.prune().await {
                 error!(server=server.id(), error=?e, "Failed to prune server directory");
-                return Ok(());
+                continue;
             }
         }
 
@@ -363,6 +791,244 @@ impl Runnable for Remove {
     }
 }
 
+#[derive(Args)]
+pub struct Skip {
+    #[clap(flatten)]
+    selector: Selector,
+    /// Stop skipping the items.
+    #[clap(long)]
+    unskip: bool,
+}
+
+#[async_trait]
+impl Runnable for Skip {
+    async fn run(self, flick_sync: FlickSync, console: Console) -> Result {
+        let selected = self.selector.items(&flick_sync).await?;
+        let action = if self.unskip { "unskip" } else { "skip" };
+        if !self.selector.preview(&console, action, &selected) {
+            return Ok(());
+        }
+
+        for item in selected {
+            if !item.server.set_skipped(&item.id, !self.unskip).await? {
+                if self.unskip {
+                    console.println(format!("{item} was not being skipped."));
+                } else {
+                    console.println(format!("{item} is already being skipped."));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Args)]
+pub struct Pin {
+    #[clap(flatten)]
+    selector: Selector,
+    /// Allow the items to be evicted again.
+    #[clap(long)]
+    unpin: bool,
+}
+
+#[async_trait]
+impl Runnable for Pin {
+    async fn run(self, flick_sync: FlickSync, console: Console) -> Result {
+        let selected = self.selector.items(&flick_sync).await?;
+        let action = if self.unpin { "unpin" } else { "pin" };
+        if !self.selector.preview(&console, action, &selected) {
+            return Ok(());
+        }
+
+        for item in selected {
+            if !item.server.set_pinned(&item.id, !self.unpin).await? {
+                if self.unpin {
+                    console.println(format!("{item} was not pinned."));
+                } else {
+                    console.println(format!("{item} is already pinned."));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Args)]
+pub struct Media {
+    /// The server the video is on.
+    server: String,
+    /// The id of the video.
+    id: String,
+    /// The id of the media version to sync.
+    #[clap(long, conflicts_with = "auto")]
+    select: Option<String>,
+    /// Clear any chosen version so the configured rules decide.
+    #[clap(long)]
+    auto: bool,
+}
+
+#[async_trait]
+impl Runnable for Media {
+    async fn run(self, flick_sync: FlickSync, console: Console) -> Result {
+        let server = flick_sync
+            .server(&self.server)
+            .await
+            .ok_or_else(|| Error::UnknownServer(self.server.clone()))?;
+
+        if self.auto {
+            server.set_media_version(&self.id, None).await?;
+            console.println("The media version will be chosen by the configured rules.");
+            return Ok(());
+        }
+
+        let versions = server.media_versions(&self.id).await?;
+        let descriptions: Vec<String> = versions
+            .iter()
+            .map(|version| {
+                format!(
+                    "{}: {} {} {}{}",
+                    version.id,
+                    version
+                        .height
+                        .map(|h| format!("{h}p"))
+                        .unwrap_or_else(|| "unknown".to_string()),
+                    version.container.as_deref().unwrap_or_default(),
+                    DecimalBytes(version.size),
+                    if version.selected { " (selected)" } else { "" },
+                )
+            })
+            .collect();
+
+        let media_id = match self.select {
+            Some(id) => {
+                if !versions.iter().any(|v| v.id == id) {
+                    return err(format!("Unknown media version {id}"));
+                }
+                id
+            }
+            None if versions.len() < 2 => {
+                for description in descriptions {
+                    console.println(description);
+                }
+                return Ok(());
+            }
+            None => {
+                let index = console.select("Select media version", &descriptions)?;
+                versions[index].id.clone()
+            }
+        };
+
+        server.set_media_version(&self.id, Some(&media_id)).await?;
+        console.println(format!(
+            "Media version {media_id} will be synced from the next sync."
+        ));
+
+        Ok(())
+    }
+}
+
+#[derive(Args)]
+pub struct Rename {
+    /// The server the video is on.
+    server: String,
+    /// The id of the video.
+    id: String,
+    /// The path relative to the server's directory, without an extension.
+    #[clap(required_unless_present = "template")]
+    path: Option<String>,
+    /// Clear the path so the naming templates decide.
+    #[clap(long, conflicts_with = "path")]
+    template: bool,
+}
+
+#[async_trait]
+impl Runnable for Rename {
+    async fn run(self, flick_sync: FlickSync, console: Console) -> Result {
+        let server = flick_sync
+            .server(&self.server)
+            .await
+            .ok_or_else(|| Error::UnknownServer(self.server.clone()))?;
+
+        server.set_path(&self.id, self.path.as_deref()).await?;
+        console.println("The video's files will be moved on the next sync.");
+
+        Ok(())
+    }
+}
+
+#[derive(Args)]
+pub struct Redownload {
+    #[clap(flatten)]
+    selector: Selector,
+    /// Only download this part of each video again, counting from 1.
+    #[clap(short, long)]
+    part: Option<usize>,
+}
+
+#[async_trait]
+impl Runnable for Redownload {
+    async fn run(self, flick_sync: FlickSync, console: Console) -> Result {
+        let selected = self.selector.items(&flick_sync).await?;
+
+        let mut videos: Vec<(Selected, Video)> = Vec::new();
+        for item in selected {
+            for video in item.server.videos_for(&item.id).await {
+                if !videos
+                    .iter()
+                    .any(|(s, v)| s.server.id() == item.server.id() && v.id() == video.id())
+                {
+                    let selected = Selected {
+                        server: item.server.clone(),
+                        id: video.id().to_owned(),
+                        title: video.title().await,
+                    };
+                    videos.push((selected, video));
+                }
+            }
+        }
+
+        let selected: Vec<Selected> = videos.iter().map(|(s, _)| s.clone()).collect();
+        if !self.selector.preview(&console, "download again", &selected) {
+            return Ok(());
+        }
+
+        let mut failed = 0;
+        for (item, video) in videos {
+            for part in video.parts().await {
+                if let Some(index) = self.part {
+                    if part.index() + 1 != index {
+                        continue;
+                    }
+                }
+
+                match part.reset_download().await {
+                    Ok(()) => console.println(format!(
+                        "{} part {} will be downloaded on the next sync.",
+                        item,
+                        part.index() + 1
+                    )),
+                    Err(e) => {
+                        console.println(format!(
+                            "Failed to reset {} part {}: {e}",
+                            item,
+                            part.index() + 1
+                        ));
+                        failed += 1;
+                    }
+                }
+            }
+        }
+
+        if failed > 0 {
+            err(format!("Failed to reset {failed} downloads"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
 #[derive(Args)]
 pub struct Rebuild {}
 
@@ -396,3 +1062,115 @@ impl Runnable for Rebuild {
         Ok(())
     }
 }
+
+#[derive(Args)]
+pub struct Wizard {
+    /// The servers to choose libraries from. Can be repeated. When not passed
+    /// all servers are included.
+    #[clap(short = 's', long = "server")]
+    ids: Vec<String>,
+    /// Only sync unplayed items.
+    #[clap(short, long)]
+    only_unplayed: bool,
+}
+
+#[async_trait]
+impl Runnable for Wizard {
+    async fn run(self, flick_sync: FlickSync, console: Console) -> Result {
+        let servers = select_servers(&flick_sync, &self.ids).await?;
+        if servers.is_empty() {
+            return err("Log in to a server before running the wizard");
+        }
+
+        let profiles = flick_sync.profile_names().await;
+        let mut bitrates = Vec::new();
+        for profile in profiles.iter() {
+            bitrates.push(flick_sync.profile_bitrate(profile).await);
+        }
+
+        let available = available_space(flick_sync.media_root().await)?;
+        let mut total = 0;
+        let mut chosen = Vec::new();
+
+        for server in servers {
+            console.println(format!(
+                "Estimating the size of the libraries on {}...",
+                server.id()
+            ));
+
+            for estimate in server.estimate_libraries().await? {
+                if estimate.videos == 0 {
+                    continue;
+                }
+
+                let mut items = vec!["Skip".to_string()];
+                items.extend(
+                    profiles
+                        .iter()
+                        .zip(bitrates.iter())
+                        .map(|(profile, bitrate)| {
+                            format!("{profile} ({})", DecimalBytes(estimate.size_at(*bitrate)))
+                        }),
+                );
+
+                let index = console.select(
+                    format!(
+                        "'{}' on {} has {} videos, {} of {} free remains",
+                        estimate.title,
+                        server.id(),
+                        estimate.videos,
+                        DecimalBytes(available.saturating_sub(total)),
+                        DecimalBytes(available)
+                    ),
+                    &items,
+                )?;
+                if index == 0 {
+                    continue;
+                }
+
+                let profile = profiles[index - 1].clone();
+                total += estimate.size_at(bitrates[index - 1]);
+                if total > available {
+                    console.println("This selection no longer fits in the free space.");
+                }
+
+                chosen.push((server.clone(), estimate, profile));
+            }
+        }
+
+        if chosen.is_empty() {
+            console.println("No libraries were selected.");
+            return Ok(());
+        }
+
+        console.println(format!(
+            "The selected libraries need about {} of the {} free.",
+            DecimalBytes(total),
+            DecimalBytes(available)
+        ));
+        if console.select("Add these libraries to the sync list?", &["Yes", "No"])? != 0 {
+            return Ok(());
+        }
+
+        // Queries keep the libraries in sync as items are added to them.
+        for (server, estimate, profile) in chosen {
+            server
+                .add_query(
+                    &format!("library(\"{}\")", estimate.id),
+                    Some(profile),
+                    self.only_unplayed,
+                    None,
+                    false,
+                )
+                .await?;
+
+            console.println(format!(
+                "Added '{}' to the sync list for {}",
+                estimate.title,
+                server.id()
+            ));
+        }
+
+        Ok(())
+    }
+}
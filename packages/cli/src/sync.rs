@@ -1,32 +1,233 @@
-use std::sync::Arc;
+use std::{
+    cmp::max,
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use async_std::task::sleep;
 
 use async_trait::async_trait;
-use clap::Args;
-use flick_sync::{FlickSync, Progress, TransferState, VideoPart};
+use clap::{Args, ValueEnum};
+use flick_sync::{
+    lock_store, operation_id, Choice, Conflict, ConflictResolver, Correlation, ErrorAction,
+    ErrorClass, Event, EventSink, FlickSync, Progress, QueueOrder, ReportFormat, RunReport, Server,
+    SyncHistoryEntry, TransferState, VideoPart,
+};
 use futures::future::join_all;
+use indicatif::DecimalBytes;
 use tokio::sync::{OwnedSemaphorePermit, Semaphore};
-use tracing::{debug, error, instrument};
+use tracing::{debug, error, info_span, instrument, warn, Instrument};
 
 use crate::{
     console::{Bar, ProgressType},
+    error::err,
     select_servers, Console, Result, Runnable,
 };
 
+struct ConflictPrompt {
+    console: Console,
+}
+
+impl ConflictResolver for ConflictPrompt {
+    fn resolve(&self, conflict: &Conflict) -> Option<Choice> {
+        let resolutions = conflict.resolutions();
+
+        let mut items: Vec<String> = resolutions.iter().map(|r| r.to_string()).collect();
+        items.extend(resolutions.iter().map(|r| format!("{r} (always)")));
+
+        let index = match self.console.select(conflict.to_string(), &items) {
+            Ok(index) => index,
+            Err(e) => {
+                warn!(error=?e, "Unable to prompt for a conflict, using the default");
+                return None;
+            }
+        };
+
+        Some(Choice {
+            resolution: resolutions[index % resolutions.len()],
+            remember: index >= resolutions.len(),
+        })
+    }
+}
+
+async fn enable_prompts(flick_sync: &FlickSync, console: &Console) -> Result {
+    if !console.is_interactive() {
+        return err("Conflicts cannot be prompted for when running non-interactively");
+    }
+
+    flick_sync
+        .set_conflict_resolver(Arc::new(ConflictPrompt {
+            console: console.clone(),
+        }))
+        .await;
+
+    Ok(())
+}
+
+async fn print_plan(
+    flick_sync: &FlickSync,
+    servers: Vec<Server>,
+    console: &Console,
+    include_downloads: bool,
+) {
+    for path in flick_sync.plan_prune_root().await {
+        console.println(format!("Would delete unknown path {}", path.display()));
+    }
+
+    for server in servers {
+        let plan = match server.plan().await {
+            Ok(plan) => plan,
+            Err(e) => {
+                error!(server=server.id(), error=?e, "Failed to plan server update");
+                continue;
+            }
+        };
+
+        console.println(format!("Server {}:", server.id()));
+
+        for removal in plan.removals {
+            console.println(format!(
+                "  Would remove '{}' ({}): {}",
+                removal.title,
+                DecimalBytes(removal.size),
+                removal.reason
+            ));
+        }
+
+        for deletion in plan.deletions {
+            console.println(format!(
+                "  Would delete {} ({})",
+                deletion.path.display(),
+                DecimalBytes(deletion.size)
+            ));
+        }
+
+        if include_downloads {
+            let mut total = 0;
+
+            for download in plan.downloads {
+                let action = if download.transcode {
+                    "transcode"
+                } else {
+                    "download"
+                };
+
+                console.println(format!(
+                    "  Would {action} '{}' part {} ({})",
+                    download.title,
+                    download.part + 1,
+                    DecimalBytes(download.size)
+                ));
+
+                total += download.size;
+            }
+
+            console.println(format!("  Total to download: {}", DecimalBytes(total)));
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct Plan {
+    /// The servers to plan. Can be repeated. When not passed all servers are
+    /// included.
+    #[clap(short = 's', long = "server")]
+    ids: Vec<String>,
+}
+
+#[async_trait]
+impl Runnable for Plan {
+    async fn run(self, flick_sync: FlickSync, console: Console) -> Result {
+        let mut total_download = 0;
+        let mut total_disk = 0;
+
+        let servers = select_servers(&flick_sync, &self.ids).await?;
+        for (pos, server) in servers.iter().enumerate() {
+            let plan = match server.plan().await {
+                Ok(plan) => plan,
+                Err(e) => {
+                    error!(server=server.id(), error=?e, "Failed to plan server update");
+                    continue;
+                }
+            };
+
+            if pos > 0 {
+                console.println("");
+            }
+            console.println(format!("Server {}:", server.id()));
+
+            let mut groups: BTreeMap<String, (usize, u64)> = BTreeMap::new();
+            for download in plan.downloads.iter() {
+                let group = groups.entry(download.group.clone()).or_default();
+                group.0 += 1;
+                group.1 += download.estimated;
+            }
+
+            for (group, (files, size)) in groups.iter() {
+                console.println(format!("  {group}: {files} files, {}", DecimalBytes(*size)));
+            }
+
+            let download: u64 = plan.downloads.iter().map(|d| d.estimated).sum();
+            console.println(format!("  Total to download: {}", DecimalBytes(download)));
+            console.println(format!(
+                "  Disk space used after the sync: {}",
+                DecimalBytes(plan.kept + download)
+            ));
+
+            total_download += download;
+            total_disk += plan.kept + download;
+        }
+
+        if servers.len() > 1 {
+            console.println("");
+            console.println(format!(
+                "Total to download: {}",
+                DecimalBytes(total_download)
+            ));
+            console.println(format!(
+                "Total disk space used after the sync: {}",
+                DecimalBytes(total_disk)
+            ));
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Args)]
 pub struct Prune {
     /// The servers to prune. Can be repeated. When not passed all servers and
     /// the top level directory are pruned.
     #[clap(short = 's', long = "server")]
     ids: Vec<String>,
+    /// Prompt for how to handle conflicts instead of using the defaults.
+    #[clap(short, long)]
+    interactive: bool,
+    /// List what would be deleted without changing anything.
+    #[clap(long)]
+    dry_run: bool,
 }
 
 #[async_trait]
 impl Runnable for Prune {
-    async fn run(self, flick_sync: FlickSync, _console: Console) -> Result {
-        flick_sync.prune_root().await;
-
+    async fn run(self, flick_sync: FlickSync, console: Console) -> Result {
         let servers = select_servers(&flick_sync, &self.ids).await?;
 
+        if self.dry_run {
+            print_plan(&flick_sync, servers, &console, false).await;
+            return Ok(());
+        }
+
+        if self.interactive {
+            enable_prompts(&flick_sync, &console).await?;
+        }
+
+        flick_sync.prune_root().await;
+
         for server in servers {
             if let Err(e) = server.update_state().await {
                 error!(server=server.id(), error=?e, "Failed to update server");
@@ -45,21 +246,324 @@ impl Runnable for Prune {
 
 struct DownloadProgress {
     bar: Bar,
+    last: Option<u64>,
+    transferred: Option<Arc<AtomicU64>>,
+}
+
+impl DownloadProgress {
+    fn new(bar: Bar) -> Self {
+        Self {
+            bar,
+            last: None,
+            transferred: None,
+        }
+    }
 }
 
 impl Progress for DownloadProgress {
     fn progress(&mut self, position: u64, size: u64) {
         self.bar.set_position(position);
         self.bar.set_length(size);
+
+        if let Some(ref transferred) = self.transferred {
+            let last = self.last.unwrap_or(position);
+            transferred.fetch_add(position.saturating_sub(last), Ordering::Relaxed);
+        }
+        self.last = Some(position);
+    }
+}
+
+/// The most downloads that adaptive mode will run at once.
+const ADAPTIVE_MAX_DOWNLOADS: usize = 8;
+
+struct Tuning {
+    max: usize,
+    limit: usize,
+    /// Permits to discard as downloads finish in order to lower the limit.
+    shrink: usize,
+    transferred: u64,
+    start: Instant,
+    best_rate: f64,
+}
+
+/// Limits the number of simultaneous downloads. In adaptive mode this starts
+/// with a single download, allows one more each time a download completes
+/// while the overall throughput holds up and halves the limit after a
+/// failure.
+#[derive(Clone)]
+struct DownloadPermits {
+    semaphore: Arc<Semaphore>,
+    tuning: Option<Arc<Mutex<Tuning>>>,
+}
+
+impl DownloadPermits {
+    fn fixed(count: usize) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(count)),
+            tuning: None,
+        }
+    }
+
+    fn adaptive(max: usize) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(1)),
+            tuning: Some(Arc::new(Mutex::new(Tuning {
+                max,
+                limit: 1,
+                shrink: 0,
+                transferred: 0,
+                start: Instant::now(),
+                best_rate: 0.0,
+            }))),
+        }
+    }
+
+    async fn acquire(&self) -> DownloadPermit {
+        DownloadPermit {
+            inner: Some(self.semaphore.clone().acquire_owned().await.unwrap()),
+            tuning: self.tuning.clone(),
+        }
+    }
+
+    fn record_success(&self, transferred: u64) {
+        let tuning = match self.tuning {
+            Some(ref tuning) => tuning,
+            None => return,
+        };
+        let mut tuning = tuning.lock().unwrap();
+
+        tuning.transferred += transferred;
+        let rate = tuning.transferred as f64 / tuning.start.elapsed().as_secs_f64().max(1.0);
+
+        // Keep adding downloads while they don't make things noticeably slower.
+        if rate >= tuning.best_rate * 0.9 && tuning.limit < tuning.max {
+            tuning.limit += 1;
+            if tuning.shrink > 0 {
+                tuning.shrink -= 1;
+            } else {
+                self.semaphore.add_permits(1);
+            }
+            debug!(limit = tuning.limit, rate, "Increased download concurrency");
+        }
+
+        tuning.best_rate = tuning.best_rate.max(rate);
+    }
+
+    fn record_failure(&self) {
+        let tuning = match self.tuning {
+            Some(ref tuning) => tuning,
+            None => return,
+        };
+        let mut tuning = tuning.lock().unwrap();
+
+        let limit = max(1, tuning.limit / 2);
+        tuning.shrink += tuning.limit - limit;
+        tuning.limit = limit;
+        debug!(limit, "Reduced download concurrency after a failure");
+    }
+}
+
+struct DownloadPermit {
+    inner: Option<OwnedSemaphorePermit>,
+    tuning: Option<Arc<Mutex<Tuning>>>,
+}
+
+impl Drop for DownloadPermit {
+    fn drop(&mut self) {
+        if let Some(ref tuning) = self.tuning {
+            let mut tuning = tuning.lock().unwrap();
+            if tuning.shrink > 0 {
+                tuning.shrink -= 1;
+                if let Some(inner) = self.inner.take() {
+                    inner.forget();
+                }
+            }
+        }
     }
 }
 
 struct PartTransferState {
+    flick_sync: FlickSync,
+    aborted: Arc<AtomicBool>,
     transcode_permits: TranscodePermits,
-    download_permits: Arc<Semaphore>,
+    download_permits: DownloadPermits,
+    server: String,
     title: String,
     part: VideoPart,
     console: Console,
+    failures: Failures,
+}
+
+/// Collects the failures of a sync for the summary printed at the end and the
+/// optional report.
+#[derive(Clone, Default)]
+struct Failures {
+    report: Option<Arc<RunReport>>,
+    failures: Arc<Mutex<Vec<String>>>,
+    /// The number of failures for each server.
+    servers: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl Failures {
+    fn record(&self, server: &str, failure: String) {
+        if let Some(ref report) = self.report {
+            report.record_failure(failure.clone());
+        }
+        self.failures.lock().unwrap().push(failure);
+        *self
+            .servers
+            .lock()
+            .unwrap()
+            .entry(server.to_owned())
+            .or_default() += 1;
+    }
+
+    fn record_item(&self, server: &str, item: &str, failure: String) {
+        if let Some(ref report) = self.report {
+            report.record_item_failure(server, item, failure.clone());
+        }
+        self.failures.lock().unwrap().push(failure);
+        *self
+            .servers
+            .lock()
+            .unwrap()
+            .entry(server.to_owned())
+            .or_default() += 1;
+    }
+
+    fn count(&self) -> usize {
+        self.failures.lock().unwrap().len()
+    }
+
+    fn server_count(&self, server: &str) -> usize {
+        self.servers
+            .lock()
+            .unwrap()
+            .get(server)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn summarize(&self, console: &Console) {
+        let failures = self.failures.lock().unwrap();
+        if failures.is_empty() {
+            return;
+        }
+
+        console.println(format!("{} failures during the sync:", failures.len()));
+        for failure in failures.iter() {
+            console.println(format!("  {failure}"));
+        }
+    }
+}
+
+// Failures of sync items are reported by the engine as it continues with the
+// remaining items, the report receives them as an event sink of its own.
+impl EventSink for Failures {
+    fn event(&self, event: &Event, _correlation: &Correlation) {
+        if let Event::SyncItemFailed {
+            server,
+            item,
+            error,
+        } = event
+        {
+            self.failures
+                .lock()
+                .unwrap()
+                .push(format!("Failed to update {item} on {server}: {error}"));
+            *self
+                .servers
+                .lock()
+                .unwrap()
+                .entry(server.clone())
+                .or_default() += 1;
+        }
+    }
+}
+
+/// Tallies the data downloaded from each server for the sync history.
+#[derive(Clone, Default)]
+struct Transferred {
+    /// The bytes remaining for each part that has started downloading.
+    started: Arc<Mutex<HashMap<(String, String, usize), u64>>>,
+    servers: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl Transferred {
+    fn bytes(&self, server: &str) -> u64 {
+        self.servers
+            .lock()
+            .unwrap()
+            .get(server)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+impl EventSink for Transferred {
+    fn event(&self, event: &Event, _correlation: &Correlation) {
+        match event {
+            Event::DownloadStarted {
+                server,
+                video,
+                part,
+                offset,
+                size,
+                ..
+            } => {
+                self.started.lock().unwrap().insert(
+                    (server.clone(), video.clone(), *part),
+                    size.saturating_sub(*offset),
+                );
+            }
+            Event::DownloadComplete {
+                server,
+                video,
+                part,
+                ..
+            } => {
+                let bytes = self
+                    .started
+                    .lock()
+                    .unwrap()
+                    .remove(&(server.clone(), video.clone(), *part))
+                    .unwrap_or_default();
+                *self
+                    .servers
+                    .lock()
+                    .unwrap()
+                    .entry(server.clone())
+                    .or_default() += bytes;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Waits before another attempt if the error's configured action allows one.
+async fn should_retry(
+    flick_sync: &FlickSync,
+    error: &flick_sync::Error,
+    retries: &mut u32,
+) -> bool {
+    match flick_sync.error_action(error).await {
+        ErrorAction::Retry { attempts } if *retries < attempts => {
+            *retries += 1;
+            let delay = flick_sync.retry_delay(*retries).await;
+            warn!(error=?error, retries, ?delay, "Operation failed, retrying");
+            sleep(delay).await;
+            true
+        }
+        _ => false,
+    }
+}
+
+fn describe_failure(subject: &str, error: &flick_sync::Error, retries: u32) -> String {
+    if retries > 0 {
+        format!("{subject}: {error} (after {retries} retries)")
+    } else {
+        format!("{subject}: {error}")
+    }
 }
 
 struct TranscodePermit {
@@ -141,49 +645,106 @@ impl TranscodePermits {
     }
 }
 
-async fn complete_transcode(state: &PartTransferState) -> Result {
+async fn complete_transcode(state: &PartTransferState) -> flick_sync::Result {
     let bar = state
         .console
         .add_progress_bar(&format!("🔄 {}", state.title), ProgressType::Percent);
     state
         .part
-        .wait_for_download_to_be_available(DownloadProgress { bar })
+        .wait_for_download_to_be_available(DownloadProgress::new(bar))
         .await?;
 
     Ok(())
 }
 
-async fn complete_download(state: &PartTransferState) -> Result {
-    let _permit = state.download_permits.acquire().await.unwrap();
+async fn complete_download(state: &PartTransferState) -> flick_sync::Result {
+    state.flick_sync.wait_for_download_window().await;
+    let _permit = state.download_permits.acquire().await;
 
     let bar = state
         .console
         .add_progress_bar(&format!("💾 {}", state.title), ProgressType::Bytes);
-    state.part.download(DownloadProgress { bar }).await?;
+    let transferred = Arc::new(AtomicU64::new(0));
+    let progress = DownloadProgress {
+        transferred: Some(transferred.clone()),
+        ..DownloadProgress::new(bar)
+    };
 
-    Ok(())
+    match state.part.download(progress).await {
+        Ok(()) => {
+            state
+                .download_permits
+                .record_success(transferred.load(Ordering::Relaxed));
+            Ok(())
+        }
+        Err(e) => {
+            state.download_permits.record_failure();
+            Err(e)
+        }
+    }
 }
 
-#[instrument(level = "trace", skip(state), fields(video=state.part.id(), part=state.part.index()))]
-async fn download_part(mut state: PartTransferState) {
+async fn transfer_part(state: &mut PartTransferState) -> flick_sync::Result {
     if state.part.transfer_state().await != TransferState::Downloading {
         let _permit = state.transcode_permits.acquire().await;
 
-        if let Err(e) = state.part.negotiate_transfer_type().await {
-            error!(error=?e);
-            return;
-        }
+        state.part.negotiate_transfer_type().await?;
 
         if state.part.transfer_state().await == TransferState::Transcoding {
-            if let Err(e) = complete_transcode(&state).await {
-                error!(error=?e);
-                return;
-            }
+            complete_transcode(state).await?;
         }
     }
 
-    if let Err(e) = complete_download(&state).await {
-        error!(error=?e);
+    complete_download(state).await
+}
+
+#[instrument(
+    level = "trace",
+    skip(state),
+    fields(
+        video=state.part.id(),
+        part=state.part.index(),
+        operation=%operation_id(&state.server, state.part.id(), Some(state.part.index())),
+    )
+)]
+async fn download_part(mut state: PartTransferState) {
+    let mut retries = 0;
+
+    loop {
+        if state.aborted.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let e = match transfer_part(&mut state).await {
+            Ok(()) => return,
+            Err(e) => e,
+        };
+
+        if should_retry(&state.flick_sync, &e, &mut retries).await {
+            continue;
+        }
+
+        if state.flick_sync.error_action(&e).await == ErrorAction::Abort {
+            error!(error=?e, "Transfer failed, aborting the sync");
+            state.aborted.store(true, Ordering::Relaxed);
+        } else {
+            error!(error=?e);
+        }
+
+        state.failures.record_item(
+            &state.server,
+            state.part.id(),
+            describe_failure(&state.title, &e, retries),
+        );
+        state
+            .flick_sync
+            .emit_event(Event::TransferFailed {
+                server: state.server.clone(),
+                video: state.part.id().to_owned(),
+                error: e.to_string(),
+            })
+            .await;
+        return;
     }
 }
 
@@ -192,6 +753,45 @@ pub struct Sync {
     /// The servers to sync. Can be repeated. When not passed all servers are listed.
     #[clap(short = 's', long = "server")]
     ids: Vec<String>,
+    /// Prompt for how to handle conflicts instead of using the defaults.
+    #[clap(short, long)]
+    interactive: bool,
+    /// List what would be downloaded, transcoded or deleted without changing
+    /// anything.
+    #[clap(long)]
+    dry_run: bool,
+    /// Adjust the number of simultaneous downloads based on throughput and
+    /// failures instead of using the configured maximum.
+    #[clap(long)]
+    adaptive: bool,
+    /// Write a report of the sync into the store, either "html", "markdown"
+    /// or "json".
+    #[clap(long)]
+    report: Option<ReportFormat>,
+    /// Archive each server's metadata after updating it, see the snapshot
+    /// command.
+    #[clap(long)]
+    snapshot: bool,
+    /// Treat servers that cannot be reached as failures instead of skipping
+    /// them.
+    #[clap(long)]
+    require_all: bool,
+    /// Stop starting new downloads once this many bytes are queued, leaving
+    /// the rest for later syncs.
+    #[clap(long, value_name = "BYTES")]
+    max_bytes: Option<u64>,
+    /// Stop starting new downloads once this many files are queued, leaving
+    /// the rest for later syncs.
+    #[clap(long, value_name = "COUNT")]
+    max_items: Option<usize>,
+    /// The order to start downloads in, either "playlists", "newest" or
+    /// "smallest". Overrides the configured order.
+    #[clap(long)]
+    order: Option<QueueOrder>,
+    /// Show a full screen dashboard of the transfers and log while syncing.
+    /// Plain output is used when not writing to a terminal.
+    #[clap(long)]
+    tui: bool,
 }
 
 #[async_trait]
@@ -199,23 +799,191 @@ impl Runnable for Sync {
     async fn run(self, flick_sync: FlickSync, console: Console) -> Result {
         let servers = select_servers(&flick_sync, &self.ids).await?;
 
-        let max_downloads = flick_sync.max_downloads().await;
-        let download_permits = Arc::new(Semaphore::new(max_downloads));
+        if self.dry_run {
+            print_plan(&flick_sync, servers, &console, true).await;
+            return Ok(());
+        }
+
+        if self.interactive {
+            enable_prompts(&flick_sync, &console).await?;
+        }
+
+        // Everything logged during the sync carries the run's id, as do the
+        // events, reports and history it produces.
+        let run = flick_sync.start_run();
+        self.sync(flick_sync, console, servers)
+            .instrument(info_span!("sync", run = %run))
+            .await
+    }
+}
+
+impl Sync {
+    async fn sync(self, flick_sync: FlickSync, console: Console, servers: Vec<Server>) -> Result {
+        let report = self.report.map(|_| Arc::new(RunReport::default()));
+        if let Some(ref report) = report {
+            flick_sync.add_event_sink(report.clone()).await;
+        }
+        let failures = Failures {
+            report: report.clone(),
+            ..Default::default()
+        };
+        flick_sync.add_event_sink(Arc::new(failures.clone())).await;
+        let transferred = Transferred::default();
+        flick_sync
+            .add_event_sink(Arc::new(transferred.clone()))
+            .await;
+
+        let started = SystemTime::now();
+        let timer = Instant::now();
+        let mut synced = Vec::new();
+
+        // Downloads already in progress count towards the limits but are
+        // always continued.
+        let mut queued_bytes = 0;
+        let mut queued_items = 0;
+        let mut limited = false;
+
+        let (max_downloads, download_permits) = if self.adaptive {
+            (
+                ADAPTIVE_MAX_DOWNLOADS,
+                DownloadPermits::adaptive(ADAPTIVE_MAX_DOWNLOADS),
+            )
+        } else {
+            let max_downloads = flick_sync.max_downloads().await;
+            (max_downloads, DownloadPermits::fixed(max_downloads))
+        };
         let mut jobs = Vec::new();
+        let aborted = Arc::new(AtomicBool::new(false));
+
+        let dashboard = if self.tui {
+            console.show_dashboard()
+        } else {
+            None
+        };
 
         flick_sync.prune_root().await;
 
         for server in servers {
-            if let Err(e) = server.update_state().await {
+            let mut connected = server.connect().await;
+            if matches!(connected, Err(ref e) if e.class() == ErrorClass::Auth) {
+                match server.refresh_token().await {
+                    Ok(true) => connected = server.connect().await,
+                    Ok(false) => {}
+                    Err(e) => warn!(server=server.id(), error=?e, "Failed to refresh token"),
+                }
+            }
+
+            if let Err(e) = connected {
+                // Only network failures mean the server is offline, other
+                // problems such as lost authentication still need attention.
+                if self.require_all || e.class() != ErrorClass::Network {
+                    error!(server=server.id(), error=?e, "Failed to connect to server");
+                    failures.record(
+                        server.id(),
+                        format!("Failed to connect to {}: {e}", server.id()),
+                    );
+                    synced.push(server);
+                } else {
+                    warn!(server=server.id(), error=?e, "Skipping unreachable server");
+                    console.println(format!(
+                        "Skipping {} as it could not be reached",
+                        server.id()
+                    ));
+                }
+                continue;
+            }
+
+            synced.push(server.clone());
+            let previous_sync = server.last_synced().await;
+
+            let mut retries = 0;
+            let updated = loop {
+                match server.update_state().await {
+                    Ok(()) => break Ok(()),
+                    Err(e) => {
+                        if !should_retry(&flick_sync, &e, &mut retries).await {
+                            break Err(e);
+                        }
+                    }
+                }
+            };
+
+            if let Err(e) = updated {
                 error!(server=server.id(), error=?e, "Failed to update server");
+                failures.record(
+                    server.id(),
+                    describe_failure(&format!("Failed to update {}", server.id()), &e, retries),
+                );
+
+                if flick_sync.error_action(&e).await == ErrorAction::Abort {
+                    aborted.store(true, Ordering::Relaxed);
+                    break;
+                }
                 continue;
             }
 
+            if self.snapshot {
+                if let Err(e) = server.write_snapshot().await {
+                    error!(server=server.id(), error=?e, "Failed to write snapshot");
+                    failures.record(
+                        server.id(),
+                        format!("Failed to snapshot {}: {e}", server.id()),
+                    );
+                }
+            }
+
             if let Err(e) = server.prune().await {
                 error!(server=server.id(), error=?e, "Failed to prune server directory");
+                failures.record(server.id(), format!("Failed to prune {}: {e}", server.id()));
                 continue;
             }
 
+            if let Err(e) = server.publish_downloads().await {
+                failures.record(
+                    server.id(),
+                    format!("Failed to upload downloads for {}: {e}", server.id()),
+                );
+            }
+
+            let deferred: HashSet<String> = match server.enforce_size_limit().await {
+                Ok(report) => {
+                    for video in report.evicted.iter() {
+                        console.println(format!(
+                            "Evicted '{}' to free {}: {}",
+                            video.title,
+                            DecimalBytes(video.size),
+                            video.reason
+                        ));
+                    }
+
+                    for video in report.deferred.iter() {
+                        console.println(format!(
+                            "Not downloading '{}' ({}) as it would exceed the size limit: {}",
+                            video.title,
+                            DecimalBytes(video.size),
+                            video.reason
+                        ));
+                    }
+
+                    // Evicted videos wait for space to free up in a later
+                    // sync rather than being downloaded again now.
+                    report
+                        .deferred
+                        .into_iter()
+                        .chain(report.evicted)
+                        .map(|v| v.video)
+                        .collect()
+                }
+                Err(e) => {
+                    error!(server=server.id(), error=?e, "Failed to apply size limits");
+                    failures.record(
+                        server.id(),
+                        format!("Failed to apply size limits for {}: {e}", server.id()),
+                    );
+                    continue;
+                }
+            };
+
             let max_transcodes = server.max_transcodes().await;
 
             let mut transfers = Vec::new();
@@ -226,9 +994,20 @@ impl Runnable for Sync {
                 max_downloads, max_transcodes, "Starting transfer jobs"
             );
 
-            for video in server.videos().await {
+            for video in server.download_queue(self.order, previous_sync).await {
+                if video.is_skipped().await || deferred.contains(video.id()) {
+                    continue;
+                }
+
                 let title = video.title().await;
                 for part in video.parts().await {
+                    if part.is_quarantined().await {
+                        console.println(format!(
+                            "Not downloading '{title}' as it failed verification, redownload it to try again"
+                        ));
+                        continue;
+                    }
+
                     if part.verify_download().await.is_err() {
                         continue;
                     }
@@ -240,15 +1019,29 @@ impl Runnable for Sync {
                             transcode_permits.reserve();
                         }
                         TransferState::Downloaded => continue,
+                        TransferState::Waiting
+                            if self.max_bytes.is_some_and(|max| queued_bytes >= max)
+                                || self.max_items.is_some_and(|max| queued_items >= max) =>
+                        {
+                            limited = true;
+                            continue;
+                        }
                         TransferState::Downloading | TransferState::Waiting => (),
                     };
 
+                    queued_bytes += part.size().await;
+                    queued_items += 1;
+
                     transfers.push(PartTransferState {
+                        flick_sync: flick_sync.clone(),
+                        aborted: aborted.clone(),
                         download_permits: download_permits.clone(),
+                        server: server.id().to_owned(),
                         part,
                         title: title.clone(),
                         console: console.clone(),
                         transcode_permits,
+                        failures: failures.clone(),
                     });
                 }
             }
@@ -259,7 +1052,128 @@ impl Runnable for Sync {
         }
 
         join_all(jobs).await;
+        drop(dashboard);
 
-        Ok(())
+        if limited {
+            console.println(
+                "Reached the download limit, the remaining videos will be downloaded by later syncs",
+            );
+        }
+
+        if let Err(e) = flick_sync.export_playlists().await {
+            error!(error=?e, "Failed to export playlists");
+            console.println(format!("Failed to export playlists: {e}"));
+        }
+
+        if let Err(e) = flick_sync.export_metadata().await {
+            console.println(format!("Failed to export metadata: {e}"));
+        }
+
+        failures.summarize(&console);
+
+        let duration = timer.elapsed();
+        for server in synced {
+            let entry = SyncHistoryEntry {
+                started,
+                duration,
+                bytes: transferred.bytes(server.id()),
+                errors: failures.server_count(server.id()),
+                run: flick_sync.run_id(),
+            };
+
+            if let Err(e) = server.record_sync(entry).await {
+                warn!(server=server.id(), error=?e, "Failed to record the sync history");
+            }
+        }
+
+        flick_sync
+            .emit_event(Event::SyncComplete {
+                failures: failures.count(),
+            })
+            .await;
+        flick_sync.flush_events().await;
+
+        if let (Some(format), Some(report)) = (self.report, report) {
+            let path = report.write(&flick_sync, format).await?;
+            console.println(format!("Wrote the sync report to {}", path.display()));
+        }
+
+        if aborted.load(Ordering::Relaxed) {
+            return err("The sync was aborted after an error");
+        }
+
+        match failures.count() {
+            0 => Ok(()),
+            count => err(format!("The sync finished with {count} failures")),
+        }
+    }
+}
+
+/// How often the wait command checks the store.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, ValueEnum)]
+enum WaitCondition {
+    /// No other process is using the store.
+    Idle,
+    /// No other process is using the store and the last sync of every server
+    /// completed without errors. Videos that sync deliberately left for later,
+    /// such as those over the size limits, do not need to be downloaded.
+    Synced,
+}
+
+#[derive(Args)]
+pub struct Wait {
+    /// What to wait for.
+    #[clap(long, value_enum, default_value = "synced")]
+    until: WaitCondition,
+    /// Give up and fail after this many seconds.
+    #[clap(long, value_name = "SECONDS")]
+    timeout: Option<u64>,
+}
+
+#[async_trait]
+impl Runnable for Wait {
+    async fn run(self, flick_sync: FlickSync, console: Console) -> Result {
+        let store = flick_sync.path().await;
+        let started = Instant::now();
+
+        loop {
+            match lock_store(&store, false).await {
+                Ok(_lock) => {
+                    // Read the store again as another process may have
+                    // changed it.
+                    let flick_sync = FlickSync::new(&store).await?;
+
+                    if matches!(self.until, WaitCondition::Idle) {
+                        return Ok(());
+                    }
+
+                    let mut unsynced = Vec::new();
+                    for server in flick_sync.servers().await {
+                        match server.sync_history().await.last() {
+                            Some(entry) if entry.errors == 0 => {}
+                            _ => unsynced.push(server.id().to_owned()),
+                        }
+                    }
+
+                    if unsynced.is_empty() {
+                        return Ok(());
+                    }
+
+                    debug!(?unsynced, "Store is idle but not fully synced");
+                }
+                Err(flick_sync::Error::StoreLocked) => {}
+                Err(e) => return Err(e.into()),
+            }
+
+            if let Some(timeout) = self.timeout {
+                if started.elapsed() >= Duration::from_secs(timeout) {
+                    return err(format!("Gave up waiting after {timeout} seconds"));
+                }
+            }
+
+            sleep(WAIT_POLL_INTERVAL).await;
+        }
     }
 }
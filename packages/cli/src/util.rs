@@ -1,13 +1,162 @@
+use std::{
+    env::{set_current_dir, temp_dir},
+    path::{Path, PathBuf},
+    process::Command,
+    time::SystemTime,
+};
+
+use async_std::fs::{remove_file, write};
 use async_trait::async_trait;
-use clap::Args;
-use flick_sync::{FlickSync, ItemType, VideoStats};
+use clap::{Args, CommandFactory, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use flick_sync::{
+    check_store, config_schema, lock_store, state_schema, FlickSync, ItemType, VideoStats,
+};
+use fs2::available_space;
 use indicatif::{DecimalBytes, HumanDuration};
+use tracing::warn;
 
-use crate::{Console, Result, Runnable};
+use crate::{error::err, select_servers, Console, Result, Runnable};
 
 #[derive(Args)]
 pub struct Stats {}
 
+#[derive(Args)]
+pub struct Status {
+    /// The servers to summarise. Can be repeated. When not passed all servers
+    /// are included.
+    #[clap(short = 's', long = "server")]
+    ids: Vec<String>,
+}
+
+#[async_trait]
+impl Runnable for Status {
+    async fn run(self, flick_sync: FlickSync, console: Console) -> Result {
+        for (pos, server) in select_servers(&flick_sync, &self.ids)
+            .await?
+            .iter()
+            .enumerate()
+        {
+            let status = server.status().await;
+
+            if pos > 0 {
+                console.println("");
+            }
+
+            console.println(format!("Server {} ({}):", server.id(), status.name));
+            console.println(format!(
+                "  Items: {} libraries, {} collections, {} playlists",
+                status.libraries, status.collections, status.playlists
+            ));
+            console.println(format!(
+                "  Videos: {} downloaded, {} pending, {} skipped",
+                status.downloaded, status.pending, status.skipped
+            ));
+            console.println(format!(
+                "  Disk usage: {}",
+                DecimalBytes(status.local_bytes)
+            ));
+            console.println(format!(
+                "  Pending downloads: {}",
+                DecimalBytes(status.pending_bytes)
+            ));
+            console.println(format!(
+                "  Stale transcode sessions: {}",
+                status.transcode_sessions
+            ));
+
+            let last_synced = match status.last_synced {
+                Some(time) => format!(
+                    "{} ago",
+                    HumanDuration(SystemTime::now().duration_since(time).unwrap_or_default())
+                ),
+                None => "never".to_string(),
+            };
+            console.println(format!("  Last successful sync: {last_synced}"));
+
+            if let Some(time) = status.auth_failed {
+                console.println(format!(
+                    "  Warning: the server rejected the login {} ago, run `login {}` again",
+                    HumanDuration(SystemTime::now().duration_since(time).unwrap_or_default()),
+                    server.id()
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Args)]
+pub struct History {
+    /// The servers to show. Can be repeated. When not passed all servers are
+    /// included.
+    #[clap(short = 's', long = "server")]
+    ids: Vec<String>,
+}
+
+#[async_trait]
+impl Runnable for History {
+    async fn run(self, flick_sync: FlickSync, console: Console) -> Result {
+        for (pos, server) in select_servers(&flick_sync, &self.ids)
+            .await?
+            .iter()
+            .enumerate()
+        {
+            if pos > 0 {
+                console.println("");
+            }
+
+            console.println(format!("Server {}:", server.id()));
+
+            let history = server.sync_history().await;
+            if history.is_empty() {
+                console.println("  No syncs recorded");
+                continue;
+            }
+
+            for entry in history.iter().rev() {
+                let ago = SystemTime::now()
+                    .duration_since(entry.started)
+                    .unwrap_or_default();
+                console.println(format!(
+                    "  {} ago: took {}, downloaded {}, {} failures",
+                    HumanDuration(ago),
+                    HumanDuration(entry.duration),
+                    DecimalBytes(entry.bytes),
+                    entry.errors
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Args)]
+pub struct Snapshot {
+    /// The servers to snapshot. Can be repeated. When not passed all servers
+    /// are included.
+    #[clap(short = 's', long = "server")]
+    ids: Vec<String>,
+}
+
+#[async_trait]
+impl Runnable for Snapshot {
+    async fn run(self, flick_sync: FlickSync, console: Console) -> Result {
+        for server in select_servers(&flick_sync, &self.ids).await? {
+            let path = server.write_snapshot().await?;
+            console.println(format!(
+                "Wrote a snapshot of {} to {}",
+                server.id(),
+                path.display()
+            ));
+        }
+
+        Ok(())
+    }
+}
+
 fn percent<T: Into<u64>>(a: T, b: T) -> String {
     let a = a.into();
     let b = b.into();
@@ -97,18 +246,62 @@ impl Runnable for Stats {
 }
 
 #[derive(Args)]
-pub struct List {}
+pub struct List {
+    /// Explain which sync items caused a video to be downloaded.
+    #[clap(long, value_name = "VIDEO")]
+    why: Option<String>,
+    /// Fetch the details of every item from the servers instead of using
+    /// the cached details.
+    #[clap(long)]
+    refresh: bool,
+}
+
+impl List {
+    async fn explain(&self, flick_sync: &FlickSync, console: &Console, id: &str) -> Result {
+        let mut found = false;
+
+        for server in flick_sync.servers().await {
+            let sources = match server.video_sources(id).await {
+                Some(sources) => sources,
+                None => continue,
+            };
+            found = true;
+
+            if sources.is_empty() {
+                console.println(format!(
+                    "{}/{id} has not been included by any sync item since it was last synced.",
+                    server.id()
+                ));
+            } else {
+                console.println(format!("{}/{id} is included by:", server.id()));
+                for source in sources {
+                    console.println(format!("  {source}"));
+                }
+            }
+        }
+
+        if !found {
+            console.println(format!("{id} is not a synced video."));
+        }
+
+        Ok(())
+    }
+}
 
 #[async_trait]
 impl Runnable for List {
     async fn run(self, flick_sync: FlickSync, console: Console) -> Result {
+        if let Some(ref id) = self.why {
+            return self.explain(&flick_sync, &console, id).await;
+        }
+
         let servers = flick_sync.servers().await;
         for (pos, server) in servers.iter().enumerate() {
             if pos > 0 {
                 console.println("");
             }
 
-            for item in server.list_syncs().await? {
+            for item in server.list_syncs(self.refresh).await? {
                 let type_name = match item.item_type {
                     ItemType::Playlist => "Playlist",
                     ItemType::MovieCollection => "Movie Collection",
@@ -120,14 +313,26 @@ impl Runnable for List {
                     ItemType::Unknown => "Unknown",
                 };
 
-                let selected = if item.only_unplayed {
-                    "unplayed"
+                let selected = match (item.max_episodes, item.only_unplayed) {
+                    (Some(count), _) => format!("next {count}"),
+                    (None, true) => "unplayed".to_string(),
+                    (None, false) => "all".to_string(),
+                };
+                let selected = if item.latest_season {
+                    format!("{selected}, latest season")
                 } else {
-                    "all"
+                    selected
+                };
+
+                let skipped = match (item.skipped, item.pinned) {
+                    (true, true) => "skipped, pinned",
+                    (true, false) => "skipped",
+                    (false, true) => "pinned",
+                    (false, false) => "",
                 };
 
                 console.println(format!(
-                    "{:10} {:8} {type_name:16}  {:20} {selected:3} {:10}",
+                    "{:10} {:8} {type_name:16}  {:20} {selected:3} {:10} {skipped}",
                     server.id(),
                     item.id,
                     item.title,
@@ -139,3 +344,470 @@ impl Runnable for List {
         Ok(())
     }
 }
+
+#[derive(Clone, Copy, ValueEnum)]
+enum SchemaFile {
+    Config,
+    State,
+}
+
+#[derive(Args)]
+pub struct Schema {
+    /// The file to describe.
+    #[clap(value_enum)]
+    file: SchemaFile,
+}
+
+impl Schema {
+    pub fn print(self, console: Console) -> Result {
+        let schema = match self.file {
+            SchemaFile::Config => config_schema()?,
+            SchemaFile::State => state_schema()?,
+        };
+
+        console.println(schema);
+
+        Ok(())
+    }
+}
+
+#[derive(Args)]
+pub struct Verify {
+    /// The servers to verify. Can be repeated. When not passed all servers are
+    /// verified.
+    #[clap(short = 's', long = "server")]
+    ids: Vec<String>,
+    /// Reset any damaged downloads so they are downloaded again on the next
+    /// sync.
+    #[clap(long)]
+    repair: bool,
+}
+
+impl Verify {
+    /// Only repairing changes the state.
+    pub fn writes(&self) -> bool {
+        self.repair
+    }
+}
+
+#[async_trait]
+impl Runnable for Verify {
+    async fn run(self, flick_sync: FlickSync, console: Console) -> Result {
+        let mut damaged = 0;
+        let mut failed = 0;
+
+        for server in select_servers(&flick_sync, &self.ids).await? {
+            for video in server.videos().await {
+                for part in video.parts().await {
+                    let issue = match part.check_download().await {
+                        Some(issue) => issue,
+                        None => continue,
+                    };
+
+                    damaged += 1;
+                    console.println(format!(
+                        "{}: '{}' part {}: {issue}",
+                        server.id(),
+                        video.title().await,
+                        part.index() + 1
+                    ));
+
+                    if self.repair {
+                        if let Err(e) = part.reset_download().await {
+                            console.println(format!("  Failed to reset the download: {e}"));
+                            failed += 1;
+                        }
+                    }
+                }
+            }
+        }
+
+        if damaged == 0 {
+            console.println("All downloads verified.");
+            return Ok(());
+        }
+
+        if !self.repair {
+            return err(format!("Found {damaged} damaged downloads"));
+        }
+
+        let reset = damaged - failed;
+        if reset > 0 {
+            console.println(format!(
+                "Reset {reset} damaged downloads, they will be downloaded again on the next sync."
+            ));
+        }
+
+        if failed > 0 {
+            err(format!("Failed to reset {failed} damaged downloads"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct Doctor {}
+
+struct Report<'a> {
+    console: &'a Console,
+    problems: usize,
+}
+
+impl<'a> Report<'a> {
+    fn ok<S: AsRef<str>>(&self, check: S) {
+        self.console.println(format!("✅ {}", check.as_ref()));
+    }
+
+    fn problem<S: AsRef<str>, R: AsRef<str>>(&mut self, check: S, remedy: R) {
+        self.problems += 1;
+        self.console.println(format!("❌ {}", check.as_ref()));
+        self.console.println(format!("   {}", remedy.as_ref()));
+    }
+}
+
+impl Doctor {
+    /// Runs the checks against the store at `path`. The config and state are
+    /// checked before the store is opened so that problems that stop it
+    /// opening can still be diagnosed.
+    pub async fn diagnose(self, path: &Path, console: Console) -> Result {
+        let mut report = Report {
+            console: &console,
+            problems: 0,
+        };
+
+        let probe = path.join(".flicksync.doctor");
+        match write(&probe, "").await {
+            Ok(()) => {
+                let _ = remove_file(&probe).await;
+                report.ok(format!("Store {} is writable", path.display()));
+            }
+            Err(e) => report.problem(
+                format!("Store {} is not writable: {e}", path.display()),
+                "Check the permissions of the store directory and the disk it is on.",
+            ),
+        }
+
+        match check_store(path).await {
+            Ok(()) => report.ok("Config and state files can be read"),
+            Err(e) => report.problem(
+                format!("Config or state file cannot be read: {e}"),
+                "Fix the config file, or run `flick-sync rebuild` to recover the state from downloaded files.",
+            ),
+        }
+
+        match lock_store(path, false).await {
+            Ok(_) => report.ok("Store is not in use by another process"),
+            Err(flick_sync::Error::StoreLocked) => report.problem(
+                "Store is in use by another process",
+                "Wait for the other process to finish or pass --wait-lock to queue behind it.",
+            ),
+            Err(e) => report.problem(
+                format!("Unable to check the store lock: {e}"),
+                "Check the permissions of the store directory.",
+            ),
+        }
+
+        match FlickSync::new(path).await {
+            Ok(flick_sync) => Self::check_servers(&flick_sync, path, &mut report).await,
+            Err(e) => report.problem(
+                format!("Store cannot be opened: {e}"),
+                "Fix the problems above, the servers cannot be checked until the store opens.",
+            ),
+        }
+
+        if report.problems == 0 {
+            console.println("No problems found.");
+            Ok(())
+        } else {
+            err(format!("Found {} problems", report.problems))
+        }
+    }
+
+    async fn check_servers(flick_sync: &FlickSync, path: &Path, report: &mut Report<'_>) {
+        let media_root = flick_sync.media_root().await;
+        if media_root != path {
+            match flick_sync.check_media_root().await {
+                Ok(()) => report.ok(format!("Media root {} is available", media_root.display())),
+                Err(e) => report.problem(
+                    e.to_string(),
+                    "Mount the share holding the media root before syncing.",
+                ),
+            }
+        }
+
+        let mut outstanding = 0;
+        for server in flick_sync.servers().await {
+            match server.connect().await {
+                Ok(_) => report.ok(format!("Server {} is reachable and authenticated", server.id())),
+                Err(e) => report.problem(
+                    format!("Unable to connect to server {}: {e}", server.id()),
+                    format!(
+                        "Check the server is running and reachable, or log in again with `flick-sync login {}`.",
+                        server.id()
+                    ),
+                ),
+            }
+
+            if let Ok(plan) = server.plan().await {
+                outstanding += plan.downloads.iter().map(|d| d.size).sum::<u64>();
+            }
+        }
+
+        match flick_sync.check_ffmpeg().await {
+            Some(Ok(version)) => report.ok(format!("ffmpeg is available: {version}")),
+            Some(Err(e)) => report.problem(
+                format!("ffmpeg cannot be run for local transcodes: {e}"),
+                "Install ffmpeg or set `ffmpeg` in the config to the path of the binary.",
+            ),
+            None => {}
+        }
+
+        match available_space(path) {
+            Ok(available) if available < outstanding => report.problem(
+                format!(
+                    "Only {} free but {} is still to download",
+                    DecimalBytes(available),
+                    DecimalBytes(outstanding)
+                ),
+                "Free up disk space, remove some items or set a maximum size for the store.",
+            ),
+            Ok(available) => report.ok(format!("{} of disk space free", DecimalBytes(available))),
+            Err(e) => report.problem(
+                format!("Unable to check free disk space: {e}"),
+                "Check the store is on a mounted filesystem.",
+            ),
+        }
+    }
+}
+
+#[async_trait]
+impl Runnable for Doctor {
+    async fn run(self, flick_sync: FlickSync, console: Console) -> Result {
+        self.diagnose(&flick_sync.path().await, console).await
+    }
+}
+
+#[derive(Subcommand)]
+enum ConfigAction {
+    /// Prints a setting, e.g. `servers.home.maxTranscodes`.
+    Get { key: String },
+    /// Changes a setting. The value is parsed as JSON, anything else is
+    /// treated as a string.
+    Set { key: String, value: String },
+    /// Removes a setting, returning it to its default.
+    Unset { key: String },
+}
+
+#[derive(Args)]
+pub struct Config {
+    #[clap(subcommand)]
+    action: ConfigAction,
+}
+
+impl Config {
+    /// Whether this changes the config rather than reading it.
+    pub fn writes(&self) -> bool {
+        !matches!(self.action, ConfigAction::Get { .. })
+    }
+}
+
+#[async_trait]
+impl Runnable for Config {
+    async fn run(self, flick_sync: FlickSync, console: Console) -> Result {
+        match self.action {
+            ConfigAction::Get { key } => match flick_sync.config_value(&key).await? {
+                Some(value) => console.println(value),
+                None => return err(format!("{key} is not set")),
+            },
+            ConfigAction::Set { key, value } => {
+                flick_sync.set_config_value(&key, Some(&value)).await?;
+            }
+            ConfigAction::Unset { key } => {
+                flick_sync.set_config_value(&key, None).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Args)]
+pub struct Completions {
+    /// The shell to generate completions for.
+    #[clap(value_enum)]
+    shell: Shell,
+}
+
+impl Completions {
+    pub fn generate(self) {
+        clap_complete::generate(
+            self.shell,
+            &mut crate::Args::command(),
+            "flick-sync",
+            &mut std::io::stdout(),
+        );
+    }
+}
+
+#[derive(Args)]
+pub struct Eject {}
+
+/// The top directory of the filesystem holding the path.
+#[cfg(unix)]
+fn mount_point(path: &Path) -> Option<PathBuf> {
+    use std::os::unix::fs::MetadataExt;
+
+    let path = path.canonicalize().ok()?;
+    let device = std::fs::metadata(&path).ok()?.dev();
+
+    let mut mount_point = path.clone();
+    for ancestor in path.ancestors().skip(1) {
+        match std::fs::metadata(ancestor) {
+            Ok(stats) if stats.dev() == device => mount_point = ancestor.to_owned(),
+            _ => break,
+        }
+    }
+
+    Some(mount_point)
+}
+
+#[cfg(not(unix))]
+fn mount_point(_path: &Path) -> Option<PathBuf> {
+    None
+}
+
+/// Whether the filesystem holding the path is on a removable or external
+/// drive. Internal filesystems that are mounted separately, such as `/home`,
+/// must never be unmounted.
+#[cfg(target_os = "linux")]
+fn is_removable(path: &Path, _mount_point: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    let device = match std::fs::metadata(path) {
+        Ok(stats) => stats.dev(),
+        Err(_) => return false,
+    };
+    let major = ((device >> 8) & 0xfff) | ((device >> 32) & !0xfff);
+    let minor = (device & 0xff) | ((device >> 12) & !0xff);
+
+    let device_path = match Path::new(&format!("/sys/dev/block/{major}:{minor}")).canonicalize() {
+        Ok(device_path) => device_path,
+        Err(_) => return false,
+    };
+
+    // USB drives often do not report themselves as removable.
+    let on_usb = device_path
+        .components()
+        .any(|component| component.as_os_str().to_string_lossy().starts_with("usb"));
+
+    // A partition is listed beneath the disk that holds it.
+    on_usb
+        || device_path.ancestors().take(2).any(|dir| {
+            std::fs::read_to_string(dir.join("removable"))
+                .is_ok_and(|removable| removable.trim() == "1")
+        })
+}
+
+#[cfg(target_os = "macos")]
+fn is_removable(_path: &Path, mount_point: &Path) -> bool {
+    let output = match Command::new("diskutil")
+        .arg("info")
+        .arg(mount_point)
+        .output()
+    {
+        Ok(output) if output.status.success() => output,
+        _ => return false,
+    };
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .any(|line| match line.split_once(':') {
+            Some((key, value)) => match key.trim() {
+                "Removable Media" => value.trim() == "Removable",
+                "Device Location" => value.trim() == "External",
+                _ => false,
+            },
+            None => false,
+        })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn is_removable(_path: &Path, _mount_point: &Path) -> bool {
+    false
+}
+
+/// The commands to try, in order, to unmount a drive.
+fn unmount_commands(mount_point: &Path) -> Vec<Vec<String>> {
+    let mount_point = mount_point.to_string_lossy().into_owned();
+
+    if cfg!(target_os = "macos") {
+        vec![vec!["diskutil".into(), "eject".into(), mount_point]]
+    } else if cfg!(target_os = "linux") {
+        vec![
+            vec![
+                "gio".into(),
+                "mount".into(),
+                "--unmount".into(),
+                mount_point.clone(),
+            ],
+            vec!["umount".into(), mount_point],
+        ]
+    } else {
+        Vec::new()
+    }
+}
+
+#[async_trait]
+impl Runnable for Eject {
+    async fn run(self, flick_sync: FlickSync, console: Console) -> Result {
+        let store = flick_sync.path().await;
+        drop(flick_sync);
+
+        {
+            let _lock = lock_store(&store, true).await?;
+            // Read the store again now that no other process is using it.
+            let flick_sync = FlickSync::new(&store).await?;
+            flick_sync.sync_to_disk().await?;
+        }
+        console.println("The store has been written to disk.");
+
+        let mount_point = match mount_point(&store) {
+            Some(mount_point)
+                if mount_point.parent().is_some() && is_removable(&store, &mount_point) =>
+            {
+                mount_point
+            }
+            Some(_) => {
+                console.println("The store is not on a removable drive.");
+                return Ok(());
+            }
+            None => {
+                console.println("Eject the drive using the operating system.");
+                return Ok(());
+            }
+        };
+
+        // The drive cannot be unmounted while this process is using it.
+        set_current_dir(temp_dir())?;
+
+        for command in unmount_commands(&mount_point) {
+            match Command::new(&command[0]).args(&command[1..]).status() {
+                Ok(status) if status.success() => {
+                    console.println(format!(
+                        "Unmounted {}, the drive can be removed.",
+                        mount_point.display()
+                    ));
+                    return Ok(());
+                }
+                Ok(status) => warn!(command = command[0], %status, "Unmount failed"),
+                Err(e) => warn!(command = command[0], error=?e, "Unable to run unmount"),
+            }
+        }
+
+        err(format!(
+            "Unable to unmount {}, unmount it using the operating system",
+            mount_point.display()
+        ))
+    }
+}
@@ -0,0 +1,303 @@
+//! Where downloaded videos and artwork are written. Paths given to a
+//! [`StorageBackend`] are the full paths within the store.
+
+use std::{
+    io::ErrorKind,
+    path::{Component, Path, PathBuf},
+    pin::Pin,
+};
+
+use async_std::fs::{create_dir_all, metadata, remove_file, rename, File, OpenOptions};
+use async_trait::async_trait;
+use futures::AsyncWrite;
+use isahc::{
+    auth::{Authentication, Credentials},
+    config::Configurable,
+    http::{request::Builder, StatusCode},
+    AsyncBody, AsyncReadResponseExt, Request,
+};
+use tracing::{debug, trace};
+
+use crate::{
+    config::MediaStorage,
+    s3::{S3Storage, DEFAULT_REGION},
+    Error, Result,
+};
+
+/// A file being written to a storage backend.
+pub(crate) type MediaWriter = Pin<Box<dyn AsyncWrite + Send + Sync>>;
+
+#[async_trait]
+pub(crate) trait StorageBackend: Send + Sync {
+    /// Opens a file for writing, creating any missing directories. With
+    /// `append` writing continues from the end of an existing file, otherwise
+    /// any existing file is replaced.
+    async fn create(&self, path: &Path, append: bool) -> Result<MediaWriter>;
+
+    /// Moves a file, creating any missing directories.
+    async fn rename(&self, from: &Path, to: &Path) -> Result;
+
+    /// Called once a file has been completely written and checked. A failure
+    /// is retried by later syncs.
+    async fn publish(&self, path: &Path) -> Result;
+
+    /// Deletes a file. A file that does not exist is not an error.
+    async fn remove(&self, path: &Path) -> Result;
+}
+
+impl MediaStorage {
+    pub(crate) fn backend(&self, root: &Path) -> Box<dyn StorageBackend> {
+        match self {
+            Self::Local => Box::new(LocalStorage),
+            Self::Webdav {
+                url,
+                username,
+                password,
+            } => Box::new(WebDavStorage {
+                root: root.to_owned(),
+                url: url.trim_end_matches('/').to_owned(),
+                credentials: username.as_ref().map(|username| {
+                    Credentials::new(username.as_str(), password.as_deref().unwrap_or_default())
+                }),
+            }),
+            Self::S3 {
+                endpoint,
+                bucket,
+                region,
+                prefix,
+                access_key,
+                secret_key,
+            } => Box::new(S3Storage {
+                root: root.to_owned(),
+                endpoint: endpoint.clone(),
+                bucket: bucket.clone(),
+                region: region.clone().unwrap_or_else(|| DEFAULT_REGION.to_owned()),
+                prefix: prefix.clone().unwrap_or_default(),
+                access_key: access_key.clone(),
+                secret_key: secret_key.clone(),
+            }),
+        }
+    }
+}
+
+/// Files in the local filesystem.
+pub(crate) struct LocalStorage;
+
+#[async_trait]
+impl StorageBackend for LocalStorage {
+    async fn create(&self, path: &Path, append: bool) -> Result<MediaWriter> {
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent).await?;
+        }
+
+        let file = OpenOptions::new()
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .create(true)
+            .open(path)
+            .await?;
+
+        Ok(Box::pin(file))
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> Result {
+        if let Some(parent) = to.parent() {
+            create_dir_all(parent).await?;
+        }
+
+        rename(from, to).await?;
+        Ok(())
+    }
+
+    async fn publish(&self, _path: &Path) -> Result {
+        Ok(())
+    }
+
+    async fn remove(&self, path: &Path) -> Result {
+        match remove_file(path).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Percent encodes a single segment of a URL path.
+pub(crate) fn encode_segment(segment: &str) -> String {
+    let mut encoded = String::new();
+
+    for byte in segment.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+
+    encoded
+}
+
+/// Mirrors the store to a WebDAV share, such as a NAS or a Nextcloud
+/// instance. Files are written to the local store as normal, uploaded once
+/// they are complete and deleted from the share when they are removed
+/// locally. The local files are still needed to resume and verify downloads
+/// so this saves no local space.
+struct WebDavStorage {
+    root: PathBuf,
+    /// The URL of the directory on the share that mirrors the store.
+    url: String,
+    credentials: Option<Credentials>,
+}
+
+impl WebDavStorage {
+    /// The URLs of the directories that contain a file on the share, outermost
+    /// first, and the URL of the file.
+    fn urls(&self, path: &Path) -> Result<(Vec<String>, String)> {
+        let relative = path
+            .strip_prefix(&self.root)
+            .map_err(|_| Error::RemoteStorage {
+                path: path.to_owned(),
+                message: "the file is outside of the store".to_string(),
+            })?;
+
+        let mut directories = Vec::new();
+        let mut url = self.url.clone();
+        for component in relative.components() {
+            if let Component::Normal(segment) = component {
+                if url != self.url {
+                    directories.push(url.clone());
+                }
+
+                url.push('/');
+                url.push_str(&encode_segment(&segment.to_string_lossy()));
+            }
+        }
+
+        Ok((directories, url))
+    }
+
+    fn request(&self, method: &str, url: &str) -> Builder {
+        let builder = Request::builder().method(method).uri(url);
+
+        match self.credentials {
+            Some(ref credentials) => builder
+                .authentication(Authentication::basic())
+                .credentials(credentials.clone()),
+            None => builder,
+        }
+    }
+
+    /// Sends a request, returning the response status. Statuses other than
+    /// success and those in `allowed` are an error.
+    async fn send(
+        &self,
+        path: &Path,
+        builder: Builder,
+        body: AsyncBody,
+        allowed: &[StatusCode],
+    ) -> Result<StatusCode> {
+        let error = |message: String| Error::RemoteStorage {
+            path: path.to_owned(),
+            message,
+        };
+
+        let request = builder.body(body).map_err(|e| error(e.to_string()))?;
+        let mut response = isahc::send_async(request)
+            .await
+            .map_err(|e| error(e.to_string()))?;
+
+        let status = response.status();
+        if status.is_success() || allowed.contains(&status) {
+            Ok(status)
+        } else {
+            let body = response.text().await.unwrap_or_default();
+            Err(error(format!("the server responded with {status}: {body}")))
+        }
+    }
+
+    /// Creates the directories on the share that will contain a file. A
+    /// directory that already exists is reported as not allowed.
+    async fn create_directories(&self, path: &Path, directories: Vec<String>) -> Result {
+        for directory in directories {
+            self.send(
+                path,
+                self.request("MKCOL", &directory),
+                AsyncBody::empty(),
+                &[StatusCode::METHOD_NOT_ALLOWED],
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl StorageBackend for WebDavStorage {
+    async fn create(&self, path: &Path, append: bool) -> Result<MediaWriter> {
+        LocalStorage.create(path, append).await
+    }
+
+    /// Files that have not been uploaded yet are only moved locally.
+    async fn rename(&self, from: &Path, to: &Path) -> Result {
+        LocalStorage.rename(from, to).await?;
+
+        let (_, source) = self.urls(from)?;
+        let (directories, destination) = self.urls(to)?;
+        self.create_directories(to, directories).await?;
+
+        let status = self
+            .send(
+                from,
+                self.request("MOVE", &source)
+                    .header("Destination", destination)
+                    .header("Overwrite", "T"),
+                AsyncBody::empty(),
+                &[StatusCode::NOT_FOUND],
+            )
+            .await?;
+
+        if status == StatusCode::NOT_FOUND {
+            trace!(path=?from, "File was not on the share, moved locally");
+        }
+
+        Ok(())
+    }
+
+    async fn publish(&self, path: &Path) -> Result {
+        let (directories, url) = self.urls(path)?;
+        self.create_directories(path, directories).await?;
+
+        let size = metadata(path).await?.len();
+        let file = File::open(path).await?;
+
+        debug!(?path, size, "Uploading file to the WebDAV share");
+        self.send(
+            path,
+            self.request("PUT", &url),
+            AsyncBody::from_reader_sized(file, size),
+            &[],
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn remove(&self, path: &Path) -> Result {
+        LocalStorage.remove(path).await?;
+
+        let (_, url) = self.urls(path)?;
+        trace!(?path, "Deleting file from the WebDAV share");
+        self.send(
+            path,
+            self.request("DELETE", &url),
+            AsyncBody::empty(),
+            &[StatusCode::NOT_FOUND],
+        )
+        .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,90 @@
+//! Recompression of artwork and metadata files to save space on small
+//! devices.
+
+use std::{io::Write, path::Path};
+
+use async_std::{
+    fs::{read, write},
+    task::spawn_blocking,
+};
+use flate2::{write::GzEncoder, Compression};
+use image::{guess_format, load_from_memory, ImageFormat};
+use mozjpeg::{ColorSpace, Compress};
+
+use crate::{config::ArtworkFormat, Error, Result};
+
+/// The quality artwork is encoded at when none is configured.
+const DEFAULT_QUALITY: u8 = 80;
+
+impl ArtworkFormat {
+    pub(crate) fn extension(&self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpg",
+            Self::Webp => "webp",
+        }
+    }
+
+    fn image_format(&self) -> ImageFormat {
+        match self {
+            Self::Jpeg => ImageFormat::Jpeg,
+            Self::Webp => ImageFormat::WebP,
+        }
+    }
+}
+
+fn encode(data: &[u8], format: ArtworkFormat, quality: u8) -> Result<Vec<u8>> {
+    let image = load_from_memory(data).map_err(|e| Error::ArtworkEncoding(e.to_string()))?;
+
+    match format {
+        ArtworkFormat::Jpeg => {
+            // JPEG has no alpha channel.
+            let image = image.to_rgb8();
+
+            let mut compress = Compress::new(ColorSpace::JCS_RGB);
+            compress.set_size(image.width() as usize, image.height() as usize);
+            compress.set_quality(f32::from(quality));
+
+            let error = |e: std::io::Error| Error::ArtworkEncoding(e.to_string());
+            let mut started = compress.start_compress(Vec::new()).map_err(error)?;
+            started.write_scanlines(image.as_raw()).map_err(error)?;
+            started.finish().map_err(error)
+        }
+        ArtworkFormat::Webp => {
+            let encoder = webp::Encoder::from_image(&image)
+                .map_err(|e| Error::ArtworkEncoding(e.to_string()))?;
+            Ok(encoder.encode(f32::from(quality)).to_vec())
+        }
+    }
+}
+
+/// Re-encodes a downloaded artwork file in place. Files already in the
+/// requested format are left alone when re-encoding doesn't make them
+/// smaller.
+pub(crate) async fn recompress_artwork(
+    path: &Path,
+    format: ArtworkFormat,
+    quality: Option<u8>,
+) -> Result {
+    let data = read(path).await?;
+    let quality = quality.unwrap_or(DEFAULT_QUALITY);
+
+    let original = data.len();
+    let same_format = guess_format(&data).ok() == Some(format.image_format());
+
+    let encoded = spawn_blocking(move || encode(&data, format, quality)).await?;
+    if same_format && encoded.len() >= original {
+        return Ok(());
+    }
+
+    write(path, encoded).await?;
+
+    Ok(())
+}
+
+/// Compresses data in the gzip format. The header has no timestamp so the
+/// same contents always compress to the same bytes.
+pub(crate) fn gzip(contents: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(contents)?;
+    Ok(encoder.finish()?)
+}
@@ -1,15 +1,37 @@
-use std::{cmp::Ordering, collections::HashMap};
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
 
 use plex_api::{
     media_container::server::library::{AudioCodec, ContainerFormat, VideoCodec},
     transcode::{AudioSetting, Constraint, Limitation, VideoSetting, VideoTranscodeOptions},
 };
+use schemars::{
+    schema::{RootSchema, Schema, SchemaObject, SingleOrVec},
+    schema_for, JsonSchema,
+};
 use serde::{Deserialize, Serialize};
+use serde_json::{from_str, Map, Value};
 use serde_plain::derive_display_from_serialize;
+use time::OffsetDateTime;
 
-use crate::util::{derive_list_item, from_list, into_list, ListItem};
+use crate::{
+    conflict::{ConflictKind, Resolution},
+    error::{Error, ErrorAction, ErrorClass},
+    filter::Filter,
+    schedule::parse_time,
+    schema::{migrate_config, FORMAT_VERSION},
+    state::{ArtworkKind, HashAlgorithm},
+    template::{DEFAULT_EPISODE_TEMPLATE, DEFAULT_MOVIE_TEMPLATE, PLEX_EPISODE_TEMPLATE},
+    util::{derive_list_item, from_list, into_list, ListItem},
+    DEFAULT_PROFILES,
+};
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
 #[serde(tag = "type")]
 pub enum ServerConnection {
     MyPlex {
@@ -22,7 +44,7 @@ pub enum ServerConnection {
     },
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct SyncItem {
     pub(crate) id: String,
@@ -30,11 +52,57 @@ pub(crate) struct SyncItem {
     pub(crate) transcode_profile: Option<String>,
     #[serde(default)]
     pub(crate) only_unplayed: bool,
+    /// For shows and seasons only sync this many unplayed episodes, starting
+    /// from the earliest.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) max_episodes: Option<u32>,
+    /// For shows only sync the most recent season.
+    #[serde(default)]
+    pub(crate) latest_season: bool,
+    /// When the item was added to the sync.
+    #[serde(
+        default,
+        with = "time::serde::rfc3339::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schemars(with = "Option<String>")]
+    pub(crate) added: Option<OffsetDateTime>,
+    /// Downloads for videos from items with a higher priority are started
+    /// first, defaults to 0.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) priority: Option<i32>,
 }
 
 derive_list_item!(SyncItem);
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
+/// A selection expression that is evaluated on every sync, adding whatever
+/// movies and shows currently match it.
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SyncQuery {
+    /// See [`Filter`](crate::Filter) for the syntax.
+    pub(crate) query: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) transcode_profile: Option<String>,
+    #[serde(default)]
+    pub(crate) only_unplayed: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) max_episodes: Option<u32>,
+    #[serde(default)]
+    pub(crate) latest_season: bool,
+}
+
+/// Prefixes the query recorded as the source of the videos a query adds.
+pub(crate) const QUERY_SOURCE: &str = "query:";
+
+impl SyncQuery {
+    /// The source recorded for the videos this query adds.
+    pub(crate) fn source(&self) -> String {
+        format!("{QUERY_SOURCE}{}", self.query)
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct ServerConfig {
     pub(crate) connection: ServerConnection,
@@ -44,14 +112,372 @@ pub(crate) struct ServerConfig {
         serialize_with = "into_list",
         deserialize_with = "from_list"
     )]
+    #[schemars(with = "Vec<SyncItem>")]
     pub(crate) syncs: HashMap<String, SyncItem>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) queries: Vec<SyncQuery>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub(crate) max_transcodes: Option<usize>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub(crate) transcode_profile: Option<String>,
+    /// Whether plex.tv relay connections may be used when the server cannot
+    /// be reached directly, defaults to true. Relays are limited to a low
+    /// bandwidth which makes large downloads slow.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) allow_relay: Option<bool>,
+    /// Where videos are transcoded, defaults to the server.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) transcoder: Option<Transcoder>,
+    /// Rating keys of items that should not be downloaded.
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    pub(crate) skipped: HashSet<String>,
+    /// Rating keys of items that should never be evicted.
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    pub(crate) pinned: HashSet<String>,
+    /// Maximum space in bytes to use for this server's downloads.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) max_size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) eviction_policy: Option<EvictionPolicy>,
+    /// Rules for choosing between an item's media versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) media_selection: Option<MediaSelection>,
+    /// The media version chosen for specific videos, by rating key.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub(crate) media_versions: HashMap<String, String>,
+    /// Paths for specific videos, by rating key, used instead of the naming
+    /// templates. They are relative to the server's directory and have no
+    /// extension.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub(crate) paths: HashMap<String, String>,
+}
+
+/// A period of each day with its own download rate.
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct BandwidthWindow {
+    /// The local time the window starts, as `HH:MM`.
+    pub(crate) start: String,
+    /// The local time the window ends, as `HH:MM`. Windows that end before
+    /// they start run past midnight.
+    pub(crate) end: String,
+    /// Maximum rate in kilobytes per second for each download, 0 pauses
+    /// downloads. Unlimited when not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) rate: Option<u64>,
+}
+
+/// How long to wait between retries of a failed operation. The delay doubles
+/// after each attempt.
+#[derive(Deserialize, Serialize, Default, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RetryPolicy {
+    /// Seconds to wait before the first retry, defaults to 5.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) initial_delay: Option<u64>,
+    /// The longest to wait between retries in seconds, defaults to 300.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) max_delay: Option<u64>,
+}
+
+impl RetryPolicy {
+    /// The delay before the given retry, counting from 1.
+    pub(crate) fn delay(&self, retry: u32) -> Duration {
+        let initial = self.initial_delay.unwrap_or(5);
+        let max = self.max_delay.unwrap_or(300);
+        let factor = 1u64
+            .checked_shl(retry.saturating_sub(1))
+            .unwrap_or(u64::MAX);
+
+        Duration::from_secs(initial.saturating_mul(factor).min(max))
+    }
+}
+
+/// The order that a sync starts pending downloads in, after any per-item
+/// priorities and items added since the last sync.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueOrder {
+    /// Videos included by playlists first.
+    Playlists,
+    /// The most recently released videos first.
+    Newest,
+    /// The smallest videos first.
+    Smallest,
+}
+
+impl FromStr for QueueOrder {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s.to_lowercase().as_str() {
+            "playlists" => Ok(Self::Playlists),
+            "newest" => Ok(Self::Newest),
+            "smallest" => Ok(Self::Smallest),
+            _ => Err(Error::UnknownQueueOrder(s.to_owned())),
+        }
+    }
+}
+
+/// The kinds of event that can be sent to a webhook.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum WebhookTrigger {
+    SyncComplete,
+    Downloaded,
+    Failure,
+}
+
+/// A URL that a JSON description of sync events is posted to.
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Webhook {
+    pub(crate) url: String,
+    /// The events to send, all of them when empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) events: Vec<WebhookTrigger>,
+}
+
+/// A push notification service that is sent a summary after each sync.
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[serde(tag = "service", rename_all = "lowercase")]
+pub(crate) enum Notification {
+    /// Publishes to a topic on an ntfy server, for example
+    /// `https://ntfy.sh`.
+    Ntfy {
+        url: String,
+        topic: String,
+        /// An access token for protected topics.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+    },
+    /// Sends a message to a Gotify server using an application token.
+    Gotify {
+        url: String,
+        token: String,
+        /// The message priority, defaults to 5.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        priority: Option<u8>,
+    },
+}
+
+/// How files are placed in exported playlist folders.
+#[derive(Deserialize, Serialize, Default, Clone, Copy, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ExportMode {
+    /// Hard links to the downloads, copies where links are not supported.
+    #[default]
+    Links,
+    /// Copies of the downloads.
+    Copies,
+}
+
+/// Exports playlists, and optionally collections, as flat folders of
+/// numbered files for players such as car stereos that ignore nested
+/// directories.
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PlaylistExport {
+    /// The directory, relative to the store, that folders are written to.
+    pub(crate) directory: PathBuf,
+    #[serde(default)]
+    pub(crate) mode: ExportMode,
+    /// Also write a simple m3u file listing each folder's files.
+    #[serde(default)]
+    pub(crate) m3u: bool,
+    /// Export collections as well as playlists.
+    #[serde(default)]
+    pub(crate) collections: bool,
+}
+
+/// Formats of metadata files that can be written for other software.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum MetadataFormat {
+    /// Kodi style nfo files beside each video.
+    Nfo,
+    /// JSON files beside each video.
+    Json,
+    /// A page in the root of the store linking to every video.
+    Html,
+    /// A JSON list of every downloaded file in the root of the store.
+    Manifest,
+}
+
+/// What a sync does with shows that have no episodes to sync, or only
+/// specials.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum EmptyShowPolicy {
+    /// Leave the show out of the sync.
+    Skip,
+    /// Leave the show out of the sync and log a warning.
+    Warn,
+    /// Keep the artwork of the show and its seasons but no episodes.
+    Artwork,
+}
+
+/// Formats that downloaded artwork can be re-encoded in.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ArtworkFormat {
+    Jpeg,
+    Webp,
+}
+
+/// Recompression of artwork and metadata files to save space.
+#[derive(Deserialize, Serialize, Default, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct FileCompression {
+    /// Re-encodes downloaded artwork in this format. Artwork is downloaded
+    /// again when this changes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) artwork_format: Option<ArtworkFormat>,
+    /// The quality artwork is re-encoded at, from 1 to 100, defaults to 80.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) artwork_quality: Option<u8>,
+    /// Writes JSON sidecars and the manifest gzip compressed, with a `.gz`
+    /// extension. Nfo files and the HTML index are always left uncompressed
+    /// as other software reads them directly.
+    #[serde(default)]
+    pub(crate) gzip_metadata: bool,
+}
+
+/// Where downloaded videos and artwork are written. Remote storage mirrors
+/// the store's directory rather than replacing it, so it needs as much local
+/// space as local storage does.
+#[derive(Deserialize, Serialize, Default, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum MediaStorage {
+    /// Files in the store's directory.
+    #[default]
+    Local,
+    /// Files in the store's directory mirrored to a WebDAV share, for example
+    /// `https://cloud.example.com/remote.php/dav/files/user/Flicks`. Files are
+    /// uploaded once complete and deleted from the share when they are
+    /// removed or pruned from the store.
+    Webdav {
+        url: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        username: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        password: Option<String>,
+    },
+    /// Files in the store's directory mirrored to an S3 compatible object
+    /// store, such as AWS or MinIO. Files are uploaded once complete and
+    /// deleted from the bucket when they are removed or pruned from the store.
+    #[serde(rename_all = "camelCase")]
+    S3 {
+        /// The scheme and host of the service, for example
+        /// `https://s3.eu-west-2.amazonaws.com`.
+        endpoint: String,
+        bucket: String,
+        /// Defaults to `us-east-1`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        region: Option<String>,
+        /// Prefixed to the key of every object.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        prefix: Option<String>,
+        access_key: String,
+        secret_key: String,
+    },
+}
+
+/// How the state is stored.
+#[derive(Deserialize, Serialize, Default, Clone, Copy, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum StateBackend {
+    /// A single JSON file that is rewritten whenever the state changes.
+    #[default]
+    Json,
+    /// An SQLite database where only the videos that change are written,
+    /// better suited to large libraries. The mobile app cannot read this, so
+    /// only use it for stores that are not opened on a device.
+    Sqlite,
+}
+
+/// Where videos that need transcoding are transcoded.
+#[derive(Deserialize, Serialize, Default, Clone, Copy, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Transcoder {
+    /// The Plex server transcodes videos in a transcode session.
+    #[default]
+    Server,
+    /// The original file is downloaded and then transcoded with ffmpeg.
+    Local,
+}
+
+/// Which of an item's media versions to prefer when there is more than one.
+#[derive(Deserialize, Serialize, Default, Clone, Copy, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum MediaPreference {
+    /// The first version listed by the server.
+    #[default]
+    First,
+    /// The version with the highest resolution.
+    Highest,
+    /// The version with the lowest resolution.
+    Lowest,
+    /// The version with the smallest files.
+    Smallest,
+}
+
+#[derive(Deserialize, Serialize, Default, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct MediaSelection {
+    #[serde(default)]
+    pub(crate) prefer: MediaPreference,
+    /// Versions taller than this are only used when there is no other choice.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) max_resolution: Option<u32>,
+}
+
+/// How downloaded videos and artwork are arranged within a server's
+/// directory.
+#[derive(Deserialize, Serialize, Default, Clone, Copy, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Layout {
+    /// Videos are grouped by library and title with artwork in hidden files.
+    #[default]
+    Default,
+    /// Follows the Plex and Jellyfin naming guidelines, including artwork, so
+    /// a library directory can be added to another media server as is.
+    Plex,
+}
+
+impl Layout {
+    pub(crate) fn movie_template(&self) -> &'static str {
+        DEFAULT_MOVIE_TEMPLATE
+    }
+
+    pub(crate) fn episode_template(&self) -> &'static str {
+        match self {
+            Self::Default => DEFAULT_EPISODE_TEMPLATE,
+            Self::Plex => PLEX_EPISODE_TEMPLATE,
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+/// Decides which downloaded videos may be deleted to make space for new ones
+/// when a size limit would be exceeded.
+#[derive(Deserialize, Serialize, Default, Clone, Copy, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum EvictionPolicy {
+    /// Never evict, new downloads are deferred instead.
+    #[default]
+    None,
+    /// Evict watched videos, least recently watched first, to make space for
+    /// unwatched videos.
+    Watched,
+    /// Evict videos with the oldest air date to make space for newer videos.
+    Oldest,
+    /// Evict watched videos first, least recently watched first, followed by
+    /// unwatched episodes furthest from the next episode to watch. Videos in
+    /// progress and the next episode of each show are kept.
+    Smart,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "lowercase")]
 pub(crate) enum H264Profile {
     Baseline,
@@ -61,22 +487,74 @@ pub(crate) enum H264Profile {
 
 derive_display_from_serialize!(H264Profile);
 
-#[derive(Deserialize, Serialize, Default, Clone, Debug, PartialEq)]
+/// Hardware encoders that ffmpeg can use for local transcodes.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum HardwareAcceleration {
+    /// Intel and AMD GPUs on Linux.
+    Vaapi,
+    /// NVIDIA GPUs.
+    Nvenc,
+    /// Apple devices.
+    VideoToolbox,
+}
+
+/// A kind of device that the store's files are copied to and played on.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum DeviceProfile {
+    /// iPhones and iPads, playing files in the Files app or VLC.
+    Apple,
+}
+
+impl DeviceProfile {
+    /// Restricts a transcode profile to what the device plays natively.
+    pub(crate) fn apply(&self, profile: TranscodeProfile) -> TranscodeProfile {
+        match self {
+            Self::Apple => TranscodeProfile {
+                containers: Some(vec![ContainerFormat::Mp4]),
+                video_codecs: Some(vec![VideoCodec::H264]),
+                audio_codecs: Some(vec![AudioCodec::Aac]),
+                audio_channels: Some(profile.audio_channels.unwrap_or(2)),
+                h264_profiles: Some(profile.h264_profiles.clone().unwrap_or_else(|| {
+                    vec![H264Profile::Baseline, H264Profile::Main, H264Profile::High]
+                })),
+                ..profile
+            },
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Default, Clone, Debug, PartialEq, JsonSchema)]
 pub(crate) struct TranscodeProfile {
     /// Maximum bitrate in kbps.
     pub(crate) bitrate: Option<u32>,
     /// width, height.
     pub(crate) dimensions: Option<(u32, u32)>,
     /// Valid video container formats.
+    #[schemars(with = "Option<Vec<String>>")]
     pub(crate) containers: Option<Vec<ContainerFormat>>,
     /// Valid video codecs.
+    #[schemars(with = "Option<Vec<String>>")]
     pub(crate) video_codecs: Option<Vec<VideoCodec>>,
     /// Valid audio codecs.
+    #[schemars(with = "Option<Vec<String>>")]
     pub(crate) audio_codecs: Option<Vec<AudioCodec>>,
     /// Audio channels
     pub(crate) audio_channels: Option<u16>,
     /// Allowable h264 profiles (baseline, main, high)
     pub(crate) h264_profiles: Option<Vec<H264Profile>>,
+    /// Whether the subtitles selected on the server are burnt into the video,
+    /// defaults to true.
+    pub(crate) burn_subtitles: Option<bool>,
+    /// The preferred audio language as an ISO 639 code, e.g. `eng`. For server
+    /// transcodes the track is selected on the server, which Plex remembers
+    /// for the account.
+    pub(crate) audio_language: Option<String>,
+    /// The hardware encoder to use when transcoding locally.
+    pub(crate) hardware_acceleration: Option<HardwareAcceleration>,
+    /// The device for VAAPI, defaults to `/dev/dri/renderD128`.
+    pub(crate) hardware_device: Option<PathBuf>,
 }
 
 impl TranscodeProfile {
@@ -112,7 +590,7 @@ impl TranscodeProfile {
             width,
             height,
             audio_boost: None,
-            burn_subtitles: true,
+            burn_subtitles: self.burn_subtitles.unwrap_or(true),
             containers: self
                 .containers
                 .clone()
@@ -150,14 +628,424 @@ impl PartialOrd for TranscodeProfile {
     }
 }
 
-#[derive(Deserialize, Serialize, Default, Clone, Debug)]
+#[derive(Deserialize, Serialize, Default, Clone, Debug, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct Config {
+    /// The format version the config was written with, older configs are
+    /// migrated when loaded.
+    #[serde(default)]
+    pub(crate) version: u32,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub(crate) max_downloads: Option<usize>,
+    /// Download videos from items added since the last sync before any
+    /// others, defaults to true.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) prioritize_new: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) queue_order: Option<QueueOrder>,
+    /// Maximum rate in kilobytes per second for each download.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) max_download_rate: Option<u64>,
+    /// Times of day with a different download rate, `maxDownloadRate`
+    /// applies outside of them. The first matching window is used.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) bandwidth_schedule: Vec<BandwidthWindow>,
+    /// Seconds to wait when connecting to a server before treating it as
+    /// unreachable, defaults to 30.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) connect_timeout: Option<u64>,
+    /// Seconds that details of sync items and the capabilities fetched from a
+    /// server are reused for before being fetched again, defaults to a day.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) item_cache_ttl: Option<u64>,
+    /// The ffmpeg binary used by servers that transcode locally, defaults to
+    /// finding `ffmpeg` on the path.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) ffmpeg: Option<PathBuf>,
+    /// A command and arguments run on every completed download with the
+    /// file's path appended. Downloads are only accepted if it exits
+    /// successfully, otherwise they are moved to the quarantine directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) verify_command: Option<Vec<String>>,
+    /// The hash recorded for completed downloads, defaults to sha1. Existing
+    /// downloads keep the hash they were recorded with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) hash_algorithm: Option<HashAlgorithm>,
+    /// How the state is stored, defaults to json. A change takes effect, and
+    /// the existing state is migrated, the next time the store is opened.
+    /// The mobile app can only open stores that use json.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) state_backend: Option<StateBackend>,
+    /// Where downloaded videos and artwork are written or mirrored, defaults
+    /// to local.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) media_storage: Option<MediaStorage>,
+    /// Recompresses downloaded artwork and metadata files to save space.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) compression: Option<FileCompression>,
     #[serde(default)]
     pub(crate) servers: HashMap<String, ServerConfig>,
     pub(crate) device: Option<String>,
+    /// The kind of device the files are played on. Transcodes are restricted
+    /// to formats it plays and an index of the videos is written into the
+    /// store. Profiles that download originals are left unchanged.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) device_profile: Option<DeviceProfile>,
     #[serde(default)]
     pub(crate) profiles: HashMap<String, TranscodeProfile>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub(crate) conflict_resolutions: HashMap<ConflictKind, Resolution>,
+    /// How a sync handles failures of each class of error, by default network
+    /// errors are retried three times and other failed items are skipped.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub(crate) error_handling: HashMap<ErrorClass, ErrorAction>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) retry: Option<RetryPolicy>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) webhooks: Vec<Webhook>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) notifications: Vec<Notification>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) playlist_export: Option<PlaylistExport>,
+    /// Metadata files to write once a sync's downloads have finished.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) metadata_exporters: Vec<MetadataFormat>,
+    /// Maximum space in bytes to use for all downloads in the store.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) max_size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) eviction_policy: Option<EvictionPolicy>,
+    /// A directory relative to the store, for example `.metadata`, to write
+    /// artwork beneath instead of beside the media files.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) metadata_root: Option<PathBuf>,
+    /// An absolute path, such as a mounted network share, to keep downloaded
+    /// media and artwork in instead of the store's directory. The config and
+    /// state stay in the store.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) media_root: Option<PathBuf>,
+    /// The kinds of artwork to download for items, defaults to just posters.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) artwork: Option<Vec<ArtworkKind>>,
+    /// What to do with shows that have no episodes to sync once filters are
+    /// applied, or only specials. By default they are synced like any other
+    /// show.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) empty_shows: Option<EmptyShowPolicy>,
+    /// How downloaded files are arranged, the templates below override the
+    /// layout's paths for videos.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) layout: Option<Layout>,
+    /// The path of downloaded movies relative to the server's directory. The
+    /// placeholders `{library}`, `{title}`, `{year}`, `{part}` and `{ext}` are
+    /// available.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) movie_template: Option<String>,
+    /// The path of downloaded episodes relative to the server's directory. The
+    /// placeholders `{library}`, `{show}`, `{show_year}`, `{season}`,
+    /// `{episode}`, `{title}`, `{part}` and `{ext}` are available. Numbers can
+    /// be zero padded, e.g. `{season:02}`, and values passed through `slug`,
+    /// `lower`, `upper`, `truncate(n)` or `pad(n)`, e.g. `{title|slug}`.
+    /// Sections in square brackets like `[ ({show_year})]` are left out when
+    /// a value in them is unknown, `{show_year?}` renders nothing instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) episode_template: Option<String>,
+}
+
+impl Config {
+    /// Parses a config file, migrating older layouts. Returns whether the
+    /// config was migrated and so should be written back.
+    pub(crate) fn parse(str: &str) -> Result<(Self, bool), Error> {
+        let mut value: Value = from_str(str).map_err(|e| Error::InvalidConfig(e.to_string()))?;
+        let migrated = migrate_config(&mut value)?;
+
+        let mut config: Config = serde_path_to_error::deserialize(value)
+            .map_err(|e| Error::InvalidConfig(format!("{}: {}", e.path(), e.inner())))?;
+        config.version = FORMAT_VERSION;
+        config.validate()?;
+
+        Ok((config, migrated))
+    }
+
+    /// Checks the settings that serde cannot, naming the offending key.
+    fn validate(&self) -> Result<(), Error> {
+        let check_profile = |key: String, profile: &Option<String>| match profile {
+            Some(profile)
+                if !self.profiles.contains_key(profile)
+                    && !DEFAULT_PROFILES.contains_key(profile) =>
+            {
+                Err(Error::InvalidConfig(format!(
+                    "{key}: unknown transcode profile {profile}"
+                )))
+            }
+            _ => Ok(()),
+        };
+
+        for (id, server) in self.servers.iter() {
+            check_profile(
+                format!("servers.{id}.transcodeProfile"),
+                &server.transcode_profile,
+            )?;
+
+            for sync in server.syncs.values() {
+                check_profile(
+                    format!("servers.{id}.syncs[id={}].transcodeProfile", sync.id),
+                    &sync.transcode_profile,
+                )?;
+            }
+
+            for (index, query) in server.queries.iter().enumerate() {
+                check_profile(
+                    format!("servers.{id}.queries[{index}].transcodeProfile"),
+                    &query.transcode_profile,
+                )?;
+
+                if let Err(e) = Filter::from_str(&query.query) {
+                    return Err(Error::InvalidConfig(format!(
+                        "servers.{id}.queries[{index}].query: {e}"
+                    )));
+                }
+            }
+
+            for (key, path) in server.paths.iter() {
+                if !is_relative_path(path) {
+                    return Err(Error::InvalidConfig(format!(
+                        "servers.{id}.paths.{key}: must be relative to the server's directory"
+                    )));
+                }
+            }
+        }
+
+        for (index, window) in self.bandwidth_schedule.iter().enumerate() {
+            for (field, time) in [("start", &window.start), ("end", &window.end)] {
+                if parse_time(time).is_none() {
+                    return Err(Error::InvalidConfig(format!(
+                        "bandwidthSchedule[{index}].{field}: expected a time as HH:MM"
+                    )));
+                }
+            }
+        }
+
+        if self
+            .compression
+            .as_ref()
+            .and_then(|compression| compression.artwork_quality)
+            .is_some_and(|quality| !(1..=100).contains(&quality))
+        {
+            return Err(Error::InvalidConfig(
+                "compression.artworkQuality: must be between 1 and 100".to_string(),
+            ));
+        }
+
+        // Absolute paths would place files outside of the store.
+        if self.metadata_root.as_ref().is_some_and(|p| p.is_absolute()) {
+            return Err(Error::InvalidConfig(
+                "metadataRoot: must be relative to the store".to_string(),
+            ));
+        }
+        if self.media_root.as_ref().is_some_and(|p| !p.is_absolute()) {
+            return Err(Error::InvalidConfig(
+                "mediaRoot: must be an absolute path".to_string(),
+            ));
+        }
+        if self
+            .playlist_export
+            .as_ref()
+            .is_some_and(|export| export.directory.is_absolute())
+        {
+            return Err(Error::InvalidConfig(
+                "playlistExport.directory: must be relative to the store".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks that a path stays within the directory it is relative to.
+pub(crate) fn is_relative_path(path: &str) -> bool {
+    let path = Path::new(path);
+    !path.as_os_str().is_empty()
+        && path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+}
+
+/// Converts a snake_case key to the camelCase used in the config file.
+fn camel_case(key: &str) -> String {
+    let mut result = String::new();
+    let mut upper = false;
+
+    for c in key.chars() {
+        if c == '_' {
+            upper = true;
+        } else if upper {
+            result.extend(c.to_uppercase());
+            upper = false;
+        } else {
+            result.push(c);
+        }
+    }
+
+    result
+}
+
+/// Finds the name of an object's field, accepting snake_case for camelCase.
+fn field_name(object: &Map<String, Value>, segment: &str) -> String {
+    if object.contains_key(segment) {
+        segment.to_owned()
+    } else {
+        camel_case(segment)
+    }
+}
+
+/// Looks up a dotted key such as `servers.home.maxTranscodes` in the
+/// serialized config. Array elements are addressed by index.
+pub(crate) fn lookup<'a>(value: &'a Value, key: &str) -> Option<&'a Value> {
+    key.split('.')
+        .try_fold(value, |value, segment| match value {
+            Value::Object(object) => object.get(&field_name(object, segment)),
+            Value::Array(array) => array.get(segment.parse::<usize>().ok()?),
+            _ => None,
+        })
+}
+
+/// Sets, or with `None` removes, the value at a dotted key in the serialized
+/// config, creating intermediate objects as needed.
+pub(crate) fn update(root: &mut Value, key: &str, new_value: Option<Value>) -> Result<(), Error> {
+    let not_found = || Error::InvalidConfig(format!("{key}: not found"));
+    let (parents, last) = match key.rsplit_once('.') {
+        Some((parents, last)) => (Some(parents), last),
+        None => (None, key),
+    };
+
+    let mut value = root;
+    for segment in parents.into_iter().flat_map(|parents| parents.split('.')) {
+        value = match value {
+            Value::Object(object) => {
+                let name = field_name(object, segment);
+                if new_value.is_none() && !object.contains_key(&name) {
+                    return Err(not_found());
+                }
+                object
+                    .entry(name)
+                    .or_insert_with(|| Value::Object(Map::new()))
+            }
+            Value::Array(array) => segment
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| array.get_mut(index))
+                .ok_or_else(not_found)?,
+            _ => return Err(not_found()),
+        };
+    }
+
+    match (value, new_value) {
+        (Value::Object(object), Some(new_value)) => {
+            let name = field_name(object, last);
+            object.insert(name, new_value);
+        }
+        (Value::Object(object), None) => {
+            let name = field_name(object, last);
+            object.remove(&name).ok_or_else(not_found)?;
+        }
+        (Value::Array(array), new_value) => {
+            let index = last.parse::<usize>().map_err(|_| not_found())?;
+            if index >= array.len() {
+                return Err(not_found());
+            }
+            match new_value {
+                Some(new_value) => array[index] = new_value,
+                None => {
+                    array.remove(index);
+                }
+            }
+        }
+        _ => return Err(not_found()),
+    }
+
+    Ok(())
+}
+
+/// The schemas that a schema stands for, following references and the
+/// alternatives of optional settings and enums.
+fn expand_schema<'a>(root: &'a RootSchema, schema: &'a SchemaObject) -> Vec<&'a SchemaObject> {
+    let mut schemas = vec![schema];
+
+    if let Some(name) = schema
+        .reference
+        .as_deref()
+        .and_then(|reference| reference.strip_prefix("#/definitions/"))
+    {
+        if let Some(Schema::Object(definition)) = root.definitions.get(name) {
+            schemas.extend(expand_schema(root, definition));
+        }
+    }
+
+    if let Some(ref subschemas) = schema.subschemas {
+        for alternatives in [&subschemas.all_of, &subschemas.any_of, &subschemas.one_of]
+            .into_iter()
+            .flatten()
+        {
+            for alternative in alternatives {
+                if let Schema::Object(alternative) = alternative {
+                    schemas.extend(expand_schema(root, alternative));
+                }
+            }
+        }
+    }
+
+    schemas
+}
+
+/// Whether a dotted key such as `servers.home.maxTranscodes` names a setting
+/// that the config can hold, whether or not it is currently set.
+pub(crate) fn is_setting(key: &str) -> bool {
+    let root = schema_for!(Config);
+    let mut schemas = vec![&root.schema];
+
+    for segment in key.split('.') {
+        let mut children = Vec::new();
+
+        for schema in schemas
+            .into_iter()
+            .flat_map(|schema| expand_schema(&root, schema))
+        {
+            if let Some(ref object) = schema.object {
+                let property = object
+                    .properties
+                    .get(segment)
+                    .or_else(|| object.properties.get(&camel_case(segment)));
+
+                match (property, object.additional_properties.as_deref()) {
+                    (Some(Schema::Object(property)), _) => children.push(property),
+                    (None, Some(Schema::Object(value))) => children.push(value),
+                    _ => {}
+                }
+            }
+
+            if let Some(ref array) = schema.array {
+                match (&array.items, segment.parse::<usize>()) {
+                    (Some(SingleOrVec::Single(item)), Ok(_)) => {
+                        if let Schema::Object(item) = item.as_ref() {
+                            children.push(item);
+                        }
+                    }
+                    (Some(SingleOrVec::Vec(items)), Ok(index)) => {
+                        if let Some(Schema::Object(item)) = items.get(index) {
+                            children.push(item);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        if children.is_empty() {
+            return false;
+        }
+        schemas = children;
+    }
+
+    true
 }
@@ -0,0 +1,231 @@
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+/// The different kinds of conflict, used to remember a choice for future syncs.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Hash, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum ConflictKind {
+    MediaReplaced,
+    ItemMissing,
+    DuplicateName,
+}
+
+/// A situation encountered during a sync where there is more than one sensible
+/// way to proceed.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Conflict {
+    /// The media for a video has been replaced on the server since it was
+    /// downloaded.
+    MediaReplaced {
+        server: String,
+        video: String,
+        title: String,
+    },
+    /// An item in the sync list no longer exists on the server.
+    ItemMissing { server: String, item: String },
+    /// A new movie or show has the same title and year as one already synced
+    /// from the same library so its files would be stored under the same
+    /// name.
+    DuplicateName {
+        server: String,
+        item: String,
+        title: String,
+    },
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum Resolution {
+    /// Delete the existing download and fetch the new media.
+    Redownload,
+    /// Keep the existing download even though it no longer matches the server.
+    KeepExisting,
+    /// Leave the item in the sync list but skip it for this sync.
+    Ignore,
+    /// Remove the item from the sync list.
+    RemoveSync,
+    /// Store the item under a name made unique with its ID.
+    Rename,
+}
+
+impl fmt::Display for Resolution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Redownload => f.pad("Re-download"),
+            Self::KeepExisting => f.pad("Keep existing download"),
+            Self::Ignore => f.pad("Ignore"),
+            Self::RemoveSync => f.pad("Remove from sync list"),
+            Self::Rename => f.pad("Store under a unique name"),
+        }
+    }
+}
+
+impl Conflict {
+    pub fn kind(&self) -> ConflictKind {
+        match self {
+            Self::MediaReplaced { .. } => ConflictKind::MediaReplaced,
+            Self::ItemMissing { .. } => ConflictKind::ItemMissing,
+            Self::DuplicateName { .. } => ConflictKind::DuplicateName,
+        }
+    }
+
+    /// The possible resolutions for this conflict. The first is the default
+    /// used when nothing else has been chosen.
+    pub fn resolutions(&self) -> &'static [Resolution] {
+        match self {
+            Self::MediaReplaced { .. } => &[Resolution::Redownload, Resolution::KeepExisting],
+            Self::ItemMissing { .. } => &[Resolution::Ignore, Resolution::RemoveSync],
+            Self::DuplicateName { .. } => &[Resolution::Rename, Resolution::Ignore],
+        }
+    }
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MediaReplaced {
+                server,
+                video,
+                title,
+            } => write!(
+                f,
+                "The media for '{title}' ({server}/{video}) has been replaced on the server"
+            ),
+            Self::ItemMissing { server, item } => {
+                write!(
+                    f,
+                    "Sync item {server}/{item} no longer exists on the server"
+                )
+            }
+            Self::DuplicateName {
+                server,
+                item,
+                title,
+            } => write!(
+                f,
+                "'{title}' ({server}/{item}) has the same name as an item already synced"
+            ),
+        }
+    }
+}
+
+/// A choice made by a [`ConflictResolver`].
+#[derive(Clone, Copy, Debug)]
+pub struct Choice {
+    pub resolution: Resolution,
+    /// Use the same resolution for all future conflicts of this kind.
+    pub remember: bool,
+}
+
+/// Allows a frontend to choose how conflicts are resolved, for example by
+/// prompting the user. The store is not locked while it is asked.
+pub trait ConflictResolver: Send + Sync {
+    /// Returns the choice to make or `None` to apply the default.
+    fn resolve(&self, conflict: &Conflict) -> Option<Choice>;
+}
+
+/// Tracks the resolutions used during a single state update. When there is a
+/// resolver, conflicts without a remembered resolution or an earlier decision
+/// are collected rather than resolved so that the resolver can be asked once
+/// the store is unlocked.
+pub(crate) struct Conflicts {
+    pub(crate) server: String,
+    ask: bool,
+    resolutions: HashMap<ConflictKind, Resolution>,
+    decisions: HashMap<Conflict, Resolution>,
+    pending: Vec<Conflict>,
+}
+
+impl Conflicts {
+    pub(crate) fn new(
+        server: &str,
+        ask: bool,
+        resolutions: &HashMap<ConflictKind, Resolution>,
+        decisions: &HashMap<Conflict, Resolution>,
+    ) -> Self {
+        Self {
+            server: server.to_owned(),
+            ask,
+            resolutions: resolutions.clone(),
+            decisions: decisions.clone(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Returns the resolution to use or `None` when the conflict has been
+    /// collected to be asked about, in which case the affected item should be
+    /// left as it is.
+    pub(crate) fn resolve(&mut self, conflict: Conflict) -> Option<Resolution> {
+        let allowed = conflict.resolutions();
+
+        if let Some(resolution) = self.resolutions.get(&conflict.kind()) {
+            if allowed.contains(resolution) {
+                return Some(*resolution);
+            }
+        }
+
+        if let Some(resolution) = self.decisions.get(&conflict) {
+            return Some(*resolution);
+        }
+
+        if self.ask {
+            if !self.pending.contains(&conflict) {
+                self.pending.push(conflict);
+            }
+
+            return None;
+        }
+
+        Some(allowed[0])
+    }
+
+    /// The conflicts collected to be asked about.
+    pub(crate) fn pending(self) -> Vec<Conflict> {
+        self.pending
+    }
+}
+
+/// Asks the resolver about conflicts collected during a state update, adding
+/// the choices to `decisions` and any to remember to `resolutions`. Returns
+/// whether `resolutions` changed. Resolvers may wait for the user so no locks
+/// should be held.
+pub(crate) fn ask(
+    resolver: &dyn ConflictResolver,
+    conflicts: Vec<Conflict>,
+    decisions: &mut HashMap<Conflict, Resolution>,
+    resolutions: &mut HashMap<ConflictKind, Resolution>,
+) -> bool {
+    let mut remembered = false;
+
+    for conflict in conflicts {
+        let kind = conflict.kind();
+        let allowed = conflict.resolutions();
+
+        // An earlier answer may have been remembered for this kind.
+        let resolution = match resolutions.get(&kind) {
+            Some(resolution) if allowed.contains(resolution) => *resolution,
+            _ => match resolver.resolve(&conflict) {
+                Some(choice) if allowed.contains(&choice.resolution) => {
+                    if choice.remember {
+                        resolutions.insert(kind, choice.resolution);
+                        remembered = true;
+                    }
+
+                    choice.resolution
+                }
+                Some(choice) => {
+                    warn!(?kind, resolution=?choice.resolution, "Invalid resolution for conflict");
+                    allowed[0]
+                }
+                None => allowed[0],
+            },
+        };
+
+        decisions.insert(conflict, resolution);
+    }
+
+    remembered
+}
@@ -1,5 +1,39 @@
+use std::{io::ErrorKind, path::PathBuf};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+/// Broad categories of error, used to decide how a sync handles a failure.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Hash, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorClass {
+    /// Connection failures, timeouts and server side errors.
+    Network,
+    /// The item or file no longer exists on the server.
+    NotFound,
+    /// The server rejected the credentials.
+    Auth,
+    /// The server or local transcode failed.
+    Transcode,
+    /// Reading or writing the store failed.
+    Storage,
+    Other,
+}
+
+/// What a sync does when transferring an item fails.
+#[derive(Deserialize, Serialize, Default, Clone, Copy, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(tag = "action", rename_all = "camelCase")]
+pub enum ErrorAction {
+    /// Try the item again, up to this many more times, before skipping it.
+    Retry { attempts: u32 },
+    /// Skip the item and report the failure.
+    #[default]
+    Skip,
+    /// Stop transferring any further items.
+    Abort,
+}
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("{source}")]
@@ -17,12 +51,23 @@ pub enum Error {
         #[from]
         source: plex_api::Error,
     },
+    #[error("Unable to access the state database: {source}")]
+    DatabaseError {
+        #[from]
+        source: rusqlite::Error,
+    },
     #[error("A server with this identifier already exists")]
     ServerExists,
+    #[error("Server {0} could not be reached")]
+    ServerUnreachable(String),
     #[error("The server is no longer registered to this account")]
     MyPlexServerNotFound,
     #[error("This server is no longer authenticated correctly. Try logging in again")]
     ServerNotAuthenticated,
+    #[error("The server at {0} is not the server previously synced")]
+    ServerMismatch(String),
+    #[error("Server {0} could only be reached through a relay, which is not allowed")]
+    RelayForbidden(String),
     #[error("Item {0} was not found on the server")]
     ItemNotFound(String),
     #[error("Item {0} is not supported.")]
@@ -31,6 +76,8 @@ pub enum Error {
     ItemIncomplete(String, String),
     #[error("The item appears to be missing on the server")]
     MissingItem,
+    #[error("Item {0} is stored as a different type of video")]
+    UnexpectedVideoType(String),
     #[error("Cannot download an item until the item is available (call wait_for_download)")]
     DownloadUnavailable,
     #[error("Server dropped the transcode session")]
@@ -39,12 +86,89 @@ pub enum Error {
     TranscodeFailed,
     #[error("Transcoding was skipped")]
     TranscodeSkipped,
+    #[error("Local transcode failed: {0}")]
+    LocalTranscodeFailed(String),
+    #[error("Unable to re-encode artwork: {0}")]
+    ArtworkEncoding(String),
+    #[error("Unable to store {path} in the remote storage: {message}")]
+    RemoteStorage { path: PathBuf, message: String },
     #[error("Unknown transcode profile {0}")]
     UnknownProfile(String),
+    #[error("Unknown library {0}")]
+    UnknownLibrary(String),
+    #[error("Invalid filter: {0}")]
+    InvalidFilter(String),
+    #[error("Downloaded file {path} is {actual} bytes but {expected} bytes were expected")]
+    DownloadMismatch {
+        path: PathBuf,
+        expected: u64,
+        actual: u64,
+    },
+    #[error("The server responded to the download with status {0}")]
+    DownloadRequestFailed(u16),
+    #[error("The server refused to select the audio track with status {0}")]
+    AudioSelectionFailed(u16),
+    #[error("Downloaded file {path} failed verification: {message}")]
+    VerificationFailed { path: PathBuf, message: String },
+    #[error("Invalid config: {0}")]
+    InvalidConfig(String),
+    #[error("Invalid naming template: {0}")]
+    InvalidTemplate(String),
+    #[error("Unknown report format {0}")]
+    UnknownReportFormat(String),
+    #[error("Unknown download order {0}")]
+    UnknownQueueOrder(String),
+    #[error("The store is in use by another process")]
+    StoreLocked,
+    #[error("The media root {0} is not available, check that it is mounted")]
+    MediaRootUnavailable(PathBuf),
     #[error("Unknown error")]
     Unknown(String),
 }
 
+impl Error {
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            Self::IoError { source } => match source.kind() {
+                ErrorKind::TimedOut
+                | ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::ConnectionRefused
+                | ErrorKind::BrokenPipe
+                | ErrorKind::UnexpectedEof => ErrorClass::Network,
+                _ => ErrorClass::Storage,
+            },
+            Self::PlexError { source } => match source {
+                plex_api::Error::ItemNotFound => ErrorClass::NotFound,
+                plex_api::Error::UnexpectedApiResponse { status_code, .. } => match status_code {
+                    401 | 403 => ErrorClass::Auth,
+                    404 => ErrorClass::NotFound,
+                    _ => ErrorClass::Network,
+                },
+                _ => ErrorClass::Network,
+            },
+            Self::ServerUnreachable(_) | Self::RelayForbidden(_) => ErrorClass::Network,
+            Self::MyPlexServerNotFound | Self::ServerNotAuthenticated => ErrorClass::Auth,
+            Self::ItemNotFound(_) | Self::MissingItem => ErrorClass::NotFound,
+            Self::TranscodeLost
+            | Self::TranscodeFailed
+            | Self::TranscodeSkipped
+            | Self::LocalTranscodeFailed(_) => ErrorClass::Transcode,
+            Self::DownloadMismatch { .. } => ErrorClass::Network,
+            Self::DownloadRequestFailed(status) => match status {
+                401 | 403 => ErrorClass::Auth,
+                404 => ErrorClass::NotFound,
+                _ => ErrorClass::Network,
+            },
+            Self::StoreLocked
+            | Self::DatabaseError { .. }
+            | Self::RemoteStorage { .. }
+            | Self::MediaRootUnavailable(_) => ErrorClass::Storage,
+            _ => ErrorClass::Other,
+        }
+    }
+}
+
 impl From<Error> for String {
     fn from(value: Error) -> Self {
         value.to_string()
@@ -0,0 +1,211 @@
+use std::{path::PathBuf, sync::Arc};
+
+use crate::Progress;
+
+/// A structured event emitted while syncing.
+#[derive(Clone, Debug)]
+pub enum Event {
+    /// The server has started transcoding a video part.
+    TranscodeStarted {
+        server: String,
+        video: String,
+        part: usize,
+    },
+    /// Progress of a server transcode as a percentage.
+    TranscodeProgress {
+        server: String,
+        video: String,
+        part: usize,
+        percent: u64,
+    },
+    /// A video part has started downloading. `offset` is non-zero when resuming
+    /// a previous download.
+    DownloadStarted {
+        server: String,
+        video: String,
+        part: usize,
+        path: PathBuf,
+        offset: u64,
+        size: u64,
+    },
+    DownloadProgress {
+        server: String,
+        video: String,
+        part: usize,
+        position: u64,
+        size: u64,
+    },
+    DownloadComplete {
+        server: String,
+        video: String,
+        part: usize,
+        path: PathBuf,
+    },
+    /// A sync item or query could not be updated. Videos it previously
+    /// included are kept until a later update succeeds.
+    SyncItemFailed {
+        server: String,
+        item: String,
+        error: String,
+    },
+    /// Transferring a video part failed. Sent by the application running the
+    /// sync through [`FlickSync::emit_event`](crate::FlickSync::emit_event).
+    TransferFailed {
+        server: String,
+        video: String,
+        error: String,
+    },
+    /// A sync has finished. Sent by the application running the sync through
+    /// [`FlickSync::emit_event`](crate::FlickSync::emit_event).
+    SyncComplete { failures: usize },
+    /// A video is no longer included in the sync and its files were removed.
+    VideoRemoved {
+        server: String,
+        video: String,
+        title: String,
+        reason: String,
+    },
+    /// A video's downloads were deleted to stay within the size limits.
+    VideoEvicted {
+        server: String,
+        video: String,
+        title: String,
+    },
+    /// An unexpected file was deleted from the store.
+    FilePruned { path: PathBuf },
+    /// Progress through checking the artwork of a server's items. Sent with
+    /// `completed` as zero before starting and once all items are checked.
+    ArtworkProgress {
+        server: String,
+        completed: usize,
+        total: usize,
+    },
+    /// Artwork for an item was downloaded.
+    ArtworkDownloaded {
+        server: String,
+        item: String,
+        path: PathBuf,
+        size: u64,
+    },
+}
+
+impl Event {
+    /// The operation this event is part of, see [`operation_id`].
+    pub fn operation(&self) -> Option<String> {
+        match self {
+            Self::TranscodeStarted {
+                server,
+                video,
+                part,
+            }
+            | Self::TranscodeProgress {
+                server,
+                video,
+                part,
+                ..
+            }
+            | Self::DownloadStarted {
+                server,
+                video,
+                part,
+                ..
+            }
+            | Self::DownloadProgress {
+                server,
+                video,
+                part,
+                ..
+            }
+            | Self::DownloadComplete {
+                server,
+                video,
+                part,
+                ..
+            } => Some(operation_id(server, video, Some(*part))),
+            Self::SyncItemFailed { server, item, .. }
+            | Self::ArtworkDownloaded { server, item, .. } => {
+                Some(operation_id(server, item, None))
+            }
+            Self::TransferFailed { server, video, .. }
+            | Self::VideoRemoved { server, video, .. }
+            | Self::VideoEvicted { server, video, .. } => Some(operation_id(server, video, None)),
+            Self::SyncComplete { .. } | Self::FilePruned { .. } | Self::ArtworkProgress { .. } => {
+                None
+            }
+        }
+    }
+}
+
+/// Identifies an operation on an item within a sync run, a video part's
+/// transfer when `part` is given. It is built from the same server, item and
+/// part that appear in log lines so the two can be matched up.
+pub fn operation_id(server: &str, item: &str, part: Option<usize>) -> String {
+    match part {
+        Some(part) => format!("{server}/{item}/{part}"),
+        None => format!("{server}/{item}"),
+    }
+}
+
+/// Ties an event to the sync run and operation that produced it, so that it
+/// can be matched with the logs, reports and webhook payloads of the same run.
+#[derive(Clone, Debug, Default)]
+pub struct Correlation {
+    /// The id of the sync run, see [`FlickSync::start_run`](crate::FlickSync::start_run).
+    pub run: Option<String>,
+    /// The operation within the run, see [`operation_id`].
+    pub operation: Option<String>,
+}
+
+/// Receives events from flick-sync. Implementations should return quickly as
+/// they are called inline with the operation generating the event.
+pub trait EventSink: Send + Sync {
+    fn event(&self, event: &Event, correlation: &Correlation);
+}
+
+/// A snapshot of the registered event sinks and the current sync run.
+#[derive(Clone, Default)]
+pub(crate) struct Events {
+    sinks: Vec<Arc<dyn EventSink>>,
+    run: Option<String>,
+}
+
+impl Events {
+    pub(crate) fn new(sinks: Vec<Arc<dyn EventSink>>, run: Option<String>) -> Self {
+        Self { sinks, run }
+    }
+
+    pub(crate) fn emit(&self, event: Event) {
+        let correlation = Correlation {
+            run: self.run.clone(),
+            operation: event.operation(),
+        };
+
+        for sink in self.sinks.iter() {
+            sink.event(&event, &correlation);
+        }
+    }
+}
+
+/// Forwards download progress to both a caller supplied `Progress` and the
+/// event sinks.
+pub(crate) struct EventProgress<P> {
+    pub(crate) progress: P,
+    pub(crate) events: Events,
+    pub(crate) server: String,
+    pub(crate) video: String,
+    pub(crate) part: usize,
+}
+
+impl<P: Progress> Progress for EventProgress<P> {
+    fn progress(&mut self, position: u64, size: u64) {
+        self.progress.progress(position, size);
+
+        self.events.emit(Event::DownloadProgress {
+            server: self.server.clone(),
+            video: self.video.clone(),
+            part: self.part,
+            position,
+            size,
+        });
+    }
+}
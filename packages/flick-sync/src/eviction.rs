@@ -0,0 +1,164 @@
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+};
+
+use time::OffsetDateTime;
+
+use crate::{
+    config::EvictionPolicy,
+    state::{PlaybackState, ServerState, VideoDetail, VideoState},
+};
+
+/// How strongly a video should be kept when space is short.
+pub(crate) struct Priority {
+    /// A video may only be evicted to make space for a video in a higher
+    /// class.
+    class: i64,
+    /// Orders videos within a class, higher values are kept longer.
+    value: i64,
+    /// A short explanation of the priority for reports.
+    pub(crate) reason: String,
+}
+
+impl Priority {
+    fn new<R: ToString>(class: i64, value: i64, reason: R) -> Self {
+        Self {
+            class,
+            value,
+            reason: reason.to_string(),
+        }
+    }
+
+    /// Whether a video with this priority may be evicted for one with `other`.
+    pub(crate) fn can_evict_for(&self, other: &Priority) -> bool {
+        self.class < other.class
+    }
+
+    /// Orders priorities from lowest to highest.
+    pub(crate) fn compare(&self, other: &Priority) -> Ordering {
+        (self.class, self.value).cmp(&(other.class, other.value))
+    }
+}
+
+fn is_pinned(pinned: &HashSet<String>, server_state: &ServerState, video: &VideoState) -> bool {
+    if pinned.contains(&video.id) {
+        return true;
+    }
+
+    if let VideoDetail::Episode(ref episode) = video.detail {
+        if pinned.contains(&episode.season) {
+            return true;
+        }
+
+        if let Some(season) = server_state.seasons.get(&episode.season) {
+            return pinned.contains(&season.show);
+        }
+    }
+
+    false
+}
+
+/// For every synced episode finds how many episodes it is after the next
+/// episode to watch in its show. The next episode to watch follows the last
+/// watched or partly watched episode.
+fn episode_distances(server_state: &ServerState) -> HashMap<String, i64> {
+    let mut shows: HashMap<&str, Vec<(u32, u32, &VideoState)>> = HashMap::new();
+
+    for video in server_state.videos.values() {
+        if let VideoDetail::Episode(ref episode) = video.detail {
+            if let Some(season) = server_state.seasons.get(&episode.season) {
+                shows.entry(season.show.as_str()).or_default().push((
+                    season.index,
+                    episode.index,
+                    video,
+                ));
+            }
+        }
+    }
+
+    let mut distances = HashMap::new();
+
+    for episodes in shows.values_mut() {
+        episodes.sort_by_key(|(season, index, _)| (*season, *index));
+
+        let next = episodes
+            .iter()
+            .rposition(|(_, _, v)| v.playback_state != PlaybackState::Unplayed)
+            .map(|last| last + 1)
+            .unwrap_or_default();
+
+        for (position, (_, _, video)) in episodes.iter().enumerate() {
+            distances.insert(video.id.clone(), position as i64 - next as i64);
+        }
+    }
+
+    distances
+}
+
+fn watched_reason(video: &VideoState, now: OffsetDateTime) -> String {
+    match video.last_viewed_at {
+        Some(viewed) => format!("watched {} days ago", (now - viewed).whole_days()),
+        None => "watched".to_string(),
+    }
+}
+
+/// Calculates the priority of every video on a server under the given policy.
+pub(crate) fn priorities(
+    policy: EvictionPolicy,
+    server_state: &ServerState,
+    pinned: &HashSet<String>,
+) -> HashMap<String, Priority> {
+    let now = OffsetDateTime::now_utc();
+    let distances = if policy == EvictionPolicy::Smart {
+        episode_distances(server_state)
+    } else {
+        HashMap::new()
+    };
+
+    server_state
+        .videos
+        .values()
+        .map(|video| {
+            let aired = video.air_date.to_julian_day() as i64;
+            let viewed = video
+                .last_viewed_at
+                .map(|t| t.unix_timestamp())
+                .unwrap_or_default();
+
+            let priority = match policy {
+                EvictionPolicy::None => Priority::new(0, aired, "eviction is disabled"),
+                _ if is_pinned(pinned, server_state, video) => {
+                    Priority::new(i64::MAX, aired, "pinned")
+                }
+                EvictionPolicy::Watched => match video.playback_state {
+                    PlaybackState::Played => Priority::new(0, viewed, watched_reason(video, now)),
+                    _ => Priority::new(1, aired, "unwatched"),
+                },
+                EvictionPolicy::Oldest => {
+                    Priority::new(aired, 0, format!("aired {}", video.air_date))
+                }
+                EvictionPolicy::Smart => match video.playback_state {
+                    PlaybackState::Played => Priority::new(0, viewed, watched_reason(video, now)),
+                    PlaybackState::InProgress { .. } => Priority::new(2, 0, "in progress"),
+                    PlaybackState::Unplayed => match distances.get(&video.id) {
+                        Some(0) => Priority::new(2, 0, "next episode to watch"),
+                        Some(distance) if *distance < 0 => Priority::new(
+                            1,
+                            *distance,
+                            "unwatched but later episodes have been watched",
+                        ),
+                        Some(distance) => Priority::new(
+                            1,
+                            -distance,
+                            format!("unwatched, {distance} episodes after the next to watch"),
+                        ),
+                        None => Priority::new(1, 0, "unwatched"),
+                    },
+                },
+            };
+
+            (video.id.clone(), priority)
+        })
+        .collect()
+}
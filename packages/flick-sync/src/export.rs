@@ -0,0 +1,178 @@
+//! Flat copies of playlists and collections for players, such as car stereos,
+//! that cannot follow the store's nested directories or m3u8 playlists.
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use async_std::fs::{copy, create_dir_all, hard_link, metadata, write};
+use tracing::{debug, warn};
+
+use crate::{
+    backend::LocalStorage,
+    config::{ExportMode, PlaylistExport},
+    server::prune_directory,
+    state::{ServerState, State, VideoDetail, VideoState},
+    util::safe,
+    Result,
+};
+
+/// The downloaded files of a video, relative to the store.
+fn video_files(video: &VideoState) -> impl Iterator<Item = PathBuf> + '_ {
+    video
+        .parts
+        .iter()
+        .filter(|part| !part.download.needs_download())
+        .filter_map(|part| part.download.file())
+}
+
+/// The episodes of a show in season and episode order.
+fn show_episodes<'a>(server_state: &'a ServerState, show: &str) -> Vec<&'a VideoState> {
+    let mut episodes: Vec<(u32, u32, &VideoState)> = server_state
+        .videos
+        .values()
+        .filter_map(|video| match video.detail {
+            VideoDetail::Episode(ref detail) => {
+                let season = server_state.seasons.get(&detail.season)?;
+                if season.show == show {
+                    Some((season.index, detail.index, video))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        })
+        .collect();
+
+    episodes.sort_by_key(|(season, episode, _)| (*season, *episode));
+    episodes.into_iter().map(|(_, _, video)| video).collect()
+}
+
+/// The titles and downloaded files of the lists to export.
+fn lists(server_state: &ServerState, collections: bool) -> Vec<(String, Vec<PathBuf>)> {
+    let mut lists = Vec::new();
+
+    for playlist in server_state.playlists.values() {
+        let files = playlist
+            .videos
+            .iter()
+            .filter_map(|id| server_state.videos.get(id))
+            .flat_map(video_files)
+            .collect();
+        lists.push((playlist.title.clone(), files));
+    }
+
+    if collections {
+        for collection in server_state.collections.values() {
+            let mut files = Vec::new();
+
+            for id in collection.contents.iter() {
+                if let Some(video) = server_state.videos.get(id) {
+                    files.extend(video_files(video));
+                } else if server_state.shows.contains_key(id) {
+                    for episode in show_episodes(server_state, id) {
+                        files.extend(video_files(episode));
+                    }
+                }
+            }
+
+            lists.push((collection.title.clone(), files));
+        }
+    }
+
+    lists
+}
+
+async fn place_file(source: &Path, target: &Path, mode: ExportMode) -> Result {
+    if let (Ok(existing), Ok(original)) = (metadata(target).await, metadata(source).await) {
+        if existing.len() == original.len() {
+            return Ok(());
+        }
+    }
+
+    if let Some(parent) = target.parent() {
+        create_dir_all(parent).await?;
+    }
+
+    if mode == ExportMode::Links {
+        match hard_link(source, target).await {
+            Ok(()) => return Ok(()),
+            // Filesystems such as FAT cannot link so fall back to copying.
+            Err(e) => debug!(error=?e, path=?target, "Failed to link, copying instead"),
+        }
+    }
+
+    copy(source, target).await?;
+    Ok(())
+}
+
+/// Writes a numbered folder, and optionally an m3u file, for every playlist
+/// and collection with downloaded videos and removes anything else from the
+/// export directory.
+pub(crate) async fn export(root: &Path, state: &State, export: &PlaylistExport) -> Result {
+    let directory = root.join(&export.directory);
+    let mut expected = HashSet::new();
+    let mut names = HashSet::new();
+
+    let mut server_ids: Vec<&String> = state.servers.keys().collect();
+    server_ids.sort();
+
+    for server_id in server_ids {
+        let server_state = state.servers.get(server_id).unwrap();
+        let mut lists = lists(server_state, export.collections);
+        lists.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        for (title, files) in lists {
+            if files.is_empty() {
+                continue;
+            }
+
+            let mut name = safe(&title);
+            if !names.insert(name.clone()) {
+                name = safe(format!("{title} ({server_id})"));
+                names.insert(name.clone());
+            }
+
+            let width = files.len().to_string().len().max(2);
+            let mut entries = Vec::new();
+
+            for (index, file) in files.iter().enumerate() {
+                let file_name = match file.file_name() {
+                    Some(file_name) => file_name.to_string_lossy(),
+                    None => continue,
+                };
+                let entry = format!("{:0width$} - {file_name}", index + 1);
+                let target = directory.join(&name).join(&entry);
+
+                if let Err(e) = place_file(&root.join(file), &target, export.mode).await {
+                    warn!(error=?e, path=?target, "Failed to export file");
+                    continue;
+                }
+
+                expected.insert(target);
+                entries.push(format!("{name}/{entry}"));
+            }
+
+            if export.m3u && !entries.is_empty() {
+                let path = directory.join(format!("{name}.m3u"));
+                create_dir_all(&directory).await?;
+                write(&path, entries.join("\n") + "\n").await?;
+                expected.insert(path);
+            }
+        }
+    }
+
+    if metadata(&directory).await.is_ok() {
+        prune_directory(
+            &directory,
+            &expected,
+            &Default::default(),
+            &LocalStorage,
+            None,
+        )
+        .await;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,531 @@
+use std::{
+    iter::Peekable,
+    str::{Chars, FromStr},
+};
+
+use plex_api::{library::Library as PlexLibrary, media_container::server::library::Metadata};
+
+use crate::Error;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Comparison {
+    fn compare<T: PartialOrd>(&self, value: T, expected: T) -> bool {
+        match self {
+            Self::Eq => value == expected,
+            Self::Ne => value != expected,
+            Self::Lt => value < expected,
+            Self::Le => value <= expected,
+            Self::Gt => value > expected,
+            Self::Ge => value >= expected,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Condition {
+    Genre(bool, String),
+    Collection(bool, String),
+    Library(bool, String),
+    Watched(bool),
+    Year(Comparison, u32),
+    Resolution(Comparison, u32),
+}
+
+impl Condition {
+    fn matches(&self, library: &PlexLibrary, metadata: &Metadata, height: Option<u32>) -> bool {
+        match self {
+            Self::Genre(equal, genre) => {
+                metadata
+                    .genres
+                    .iter()
+                    .any(|tag| tag.tag.eq_ignore_ascii_case(genre))
+                    == *equal
+            }
+            Self::Collection(equal, collection) => {
+                metadata
+                    .collections
+                    .iter()
+                    .any(|tag| tag.tag.eq_ignore_ascii_case(collection))
+                    == *equal
+            }
+            Self::Library(equal, name) => {
+                (library.id() == name || library.title().eq_ignore_ascii_case(name)) == *equal
+            }
+            Self::Watched(watched) => (metadata.view_count.unwrap_or_default() > 0) == *watched,
+            Self::Year(comparison, year) => metadata
+                .year
+                .map(|y| comparison.compare(y, *year))
+                .unwrap_or_default(),
+            // Shows have no single resolution so never match.
+            Self::Resolution(comparison, resolution) => height
+                .map(|h| comparison.compare(h, *resolution))
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    And,
+    Or,
+    Not,
+    Word(String),
+    Text(String),
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, Error> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            // A `!` starting `!=` is part of a comparison rather than a negation.
+            '!' if chars.clone().nth(1) == Some('=') => tokens.push(Token::Word(word(&mut chars))),
+            '(' | ')' | '&' | '|' | '!' => {
+                chars.next();
+                tokens.push(match c {
+                    '(' => Token::Open,
+                    ')' => Token::Close,
+                    '&' => Token::And,
+                    '|' => Token::Or,
+                    _ => Token::Not,
+                });
+            }
+            '"' => {
+                chars.next();
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => text.push(c),
+                        None => {
+                            return Err(Error::InvalidFilter(format!(
+                                "Unterminated quote in '{s}'"
+                            )))
+                        }
+                    }
+                }
+                tokens.push(Token::Text(text));
+            }
+            _ => tokens.push(Token::Word(word(&mut chars))),
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn word(chars: &mut Peekable<Chars<'_>>) -> String {
+    let mut word = String::new();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() || "()&|\"".contains(c) {
+            break;
+        }
+        word.push(c);
+        chars.next();
+    }
+    word
+}
+
+fn build_condition(field: &str, comparison: Comparison, value: &str) -> Result<Condition, Error> {
+    let field = field.trim().to_lowercase();
+    let value = value.trim();
+
+    let equality = || match comparison {
+        Comparison::Eq => Ok(true),
+        Comparison::Ne => Ok(false),
+        _ => Err(Error::InvalidFilter(format!(
+            "Only = and != can be used with {field}"
+        ))),
+    };
+
+    let number = || {
+        value
+            .trim_end_matches('p')
+            .parse::<u32>()
+            .map_err(|_| Error::InvalidFilter(format!("Expected a number for {field}")))
+    };
+
+    match field.as_str() {
+        "genre" => Ok(Condition::Genre(equality()?, value.to_owned())),
+        "collection" => Ok(Condition::Collection(equality()?, value.to_owned())),
+        "library" => Ok(Condition::Library(equality()?, value.to_owned())),
+        "watched" => {
+            let watched = match value.to_lowercase().as_str() {
+                "true" | "yes" => true,
+                "false" | "no" => false,
+                _ => {
+                    return Err(Error::InvalidFilter(
+                        "Expected true or false for watched".to_string(),
+                    ))
+                }
+            };
+
+            Ok(Condition::Watched(watched == equality()?))
+        }
+        "year" => Ok(Condition::Year(comparison, number()?)),
+        "resolution" => Ok(Condition::Resolution(comparison, number()?)),
+        _ => Err(Error::InvalidFilter(format!("Unknown field '{field}'"))),
+    }
+}
+
+const OPERATOR_CHARS: [char; 4] = ['=', '!', '<', '>'];
+
+/// Splits a term like `year>=2020` into its field, comparison and value.
+fn split_term(term: &str) -> Option<(&str, Comparison, &str)> {
+    let index = term.find(OPERATOR_CHARS)?;
+    let rest = &term[index..];
+
+    [
+        ("!=", Comparison::Ne),
+        ("<=", Comparison::Le),
+        (">=", Comparison::Ge),
+        ("=", Comparison::Eq),
+        ("<", Comparison::Lt),
+        (">", Comparison::Gt),
+    ]
+    .into_iter()
+    .find_map(|(op, comparison)| {
+        rest.strip_prefix(op)
+            .map(|value| (&term[..index], comparison, value))
+    })
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Expression {
+    Condition(Condition),
+    Not(Box<Expression>),
+    And(Vec<Expression>),
+    Or(Vec<Expression>),
+}
+
+impl Expression {
+    fn matches(&self, library: &PlexLibrary, metadata: &Metadata, height: Option<u32>) -> bool {
+        match self {
+            Self::Condition(condition) => condition.matches(library, metadata, height),
+            Self::Not(expression) => !expression.matches(library, metadata, height),
+            Self::And(expressions) => expressions
+                .iter()
+                .all(|e| e.matches(library, metadata, height)),
+            Self::Or(expressions) => expressions
+                .iter()
+                .any(|e| e.matches(library, metadata, height)),
+        }
+    }
+
+    /// The libraries that any match must be in, if that can be determined.
+    fn libraries(&self) -> Option<Vec<&str>> {
+        match self {
+            Self::Condition(Condition::Library(true, library)) => Some(vec![library.as_str()]),
+            // Every branch must match so any restricted branch restricts the
+            // whole.
+            Self::And(expressions) => expressions.iter().find_map(|e| e.libraries()),
+            // Any branch may match so all of them must be restricted.
+            Self::Or(expressions) => {
+                let mut libraries = Vec::new();
+                for expression in expressions {
+                    libraries.extend(expression.libraries()?);
+                }
+                Some(libraries)
+            }
+            _ => None,
+        }
+    }
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn invalid(&self, message: &str) -> Error {
+        Error::InvalidFilter(format!("{message} in '{}'", self.source))
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), Error> {
+        if self.next() == Some(expected.clone()) {
+            Ok(())
+        } else {
+            Err(self.invalid(&format!("Expected {expected:?}")))
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expression, Error> {
+        let mut expressions = vec![self.parse_and()?];
+
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            expressions.push(self.parse_and()?);
+        }
+
+        if expressions.len() == 1 {
+            Ok(expressions.remove(0))
+        } else {
+            Ok(Expression::Or(expressions))
+        }
+    }
+
+    fn parse_and(&mut self) -> Result<Expression, Error> {
+        let mut expressions = vec![self.parse_unary()?];
+
+        loop {
+            match self.peek() {
+                // Terms separated only by whitespace must all match.
+                None | Some(Token::Or) | Some(Token::Close) => break,
+                Some(Token::And) => {
+                    self.next();
+                }
+                _ => {}
+            }
+
+            expressions.push(self.parse_unary()?);
+        }
+
+        if expressions.len() == 1 {
+            Ok(expressions.remove(0))
+        } else {
+            Ok(Expression::And(expressions))
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Expression, Error> {
+        match self.next() {
+            Some(Token::Not) => Ok(Expression::Not(Box::new(self.parse_unary()?))),
+            Some(Token::Open) => {
+                let expression = self.parse_or()?;
+                self.expect(Token::Close)?;
+                Ok(expression)
+            }
+            Some(Token::Word(word)) => self.parse_term(&word).map(Expression::Condition),
+            Some(token) => Err(self.invalid(&format!("Unexpected {token:?}"))),
+            None => Err(self.invalid("Unexpected end of expression")),
+        }
+    }
+
+    fn parse_term(&mut self, word: &str) -> Result<Condition, Error> {
+        // The comparison may be separated from the field by whitespace, as in
+        // `year >= 2020`.
+        let mut term = word.to_owned();
+        if split_term(&term).is_none() {
+            if let Some(Token::Word(next)) = self.peek() {
+                if next.starts_with(OPERATOR_CHARS) {
+                    term.push_str(next);
+                    self.next();
+                }
+            }
+        }
+
+        if let Some((field, comparison, value)) = split_term(&term) {
+            if value.is_empty() {
+                if let Some(Token::Text(text) | Token::Word(text)) = self.peek().cloned() {
+                    self.next();
+                    return build_condition(field, comparison, &text);
+                }
+            }
+
+            return build_condition(field, comparison, value);
+        }
+
+        match word.to_lowercase().as_str() {
+            "watched" => Ok(Condition::Watched(true)),
+            "unwatched" => Ok(Condition::Watched(false)),
+            "genre" | "collection" | "library" if self.peek() == Some(&Token::Open) => {
+                self.next();
+                let value = match self.next() {
+                    Some(Token::Text(text)) | Some(Token::Word(text)) => text,
+                    _ => return Err(self.invalid(&format!("Expected a value for {word}"))),
+                };
+                self.expect(Token::Close)?;
+
+                build_condition(word, Comparison::Eq, &value)
+            }
+            _ => Err(self.invalid(&format!("Unable to parse '{word}'"))),
+        }
+    }
+}
+
+/// A selection expression matched against an item's metadata, for example
+/// `genre=Documentary year>=2020` or
+/// `library("Movies") & (genre("Comedy") | year>=2020) & !watched`.
+///
+/// Supported fields are `genre`, `collection`, `library`, `watched` (`true`
+/// or `false`), `year` and `resolution` (the video height, e.g. `1080p`, which
+/// only movies have). `genre`, `collection` and `library` can also be written as functions and
+/// `watched` or `unwatched` used alone. Conditions can be combined with `&`,
+/// `|`, `!` and parentheses, terms separated by whitespace must all match.
+/// Values containing spaces can be quoted.
+#[derive(Clone, Debug)]
+pub struct Filter {
+    expression: Expression,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Self {
+            expression: Expression::And(Vec::new()),
+        }
+    }
+}
+
+impl Filter {
+    pub(crate) fn matches(
+        &self,
+        library: &PlexLibrary,
+        metadata: &Metadata,
+        height: Option<u32>,
+    ) -> bool {
+        self.expression.matches(library, metadata, height)
+    }
+
+    /// The libraries this filter could match items in, if it is restricted to
+    /// specific libraries.
+    pub(crate) fn libraries(&self) -> Option<Vec<&str>> {
+        self.expression.libraries()
+    }
+}
+
+impl FromStr for Filter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            source: s,
+            tokens: tokenize(s)?,
+            position: 0,
+        };
+
+        if parser.peek().is_none() {
+            return Ok(Self::default());
+        }
+
+        let expression = parser.parse_or()?;
+        if parser.peek().is_some() {
+            return Err(parser.invalid("Unexpected trailing input"));
+        }
+
+        Ok(Self { expression })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Comparison, Condition, Expression, Filter};
+
+    fn parse(s: &str) -> Expression {
+        s.parse::<Filter>().unwrap().expression
+    }
+
+    fn condition(condition: Condition) -> Expression {
+        Expression::Condition(condition)
+    }
+
+    #[test]
+    fn comparisons() {
+        let expected = condition(Condition::Year(Comparison::Ge, 2020));
+        assert_eq!(parse("year>=2020"), expected);
+        assert_eq!(parse("year >= 2020"), expected);
+        assert_eq!(parse("year>= 2020"), expected);
+        assert_eq!(parse("year >=2020"), expected);
+
+        assert_eq!(
+            parse("genre != Comedy"),
+            condition(Condition::Genre(false, "Comedy".to_owned()))
+        );
+        assert_eq!(
+            parse("resolution<1080p"),
+            condition(Condition::Resolution(Comparison::Lt, 1080))
+        );
+        assert_eq!(
+            parse("collection = \"Sci Fi\""),
+            condition(Condition::Collection(true, "Sci Fi".to_owned()))
+        );
+        assert_eq!(parse("watched=false"), condition(Condition::Watched(false)));
+    }
+
+    #[test]
+    fn combinations() {
+        assert_eq!(parse(""), Expression::And(Vec::new()));
+
+        assert_eq!(
+            parse("library(\"Movies\") & (genre(Comedy) | year > 2020) & !watched"),
+            Expression::And(vec![
+                condition(Condition::Library(true, "Movies".to_owned())),
+                Expression::Or(vec![
+                    condition(Condition::Genre(true, "Comedy".to_owned())),
+                    condition(Condition::Year(Comparison::Gt, 2020)),
+                ]),
+                Expression::Not(Box::new(condition(Condition::Watched(true)))),
+            ])
+        );
+
+        assert_eq!(
+            parse("genre=Documentary unwatched"),
+            Expression::And(vec![
+                condition(Condition::Genre(true, "Documentary".to_owned())),
+                condition(Condition::Watched(false)),
+            ])
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        for filter in [
+            "year",
+            "year>=",
+            "year>=soon",
+            "genre<Comedy",
+            "rating=5",
+            "(watched",
+            "watched)",
+            "genre=\"Comedy",
+            "watched=maybe",
+        ] {
+            assert!(filter.parse::<Filter>().is_err(), "{filter}");
+        }
+    }
+
+    #[test]
+    fn libraries() {
+        let libraries = |s: &str| {
+            s.parse::<Filter>()
+                .unwrap()
+                .libraries()
+                .map(|l| l.into_iter().map(str::to_owned).collect::<Vec<_>>())
+        };
+
+        assert_eq!(libraries("genre=Comedy"), None);
+        assert_eq!(libraries("library=Movies"), Some(vec!["Movies".to_owned()]));
+        assert_eq!(
+            libraries("genre=Comedy & (library=Movies | library=TV)"),
+            Some(vec!["Movies".to_owned(), "TV".to_owned()])
+        );
+        assert_eq!(libraries("library=Movies | genre=Comedy"), None);
+        assert_eq!(libraries("!library=Movies"), None);
+        assert_eq!(libraries("library!=Movies"), None);
+    }
+}
@@ -5,43 +5,101 @@ use std::{
     ops::Deref,
     path::{Path, PathBuf},
     sync::Arc,
+    time::Duration,
 };
 
+mod backend;
+mod compress;
 mod config;
+mod conflict;
 mod error;
+mod events;
+mod eviction;
+mod export;
+mod filter;
+mod lock;
+mod metadata;
+mod notify;
+mod post;
+mod report;
+mod s3;
+mod schedule;
+mod schema;
 mod server;
+mod snapshot;
 mod state;
+mod stats;
+mod storage;
+mod template;
+mod transcode;
 mod util;
+mod verify;
+mod webhook;
 mod wrappers;
 
 use async_std::{
-    fs::{read_dir, read_to_string, remove_dir_all, remove_file, write},
+    fs::{metadata, read_dir, read_to_string, write},
     sync::RwLockReadGuard,
+    task::{sleep, spawn_blocking},
 };
 use async_std::{
     stream::StreamExt,
     sync::{Mutex, RwLock, RwLockWriteGuard},
 };
-pub use config::ServerConnection;
-use config::{Config, ServerConfig, TranscodeProfile};
-pub use error::Error;
+use config::{Config, ServerConfig, TranscodeProfile, Transcoder};
+pub use config::{QueueOrder, ServerConnection};
+pub use conflict::{Choice, Conflict, ConflictKind, ConflictResolver, Resolution};
+pub use error::{Error, ErrorAction, ErrorClass};
+use events::Events;
+pub use events::{operation_id, Correlation, Event, EventSink};
+pub use filter::Filter;
 use lazy_static::lazy_static;
+pub use lock::{lock_store, StoreLock};
 pub use plex_api;
 use plex_api::{transcode::VideoTranscodeOptions, HttpClient, HttpClientBuilder};
+pub use report::{ReportFormat, RunReport, REPORT_DIR};
+pub use schema::{config_schema, state_schema, FORMAT_VERSION};
 use serde::{de::DeserializeOwned, Serialize};
 use serde_json::{from_str, to_string_pretty};
-pub use server::{ItemType, Server, SyncItemInfo};
-use state::{ServerState, State};
+pub use server::{
+    AuthCheck, LibraryEstimate, LimitedVideo, MediaVersion, PlannedDeletion, PlannedDownload,
+    PlannedRemoval, Server, ServerFeatures, ServerStatus, SizeLimitReport, SyncHistoryEntry,
+    SyncItemInfo, SyncPlan,
+};
+pub use snapshot::{SNAPSHOT_DIR, SNAPSHOT_VERSION};
+pub use state::ItemType;
+use state::{ArtworkKind, ServerState, State};
+pub use stats::{ServerStatistics, StoreStatistics, Totals};
 use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+pub use verify::QUARANTINE_DIR;
 
 pub use wrappers::*;
 
-use crate::config::H264Profile;
+use crate::{
+    backend::{LocalStorage, StorageBackend},
+    config::{H264Profile, Layout, MetadataFormat},
+    metadata::MetadataExporter,
+    notify::Notifications,
+    s3::UPLOADS_DIR,
+    schedule::RateSchedule,
+    schema::migrate_config,
+    server::prune_directory,
+    storage::{check_state, open_store, StateStore},
+    webhook::Webhooks,
+};
 
 pub type Result<T = ()> = std::result::Result<T, Error>;
 
 pub const STATE_FILE: &str = ".flicksync.state.json";
+const STATE_BACKUP_FILE: &str = ".flicksync.state.json.bak";
+const STATE_TEMP_FILE: &str = ".flicksync.state.json.tmp";
+pub const STATE_DATABASE: &str = ".flicksync.state.db";
 pub const CONFIG_FILE: &str = "flicksync.json";
+pub const LOCK_FILE: &str = ".flicksync.lock";
+/// Written into a separate media root so that a share that is not mounted can
+/// be told apart from one where every download is missing.
+const MEDIA_MARKER: &str = ".flicksync.media";
 
 lazy_static! {
     static ref DEFAULT_PROFILES: HashMap<String, Option<TranscodeProfile>> = {
@@ -82,20 +140,57 @@ lazy_static! {
 struct Inner {
     config: RwLock<Config>,
     state: RwLock<State>,
+    store: Box<dyn StateStore>,
     path: RwLock<PathBuf>,
     servers: Mutex<HashMap<String, Server>>,
+    conflict_resolver: RwLock<Option<Arc<dyn ConflictResolver>>>,
+    event_sinks: RwLock<Vec<Arc<dyn EventSink>>>,
+    /// The id of the current sync run.
+    run: std::sync::Mutex<Option<String>>,
+    webhooks: Option<Arc<Webhooks>>,
+    notifications: Option<Arc<Notifications>>,
+    /// Recalculated whenever the state is written.
+    statistics: std::sync::Mutex<Arc<StoreStatistics>>,
 }
 
 impl Inner {
-    async fn transcode_options(&self, profile: Option<String>) -> Option<VideoTranscodeOptions> {
+    fn statistics(&self) -> Arc<StoreStatistics> {
+        self.statistics.lock().unwrap().clone()
+    }
+
+    /// Finds the named transcode profile, `None` if it downloads originals.
+    /// The profile is adjusted to suit the configured device.
+    async fn resolve_profile(&self, profile: Option<String>) -> Option<TranscodeProfile> {
+        let device_profile = self.config.read().await.device_profile;
+        let resolved = match self.find_profile(profile.clone()).await {
+            Some(resolved) => resolved,
+            None => {
+                if let Some(device_profile) = device_profile {
+                    warn!(
+                        ?device_profile,
+                        ?profile,
+                        "Originals are downloaded unchanged and may not play on the device"
+                    );
+                }
+                return None;
+            }
+        };
+
+        match device_profile {
+            Some(device_profile) => Some(device_profile.apply(resolved)),
+            None => Some(resolved),
+        }
+    }
+
+    async fn find_profile(&self, profile: Option<String>) -> Option<TranscodeProfile> {
         if let Some(ref profile) = profile {
             let config = self.config.read().await;
             if let Some(profile) = config.profiles.get(profile) {
-                return Some(profile.options());
+                return Some(profile.clone());
             }
 
             match DEFAULT_PROFILES.get(profile) {
-                Some(Some(profile)) => Some(profile.options()),
+                Some(Some(profile)) => Some(profile.clone()),
                 Some(None) => None,
                 _ => {
                     warn!("Unknown transcode profile {profile}, falling back to defaults.");
@@ -107,6 +202,12 @@ impl Inner {
         }
     }
 
+    async fn transcode_options(&self, profile: Option<String>) -> Option<VideoTranscodeOptions> {
+        self.resolve_profile(profile)
+            .await
+            .map(|profile| profile.options())
+    }
+
     async fn persist_config(&self, config: &RwLockWriteGuard<'_, Config>) -> Result {
         let path = self.path.read().await;
 
@@ -118,13 +219,141 @@ impl Inner {
 
     async fn persist_state(&self, state: &RwLockWriteGuard<'_, State>) -> Result {
         let path = self.path.read().await;
+        self.store.save(&path, state).await?;
+
+        *self.statistics.lock().unwrap() = Arc::new(StoreStatistics::from(state));
+
+        Ok(())
+    }
+
+    /// Persists the state after a change to a single video.
+    async fn persist_video(
+        &self,
+        state: &RwLockWriteGuard<'_, State>,
+        server: &str,
+        video: &str,
+    ) -> Result {
+        let path = self.path.read().await;
+        self.store.save_video(&path, state, server, video).await?;
+
+        *self.statistics.lock().unwrap() = Arc::new(StoreStatistics::from(state));
+
+        Ok(())
+    }
+
+    /// Where artwork for an item is written relative to the store.
+    async fn artwork_path(&self, path: PathBuf) -> PathBuf {
+        let config = self.config.read().await;
+        match config.metadata_root {
+            Some(ref metadata_root) => metadata_root.join(path),
+            None => path,
+        }
+    }
+
+    /// The extension of downloaded artwork, which depends on the format it
+    /// is re-encoded in.
+    async fn artwork_extension(&self) -> &'static str {
+        let config = self.config.read().await;
+        config
+            .compression
+            .as_ref()
+            .and_then(|compression| compression.artwork_format)
+            .map(|format| format.extension())
+            .unwrap_or("jpg")
+    }
+
+    async fn artwork_kinds(&self) -> Vec<ArtworkKind> {
+        let config = self.config.read().await;
+        config
+            .artwork
+            .clone()
+            .unwrap_or_else(|| vec![ArtworkKind::Poster])
+    }
+
+    async fn metadata_exporters(&self) -> Vec<Box<dyn MetadataExporter>> {
+        let config = self.config.read().await;
+        let gzip = config
+            .compression
+            .as_ref()
+            .is_some_and(|compression| compression.gzip_metadata);
+
+        let mut formats = config.metadata_exporters.clone();
+        // Devices browse the store through the index.
+        if config.device_profile.is_some() && !formats.contains(&MetadataFormat::Html) {
+            formats.push(MetadataFormat::Html);
+        }
+
+        formats
+            .iter()
+            // Devices cannot open a compressed index.
+            .map(|format| format.exporter(gzip && *format != MetadataFormat::Html))
+            .collect()
+    }
+
+    async fn storage(&self) -> Box<dyn StorageBackend> {
+        let media_root = self.media_root().await;
+        let config = self.config.read().await;
+        config
+            .media_storage
+            .clone()
+            .unwrap_or_default()
+            .backend(&media_root)
+    }
+
+    /// Where downloaded media and artwork are kept. Paths in the state are
+    /// relative to this, which is the store's directory unless a separate
+    /// media root is configured.
+    async fn media_root(&self) -> PathBuf {
+        let media_root = self.config.read().await.media_root.clone();
+        match media_root {
+            Some(media_root) => media_root,
+            None => self.path.read().await.clone(),
+        }
+    }
 
-        let str = to_string_pretty(&state.deref())?;
-        write(path.join(STATE_FILE), str).await?;
+    /// Fails if a separate media root looks like a share that is not mounted,
+    /// before its missing files are mistaken for lost downloads. A media root
+    /// that is in use is marked so an empty mount point can be recognised.
+    async fn check_media_root(&self) -> Result {
+        let media_root = match self.config.read().await.media_root.clone() {
+            Some(media_root) => media_root,
+            None => return Ok(()),
+        };
 
+        let marker = media_root.join(MEDIA_MARKER);
+        if metadata(&marker).await.is_ok() {
+            return Ok(());
+        }
+
+        let mut entries = match read_dir(&media_root).await {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!(error=?e, path=?media_root, "Unable to read the media root");
+                return Err(Error::MediaRootUnavailable(media_root));
+            }
+        };
+
+        // An empty directory is what an unmounted share usually looks like,
+        // unless nothing has been downloaded yet.
+        if entries.next().await.is_none() && self.statistics().totals.downloaded_bytes > 0 {
+            return Err(Error::MediaRootUnavailable(media_root));
+        }
+
+        write(&marker, "").await?;
         Ok(())
     }
 
+    async fn layout(&self) -> Layout {
+        self.config.read().await.layout.unwrap_or_default()
+    }
+
+    async fn events(&self) -> Events {
+        Events::new(
+            self.event_sinks.read().await.clone(),
+            self.run.lock().unwrap().clone(),
+        )
+    }
+
     async fn client(&self) -> HttpClient {
         let config = self.config.read().await;
         let state = self.state.read().await;
@@ -173,26 +402,316 @@ where
     }
 }
 
+/// Checks that the config and state of a store can be read without opening
+/// it, so problems can be diagnosed when opening fails. Unlike opening the
+/// store this never writes and does not fall back to a backup or an empty
+/// state.
+pub async fn check_store(path: &Path) -> Result {
+    let config = match read_to_string(path.join(CONFIG_FILE)).await {
+        Ok(str) => Config::parse(&str)?.0,
+        Err(e) if e.kind() == ErrorKind::NotFound => Config::default(),
+        Err(e) => return Err(e.into()),
+    };
+
+    check_state(path, config.state_backend.unwrap_or_default()).await
+}
+
+/// Reads the config, migrating it from older formats. A missing config is
+/// created but a config that cannot be read is an error rather than being
+/// replaced.
+async fn read_config(path: &Path) -> Result<Config> {
+    match read_to_string(path).await {
+        Ok(str) => {
+            let (config, migrated) = Config::parse(&str)?;
+
+            if migrated {
+                info!("Writing the migrated config");
+                write(path, to_string_pretty(&config)?).await?;
+            }
+
+            Ok(config)
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let config = Config {
+                version: FORMAT_VERSION,
+                ..Default::default()
+            };
+            write(path, to_string_pretty(&config)?).await?;
+            Ok(config)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
 impl FlickSync {
     pub async fn max_downloads(&self) -> usize {
         let config = self.inner.config.read().await;
         config.max_downloads.unwrap_or(2)
     }
 
+    /// The names of the configured and built in transcode profiles.
+    pub async fn profile_names(&self) -> Vec<String> {
+        let config = self.inner.config.read().await;
+        let mut names: Vec<String> = config
+            .profiles
+            .keys()
+            .chain(DEFAULT_PROFILES.keys())
+            .cloned()
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// The video bitrate in kbps that a transcode profile converts to, or
+    /// `None` if the profile downloads original files.
+    pub async fn profile_bitrate(&self, profile: &str) -> Option<u32> {
+        self.inner
+            .transcode_options(Some(profile.to_owned()))
+            .await
+            .map(|options| options.bitrate)
+    }
+
+    /// The configured way to handle an error during a sync.
+    pub async fn error_action(&self, error: &Error) -> ErrorAction {
+        let config = self.inner.config.read().await;
+        let class = error.class();
+
+        match config.error_handling.get(&class) {
+            Some(action) => *action,
+            None if class == ErrorClass::Network => ErrorAction::Retry { attempts: 3 },
+            None => ErrorAction::default(),
+        }
+    }
+
+    /// How long to wait before the given retry of a failed operation,
+    /// counting from 1.
+    pub async fn retry_delay(&self, retry: u32) -> Duration {
+        let config = self.inner.config.read().await;
+        config.retry.clone().unwrap_or_default().delay(retry)
+    }
+
+    /// The directory holding the store.
+    pub async fn path(&self) -> PathBuf {
+        self.inner.path.read().await.clone()
+    }
+
+    /// The directory holding downloaded media and artwork, the store's
+    /// directory unless a separate media root is configured.
+    pub async fn media_root(&self) -> PathBuf {
+        self.inner.media_root().await
+    }
+
+    /// Checks that a separate media root is available, failing if it looks
+    /// like a share that is not mounted.
+    pub async fn check_media_root(&self) -> Result {
+        self.inner.check_media_root().await
+    }
+
+    /// Checks that ffmpeg can be run, returning its version. Returns `None`
+    /// when no server transcodes locally so ffmpeg is not needed.
+    pub async fn check_ffmpeg(&self) -> Option<Result<String>> {
+        let ffmpeg = {
+            let config = self.inner.config.read().await;
+            if !config
+                .servers
+                .values()
+                .any(|server| server.transcoder.unwrap_or_default() == Transcoder::Local)
+            {
+                return None;
+            }
+
+            config.ffmpeg.clone()
+        };
+
+        Some(transcode::version(ffmpeg).await)
+    }
+
     pub async fn new(path: &Path) -> Result<Self> {
-        let config: Config = read_or_default(&path.join(CONFIG_FILE)).await?;
-        let state: State = read_or_default(&path.join(STATE_FILE)).await?;
+        let config = read_config(&path.join(CONFIG_FILE)).await?;
+        let (store, state) = open_store(path, config.state_backend.unwrap_or_default()).await?;
+        let statistics = StoreStatistics::from(&state);
+
+        let webhooks = if config.webhooks.is_empty() {
+            None
+        } else {
+            Some(Webhooks::new(config.webhooks.clone()))
+        };
+        let notifications = if config.notifications.is_empty() {
+            None
+        } else {
+            Some(Notifications::new(config.notifications.clone()))
+        };
+
+        let mut event_sinks: Vec<Arc<dyn EventSink>> = Vec::new();
+        if let Some(ref webhooks) = webhooks {
+            event_sinks.push(webhooks.clone());
+        }
+        if let Some(ref notifications) = notifications {
+            event_sinks.push(notifications.clone());
+        }
 
         Ok(Self {
             inner: Arc::new(Inner {
                 config: RwLock::new(config),
                 state: RwLock::new(state),
+                store,
                 path: RwLock::new(path.to_owned()),
                 servers: Default::default(),
+                conflict_resolver: Default::default(),
+                event_sinks: RwLock::new(event_sinks),
+                run: Default::default(),
+                webhooks,
+                notifications,
+                statistics: std::sync::Mutex::new(Arc::new(statistics)),
             }),
         })
     }
 
+    /// Aggregate statistics for the store, kept up to date as the state
+    /// changes so they are cheap to read.
+    pub fn statistics(&self) -> Arc<StoreStatistics> {
+        self.inner.statistics()
+    }
+
+    /// Reads a setting from the config as JSON, by a dotted key such as
+    /// `servers.home.maxTranscodes`. Keys may be given in snake_case.
+    pub async fn config_value(&self, key: &str) -> Result<Option<String>> {
+        let config = self.inner.config.read().await;
+        let value = serde_json::to_value(config.deref())?;
+
+        match config::lookup(&value, key) {
+            Some(value) => Ok(Some(to_string_pretty(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Changes, or with `None` removes, a setting in the config. The value is
+    /// parsed as JSON, falling back to a plain string, and the resulting config
+    /// is validated before it is written. Only the setting changes in the
+    /// file, everything else is kept as written.
+    pub async fn set_config_value(&self, key: &str, value: Option<&str>) -> Result {
+        let value = value.map(|value| {
+            from_str::<serde_json::Value>(value)
+                .unwrap_or_else(|_| serde_json::Value::String(value.to_owned()))
+        });
+        if value.is_some() && !config::is_setting(key) {
+            return Err(Error::InvalidConfig(format!("{key}: unknown setting")));
+        }
+
+        let mut config = self.inner.config.write().await;
+        let path = self.inner.path.read().await.join(CONFIG_FILE);
+
+        let mut json = match read_to_string(&path).await {
+            Ok(str) => from_str::<serde_json::Value>(&str)
+                .map_err(|e| Error::InvalidConfig(e.to_string()))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => serde_json::to_value(config.deref())?,
+            Err(e) => return Err(e.into()),
+        };
+        migrate_config(&mut json)?;
+        config::update(&mut json, key, value)?;
+
+        let (updated, _) = Config::parse(&json.to_string())?;
+        write(&path, to_string_pretty(&json)?).await?;
+
+        *config = updated;
+        Ok(())
+    }
+
+    /// Sets a resolver to choose how to handle conflicts during syncs. Without
+    /// one remembered choices or defaults are used.
+    pub async fn set_conflict_resolver(&self, resolver: Arc<dyn ConflictResolver>) {
+        let mut conflict_resolver = self.inner.conflict_resolver.write().await;
+        *conflict_resolver = Some(resolver);
+    }
+
+    /// Registers a sink to receive events about sync progress.
+    pub async fn add_event_sink(&self, sink: Arc<dyn EventSink>) {
+        let mut event_sinks = self.inner.event_sinks.write().await;
+        event_sinks.push(sink);
+    }
+
+    /// Starts a new sync run, returning its id. Events sent from now on and
+    /// the sync history recorded for servers carry the id so that they can be
+    /// matched with the logs of the run.
+    pub fn start_run(&self) -> String {
+        let run = Uuid::new_v4().to_string();
+        *self.inner.run.lock().unwrap() = Some(run.clone());
+        run
+    }
+
+    /// The id of the current sync run if one has been started.
+    pub fn run_id(&self) -> Option<String> {
+        self.inner.run.lock().unwrap().clone()
+    }
+
+    /// Sends an event that the application determines, such as a failed
+    /// transfer or the end of a sync, to the registered sinks.
+    pub async fn emit_event(&self, event: Event) {
+        self.inner.events().await.emit(event);
+    }
+
+    /// Waits for any webhook requests and notifications sent for past events
+    /// to complete.
+    pub async fn flush_events(&self) {
+        if let Some(ref webhooks) = self.inner.webhooks {
+            webhooks.flush().await;
+        }
+        if let Some(ref notifications) = self.inner.notifications {
+            notifications.flush().await;
+        }
+    }
+
+    /// Writes the state and flushes every file in the store to disk so that
+    /// the drive holding it can be safely removed.
+    pub async fn sync_to_disk(&self) -> Result {
+        self.flush_events().await;
+
+        {
+            let state = self.inner.state.write().await;
+            self.inner.persist_state(&state).await?;
+        }
+
+        let root = self.inner.path.read().await.clone();
+        let media_root = self.inner.media_root().await;
+        let mut files = vec![
+            root.join(CONFIG_FILE),
+            root.join(self.inner.store.files()[0]),
+        ];
+        {
+            let state = self.inner.state.read().await;
+            for server_state in state.servers.values() {
+                for video in server_state.videos.values() {
+                    files.extend(
+                        video.parts.iter().filter_map(|part| {
+                            part.download.file().map(|file| media_root.join(file))
+                        }),
+                    );
+                }
+            }
+        }
+        // Directories can only be flushed on unix.
+        if cfg!(unix) {
+            if media_root != root {
+                files.push(media_root);
+            }
+            files.push(root);
+        }
+
+        spawn_blocking(move || {
+            for file in files {
+                match std::fs::File::open(&file).and_then(|f| f.sync_all()) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == ErrorKind::NotFound => {}
+                    Err(e) => warn!(error=?e, path=?file, "Failed to flush file"),
+                }
+            }
+        })
+        .await;
+
+        Ok(())
+    }
+
     /// Adds a new server
     pub async fn add_server(
         &self,
@@ -213,7 +732,8 @@ impl FlickSync {
             id.to_owned(),
             ServerState {
                 token: auth_token.to_owned(),
-                name: server.media_container.friendly_name,
+                name: server.media_container.friendly_name.clone(),
+                machine_id: Some(server.machine_identifier().to_owned()),
                 ..Default::default()
             },
         );
@@ -223,8 +743,18 @@ impl FlickSync {
             ServerConfig {
                 connection,
                 syncs: Default::default(),
+                queries: Default::default(),
                 max_transcodes: None,
                 transcode_profile,
+                transcoder: None,
+                skipped: Default::default(),
+                pinned: Default::default(),
+                max_size: None,
+                eviction_policy: None,
+                media_selection: None,
+                media_versions: Default::default(),
+                paths: Default::default(),
+                allow_relay: None,
             },
         );
 
@@ -268,22 +798,127 @@ impl FlickSync {
             .collect()
     }
 
+    /// Waits while the bandwidth schedule pauses downloads, so that transfers
+    /// are not started only to stall.
+    pub async fn wait_for_download_window(&self) {
+        loop {
+            let (rate, remaining) = {
+                let config = self.inner.config.read().await;
+                RateSchedule::from(&config).current()
+            };
+
+            if rate != Some(0) {
+                return;
+            }
+
+            debug!(?remaining, "Downloads are paused by the bandwidth schedule");
+            sleep(remaining).await;
+        }
+    }
+
+    /// Writes the configured playlist export, does nothing if there is none.
+    pub async fn export_playlists(&self) -> Result {
+        let export = match self.inner.config.read().await.playlist_export.clone() {
+            Some(export) => export,
+            None => return Ok(()),
+        };
+
+        let root = self.inner.media_root().await;
+        let state = self.inner.state.read().await.clone();
+
+        export::export(&root, &state, &export).await
+    }
+
+    /// Writes the metadata files of every configured exporter. A failing
+    /// exporter does not stop the others from running.
+    pub async fn export_metadata(&self) -> Result {
+        let exporters = self.inner.metadata_exporters().await;
+        if exporters.is_empty() {
+            return Ok(());
+        }
+
+        let root = self.inner.media_root().await;
+        let state = self.inner.state.read().await.clone();
+
+        let mut result = Ok(());
+        for exporter in exporters {
+            if let Err(e) = exporter.export(&root, &state).await {
+                error!(error=?e, "Failed to export metadata");
+                result = Err(e);
+            }
+        }
+
+        result
+    }
+
     pub async fn prune_root(&self) {
         info!("Pruning root filesystem");
 
-        let servers: HashSet<String> = {
+        self.prune_root_entries(false).await;
+    }
+
+    /// Lists the unknown files and directories that `prune_root` would delete.
+    pub async fn plan_prune_root(&self) -> Vec<PathBuf> {
+        self.prune_root_entries(true).await
+    }
+
+    async fn prune_root_entries(&self, dry_run: bool) -> Vec<PathBuf> {
+        let mut pruned = Vec::new();
+
+        let root_files: HashSet<&str> = self
+            .inner
+            .metadata_exporters()
+            .await
+            .iter()
+            .flat_map(|exporter| exporter.root_files().iter().copied())
+            .collect();
+
+        let store_root = self.inner.path.read().await.clone();
+
+        let (servers, metadata_dir, export_dir, media_dir) = {
             let config: RwLockReadGuard<'_, Config> = self.inner.config.read().await;
 
-            config.servers.keys().cloned().collect()
+            let servers: HashSet<String> = config.servers.keys().cloned().collect();
+            let metadata_dir = config
+                .metadata_root
+                .as_ref()
+                .and_then(|root| root.iter().next())
+                .and_then(|dir| dir.to_str())
+                .map(|dir| dir.to_owned());
+            let export_dir = config
+                .playlist_export
+                .as_ref()
+                .and_then(|export| export.directory.iter().next())
+                .and_then(|dir| dir.to_str())
+                .map(|dir| dir.to_owned());
+            // A media root within the store must not be pruned.
+            let media_dir = config
+                .media_root
+                .as_ref()
+                .and_then(|root| root.strip_prefix(&store_root).ok())
+                .and_then(|root| root.iter().next())
+                .and_then(|dir| dir.to_str())
+                .map(|dir| dir.to_owned());
+
+            (servers, metadata_dir, export_dir, media_dir)
         };
 
+        let events = self.inner.events().await;
+        // Media is only kept in the store's directory when there is no
+        // separate media root.
+        let storage: Box<dyn StorageBackend> =
+            if self.inner.config.read().await.media_root.is_none() {
+                self.inner.storage().await
+            } else {
+                Box::new(LocalStorage)
+            };
         let root = self.inner.path.write().await;
 
         let mut reader = match read_dir(root.as_path()).await {
             Ok(reader) => reader,
             Err(e) => {
                 tracing::error!(error=?e, path=%root.display(), "Failed to read directory");
-                return;
+                return pruned;
             }
         };
 
@@ -291,27 +926,46 @@ impl FlickSync {
             match reader.next().await {
                 Some(Ok(entry)) => {
                     if let Some(str) = entry.file_name().to_str() {
-                        if str == STATE_FILE || str == CONFIG_FILE || servers.contains(str) {
+                        if str == STATE_FILE
+                            || str == STATE_BACKUP_FILE
+                            || str == STATE_TEMP_FILE
+                            || self.inner.store.files().contains(&str)
+                            || str == LOCK_FILE
+                            || str == REPORT_DIR
+                            || str == SNAPSHOT_DIR
+                            || str == QUARANTINE_DIR
+                            || str == UPLOADS_DIR
+                            || metadata_dir.as_deref() == Some(str)
+                            || export_dir.as_deref() == Some(str)
+                            || media_dir.as_deref() == Some(str)
+                            || root_files.contains(str)
+                            || str == CONFIG_FILE
+                            || servers.contains(str)
+                        {
                             continue;
                         }
                     }
 
-                    let path = entry.path();
+                    let path: PathBuf = entry.path().into();
+                    if dry_run {
+                        pruned.push(path);
+                        continue;
+                    }
+
                     match entry.file_type().await {
                         Ok(file_type) => {
                             if file_type.is_dir() {
-                                match remove_dir_all(&path).await {
-                                    Ok(()) => {
-                                        debug!(path = %path.display(), "Deleted unknown directory");
-                                    }
-                                    Err(e) => {
-                                        tracing::error!(error=?e, path=%path.display(), "Failed to delete unknown directory");
-                                    }
+                                if prune_directory(&path, &HashSet::new(), &events, &*storage, None)
+                                    .await
+                                {
+                                    pruned.push(path);
                                 }
                             } else {
-                                match remove_file(&path).await {
+                                match storage.remove(&path).await {
                                     Ok(()) => {
                                         debug!(path = %path.display(), "Deleted unknown file");
+                                        events.emit(Event::FilePruned { path: path.clone() });
+                                        pruned.push(path);
                                     }
                                     Err(e) => {
                                         tracing::error!(error=?e, path=%path.display(), "Failed to delete unknown file");
@@ -333,6 +987,8 @@ impl FlickSync {
                 }
             }
         }
+
+        pruned
     }
 
     pub async fn client(&self) -> HttpClient {
@@ -0,0 +1,43 @@
+use std::{fs::OpenOptions, path::Path};
+
+use async_std::task::spawn_blocking;
+use fs2::FileExt;
+use tracing::{debug, info};
+
+use crate::{Error, Result, LOCK_FILE};
+
+/// An exclusive lock on a store, released when dropped.
+#[derive(Debug)]
+pub struct StoreLock {
+    _file: std::fs::File,
+}
+
+/// Locks the store so that only one process can use it at a time. If another
+/// process holds the lock this either fails with [`Error::StoreLocked`] or
+/// waits for the lock to be released.
+pub async fn lock_store(path: &Path, wait: bool) -> Result<StoreLock> {
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(path.join(LOCK_FILE))?;
+
+    match file.try_lock_exclusive() {
+        Ok(()) => {}
+        Err(e) if e.kind() == fs2::lock_contended_error().kind() => {
+            if !wait {
+                return Err(Error::StoreLocked);
+            }
+
+            info!("Waiting for another process to release the store");
+            let file = spawn_blocking(move || file.lock_exclusive().map(|_| file)).await?;
+            debug!("Acquired store lock");
+
+            return Ok(StoreLock { _file: file });
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    debug!("Acquired store lock");
+    Ok(StoreLock { _file: file })
+}
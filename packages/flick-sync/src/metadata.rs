@@ -0,0 +1,405 @@
+//! Metadata files written for other software, such as media centres, to read.
+//! Each format is a [`MetadataExporter`] and every enabled exporter is run
+//! once a sync's downloads have finished.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    path::{Component, Path, PathBuf},
+};
+
+use async_std::fs::{read, write};
+use async_trait::async_trait;
+use serde_json::{json, to_string_pretty, Value};
+use tracing::debug;
+
+use crate::{
+    compress::gzip,
+    config::MetadataFormat,
+    report::escape,
+    state::{PlaybackState, ServerState, State, VideoDetail, VideoState},
+    Result,
+};
+
+/// The page listing the store's videos written by the HTML exporter.
+pub(crate) const INDEX_FILE: &str = "flicksync-index.html";
+/// The list of downloaded files written by the manifest exporter.
+pub(crate) const MANIFEST_FILE: &str = "flicksync-manifest.json";
+/// The manifest when metadata files are compressed.
+pub(crate) const MANIFEST_GZIP_FILE: &str = "flicksync-manifest.json.gz";
+
+#[async_trait]
+pub(crate) trait MetadataExporter: Send + Sync {
+    /// Files written in the root of the store that pruning must keep.
+    fn root_files(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Files written for a video, relative to the store, that pruning must
+    /// keep.
+    fn video_files(&self, _video: &VideoState) -> Vec<PathBuf> {
+        Vec::new()
+    }
+
+    async fn export(&self, root: &Path, state: &State) -> Result;
+}
+
+impl MetadataFormat {
+    /// With `gzip` the formats only read by software that can decompress
+    /// them are compressed.
+    pub(crate) fn exporter(&self, gzip: bool) -> Box<dyn MetadataExporter> {
+        match self {
+            Self::Nfo => Box::new(Sidecar {
+                extension: "nfo",
+                render: render_nfo,
+                gzip: false,
+            }),
+            Self::Json => Box::new(Sidecar {
+                extension: if gzip { "json.gz" } else { "json" },
+                render: render_json,
+                gzip,
+            }),
+            Self::Html => Box::new(HtmlIndex),
+            Self::Manifest => Box::new(Manifest { gzip }),
+        }
+    }
+}
+
+/// The first file of a video once every part has downloaded.
+fn downloaded_file(video: &VideoState) -> Option<PathBuf> {
+    if video
+        .parts
+        .iter()
+        .any(|part| part.download.needs_download())
+    {
+        return None;
+    }
+
+    video.parts.first().and_then(|part| part.download.file())
+}
+
+/// Avoids touching files, and so their modification times, when nothing has
+/// changed since the last sync.
+async fn write_if_changed(path: &Path, contents: &[u8]) -> Result {
+    if read(path).await.ok().as_deref() == Some(contents) {
+        return Ok(());
+    }
+
+    debug!(path=?path, "Writing metadata");
+    write(path, contents).await?;
+    Ok(())
+}
+
+fn library_title<'a>(server_state: &'a ServerState, library: &str) -> Option<&'a str> {
+    server_state
+        .libraries
+        .get(library)
+        .map(|library| library.title.as_str())
+}
+
+/// A JSON description of a video shared by the sidecar and manifest formats.
+fn describe(server_state: &ServerState, video: &VideoState) -> Value {
+    let mut value = json!({
+        "id": video.id,
+        "title": video.title,
+        "airDate": video.air_date.to_string(),
+        "played": video.playback_state == PlaybackState::Played,
+    });
+
+    match video.detail {
+        VideoDetail::Movie(ref detail) => {
+            value["type"] = "movie".into();
+            value["library"] = library_title(server_state, &detail.library).into();
+            value["year"] = detail.year.into();
+        }
+        VideoDetail::Episode(ref detail) => {
+            value["type"] = "episode".into();
+            value["episode"] = detail.index.into();
+
+            if let Some(season) = server_state.seasons.get(&detail.season) {
+                value["season"] = season.index.into();
+
+                if let Some(show) = server_state.shows.get(&season.show) {
+                    value["show"] = show.title.as_str().into();
+                    value["library"] = library_title(server_state, &show.library).into();
+                }
+            }
+        }
+    }
+
+    value
+}
+
+fn render_json(server_state: &ServerState, video: &VideoState) -> Result<String> {
+    Ok(to_string_pretty(&describe(server_state, video))?)
+}
+
+/// Kodi's nfo format.
+fn render_nfo(server_state: &ServerState, video: &VideoState) -> Result<String> {
+    let played = u32::from(video.playback_state == PlaybackState::Played);
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n");
+
+    match video.detail {
+        VideoDetail::Movie(ref detail) => {
+            let _ = writeln!(out, "<movie>");
+            let _ = writeln!(out, "  <title>{}</title>", escape(&video.title));
+            let _ = writeln!(out, "  <year>{}</year>", detail.year);
+            let _ = writeln!(out, "  <premiered>{}</premiered>", video.air_date);
+            let _ = writeln!(out, "  <playcount>{played}</playcount>");
+            let _ = writeln!(
+                out,
+                "  <uniqueid type=\"plex\" default=\"true\">{}</uniqueid>",
+                escape(&video.id)
+            );
+            let _ = writeln!(out, "</movie>");
+        }
+        VideoDetail::Episode(ref detail) => {
+            let season = server_state.seasons.get(&detail.season);
+            let show = season.and_then(|season| server_state.shows.get(&season.show));
+
+            let _ = writeln!(out, "<episodedetails>");
+            let _ = writeln!(out, "  <title>{}</title>", escape(&video.title));
+            if let Some(show) = show {
+                let _ = writeln!(out, "  <showtitle>{}</showtitle>", escape(&show.title));
+            }
+            if let Some(season) = season {
+                let _ = writeln!(out, "  <season>{}</season>", season.index);
+            }
+            let _ = writeln!(out, "  <episode>{}</episode>", detail.index);
+            let _ = writeln!(out, "  <aired>{}</aired>", video.air_date);
+            let _ = writeln!(out, "  <playcount>{played}</playcount>");
+            let _ = writeln!(
+                out,
+                "  <uniqueid type=\"plex\" default=\"true\">{}</uniqueid>",
+                escape(&video.id)
+            );
+            let _ = writeln!(out, "</episodedetails>");
+        }
+    }
+
+    Ok(out)
+}
+
+/// Writes a file beside each downloaded video.
+struct Sidecar {
+    extension: &'static str,
+    render: fn(&ServerState, &VideoState) -> Result<String>,
+    gzip: bool,
+}
+
+#[async_trait]
+impl MetadataExporter for Sidecar {
+    fn video_files(&self, video: &VideoState) -> Vec<PathBuf> {
+        downloaded_file(video)
+            .map(|file| file.with_extension(self.extension))
+            .into_iter()
+            .collect()
+    }
+
+    async fn export(&self, root: &Path, state: &State) -> Result {
+        for server_state in state.servers.values() {
+            for video in server_state.videos.values() {
+                for file in self.video_files(video) {
+                    let contents = (self.render)(server_state, video)?;
+                    let contents = if self.gzip {
+                        gzip(contents.as_bytes())?
+                    } else {
+                        contents.into_bytes()
+                    };
+                    write_if_changed(&root.join(file), &contents).await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A link relative to the store's root.
+fn href(path: &Path) -> String {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[derive(Default)]
+struct LibraryIndex {
+    /// Label and link of each movie.
+    movies: Vec<(String, String)>,
+    /// Season, episode, label and link of each episode, by show.
+    shows: BTreeMap<String, Vec<(u32, u32, String, String)>>,
+}
+
+/// A single page listing every downloaded video with links to the files.
+struct HtmlIndex;
+
+#[async_trait]
+impl MetadataExporter for HtmlIndex {
+    fn root_files(&self) -> &'static [&'static str] {
+        &[INDEX_FILE]
+    }
+
+    async fn export(&self, root: &Path, state: &State) -> Result {
+        let mut out = String::from(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>FlickSync</title>\n</head>\n<body>\n",
+        );
+
+        let servers: BTreeMap<&String, &ServerState> = state.servers.iter().collect();
+        for (server_id, server_state) in servers {
+            let mut libraries: BTreeMap<String, LibraryIndex> = BTreeMap::new();
+
+            for video in server_state.videos.values() {
+                let file = match downloaded_file(video) {
+                    Some(file) => href(&file),
+                    None => continue,
+                };
+
+                match video.detail {
+                    VideoDetail::Movie(ref detail) => {
+                        let library = library_title(server_state, &detail.library)
+                            .unwrap_or_default()
+                            .to_owned();
+                        libraries
+                            .entry(library)
+                            .or_default()
+                            .movies
+                            .push((format!("{} ({})", video.title, detail.year), file));
+                    }
+                    VideoDetail::Episode(ref detail) => {
+                        let season = match server_state.seasons.get(&detail.season) {
+                            Some(season) => season,
+                            None => continue,
+                        };
+                        let show = match server_state.shows.get(&season.show) {
+                            Some(show) => show,
+                            None => continue,
+                        };
+                        let library = library_title(server_state, &show.library)
+                            .unwrap_or_default()
+                            .to_owned();
+
+                        libraries
+                            .entry(library)
+                            .or_default()
+                            .shows
+                            .entry(show.title.clone())
+                            .or_default()
+                            .push((
+                                season.index,
+                                detail.index,
+                                format!("S{:02}E{:02} {}", season.index, detail.index, video.title),
+                                file,
+                            ));
+                    }
+                }
+            }
+
+            if libraries.is_empty() {
+                continue;
+            }
+
+            let _ = writeln!(out, "<h1>{}</h1>", escape(server_id));
+            for (title, mut library) in libraries {
+                let _ = writeln!(out, "<h2>{}</h2>\n<ul>", escape(&title));
+
+                library.movies.sort();
+                for (label, file) in library.movies {
+                    let _ = writeln!(
+                        out,
+                        "<li><a href=\"{}\">{}</a></li>",
+                        escape(&file),
+                        escape(&label)
+                    );
+                }
+
+                for (show, mut episodes) in library.shows {
+                    let _ = writeln!(out, "<li>{}\n<ul>", escape(&show));
+                    episodes.sort();
+                    for (_, _, label, file) in episodes {
+                        let _ = writeln!(
+                            out,
+                            "<li><a href=\"{}\">{}</a></li>",
+                            escape(&file),
+                            escape(&label)
+                        );
+                    }
+                    let _ = writeln!(out, "</ul>\n</li>");
+                }
+
+                let _ = writeln!(out, "</ul>");
+            }
+        }
+
+        out.push_str("</body>\n</html>\n");
+
+        write_if_changed(&root.join(INDEX_FILE), out.as_bytes()).await
+    }
+}
+
+/// A machine readable list of every downloaded file with its size and
+/// checksum, grouped by server.
+struct Manifest {
+    gzip: bool,
+}
+
+#[async_trait]
+impl MetadataExporter for Manifest {
+    fn root_files(&self) -> &'static [&'static str] {
+        if self.gzip {
+            &[MANIFEST_GZIP_FILE]
+        } else {
+            &[MANIFEST_FILE]
+        }
+    }
+
+    async fn export(&self, root: &Path, state: &State) -> Result {
+        let mut servers = BTreeMap::new();
+
+        for (server_id, server_state) in state.servers.iter() {
+            let mut videos: Vec<(PathBuf, Value)> = server_state
+                .videos
+                .values()
+                .filter_map(|video| {
+                    let first = downloaded_file(video)?;
+
+                    let mut value = describe(server_state, video);
+                    value["files"] = video
+                        .parts
+                        .iter()
+                        .filter_map(|part| {
+                            Some(json!({
+                                "path": href(&part.download.file()?),
+                                "size": part.size,
+                                "checksum": part.checksum,
+                            }))
+                        })
+                        .collect::<Vec<_>>()
+                        .into();
+
+                    Some((first, value))
+                })
+                .collect();
+            videos.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+            servers.insert(
+                server_id.as_str(),
+                videos
+                    .into_iter()
+                    .map(|(_, value)| value)
+                    .collect::<Vec<_>>(),
+            );
+        }
+
+        let manifest = to_string_pretty(&json!({ "servers": servers }))?;
+
+        if self.gzip {
+            write_if_changed(&root.join(MANIFEST_GZIP_FILE), &gzip(manifest.as_bytes())?).await
+        } else {
+            write_if_changed(&root.join(MANIFEST_FILE), manifest.as_bytes()).await
+        }
+    }
+}
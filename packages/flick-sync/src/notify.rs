@@ -0,0 +1,138 @@
+//! Push notifications summarising each sync, sent to ntfy or Gotify so that
+//! headless machines can report what they did.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use isahc::Request;
+use serde_json::json;
+
+use crate::{
+    config::Notification,
+    events::{Correlation, Event, EventSink},
+    post::Poster,
+    util::format_bytes,
+};
+
+const TITLE: &str = "Flick Sync";
+
+/// Totals for the sync in progress.
+#[derive(Default)]
+struct Summary {
+    /// Bytes expected for each part that has started downloading.
+    started: HashMap<(String, String, usize), u64>,
+    downloaded: usize,
+    bytes: u64,
+}
+
+/// Tallies downloads as they complete and sends a summary to the configured
+/// notification services when a sync finishes. Requests are sent in the
+/// background, [`Notifications::flush`] waits for them.
+pub(crate) struct Notifications {
+    services: Vec<Notification>,
+    summary: Mutex<Summary>,
+    poster: Poster,
+}
+
+impl Notifications {
+    pub(crate) fn new(services: Vec<Notification>) -> Arc<Self> {
+        Arc::new(Self {
+            services,
+            summary: Default::default(),
+            poster: Poster::new("notification"),
+        })
+    }
+
+    /// Waits for all notifications sent so far to complete.
+    pub(crate) async fn flush(&self) {
+        self.poster.flush().await
+    }
+
+    fn send(&self, message: String) {
+        for service in self.services.iter() {
+            let (builder, body) = match service {
+                Notification::Ntfy { url, topic, token } => {
+                    let mut builder =
+                        Request::post(format!("{}/{}", url.trim_end_matches('/'), topic))
+                            .header("Title", TITLE);
+                    if let Some(token) = token {
+                        builder = builder.header("Authorization", format!("Bearer {token}"));
+                    }
+                    (builder, message.clone())
+                }
+                Notification::Gotify {
+                    url,
+                    token,
+                    priority,
+                } => (
+                    Request::post(format!("{}/message", url.trim_end_matches('/')))
+                        .header("X-Gotify-Key", token)
+                        .header("content-type", "application/json"),
+                    json!({
+                        "title": TITLE,
+                        "message": message,
+                        "priority": priority.unwrap_or(5),
+                    })
+                    .to_string(),
+                ),
+            };
+
+            self.poster.post(builder, body);
+        }
+    }
+}
+
+impl EventSink for Notifications {
+    fn event(&self, event: &Event, correlation: &Correlation) {
+        match event {
+            Event::DownloadStarted {
+                server,
+                video,
+                part,
+                offset,
+                size,
+                ..
+            } => {
+                let mut summary = self.summary.lock().unwrap();
+                summary.started.insert(
+                    (server.clone(), video.clone(), *part),
+                    size.saturating_sub(*offset),
+                );
+            }
+            Event::DownloadComplete {
+                server,
+                video,
+                part,
+                ..
+            } => {
+                let mut summary = self.summary.lock().unwrap();
+                let bytes = summary
+                    .started
+                    .remove(&(server.clone(), video.clone(), *part))
+                    .unwrap_or_default();
+                summary.downloaded += 1;
+                summary.bytes += bytes;
+            }
+            Event::SyncComplete { failures } => {
+                let summary = std::mem::take(&mut *self.summary.lock().unwrap());
+
+                let mut message = format!(
+                    "Sync complete: downloaded {} files ({})",
+                    summary.downloaded,
+                    format_bytes(summary.bytes)
+                );
+                if *failures > 0 {
+                    message.push_str(&format!(", {failures} failures"));
+                }
+                if let Some(ref run) = correlation.run {
+                    message.push_str(&format!("\nRun {run}"));
+                }
+
+                self.send(message);
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,58 @@
+use std::{sync::Mutex, time::Duration};
+
+use async_std::task::{spawn, JoinHandle};
+use isahc::{config::Configurable, http::request::Builder, AsyncReadResponseExt};
+use tracing::{trace, warn};
+
+/// How long a service has to respond before the request is abandoned.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Sends requests to webhooks and notification services in the background so
+/// that events are not held up, [`Poster::flush`] waits for them.
+pub(crate) struct Poster {
+    /// What the requests are for, used when logging.
+    kind: &'static str,
+    pending: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Poster {
+    pub(crate) fn new(kind: &'static str) -> Self {
+        Self {
+            kind,
+            pending: Default::default(),
+        }
+    }
+
+    pub(crate) fn post(&self, builder: Builder, body: String) {
+        let request = match builder.timeout(TIMEOUT).body(body) {
+            Ok(request) => request,
+            Err(e) => {
+                warn!(kind = self.kind, error=?e, "Invalid request");
+                return;
+            }
+        };
+
+        let kind = self.kind;
+        let handle = spawn(async move {
+            let url = request.uri().to_string();
+
+            match isahc::send_async(request).await {
+                Ok(mut response) if !response.status().is_success() => {
+                    let body = response.text().await.unwrap_or_default();
+                    warn!(kind, url, status=%response.status(), body, "Request was rejected");
+                }
+                Ok(_) => trace!(kind, url, "Sent request"),
+                Err(e) => warn!(kind, url, error=?e, "Failed to send request"),
+            }
+        });
+        self.pending.lock().unwrap().push(handle);
+    }
+
+    /// Waits for all requests sent so far to complete.
+    pub(crate) async fn flush(&self) {
+        let pending: Vec<JoinHandle<()>> = self.pending.lock().unwrap().drain(..).collect();
+        for handle in pending {
+            handle.await;
+        }
+    }
+}
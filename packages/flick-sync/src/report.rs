@@ -0,0 +1,600 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::Write,
+    path::PathBuf,
+    str::FromStr,
+    sync::Mutex,
+};
+
+use async_std::fs::{copy, create_dir_all, metadata, write};
+use serde::Serialize;
+use serde_json::{json, to_string_pretty};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::{
+    events::{operation_id, Correlation, Event, EventSink},
+    state::State,
+    util::format_bytes,
+    Error, FlickSync, Result,
+};
+
+/// The directory within the store that run reports are written to.
+pub const REPORT_DIR: &str = "flicksync-reports";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportFormat {
+    Html,
+    Markdown,
+    Json,
+}
+
+impl ReportFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            Self::Html => "html",
+            Self::Markdown => "md",
+            Self::Json => "json",
+        }
+    }
+}
+
+impl FromStr for ReportFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "html" => Ok(Self::Html),
+            "markdown" | "md" => Ok(Self::Markdown),
+            "json" => Ok(Self::Json),
+            _ => Err(Error::UnknownReportFormat(s.to_owned())),
+        }
+    }
+}
+
+/// The space used by each server at the end of a run.
+#[derive(Clone, Debug)]
+struct HistoryEntry {
+    timestamp: OffsetDateTime,
+    servers: HashMap<String, u64>,
+}
+
+impl HistoryEntry {
+    fn total(&self) -> u64 {
+        self.servers.values().sum()
+    }
+}
+
+/// A problem during a sync, along with the item it affected if known.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Failure {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    item: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    operation: Option<String>,
+    message: String,
+}
+
+#[derive(Default)]
+struct RunLog {
+    /// The id of the sync run the events came from.
+    run: Option<String>,
+    /// Server, video and path of each completed download.
+    downloads: Vec<(String, String, PathBuf)>,
+    /// Title and reason for each removed video.
+    removed: Vec<(String, String)>,
+    evicted: Vec<String>,
+    pruned: Vec<PathBuf>,
+    failures: Vec<Failure>,
+    /// The number and total size of artwork downloads.
+    artwork: (usize, u64),
+}
+
+/// Collects what happens during a sync so that a report can be written into
+/// the store afterwards. Register it as an event sink before syncing.
+pub struct RunReport {
+    started: OffsetDateTime,
+    log: Mutex<RunLog>,
+}
+
+impl Default for RunReport {
+    fn default() -> Self {
+        Self {
+            started: OffsetDateTime::now_utc(),
+            log: Default::default(),
+        }
+    }
+}
+
+impl EventSink for RunReport {
+    fn event(&self, event: &Event, correlation: &Correlation) {
+        let mut log = self.log.lock().unwrap();
+        if correlation.run.is_some() {
+            log.run.clone_from(&correlation.run);
+        }
+
+        match event {
+            Event::DownloadComplete {
+                server,
+                video,
+                path,
+                ..
+            } => log
+                .downloads
+                .push((server.clone(), video.clone(), path.clone())),
+            Event::VideoRemoved { title, reason, .. } => {
+                log.removed.push((title.clone(), reason.clone()))
+            }
+            Event::VideoEvicted { title, .. } => log.evicted.push(title.clone()),
+            Event::FilePruned { path } => log.pruned.push(path.clone()),
+            Event::ArtworkDownloaded { size, .. } => {
+                log.artwork.0 += 1;
+                log.artwork.1 += size;
+            }
+            Event::SyncItemFailed {
+                server,
+                item,
+                error,
+            } => log.failures.push(Failure {
+                server: Some(server.clone()),
+                item: Some(item.clone()),
+                operation: correlation.operation.clone(),
+                message: format!("Failed to update sync item {item}: {error}"),
+            }),
+            _ => {}
+        }
+    }
+}
+
+/// Everything needed to render a report.
+struct ReportData {
+    run: Option<String>,
+    started: String,
+    finished: String,
+    downloads: Vec<(String, u64)>,
+    removed: Vec<(String, String)>,
+    evicted: Vec<String>,
+    pruned: Vec<PathBuf>,
+    failures: Vec<Failure>,
+    artwork: (usize, u64),
+    history: Vec<HistoryEntry>,
+}
+
+impl ReportData {
+    fn failure_messages(&self) -> Vec<String> {
+        self.failures.iter().map(|f| f.message.clone()).collect()
+    }
+}
+
+fn timestamp(time: OffsetDateTime) -> String {
+    time.replace_nanosecond(0)
+        .unwrap_or(time)
+        .format(&Rfc3339)
+        .unwrap_or_default()
+}
+
+pub(crate) fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// The space used after each sync recorded in the servers' sync histories.
+/// Servers synced in the same run share an entry and servers missing from an
+/// entry are assumed to be unchanged since their previous sync.
+fn storage_history(state: &State) -> Vec<HistoryEntry> {
+    let mut history: Vec<HistoryEntry> = Vec::new();
+    let mut runs: HashMap<&str, usize> = HashMap::new();
+
+    for (server, server_state) in state.servers.iter() {
+        for record in server_state.sync_history.iter() {
+            let used = match record.used {
+                Some(used) => used,
+                None => continue,
+            };
+
+            let index = match record.run.as_deref().and_then(|run| runs.get(run)) {
+                Some(index) => *index,
+                None => {
+                    history.push(HistoryEntry {
+                        timestamp: record.started,
+                        servers: HashMap::new(),
+                    });
+                    if let Some(ref run) = record.run {
+                        runs.insert(run, history.len() - 1);
+                    }
+                    history.len() - 1
+                }
+            };
+
+            history[index].servers.insert(server.clone(), used);
+        }
+    }
+
+    history.sort_by_key(|entry| entry.timestamp);
+
+    let mut last: HashMap<String, u64> = HashMap::new();
+    for entry in history.iter_mut() {
+        for (server, used) in last.iter() {
+            entry.servers.entry(server.clone()).or_insert(*used);
+        }
+        last.clone_from(&entry.servers);
+    }
+
+    history
+}
+
+/// The servers that appear anywhere in the history, in a stable order.
+fn history_servers(history: &[HistoryEntry]) -> Vec<&str> {
+    history
+        .iter()
+        .flat_map(|entry| entry.servers.keys())
+        .map(|server| server.as_str())
+        .collect::<BTreeSet<&str>>()
+        .into_iter()
+        .collect()
+}
+
+fn render_markdown(data: &ReportData) -> String {
+    let mut out = String::new();
+
+    let _ = writeln!(out, "# Sync report\n");
+    let _ = writeln!(
+        out,
+        "Started {}, finished {}.\n",
+        data.started, data.finished
+    );
+    if let Some(ref run) = data.run {
+        let _ = writeln!(out, "Run `{run}`.\n");
+    }
+
+    let downloaded: u64 = data.downloads.iter().map(|(_, size)| size).sum();
+    let _ = writeln!(out, "| | |\n|---|---|");
+    let _ = writeln!(
+        out,
+        "| Downloaded | {} ({}) |",
+        data.downloads.len(),
+        format_bytes(downloaded)
+    );
+    let _ = writeln!(
+        out,
+        "| Artwork | {} ({}) |",
+        data.artwork.0,
+        format_bytes(data.artwork.1)
+    );
+    let _ = writeln!(out, "| Removed | {} |", data.removed.len());
+    let _ = writeln!(out, "| Evicted | {} |", data.evicted.len());
+    let _ = writeln!(out, "| Pruned files | {} |", data.pruned.len());
+    let _ = writeln!(out, "| Failures | {} |", data.failures.len());
+
+    let mut section = |title: &str, items: Vec<String>| {
+        if !items.is_empty() {
+            let _ = writeln!(out, "\n## {title}\n");
+            for item in items {
+                let _ = writeln!(out, "- {item}");
+            }
+        }
+    };
+
+    section(
+        "Downloaded",
+        data.downloads
+            .iter()
+            .map(|(title, size)| format!("{title} ({})", format_bytes(*size)))
+            .collect(),
+    );
+    section(
+        "Removed",
+        data.removed
+            .iter()
+            .map(|(title, reason)| format!("{title}: {reason}"))
+            .collect(),
+    );
+    section("Evicted", data.evicted.clone());
+    section(
+        "Pruned files",
+        data.pruned
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect(),
+    );
+    section("Failures", data.failure_messages());
+
+    let servers = history_servers(&data.history);
+    let _ = writeln!(out, "\n## Storage\n");
+    let mut header = vec!["Date"];
+    header.extend(servers.iter());
+    header.push("Total");
+    let _ = writeln!(out, "| {} |", header.join(" | "));
+    let _ = writeln!(out, "|{}", "---|".repeat(header.len()));
+    for entry in data.history.iter().rev() {
+        let mut row = vec![timestamp(entry.timestamp)];
+        row.extend(
+            servers.iter().map(|server| {
+                format_bytes(entry.servers.get(*server).copied().unwrap_or_default())
+            }),
+        );
+        row.push(format_bytes(entry.total()));
+        let _ = writeln!(out, "| {} |", row.join(" | "));
+    }
+
+    out
+}
+
+/// A line graph of the total space used over the history.
+fn trend_svg(history: &[HistoryEntry]) -> String {
+    const WIDTH: f64 = 600.0;
+    const HEIGHT: f64 = 150.0;
+
+    let totals: Vec<u64> = history.iter().map(HistoryEntry::total).collect();
+    if totals.len() < 2 {
+        return String::new();
+    }
+
+    let max = totals.iter().copied().max().unwrap_or_default().max(1) as f64;
+    let step = WIDTH / (totals.len() - 1) as f64;
+    let points: Vec<String> = totals
+        .iter()
+        .enumerate()
+        .map(|(index, total)| {
+            format!(
+                "{:.1},{:.1}",
+                index as f64 * step,
+                HEIGHT - (*total as f64 / max) * HEIGHT
+            )
+        })
+        .collect();
+
+    format!(
+        r##"<svg viewBox="0 0 {WIDTH} {HEIGHT}" width="{WIDTH}" height="{HEIGHT}"><polyline fill="none" stroke="#e5a00d" stroke-width="2" points="{}"/></svg>"##,
+        points.join(" ")
+    )
+}
+
+fn render_html(data: &ReportData) -> String {
+    let mut out = String::new();
+
+    let _ = writeln!(
+        out,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Sync report</title>\n</head>\n<body>"
+    );
+    let _ = writeln!(out, "<h1>Sync report</h1>");
+    let _ = writeln!(
+        out,
+        "<p>Started {}, finished {}.</p>",
+        data.started, data.finished
+    );
+    if let Some(ref run) = data.run {
+        let _ = writeln!(out, "<p>Run <code>{}</code>.</p>", escape(run));
+    }
+
+    let downloaded: u64 = data.downloads.iter().map(|(_, size)| size).sum();
+    let _ = writeln!(out, "<table>");
+    let _ = writeln!(
+        out,
+        "<tr><th>Downloaded</th><td>{} ({})</td></tr>",
+        data.downloads.len(),
+        format_bytes(downloaded)
+    );
+    let _ = writeln!(
+        out,
+        "<tr><th>Artwork</th><td>{} ({})</td></tr>",
+        data.artwork.0,
+        format_bytes(data.artwork.1)
+    );
+    let _ = writeln!(
+        out,
+        "<tr><th>Removed</th><td>{}</td></tr>",
+        data.removed.len()
+    );
+    let _ = writeln!(
+        out,
+        "<tr><th>Evicted</th><td>{}</td></tr>",
+        data.evicted.len()
+    );
+    let _ = writeln!(
+        out,
+        "<tr><th>Pruned files</th><td>{}</td></tr>",
+        data.pruned.len()
+    );
+    let _ = writeln!(
+        out,
+        "<tr><th>Failures</th><td>{}</td></tr>",
+        data.failures.len()
+    );
+    let _ = writeln!(out, "</table>");
+
+    let mut section = |title: &str, items: Vec<String>| {
+        if !items.is_empty() {
+            let _ = writeln!(out, "<h2>{title}</h2>\n<ul>");
+            for item in items {
+                let _ = writeln!(out, "<li>{}</li>", escape(&item));
+            }
+            let _ = writeln!(out, "</ul>");
+        }
+    };
+
+    section(
+        "Downloaded",
+        data.downloads
+            .iter()
+            .map(|(title, size)| format!("{title} ({})", format_bytes(*size)))
+            .collect(),
+    );
+    section(
+        "Removed",
+        data.removed
+            .iter()
+            .map(|(title, reason)| format!("{title}: {reason}"))
+            .collect(),
+    );
+    section("Evicted", data.evicted.clone());
+    section(
+        "Pruned files",
+        data.pruned
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect(),
+    );
+    section("Failures", data.failure_messages());
+
+    let servers = history_servers(&data.history);
+    let _ = writeln!(out, "<h2>Storage</h2>");
+    let _ = writeln!(out, "{}", trend_svg(&data.history));
+    let _ = writeln!(out, "<table>\n<tr><th>Date</th>");
+    for server in servers.iter() {
+        let _ = writeln!(out, "<th>{}</th>", escape(server));
+    }
+    let _ = writeln!(out, "<th>Total</th></tr>");
+    for entry in data.history.iter().rev() {
+        let _ = write!(out, "<tr><td>{}</td>", timestamp(entry.timestamp));
+        for server in servers.iter() {
+            let _ = write!(
+                out,
+                "<td>{}</td>",
+                format_bytes(entry.servers.get(*server).copied().unwrap_or_default())
+            );
+        }
+        let _ = writeln!(out, "<td>{}</td></tr>", format_bytes(entry.total()));
+    }
+    let _ = writeln!(out, "</table>\n</body>\n</html>");
+
+    out
+}
+
+fn render_json(data: &ReportData) -> Result<String> {
+    let report = json!({
+        "run": data.run,
+        "started": data.started,
+        "finished": data.finished,
+        "downloads": data
+            .downloads
+            .iter()
+            .map(|(title, size)| json!({ "title": title, "size": size }))
+            .collect::<Vec<_>>(),
+        "removed": data
+            .removed
+            .iter()
+            .map(|(title, reason)| json!({ "title": title, "reason": reason }))
+            .collect::<Vec<_>>(),
+        "evicted": data.evicted,
+        "pruned": data.pruned,
+        "failures": data.failures,
+        "artwork": { "count": data.artwork.0, "size": data.artwork.1 },
+    });
+
+    Ok(to_string_pretty(&report)?)
+}
+
+impl RunReport {
+    /// Records a problem that should appear in the report.
+    pub fn record_failure<S: ToString>(&self, failure: S) {
+        self.log.lock().unwrap().failures.push(Failure {
+            server: None,
+            item: None,
+            operation: None,
+            message: failure.to_string(),
+        });
+    }
+
+    /// Records a problem with a specific item that should appear in the
+    /// report.
+    pub fn record_item_failure<S: ToString>(&self, server: &str, item: &str, failure: S) {
+        self.log.lock().unwrap().failures.push(Failure {
+            server: Some(server.to_owned()),
+            item: Some(item.to_owned()),
+            operation: Some(operation_id(server, item, None)),
+            message: failure.to_string(),
+        });
+    }
+
+    /// Writes the report into the store's report directory, also as `latest`.
+    /// The storage history comes from the servers' sync histories so record
+    /// the syncs first. Returns the path to the report.
+    pub async fn write(&self, flick_sync: &FlickSync, format: ReportFormat) -> Result<PathBuf> {
+        let root = flick_sync.inner.path.read().await.clone();
+        let media_root = flick_sync.inner.media_root().await;
+        let finished = OffsetDateTime::now_utc();
+
+        let (titles, history) = {
+            let state = flick_sync.inner.state.read().await;
+
+            let mut titles: HashMap<(String, String), String> = HashMap::new();
+            for (server, server_state) in state.servers.iter() {
+                for video in server_state.videos.values() {
+                    titles.insert((server.clone(), video.id.clone()), video.title.clone());
+                }
+            }
+
+            (titles, storage_history(&state))
+        };
+
+        let (run, downloads, removed, evicted, pruned, failures, artwork) = {
+            let log = self.log.lock().unwrap();
+
+            let downloads: Vec<(String, PathBuf)> = log
+                .downloads
+                .iter()
+                .map(|(server, video, path)| {
+                    let title = titles
+                        .get(&(server.clone(), video.clone()))
+                        .cloned()
+                        .unwrap_or_else(|| video.clone());
+                    (title, media_root.join(path))
+                })
+                .collect();
+
+            (
+                log.run.clone(),
+                downloads,
+                log.removed.clone(),
+                log.evicted.clone(),
+                log.pruned.clone(),
+                log.failures.clone(),
+                log.artwork,
+            )
+        };
+
+        let mut sized_downloads = Vec::new();
+        for (title, path) in downloads {
+            let size = metadata(&path).await.map(|m| m.len()).unwrap_or_default();
+            sized_downloads.push((title, size));
+        }
+
+        let data = ReportData {
+            run,
+            started: timestamp(self.started),
+            finished: timestamp(finished),
+            downloads: sized_downloads,
+            removed,
+            evicted,
+            pruned,
+            failures,
+            artwork,
+            history,
+        };
+
+        let content = match format {
+            ReportFormat::Html => render_html(&data),
+            ReportFormat::Markdown => render_markdown(&data),
+            ReportFormat::Json => render_json(&data)?,
+        };
+
+        let directory = root.join(REPORT_DIR);
+        create_dir_all(&directory).await?;
+
+        let name = timestamp(finished).replace(':', "-");
+        let path = directory.join(format!("sync-{name}.{}", format.extension()));
+        write(&path, content).await?;
+        copy(
+            &path,
+            directory.join(format!("latest.{}", format.extension())),
+        )
+        .await?;
+
+        Ok(path)
+    }
+}
@@ -0,0 +1,177 @@
+//! Daily windows that change the download rate, for example to only download
+//! overnight or to slow downloads during the day.
+
+use std::time::Duration;
+
+use chrono::{Local, Timelike};
+
+use crate::config::Config;
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// Parses a time of day written as `HH:MM` into minutes since midnight.
+pub(crate) fn parse_time(time: &str) -> Option<u32> {
+    let (hours, minutes) = time.split_once(':')?;
+    let hours: u32 = hours.parse().ok()?;
+    let minutes: u32 = minutes.parse().ok()?;
+
+    (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+}
+
+/// The download rate through the day in kilobytes per second. `None` is
+/// unlimited and `Some(0)` pauses downloads.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct RateSchedule {
+    default: Option<u64>,
+    /// The start and end minute of each window and its rate.
+    windows: Vec<(u32, u32, Option<u64>)>,
+}
+
+impl RateSchedule {
+    pub(crate) fn from(config: &Config) -> Self {
+        Self {
+            // A maximum rate of 0 has always meant unlimited.
+            default: config.max_download_rate.filter(|rate| *rate > 0),
+            windows: config
+                .bandwidth_schedule
+                .iter()
+                .filter_map(|window| {
+                    Some((
+                        parse_time(&window.start)?,
+                        parse_time(&window.end)?,
+                        window.rate,
+                    ))
+                })
+                .collect(),
+        }
+    }
+
+    /// The rate at a minute of the day and the minutes until it may change.
+    /// The first window containing the minute wins.
+    fn rate_at(&self, minute: u32) -> (Option<u64>, u32) {
+        let mut rate = None;
+        let mut until = MINUTES_PER_DAY;
+
+        for &(start, end, window_rate) in self.windows.iter() {
+            let inside = if start <= end {
+                minute >= start && minute < end
+            } else {
+                // The window runs past midnight.
+                minute >= start || minute < end
+            };
+            if inside && rate.is_none() {
+                rate = Some(window_rate);
+            }
+
+            for boundary in [start, end] {
+                let distance = (boundary + MINUTES_PER_DAY - minute) % MINUTES_PER_DAY;
+                if distance > 0 {
+                    until = until.min(distance);
+                }
+            }
+        }
+
+        (rate.unwrap_or(self.default), until)
+    }
+
+    /// The rate now and how long until it may change.
+    pub(crate) fn current(&self) -> (Option<u64>, Duration) {
+        let now = Local::now();
+        let (rate, until) = self.rate_at(now.hour() * 60 + now.minute());
+
+        (
+            rate,
+            Duration::from_secs(u64::from(until) * 60 - u64::from(now.second())),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_time, RateSchedule};
+
+    fn windows(default: Option<u64>, windows: &[(&str, &str, Option<u64>)]) -> RateSchedule {
+        RateSchedule {
+            default,
+            windows: windows
+                .iter()
+                .map(|(start, end, rate)| {
+                    (parse_time(start).unwrap(), parse_time(end).unwrap(), *rate)
+                })
+                .collect(),
+        }
+    }
+
+    fn at(schedule: &RateSchedule, time: &str) -> (Option<u64>, u32) {
+        schedule.rate_at(parse_time(time).unwrap())
+    }
+
+    #[test]
+    fn times() {
+        assert_eq!(parse_time("00:00"), Some(0));
+        assert_eq!(parse_time("06:30"), Some(390));
+        assert_eq!(parse_time("23:59"), Some(1439));
+        assert_eq!(parse_time("24:00"), None);
+        assert_eq!(parse_time("12:60"), None);
+        assert_eq!(parse_time("noon"), None);
+    }
+
+    #[test]
+    fn overnight() {
+        let schedule = windows(Some(50), &[("22:00", "06:00", Some(500))]);
+
+        assert_eq!(at(&schedule, "23:00"), (Some(500), 7 * 60));
+        assert_eq!(at(&schedule, "02:00"), (Some(500), 4 * 60));
+        assert_eq!(at(&schedule, "12:00"), (Some(50), 10 * 60));
+    }
+
+    #[test]
+    fn overlapping() {
+        let schedule = windows(
+            None,
+            &[("09:00", "17:00", Some(100)), ("12:00", "13:00", Some(10))],
+        );
+
+        assert_eq!(at(&schedule, "12:30"), (Some(100), 30));
+        assert_eq!(at(&schedule, "10:00"), (Some(100), 2 * 60));
+        assert_eq!(at(&schedule, "08:00"), (None, 60));
+
+        let schedule = windows(
+            None,
+            &[("12:00", "13:00", Some(10)), ("09:00", "17:00", Some(100))],
+        );
+
+        assert_eq!(at(&schedule, "12:30"), (Some(10), 30));
+        assert_eq!(at(&schedule, "13:00"), (Some(100), 4 * 60));
+    }
+
+    #[test]
+    fn paused() {
+        let schedule = windows(Some(50), &[("08:00", "18:00", Some(0))]);
+
+        assert_eq!(at(&schedule, "08:00"), (Some(0), 10 * 60));
+        assert_eq!(at(&schedule, "17:59"), (Some(0), 1));
+        assert_eq!(at(&schedule, "18:00"), (Some(50), 14 * 60));
+
+        // An unlimited window overrides a default rate.
+        let schedule = windows(Some(50), &[("01:00", "02:00", None)]);
+        assert_eq!(at(&schedule, "01:30"), (None, 30));
+    }
+
+    #[test]
+    fn boundaries() {
+        assert_eq!(at(&windows(Some(50), &[]), "12:00"), (Some(50), 24 * 60));
+
+        let schedule = windows(None, &[("00:00", "06:00", Some(500))]);
+
+        assert_eq!(at(&schedule, "00:00"), (Some(500), 6 * 60));
+        assert_eq!(at(&schedule, "05:59"), (Some(500), 1));
+        assert_eq!(at(&schedule, "06:00"), (None, 18 * 60));
+        assert_eq!(at(&schedule, "23:59"), (None, 1));
+
+        // A window that starts and ends together is empty.
+        let schedule = windows(None, &[("10:00", "10:00", Some(500))]);
+        assert_eq!(at(&schedule, "10:00"), (None, 24 * 60));
+        assert_eq!(at(&schedule, "09:00"), (None, 60));
+    }
+}
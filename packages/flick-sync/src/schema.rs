@@ -0,0 +1,114 @@
+use schemars::{schema::RootSchema, schema_for};
+use serde_json::{to_string_pretty, Map, Value};
+use tracing::info;
+
+use crate::{config::Config, state::State, Error, Result};
+
+/// The version of the config and state file formats. This must be increased
+/// whenever either format changes in a way that older readers cannot handle.
+pub const FORMAT_VERSION: u32 = 2;
+
+/// Settings that version 1 configs could hold in each server's config but
+/// that apply to the whole store.
+const STORE_KEYS: [&str; 5] = [
+    "metadataRoot",
+    "artwork",
+    "layout",
+    "movieTemplate",
+    "episodeTemplate",
+];
+/// Settings that version 1 configs could hold at the top level but that
+/// apply to each server.
+const SERVER_KEYS: [&str; 2] = ["mediaSelection", "mediaVersions"];
+
+fn migrate_from_1(config: &mut Map<String, Value>) {
+    let mut store_settings = Vec::new();
+    let server_settings: Vec<(&str, Value)> = SERVER_KEYS
+        .iter()
+        .filter_map(|key| config.remove(*key).map(|value| (*key, value)))
+        .collect();
+
+    if let Some(Value::Object(servers)) = config.get_mut("servers") {
+        for server in servers.values_mut() {
+            if let Value::Object(server) = server {
+                for key in STORE_KEYS {
+                    if let Some(value) = server.remove(key) {
+                        store_settings.push((key, value));
+                    }
+                }
+
+                for (key, value) in server_settings.iter() {
+                    server
+                        .entry(key.to_string())
+                        .or_insert_with(|| value.clone());
+                }
+            }
+        }
+    }
+
+    // The first server's setting wins if they differ.
+    for (key, value) in store_settings {
+        config.entry(key).or_insert(value);
+    }
+}
+
+/// Upgrades a config written by an older version to the current layout.
+/// Returns whether anything was migrated.
+pub(crate) fn migrate_config(config: &mut Value) -> Result<bool> {
+    let config = match config {
+        Value::Object(config) => config,
+        _ => return Err(Error::InvalidConfig("expected an object".to_string())),
+    };
+
+    // Configs from before the version was recorded are version 1.
+    let version = match config.get("version") {
+        None => 1,
+        Some(version) => match version.as_u64() {
+            Some(version) => version as u32,
+            None => {
+                return Err(Error::InvalidConfig(
+                    "version: expected a number".to_string(),
+                ))
+            }
+        },
+    };
+
+    if version > FORMAT_VERSION {
+        return Err(Error::InvalidConfig(format!(
+            "version: written by a newer version of flick-sync (format {version})"
+        )));
+    }
+
+    if version == FORMAT_VERSION {
+        return Ok(false);
+    }
+
+    info!(from = version, to = FORMAT_VERSION, "Migrating the config");
+    if version < 2 {
+        migrate_from_1(config);
+    }
+    config.insert("version".to_owned(), Value::from(FORMAT_VERSION));
+
+    Ok(true)
+}
+
+fn to_json(mut schema: RootSchema, title: &str) -> Result<String> {
+    let metadata = schema.schema.metadata();
+    metadata.title = Some(title.to_owned());
+    schema
+        .schema
+        .extensions
+        .insert("x-format-version".to_owned(), Value::from(FORMAT_VERSION));
+
+    Ok(to_string_pretty(&schema)?)
+}
+
+/// A JSON Schema describing the config file.
+pub fn config_schema() -> Result<String> {
+    to_json(schema_for!(Config), "FlickSync config")
+}
+
+/// A JSON Schema describing the state file.
+pub fn state_schema() -> Result<String> {
+    to_json(schema_for!(State), "FlickSync state")
+}
@@ -1,18 +1,22 @@
 use std::{
-    cmp::Ordering,
+    cmp::{Ordering, Reverse},
     collections::{HashMap, HashSet},
     fmt,
     path::{Path, PathBuf},
+    str::FromStr,
     sync::Arc,
+    time::{Duration, Instant, SystemTime},
 };
 
 use async_recursion::async_recursion;
-use async_std::sync::Mutex;
+use async_std::sync::{Mutex, RwLockWriteGuard};
 use async_std::{
-    fs::{read_dir, remove_dir, remove_dir_all, remove_file},
+    fs::{metadata, read_dir, remove_dir},
+    future::timeout,
     stream::StreamExt,
 };
 use core::ops::Deref;
+use futures::future::join_all;
 use plex_api::{
     device::DeviceConnection,
     library::{
@@ -20,30 +24,229 @@ use plex_api::{
         Playlist, Season, Show, Video,
     },
     media_container::server::library::MetadataType,
-    MyPlexBuilder,
+    HttpClient, MyPlexBuilder,
 };
+use time::OffsetDateTime;
 use tokio::sync::{Semaphore, SemaphorePermit};
 use tracing::{debug, error, info, instrument, trace, warn};
 
 use crate::{
-    config::{Config, ServerConfig, SyncItem, TranscodeProfile},
+    backend::StorageBackend,
+    config::{
+        is_relative_path, Config, EmptyShowPolicy, QueueOrder, ServerConfig, SyncItem, SyncQuery,
+        TranscodeProfile, Transcoder, QUERY_SOURCE,
+    },
+    conflict::{self, Conflict, Conflicts, Resolution},
+    events::{Event, Events},
+    eviction,
+    filter::Filter,
+    metadata::MetadataExporter,
+    snapshot::{Snapshot, SNAPSHOT_VERSION},
     state::{
-        CollectionState, DownloadState, LibraryState, LibraryType, PlaylistState, SeasonState,
-        ServerState, ShowState, VideoDetail, VideoState,
+        choose_media, CachedCapabilities, CachedItem, CollectionState, ConnectionKind,
+        DownloadState, ItemType, LibraryState, LibraryType, PlaylistState, PreferredConnection,
+        SeasonState, ServerState, ShowState, State, SyncRecord, VideoDetail, VideoState,
+        SYNC_HISTORY_LENGTH,
     },
+    stats::{self, ServerStatistics},
     util::safe,
-    wrappers, Error, Inner, Library, Result, ServerConnection, DEFAULT_PROFILES,
+    wrappers, Error, ErrorClass, Inner, Library, Result, ServerConnection, DEFAULT_PROFILES,
 };
 
-pub enum ItemType {
-    Playlist,
-    MovieCollection,
-    ShowCollection,
-    Show,
-    Season,
-    Episode,
-    Movie,
-    Unknown,
+/// A video part that a sync would download.
+pub struct PlannedDownload {
+    pub video: String,
+    pub title: String,
+    pub part: usize,
+    pub size: u64,
+    /// The expected size of the download, estimated from the profile's
+    /// bitrate when transcoding.
+    pub estimated: u64,
+    /// Whether the server would be asked to transcode the part first.
+    pub transcode: bool,
+    /// The show, collection or playlist that the video is synced for.
+    pub group: String,
+}
+
+/// A video that would be removed along with its local files.
+pub struct PlannedRemoval {
+    pub video: String,
+    pub title: String,
+    pub size: u64,
+    pub reason: String,
+}
+
+/// An unexpected file that pruning would delete.
+pub struct PlannedDeletion {
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+#[derive(Default)]
+pub struct SyncPlan {
+    pub downloads: Vec<PlannedDownload>,
+    pub removals: Vec<PlannedRemoval>,
+    pub deletions: Vec<PlannedDeletion>,
+    /// The space used by downloads that the sync would keep.
+    pub kept: u64,
+}
+
+/// A video affected by the configured size limits.
+pub struct LimitedVideo {
+    pub video: String,
+    pub title: String,
+    pub size: u64,
+    /// Why the video was chosen.
+    pub reason: String,
+}
+
+/// The result of enforcing the configured size limits before downloading.
+#[derive(Default)]
+pub struct SizeLimitReport {
+    /// Videos whose downloads were deleted to make space.
+    pub evicted: Vec<LimitedVideo>,
+    /// Videos that should not be downloaded as they would exceed the limits.
+    pub deferred: Vec<LimitedVideo>,
+}
+
+/// Totals describing a server's content in the store, read from the state
+/// without contacting the server.
+#[derive(Default)]
+pub struct ServerStatus {
+    pub name: String,
+    pub libraries: usize,
+    pub collections: usize,
+    pub playlists: usize,
+    /// Videos with every part downloaded.
+    pub downloaded: usize,
+    /// Videos with parts still to download.
+    pub pending: usize,
+    /// Videos marked to be skipped.
+    pub skipped: usize,
+    /// Space used by the server's downloads.
+    pub local_bytes: u64,
+    /// Data still to download.
+    pub pending_bytes: u64,
+    /// Server transcode sessions left behind by a sync that did not finish.
+    pub transcode_sessions: usize,
+    /// When the server's items were last updated without any failures.
+    pub last_synced: Option<SystemTime>,
+    /// When the server last rejected the stored token, cleared once a
+    /// connection succeeds again.
+    pub auth_failed: Option<SystemTime>,
+}
+
+/// The result of checking a server's stored token without changing it.
+#[derive(Default)]
+pub struct AuthCheck {
+    /// Whether the token can still reach the configured server.
+    pub valid: bool,
+    /// Why the token was rejected.
+    pub error: Option<String>,
+    /// The Plex account that owns the token, unknown for unclaimed servers.
+    pub owner: Option<String>,
+    /// Whether the account has an active Plex Pass subscription, unknown for
+    /// unclaimed servers.
+    pub plex_pass: Option<bool>,
+    /// The names of the servers the token can access.
+    pub servers: Vec<String>,
+}
+
+/// What a server can do. Servers used without a plex.tv account, such as
+/// unclaimed servers on a local network, lack some features.
+pub struct ServerFeatures {
+    /// Whether the server is reached through a plex.tv account, needed for
+    /// Plex Home users and finding the server's current address.
+    pub plex_account: bool,
+    /// Whether the server is signed in to plex.tv.
+    pub claimed: bool,
+    /// Whether the server's owner has an active Plex Pass subscription.
+    pub plex_pass: bool,
+    /// Whether the server allows its media to be downloaded.
+    pub downloads: bool,
+    /// Whether the server can transcode video.
+    pub transcoding: bool,
+}
+
+/// A past sync of a server.
+#[derive(Clone, Debug)]
+pub struct SyncHistoryEntry {
+    pub started: SystemTime,
+    pub duration: Duration,
+    /// The data downloaded during the sync.
+    pub bytes: u64,
+    pub errors: usize,
+    /// The id of the sync run, see
+    /// [`FlickSync::start_run`](crate::FlickSync::start_run).
+    pub run: Option<String>,
+}
+
+impl From<&SyncRecord> for SyncHistoryEntry {
+    fn from(record: &SyncRecord) -> Self {
+        Self {
+            started: SystemTime::from(record.started),
+            duration: Duration::from_secs(record.duration),
+            bytes: record.bytes,
+            errors: record.errors as usize,
+            run: record.run.clone(),
+        }
+    }
+}
+
+/// One of the media versions available for a video.
+pub struct MediaVersion {
+    pub id: String,
+    pub height: Option<u32>,
+    pub container: Option<String>,
+    pub size: u64,
+    /// Whether this is the version currently synced.
+    pub selected: bool,
+}
+
+/// The size of syncing everything in a library, used to plan a first sync.
+pub struct LibraryEstimate {
+    pub id: String,
+    pub title: String,
+    pub videos: usize,
+    /// Total running time in milliseconds.
+    pub duration: u64,
+    /// Total size of the original files.
+    pub size: u64,
+}
+
+/// The bitrate in kbps assumed for the audio of transcoded videos.
+const ESTIMATED_AUDIO_BITRATE: u64 = 192;
+/// The number of downloads checked at once when verifying.
+const VERIFY_CONCURRENCY: usize = 8;
+/// How long to wait for a remembered connection before asking plex.tv.
+const PREFERRED_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often the state is written while updating item metadata.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(30);
+
+impl LibraryEstimate {
+    fn add<M: MediaItem>(&mut self, item: &M) {
+        let media = item.media();
+        if let Some(media) = media.first() {
+            for part in media.parts() {
+                self.size += part.metadata().size.unwrap_or_default();
+                self.duration += part.metadata().duration.unwrap_or_default();
+            }
+            self.videos += 1;
+        }
+    }
+
+    /// The estimated size when transcoded to a video bitrate in kbps, or the
+    /// size of the original files for `None`.
+    pub fn size_at(&self, bitrate: Option<u32>) -> u64 {
+        match bitrate {
+            // Milliseconds multiplied by kilobits per second gives bits.
+            Some(bitrate) => {
+                let size = self.duration * (bitrate as u64 + ESTIMATED_AUDIO_BITRATE) / 8;
+                size.min(self.size)
+            }
+            None => self.size,
+        }
+    }
 }
 
 pub struct SyncItemInfo {
@@ -52,6 +255,10 @@ pub struct SyncItemInfo {
     pub title: String,
     pub transcode_profile: Option<String>,
     pub only_unplayed: bool,
+    pub max_episodes: Option<u32>,
+    pub latest_season: bool,
+    pub skipped: bool,
+    pub pinned: bool,
 }
 
 #[derive(Clone)]
@@ -69,7 +276,13 @@ impl fmt::Debug for Server {
 }
 
 #[async_recursion]
-async fn prune_directory(path: &Path, expected_files: &HashSet<PathBuf>) -> bool {
+pub(crate) async fn prune_directory<'a>(
+    path: &'a Path,
+    expected_files: &'a HashSet<PathBuf>,
+    events: &'a Events,
+    storage: &'a dyn StorageBackend,
+    mut planned: Option<&'a mut Vec<PlannedDeletion>>,
+) -> bool {
     let mut reader = match read_dir(&path).await {
         Ok(reader) => reader,
         Err(e) => {
@@ -79,6 +292,9 @@ async fn prune_directory(path: &Path, expected_files: &HashSet<PathBuf>) -> bool
     };
 
     let mut should_prune = true;
+    // Subdirectories are pruned concurrently unless planning, which needs
+    // to add to the list of deletions in order.
+    let mut subdirectories = Vec::new();
 
     loop {
         match reader.next().await {
@@ -86,14 +302,31 @@ async fn prune_directory(path: &Path, expected_files: &HashSet<PathBuf>) -> bool
                 let path: PathBuf = entry.path().into();
                 match entry.file_type().await {
                     Ok(file_type) => {
-                        if file_type.is_dir() {
-                            if !prune_directory(&path, expected_files).await {
+                        if file_type.is_dir() && planned.is_none() {
+                            subdirectories.push(path);
+                        } else if file_type.is_dir() {
+                            if !prune_directory(
+                                &path,
+                                expected_files,
+                                events,
+                                storage,
+                                planned.as_deref_mut(),
+                            )
+                            .await
+                            {
                                 should_prune = false;
                             }
                         } else if !expected_files.contains(&path) {
-                            match remove_file(&path).await {
+                            if let Some(ref mut planned) = planned {
+                                let size = entry.metadata().await.map(|m| m.len()).unwrap_or(0);
+                                planned.push(PlannedDeletion { path, size });
+                                continue;
+                            }
+
+                            match storage.remove(&path).await {
                                 Ok(()) => {
                                     debug!(path = %path.display(), "Deleted unknown file");
+                                    events.emit(Event::FilePruned { path });
                                 }
                                 Err(e) => {
                                     error!(error=?e, path=%path.display(), "Failed to delete unknown file");
@@ -119,10 +352,27 @@ async fn prune_directory(path: &Path, expected_files: &HashSet<PathBuf>) -> bool
         }
     }
 
+    let results = join_all(
+        subdirectories
+            .iter()
+            .map(|path| prune_directory(path, expected_files, events, storage, None)),
+    )
+    .await;
+    if results.contains(&false) {
+        should_prune = false;
+    }
+
     if should_prune {
+        if planned.is_some() {
+            return true;
+        }
+
         match remove_dir(&path).await {
             Ok(()) => {
                 debug!(path = %path.display(), "Deleted unknown directory");
+                events.emit(Event::FilePruned {
+                    path: path.to_owned(),
+                });
                 return true;
             }
             Err(e) => {
@@ -134,6 +384,151 @@ async fn prune_directory(path: &Path, expected_files: &HashSet<PathBuf>) -> bool
     false
 }
 
+/// Reads what a server supports from its description.
+fn capabilities(server: &plex_api::Server) -> CachedCapabilities {
+    let container = &server.media_container;
+
+    CachedCapabilities {
+        claimed: container.my_plex,
+        plex_pass: container.my_plex_subscription,
+        downloads: container.allow_sync,
+        transcoding: container.transcoder_video,
+        fetched: OffsetDateTime::now_utc(),
+    }
+}
+
+/// Describes a sync item using the titles recorded in the state.
+fn describe_source(server_state: &ServerState, id: &str) -> String {
+    if let Some(query) = id.strip_prefix(QUERY_SOURCE) {
+        format!("query '{query}'")
+    } else if let Some(playlist) = server_state.playlists.get(id) {
+        format!("playlist '{}'", playlist.title)
+    } else if let Some(collection) = server_state.collections.get(id) {
+        format!("collection '{}'", collection.title)
+    } else if let Some(show) = server_state.shows.get(id) {
+        format!("show '{}'", show.title)
+    } else if let Some(season) = server_state.seasons.get(id) {
+        match server_state.shows.get(&season.show) {
+            Some(show) => format!("season '{}: {}'", show.title, season.title),
+            None => format!("season '{}'", season.title),
+        }
+    } else if let Some(video) = server_state.videos.get(id) {
+        format!("'{}'", video.title)
+    } else {
+        format!("item {id}")
+    }
+}
+
+/// Describes what a planned download belongs to, the show for episodes and
+/// otherwise the first sync item that includes it.
+fn plan_group(server_state: &ServerState, video: &VideoState) -> String {
+    if let VideoDetail::Episode(ref detail) = video.detail {
+        if let Some(show) = server_state
+            .seasons
+            .get(&detail.season)
+            .and_then(|season| server_state.shows.get(&season.show))
+        {
+            return format!("show '{}'", show.title);
+        }
+    }
+
+    match video.sources.first() {
+        Some(source) => describe_source(server_state, source),
+        None => format!("'{}'", video.title),
+    }
+}
+
+/// Explains why a video is no longer included based on the sync items that
+/// previously included it.
+fn removal_reason(
+    server_config: &ServerConfig,
+    server_state: &ServerState,
+    video: &VideoState,
+) -> String {
+    if video.sources.is_empty() {
+        return "no sync item includes it any more".to_string();
+    }
+
+    video
+        .sources
+        .iter()
+        .map(|source| {
+            let description = describe_source(server_state, source);
+            let remaining = server_config.syncs.contains_key(source)
+                || server_config.queries.iter().any(|q| q.source() == *source);
+            if remaining {
+                format!("no longer included by {description}")
+            } else {
+                format!("{description} was removed from the sync list")
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+fn expected_files(
+    server_state: &ServerState,
+    root: &Path,
+    exporters: &[Box<dyn MetadataExporter>],
+) -> HashSet<PathBuf> {
+    let mut expected_files: HashSet<PathBuf> = HashSet::new();
+
+    for collection in server_state.collections.values() {
+        expected_files.extend(collection.artwork_files().map(|file| root.join(file)));
+    }
+
+    for show in server_state.shows.values() {
+        expected_files.extend(show.artwork_files().map(|file| root.join(file)));
+    }
+
+    for season in server_state.seasons.values() {
+        expected_files.extend(season.artwork_files().map(|file| root.join(file)));
+    }
+
+    for video in server_state.videos.values() {
+        expected_files.extend(video.artwork_files().map(|file| root.join(file)));
+
+        for part in video.parts.iter() {
+            if let Some(file) = part.download.file() {
+                expected_files.insert(root.join(file));
+            }
+        }
+
+        for exporter in exporters {
+            expected_files.extend(
+                exporter
+                    .video_files(video)
+                    .iter()
+                    .map(|file| root.join(file)),
+            );
+        }
+    }
+
+    expected_files
+}
+
+async fn file_size(root: &Path, download: &DownloadState) -> u64 {
+    match download.file() {
+        Some(file) => metadata(root.join(file))
+            .await
+            .map(|stats| stats.len())
+            .unwrap_or_default(),
+        None => 0,
+    }
+}
+
+async fn local_size(server_state: &ServerState, root: &Path) -> u64 {
+    let mut size = 0;
+
+    for video in server_state.videos.values() {
+        for part in video.parts.iter() {
+            size += file_size(root, &part.download).await;
+        }
+    }
+
+    size
+}
+
 impl Server {
     pub(crate) fn new(id: &str, inner: &Arc<Inner>) -> Self {
         Self {
@@ -149,37 +544,106 @@ impl Server {
         &self.id
     }
 
-    pub async fn list_syncs(&self) -> Result<Vec<SyncItemInfo>> {
-        let plex_server = self.connect().await?;
+    /// Lists the items to sync. Their details are cached in the state and only
+    /// fetched from the server once they are older than the configured TTL or
+    /// when `refresh` is passed.
+    pub async fn list_syncs(&self, refresh: bool) -> Result<Vec<SyncItemInfo>> {
+        let now = OffsetDateTime::now_utc();
+
+        let stale: Vec<String> = {
+            let config = self.inner.config.read().await;
+            let state = self.inner.state.read().await;
+            let server_config = config.servers.get(&self.id).unwrap();
+            let item_cache = state.servers.get(&self.id).map(|ss| &ss.item_cache);
+            let ttl = time::Duration::seconds(config.item_cache_ttl.unwrap_or(86400) as i64);
+
+            server_config
+                .syncs
+                .keys()
+                .filter(|id| match item_cache.and_then(|cache| cache.get(*id)) {
+                    Some(cached) => refresh || now - cached.fetched >= ttl,
+                    None => true,
+                })
+                .cloned()
+                .collect()
+        };
+
+        let mut fetched = HashMap::new();
+        if !stale.is_empty() {
+            let plex_server = self.connect().await?;
+
+            for id in stale {
+                let item = plex_server.item_by_id(&id).await?;
+
+                let item_type = match item {
+                    Item::Movie(_) => ItemType::Movie,
+                    Item::Episode(_) => ItemType::Episode,
+                    Item::VideoPlaylist(_) => ItemType::Playlist,
+                    Item::MovieCollection(_) => ItemType::MovieCollection,
+                    Item::ShowCollection(_) => ItemType::ShowCollection,
+                    Item::Show(_) => ItemType::Show,
+                    Item::Season(_) => ItemType::Season,
+                    _ => ItemType::Unknown,
+                };
+
+                // Season titles are rarely meaningful without their show.
+                let title = match (&item, &item.metadata().parent.parent_title) {
+                    (Item::Season(_), Some(show)) => format!("{show}: {}", item.title()),
+                    _ => item.title().to_owned(),
+                };
+
+                fetched.insert(
+                    id,
+                    CachedItem {
+                        item_type,
+                        title,
+                        fetched: now,
+                    },
+                );
+            }
+        }
 
         let config = self.inner.config.read().await;
         let server_config = config.servers.get(&self.id).unwrap();
+        let mut state = self.inner.state.write().await;
+        // A server that has never been synced has no state yet.
+        let server_state = state.servers.entry(self.id.clone()).or_default();
+
+        let changed = !fetched.is_empty()
+            || server_state
+                .item_cache
+                .keys()
+                .any(|id| !server_config.syncs.contains_key(id));
+        server_state.item_cache.extend(fetched);
+        server_state
+            .item_cache
+            .retain(|id, _| server_config.syncs.contains_key(id));
 
         let mut results: Vec<SyncItemInfo> = Vec::new();
 
         for sync in server_config.syncs.values() {
-            let item = plex_server.item_by_id(&sync.id).await?;
-
-            let item_type = match item {
-                Item::Movie(_) => ItemType::Movie,
-                Item::Episode(_) => ItemType::Episode,
-                Item::VideoPlaylist(_) => ItemType::Playlist,
-                Item::MovieCollection(_) => ItemType::MovieCollection,
-                Item::ShowCollection(_) => ItemType::ShowCollection,
-                Item::Show(_) => ItemType::Show,
-                Item::Season(_) => ItemType::Season,
-                _ => ItemType::Unknown,
+            let cached = match server_state.item_cache.get(&sync.id) {
+                Some(cached) => cached,
+                None => continue,
             };
 
             results.push(SyncItemInfo {
-                id: item.rating_key().to_owned(),
-                item_type,
-                title: item.title().to_owned(),
+                id: sync.id.clone(),
+                item_type: cached.item_type,
+                title: cached.title.clone(),
                 transcode_profile: sync.transcode_profile.clone(),
                 only_unplayed: sync.only_unplayed,
+                max_episodes: sync.max_episodes,
+                latest_season: sync.latest_season,
+                skipped: server_config.skipped.contains(&sync.id),
+                pinned: server_config.pinned.contains(&sync.id),
             });
         }
 
+        if changed {
+            self.inner.persist_state(&state).await?;
+        }
+
         Ok(results)
     }
 
@@ -189,22 +653,160 @@ impl Server {
         server_config.transcode_profile.clone()
     }
 
+    pub(crate) async fn transcoder(&self) -> Transcoder {
+        let config = self.inner.config.read().await;
+        let server_config = config.servers.get(&self.id).unwrap();
+        server_config.transcoder.unwrap_or_default()
+    }
+
     pub async fn connection(&self) -> ServerConnection {
         let config = self.inner.config.read().await;
         let server_config = config.servers.get(&self.id).unwrap();
         server_config.connection.clone()
     }
 
+    /// Replaces the server's token after logging in again, keeping its sync
+    /// items and downloads.
     pub async fn update_connection(&self, auth_token: &str, server: plex_api::Server) -> Result {
+        // Opening a connection takes this before the state so do the same.
+        *self.connection.lock().await = Some(server.clone());
+
         let mut state = self.inner.state.write().await;
 
         let server_state = state.servers.entry(self.id.to_owned()).or_default();
         server_state.token = auth_token.to_owned();
-        server_state.name = server.media_container.friendly_name;
+        server_state.machine_id = Some(server.machine_identifier().to_owned());
+        server_state.name = server.media_container.friendly_name.clone();
+        server_state.auth_failed = None;
+        // The remembered connection used the previous token.
+        server_state.preferred_connection = None;
 
         self.inner.persist_state(&state).await
     }
 
+    /// Switches the server to connect straight to `url`, without plex.tv,
+    /// replacing its token. The token is empty for unclaimed servers.
+    pub async fn update_direct_connection(
+        &self,
+        url: String,
+        auth_token: &str,
+        server: plex_api::Server,
+    ) -> Result {
+        {
+            let mut config = self.inner.config.write().await;
+            let state = self.inner.state.read().await;
+
+            let machine_id = state
+                .servers
+                .get(&self.id)
+                .and_then(|ss| ss.machine_id.as_deref());
+            if machine_id.is_some_and(|id| id != server.machine_identifier()) {
+                return Err(Error::ServerMismatch(url));
+            }
+
+            let server_config = config.servers.get_mut(&self.id).unwrap();
+            server_config.connection = ServerConnection::Direct { url };
+            self.inner.persist_config(&config).await?;
+        }
+
+        self.update_connection(auth_token, server).await
+    }
+
+    /// Tries to replace a rejected token for a direct connection with a new
+    /// one from the Plex account of another server that can access the same
+    /// server. Returns whether a new token was found.
+    pub async fn refresh_token(&self) -> Result<bool> {
+        let (url, machine_id, accounts) = {
+            let config = self.inner.config.read().await;
+            let state = self.inner.state.read().await;
+
+            let url = match config.servers.get(&self.id).map(|sc| &sc.connection) {
+                Some(ServerConnection::Direct { url }) => url.clone(),
+                _ => return Ok(false),
+            };
+
+            let machine_id = match state
+                .servers
+                .get(&self.id)
+                .and_then(|ss| ss.machine_id.clone())
+            {
+                Some(machine_id) => machine_id,
+                None => return Ok(false),
+            };
+
+            let accounts: Vec<(String, String)> = config
+                .servers
+                .iter()
+                .filter_map(|(id, sc)| match sc.connection {
+                    ServerConnection::MyPlex { ref user_id, .. } => state
+                        .servers
+                        .get(id)
+                        .map(|ss| (ss.token.clone(), user_id.clone())),
+                    _ => None,
+                })
+                .collect();
+
+            (url, machine_id, accounts)
+        };
+
+        for (account_token, user_id) in accounts {
+            let token = match self
+                .account_server_token(account_token, user_id, &machine_id)
+                .await
+            {
+                Ok(Some(token)) => token,
+                Ok(None) => continue,
+                Err(e) => {
+                    debug!(error=?e, "Unable to use account to refresh token");
+                    continue;
+                }
+            };
+
+            let client = self.inner.client().await.set_x_plex_token(token.clone());
+            let server = plex_api::Server::new(url.clone(), client).await?;
+
+            info!("Refreshed the server's token through a Plex account");
+            self.update_connection(&token, server).await?;
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    /// Finds the token a Plex account uses to access the server.
+    async fn account_server_token(
+        &self,
+        account_token: String,
+        user_id: String,
+        machine_id: &str,
+    ) -> Result<Option<String>> {
+        let myplex = MyPlexBuilder::default()
+            .set_client(self.inner.client().await)
+            .set_token(account_token)
+            .set_test_token_auth(false)
+            .build()
+            .await?;
+
+        let home = myplex.home()?;
+        let myplex = home.switch_user(myplex, user_id, None).await?;
+
+        let manager = myplex.device_manager()?;
+        let device = match manager
+            .resources()
+            .await?
+            .into_iter()
+            .find(|d| d.identifier() == machine_id)
+        {
+            Some(d) => d,
+            None => return Ok(None),
+        };
+
+        match device.connect().await? {
+            DeviceConnection::Server(server) => Ok(Some(server.client().x_plex_token().to_owned())),
+            _ => Ok(None),
+        }
+    }
+
     pub(crate) async fn transcode_permit(&self) -> SemaphorePermit {
         self.transcode_requests.acquire().await.unwrap()
     }
@@ -215,6 +817,290 @@ impl Server {
         server_config.max_transcodes.unwrap_or(3)
     }
 
+    /// Estimates the size of every movie and TV library on the server. This
+    /// lists every video so can be slow for large servers.
+    pub async fn estimate_libraries(&self) -> Result<Vec<LibraryEstimate>> {
+        let server = self.connect().await?;
+        let mut estimates = Vec::new();
+
+        for library in server.libraries() {
+            let mut estimate = LibraryEstimate {
+                id: library.id().to_owned(),
+                title: library.title().to_owned(),
+                videos: 0,
+                duration: 0,
+                size: 0,
+            };
+
+            match &library {
+                PlexLibrary::Movie(lib) => {
+                    for movie in lib.movies().await? {
+                        estimate.add(&movie);
+                    }
+                }
+                PlexLibrary::TV(lib) => {
+                    for show in lib.shows().await? {
+                        for season in show.seasons().await? {
+                            for episode in season.episodes().await? {
+                                estimate.add(&episode);
+                            }
+                        }
+                    }
+                }
+                _ => continue,
+            }
+
+            estimates.push(estimate);
+        }
+
+        Ok(estimates)
+    }
+
+    /// Archives the server's metadata as seen by the last sync into the
+    /// store's snapshot directory, returning the path written.
+    pub async fn write_snapshot(&self) -> Result<PathBuf> {
+        let snapshot = {
+            let config = self.inner.config.read().await;
+            let server_config = config.servers.get(&self.id).unwrap();
+            let state = self.inner.state.read().await;
+
+            Snapshot {
+                version: SNAPSHOT_VERSION,
+                server: self.id.clone(),
+                captured: OffsetDateTime::now_utc(),
+                syncs: server_config.syncs.values().cloned().collect(),
+                queries: server_config.queries.clone(),
+                state: ServerState {
+                    token: String::new(),
+                    preferred_connection: None,
+                    ..state.servers.get(&self.id).cloned().unwrap_or_default()
+                },
+            }
+        };
+
+        let root = self.inner.path.read().await;
+        snapshot.write(&root).await
+    }
+
+    /// Lists the videos in the order that their downloads should start. Videos
+    /// from sync items with a higher priority come first, then those added
+    /// after `since` and then the rest sorted by `order`, falling back to
+    /// the configured order.
+    pub async fn download_queue(
+        &self,
+        order: Option<QueueOrder>,
+        since: Option<SystemTime>,
+    ) -> Vec<wrappers::Video> {
+        let added = self.added_since(since).await;
+        let mut videos = self.videos().await;
+
+        let keys: HashMap<String, (Reverse<i32>, bool, i64)> = {
+            let config = self.inner.config.read().await;
+            let order = order.or(config.queue_order);
+            let syncs = &config.servers.get(&self.id).unwrap().syncs;
+
+            let state = self.inner.state.read().await;
+            let server_state = match state.servers.get(&self.id) {
+                Some(ss) => ss,
+                None => return videos,
+            };
+
+            server_state
+                .videos
+                .values()
+                .map(|video| {
+                    let priority = video
+                        .sources
+                        .iter()
+                        .filter_map(|source| syncs.get(source).and_then(|sync| sync.priority))
+                        .max()
+                        .unwrap_or_default();
+
+                    let key = match order {
+                        Some(QueueOrder::Playlists) => {
+                            if video
+                                .sources
+                                .iter()
+                                .any(|source| server_state.playlists.contains_key(source))
+                            {
+                                0
+                            } else {
+                                1
+                            }
+                        }
+                        Some(QueueOrder::Newest) => -(video.air_date.to_julian_day() as i64),
+                        Some(QueueOrder::Smallest) => {
+                            video.parts.iter().map(|part| part.size as i64).sum()
+                        }
+                        None => 0,
+                    };
+
+                    (
+                        video.id.clone(),
+                        (Reverse(priority), !added.contains(&video.id), key),
+                    )
+                })
+                .collect()
+        };
+
+        videos.sort_by_key(|video| keys.get(video.id()).copied());
+        videos
+    }
+
+    /// When the server's items were last updated without any failures.
+    pub async fn last_synced(&self) -> Option<SystemTime> {
+        let state = self.inner.state.read().await;
+        state
+            .servers
+            .get(&self.id)
+            .and_then(|ss| ss.last_synced)
+            .map(SystemTime::from)
+    }
+
+    /// The videos included by sync items added after `since`, which should be
+    /// downloaded before others. Empty if this is disabled or the server has
+    /// never been synced.
+    pub async fn added_since(&self, since: Option<SystemTime>) -> HashSet<String> {
+        let since = match since {
+            Some(since) => OffsetDateTime::from(since),
+            None => return HashSet::new(),
+        };
+
+        let config = self.inner.config.read().await;
+        if !config.prioritize_new.unwrap_or(true) {
+            return HashSet::new();
+        }
+
+        let added: HashSet<&String> = config
+            .servers
+            .get(&self.id)
+            .unwrap()
+            .syncs
+            .values()
+            .filter(|sync| sync.added.is_some_and(|added| added > since))
+            .map(|sync| &sync.id)
+            .collect();
+        if added.is_empty() {
+            return HashSet::new();
+        }
+
+        let state = self.inner.state.read().await;
+        match state.servers.get(&self.id) {
+            Some(ss) => ss
+                .videos
+                .values()
+                .filter(|video| video.sources.iter().any(|source| added.contains(source)))
+                .map(|video| video.id.clone())
+                .collect(),
+            None => HashSet::new(),
+        }
+    }
+
+    /// Adds a sync to the server's history, along with the space its
+    /// downloads now use, dropping the oldest entries.
+    pub async fn record_sync(&self, entry: SyncHistoryEntry) -> Result {
+        let root = self.inner.media_root().await;
+        let mut state = self.inner.state.write().await;
+        let server_state = match state.servers.get_mut(&self.id) {
+            Some(ss) => ss,
+            None => return Ok(()),
+        };
+
+        let used = local_size(server_state, &root).await;
+        server_state.sync_history.push(SyncRecord {
+            started: OffsetDateTime::from(entry.started),
+            duration: entry.duration.as_secs(),
+            bytes: entry.bytes,
+            errors: entry.errors.try_into().unwrap_or(u32::MAX),
+            run: entry.run,
+            used: Some(used),
+        });
+
+        let length = server_state.sync_history.len();
+        if length > SYNC_HISTORY_LENGTH {
+            server_state
+                .sync_history
+                .drain(0..length - SYNC_HISTORY_LENGTH);
+        }
+
+        self.inner.persist_state(&state).await
+    }
+
+    /// The server's recorded syncs, oldest first.
+    pub async fn sync_history(&self) -> Vec<SyncHistoryEntry> {
+        let state = self.inner.state.read().await;
+        let server_state = match state.servers.get(&self.id) {
+            Some(ss) => ss,
+            None => return Vec::new(),
+        };
+
+        server_state
+            .sync_history
+            .iter()
+            .map(SyncHistoryEntry::from)
+            .collect()
+    }
+
+    /// Aggregate statistics for this server, see
+    /// [`FlickSync::statistics`](crate::FlickSync::statistics).
+    pub fn statistics(&self) -> ServerStatistics {
+        self.inner
+            .statistics()
+            .servers
+            .get(&self.id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Summarises the server's content in the store without connecting to it.
+    pub async fn status(&self) -> ServerStatus {
+        let root = self.inner.media_root().await;
+        let state = self.inner.state.read().await;
+
+        let server_state = match state.servers.get(&self.id) {
+            Some(s) => s,
+            None => return ServerStatus::default(),
+        };
+
+        let mut status = ServerStatus {
+            name: server_state.name.clone(),
+            libraries: server_state.libraries.len(),
+            collections: server_state.collections.len(),
+            playlists: server_state.playlists.len(),
+            last_synced: server_state.last_synced.map(SystemTime::from),
+            auth_failed: server_state.auth_failed.map(SystemTime::from),
+            ..Default::default()
+        };
+
+        if let Some(statistics) = self.inner.statistics().servers.get(&self.id) {
+            status.downloaded = statistics.totals.downloaded;
+            status.pending = statistics.totals.pending;
+            status.skipped = statistics.totals.skipped;
+            status.pending_bytes = statistics.totals.pending_bytes;
+        }
+
+        // Sizes on disk are measured as partial downloads count towards them
+        // and reduce what is left to download.
+        let mut partial_bytes = 0;
+        for video in server_state.videos.values() {
+            for part in video.parts.iter() {
+                let local = file_size(&root, &part.download).await;
+                status.local_bytes += local;
+
+                if matches!(part.download, DownloadState::Transcoding { .. }) {
+                    status.transcode_sessions += 1;
+                }
+
+                if stats::is_pending(video, part) {
+                    partial_bytes += local.min(part.size);
+                }
+            }
+        }
+        status.pending_bytes = status.pending_bytes.saturating_sub(partial_bytes);
+
+        status
+    }
+
     pub async fn videos(&self) -> Vec<wrappers::Video> {
         let state = self.inner.state.read().await;
         state
@@ -238,13 +1124,53 @@ impl Server {
             .collect()
     }
 
-    pub async fn libraries(&self) -> Vec<wrappers::Library> {
+    /// The synced videos that are, or are contained within, the item with the
+    /// given rating key.
+    pub async fn videos_for(&self, rating_key: &str) -> Vec<wrappers::Video> {
+        let videos = self.videos().await;
+
         let state = self.inner.state.read().await;
-        state
-            .servers
-            .get(&self.id)
-            .unwrap()
-            .libraries
+        let server_state = state.servers.get(&self.id).unwrap();
+
+        let mut keys: HashSet<&str> = HashSet::from([rating_key]);
+        if let Some(collection) = server_state.collections.get(rating_key) {
+            keys.extend(collection.contents.iter().map(String::as_str));
+        }
+        if let Some(playlist) = server_state.playlists.get(rating_key) {
+            keys.extend(playlist.videos.iter().map(String::as_str));
+        }
+
+        videos
+            .into_iter()
+            .filter(|video| {
+                if keys.contains(video.id()) {
+                    return true;
+                }
+
+                if let Some(VideoDetail::Episode(ref episode)) =
+                    server_state.videos.get(video.id()).map(|vs| &vs.detail)
+                {
+                    if keys.contains(episode.season.as_str()) {
+                        return true;
+                    }
+
+                    if let Some(season) = server_state.seasons.get(&episode.season) {
+                        return keys.contains(season.show.as_str());
+                    }
+                }
+
+                false
+            })
+            .collect()
+    }
+
+    pub async fn libraries(&self) -> Vec<wrappers::Library> {
+        let state = self.inner.state.read().await;
+        state
+            .servers
+            .get(&self.id)
+            .unwrap()
+            .libraries
             .iter()
             .map(|(id, ls)| match ls.library_type {
                 LibraryType::Movie => wrappers::Library::Movie(wrappers::MovieLibrary {
@@ -277,13 +1203,253 @@ impl Server {
             .collect()
     }
 
-    /// Connects to the Plex API for this server.
+    /// Connects to the Plex API for this server, failing with
+    /// [`Error::ServerUnreachable`] if the connection takes longer than the
+    /// configured timeout. The outcome is recorded in the state so this must
+    /// not be called while holding the state lock.
     #[instrument(level = "trace", skip(self), fields(server = self.id))]
     pub async fn connect(&self) -> Result<plex_api::Server> {
+        let seconds = {
+            let config = self.inner.config.read().await;
+            config.connect_timeout.unwrap_or(30)
+        };
+
+        let result = match timeout(Duration::from_secs(seconds), self.open_connection()).await {
+            Ok(result) => result,
+            Err(_) => {
+                warn!(seconds, "Timed out connecting to the server");
+                Err(Error::ServerUnreachable(self.id.clone()))
+            }
+        };
+
+        match result {
+            Ok((server, preferred)) => {
+                self.record_connection(false, preferred).await;
+                Ok(server)
+            }
+            Err(e) => {
+                if e.class() == ErrorClass::Auth {
+                    self.record_connection(true, None).await;
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Notes in the state whether the server rejected the stored token and
+    /// the connection to prefer next time. This waits for the state lock so
+    /// must not be called while it is held.
+    async fn record_connection(&self, failed: bool, preferred: Option<PreferredConnection>) {
+        let mut state = self.inner.state.write().await;
+        let server_state = match state.servers.get_mut(&self.id) {
+            Some(s) => s,
+            None => return,
+        };
+
+        if server_state.auth_failed.is_some() == failed && preferred.is_none() {
+            return;
+        }
+
+        if server_state.auth_failed.is_some() != failed {
+            server_state.auth_failed = if failed {
+                Some(OffsetDateTime::now_utc())
+            } else {
+                None
+            };
+        }
+        if preferred.is_some() {
+            server_state.preferred_connection = preferred;
+        }
+
+        if let Err(e) = self.inner.persist_state(&state).await {
+            warn!(error=?e, "Failed to record the connection state");
+        }
+    }
+
+    /// Checks that the stored token is still accepted, reporting the account
+    /// that owns it and the servers it can reach. Nothing is changed, rejected
+    /// tokens are reported rather than returned as errors.
+    pub async fn check_auth(&self) -> Result<AuthCheck> {
+        let connection = self.connection().await;
+        let token = {
+            let state = self.inner.state.read().await;
+            state
+                .servers
+                .get(&self.id)
+                .map(|s| s.token.clone())
+                .unwrap_or_default()
+        };
+
+        let result = match connection {
+            ServerConnection::MyPlex {
+                username,
+                user_id,
+                device_id,
+            } => self.check_myplex(token, username, user_id, device_id).await,
+            // The server reports its owner itself so plex.tv is not needed.
+            ServerConnection::Direct { url } => {
+                let client = self.inner.client().await.set_x_plex_token(token);
+                plex_api::Server::new(url, client)
+                    .await
+                    .map(|server| {
+                        let container = &server.media_container;
+                        AuthCheck {
+                            valid: true,
+                            error: None,
+                            owner: container.my_plex_username.clone(),
+                            plex_pass: container.my_plex.then_some(container.my_plex_subscription),
+                            servers: vec![container.friendly_name.clone()],
+                        }
+                    })
+                    .map_err(Error::from)
+            }
+        };
+
+        match result {
+            Ok(check) => Ok(check),
+            Err(e) if e.class() == ErrorClass::Auth => Ok(AuthCheck {
+                error: Some(e.to_string()),
+                ..Default::default()
+            }),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Detects what the server supports from its own description, without
+    /// contacting plex.tv. The capabilities seen when last connecting are
+    /// reused until they are older than the configured TTL or when `refresh`
+    /// is passed.
+    pub async fn features(&self, refresh: bool) -> Result<ServerFeatures> {
+        let plex_account = matches!(self.connection().await, ServerConnection::MyPlex { .. });
+
+        let cached = {
+            let config = self.inner.config.read().await;
+            let state = self.inner.state.read().await;
+            let ttl = time::Duration::seconds(config.item_cache_ttl.unwrap_or(86400) as i64);
+
+            state
+                .servers
+                .get(&self.id)
+                .and_then(|ss| ss.capabilities.clone())
+                .filter(|c| !refresh && OffsetDateTime::now_utc() - c.fetched < ttl)
+        };
+
+        let capabilities = match cached {
+            Some(capabilities) => capabilities,
+            None => {
+                let server = self.connect().await?;
+                let capabilities = capabilities(&server);
+
+                let mut state = self.inner.state.write().await;
+                if let Some(server_state) = state.servers.get_mut(&self.id) {
+                    server_state.capabilities = Some(capabilities.clone());
+                    self.inner.persist_state(&state).await?;
+                }
+
+                capabilities
+            }
+        };
+
+        Ok(ServerFeatures {
+            plex_account,
+            claimed: capabilities.claimed,
+            plex_pass: capabilities.plex_pass,
+            downloads: capabilities.downloads,
+            transcoding: capabilities.transcoding,
+        })
+    }
+
+    async fn check_myplex(
+        &self,
+        token: String,
+        username: String,
+        user_id: String,
+        device_id: String,
+    ) -> Result<AuthCheck> {
+        let client = self.inner.client().await;
+        let myplex = MyPlexBuilder::default()
+            .set_client(client)
+            .set_token(token)
+            .build()
+            .await?;
+
+        let plex_pass = myplex.account().map(|account| account.subscription.active);
+
+        let home = myplex.home()?;
+        let myplex = home.switch_user(myplex, user_id, None).await?;
+
+        let devices: Vec<_> = myplex
+            .device_manager()?
+            .resources()
+            .await?
+            .into_iter()
+            .filter(|d| d.is_server())
+            .collect();
+
+        let valid = devices.iter().any(|d| d.identifier() == device_id);
+
+        Ok(AuthCheck {
+            valid,
+            error: if valid {
+                None
+            } else {
+                Some(Error::MyPlexServerNotFound.to_string())
+            },
+            owner: Some(username),
+            plex_pass,
+            servers: devices.iter().map(|d| d.name().to_owned()).collect(),
+        })
+    }
+
+    /// Connects to a remembered connection, giving up quickly since plex.tv
+    /// can still find the server if the address no longer works.
+    async fn try_preferred(
+        &self,
+        client: HttpClient,
+        preferred: &PreferredConnection,
+        device_id: &str,
+    ) -> Option<(plex_api::Server, PreferredConnection)> {
+        let started = Instant::now();
+        let client = client.set_x_plex_token(preferred.token.clone());
+
+        match timeout(
+            PREFERRED_TIMEOUT,
+            plex_api::Server::new(preferred.url.clone(), client),
+        )
+        .await
+        {
+            Ok(Ok(server)) if server.machine_identifier() == device_id => {
+                let preferred = PreferredConnection {
+                    latency: started.elapsed().as_millis() as u64,
+                    ..preferred.clone()
+                };
+                Some((server, preferred))
+            }
+            Ok(Ok(_)) => {
+                debug!(
+                    url = preferred.url,
+                    "Preferred connection reached a different server"
+                );
+                None
+            }
+            Ok(Err(e)) => {
+                debug!(url = preferred.url, error=?e, "Preferred connection failed");
+                None
+            }
+            Err(_) => {
+                debug!(url = preferred.url, "Preferred connection timed out");
+                None
+            }
+        }
+    }
+
+    /// Opens a connection to the server, also returning the connection to
+    /// remember when it was found through a Plex account.
+    async fn open_connection(&self) -> Result<(plex_api::Server, Option<PreferredConnection>)> {
         let mut connection = self.connection.lock().await;
 
         if let Some(api) = connection.deref() {
-            return Ok(api.clone());
+            return Ok((api.clone(), None));
         }
 
         let config = self.inner.config.read().await;
@@ -299,15 +1465,35 @@ impl Server {
                 user_id,
                 device_id,
             } => {
-                let token = state
+                let server_state = state
                     .servers
                     .get(&self.id)
-                    .ok_or_else(|| Error::ServerNotAuthenticated)?
-                    .token
-                    .clone();
+                    .ok_or_else(|| Error::ServerNotAuthenticated)?;
+                let token = server_state.token.clone();
+
+                let allow_relay = server_config.allow_relay.unwrap_or(true);
+                let preferred = server_state
+                    .preferred_connection
+                    .clone()
+                    .filter(|p| allow_relay || p.kind != ConnectionKind::Relay);
+
+                // Local connections skip plex.tv entirely so work offline and
+                // avoid it choosing a slower route.
+                if let Some(ref preferred) = preferred {
+                    if preferred.kind == ConnectionKind::Local {
+                        if let Some((server, preferred)) = self
+                            .try_preferred(client.clone(), preferred, device_id)
+                            .await
+                        {
+                            trace!(url = preferred.url, "Connected to server");
+                            *connection = Some(server.clone());
+                            return Ok((server, Some(preferred)));
+                        }
+                    }
+                }
 
                 let myplex = MyPlexBuilder::default()
-                    .set_client(client)
+                    .set_client(client.clone())
                     .set_token(token)
                     .set_test_token_auth(false)
                     .build()
@@ -327,16 +1513,48 @@ impl Server {
                     None => return Err(Error::MyPlexServerNotFound),
                 };
 
-                match device.connect().await? {
-                    DeviceConnection::Server(server) => {
-                        trace!(url=%server.client().api_url,
-                            "Connected to server"
-                        );
-                        *connection = Some(server.as_ref().clone());
-                        Ok(*server)
-                    }
+                let started = Instant::now();
+                let server = match device.connect().await? {
+                    DeviceConnection::Server(server) => *server,
                     _ => panic!("Unexpected client connection"),
+                };
+
+                let url = server.client().api_url.to_string();
+                let found = PreferredConnection {
+                    kind: ConnectionKind::of(&url),
+                    url,
+                    token: server.client().x_plex_token().to_owned(),
+                    latency: started.elapsed().as_millis() as u64,
+                };
+
+                // plex.tv may only have found a worse route than the one that
+                // worked before, which may well still work.
+                let (server, found) = match preferred {
+                    Some(ref preferred)
+                        if preferred.kind != ConnectionKind::Local
+                            && preferred.kind < found.kind =>
+                    {
+                        self.try_preferred(client, preferred, device_id)
+                            .await
+                            .unwrap_or((server, found))
+                    }
+                    _ => (server, found),
+                };
+
+                if found.kind == ConnectionKind::Relay {
+                    if !allow_relay {
+                        return Err(Error::RelayForbidden(self.id.clone()));
+                    }
+                    warn!("Connected through a plex.tv relay, transfers will be slow");
                 }
+
+                trace!(
+                    url = found.url,
+                    latency = found.latency,
+                    "Connected to server"
+                );
+                *connection = Some(server.clone());
+                Ok((server, Some(found)))
             }
             ServerConnection::Direct { url } => {
                 let token = state
@@ -352,7 +1570,7 @@ impl Server {
                 );
                 *connection = Some(server.clone());
 
-                Ok(server)
+                Ok((server, None))
             }
         }
     }
@@ -363,6 +1581,8 @@ impl Server {
         rating_key: &str,
         transcode_profile: Option<String>,
         only_unplayed: bool,
+        max_episodes: Option<u32>,
+        latest_season: bool,
     ) -> Result {
         let mut config = self.inner.config.write().await;
 
@@ -373,18 +1593,133 @@ impl Server {
         }
 
         let server_config = config.servers.get_mut(&self.id).unwrap();
+        // A priority set in the config survives re-adding the item.
+        let priority = server_config
+            .syncs
+            .get(rating_key)
+            .and_then(|sync| sync.priority);
         server_config.syncs.insert(
             rating_key.to_owned(),
             SyncItem {
                 id: rating_key.to_owned(),
                 transcode_profile,
                 only_unplayed,
+                max_episodes,
+                latest_season,
+                added: Some(OffsetDateTime::now_utc()),
+                priority,
             },
         );
 
         self.inner.persist_config(&config).await
     }
 
+    /// Adds a query that is evaluated on every sync, syncing whatever movies
+    /// and shows currently match it.
+    pub async fn add_query(
+        &self,
+        query: &str,
+        transcode_profile: Option<String>,
+        only_unplayed: bool,
+        max_episodes: Option<u32>,
+        latest_season: bool,
+    ) -> Result {
+        Filter::from_str(query)?;
+
+        let mut config = self.inner.config.write().await;
+
+        if let Some(ref profile) = transcode_profile {
+            if !config.profiles.contains_key(profile) && !DEFAULT_PROFILES.contains_key(profile) {
+                return Err(Error::UnknownProfile(profile.to_owned()));
+            }
+        }
+
+        let server_config = config.servers.get_mut(&self.id).unwrap();
+        server_config.queries.retain(|q| q.query != query);
+        server_config.queries.push(SyncQuery {
+            query: query.to_owned(),
+            transcode_profile,
+            only_unplayed,
+            max_episodes,
+            latest_season,
+        });
+
+        self.inner.persist_config(&config).await
+    }
+
+    /// Adds every movie or show in a library that matches the filter to the
+    /// sync list. The library may be given by id or title. Returns the titles
+    /// of the items added.
+    pub async fn add_filtered_syncs(
+        &self,
+        library: &str,
+        filter: &Filter,
+        transcode_profile: Option<String>,
+        only_unplayed: bool,
+        max_episodes: Option<u32>,
+        latest_season: bool,
+    ) -> Result<Vec<String>> {
+        let server = self.connect().await?;
+
+        let library = server
+            .libraries()
+            .into_iter()
+            .find(|l| l.id() == library || l.title() == library)
+            .ok_or_else(|| Error::UnknownLibrary(library.to_owned()))?;
+
+        let mut matched: Vec<(String, String)> = Vec::new();
+
+        match &library {
+            PlexLibrary::Movie(lib) => {
+                for movie in lib.movies().await? {
+                    let height = movie.media().first().and_then(|m| m.metadata().height);
+                    if filter.matches(&library, movie.metadata(), height) {
+                        matched.push((movie.rating_key().to_owned(), movie.title().to_owned()));
+                    }
+                }
+            }
+            PlexLibrary::TV(lib) => {
+                for show in lib.shows().await? {
+                    if filter.matches(&library, show.metadata(), None) {
+                        matched.push((show.rating_key().to_owned(), show.title().to_owned()));
+                    }
+                }
+            }
+            _ => return Err(Error::ItemNotSupported(library.title().to_owned())),
+        }
+
+        let mut titles = Vec::new();
+        for (rating_key, title) in matched {
+            self.add_sync(
+                &rating_key,
+                transcode_profile.clone(),
+                only_unplayed,
+                max_episodes,
+                latest_season,
+            )
+            .await?;
+            titles.push(title);
+        }
+
+        Ok(titles)
+    }
+
+    /// Removes a query. Returns true if the query existed.
+    pub async fn remove_query(&self, query: &str) -> Result<bool> {
+        let mut config = self.inner.config.write().await;
+
+        let server_config = config.servers.get_mut(&self.id).unwrap();
+        let count = server_config.queries.len();
+        server_config.queries.retain(|q| q.query != query);
+        if server_config.queries.len() == count {
+            return Ok(false);
+        }
+
+        self.inner.persist_config(&config).await?;
+
+        Ok(true)
+    }
+
     /// Removes an item to sync based on its rating key. Returns true if the item existed.
     pub async fn remove_sync(&self, rating_key: &str) -> Result<bool> {
         let mut config = self.inner.config.write().await;
@@ -398,49 +1733,255 @@ impl Server {
         Ok(contained)
     }
 
+    /// Marks an item, either a sync item or anything contained within one, as
+    /// skipped so it is no longer downloaded, or clears the mark. Returns true
+    /// if this changed anything.
+    pub async fn set_skipped(&self, rating_key: &str, skipped: bool) -> Result<bool> {
+        let mut config = self.inner.config.write().await;
+
+        let server_config = config.servers.get_mut(&self.id).unwrap();
+        let changed = if skipped {
+            server_config.skipped.insert(rating_key.to_owned())
+        } else {
+            server_config.skipped.remove(rating_key)
+        };
+
+        if changed {
+            self.inner.persist_config(&config).await?;
+        }
+
+        Ok(changed)
+    }
+
+    /// Describes the sync items that caused a video to be downloaded, or
+    /// `None` if the video is not synced.
+    pub async fn video_sources(&self, rating_key: &str) -> Option<Vec<String>> {
+        let state = self.inner.state.read().await;
+        let server_state = state.servers.get(&self.id)?;
+        let video = server_state.videos.get(rating_key)?;
+
+        Some(
+            video
+                .sources
+                .iter()
+                .map(|source| describe_source(server_state, source))
+                .collect(),
+        )
+    }
+
+    /// Marks an item, and any videos within it, to never be evicted. Returns
+    /// true if this changed the item.
+    pub async fn set_pinned(&self, rating_key: &str, pinned: bool) -> Result<bool> {
+        let mut config = self.inner.config.write().await;
+
+        let server_config = config.servers.get_mut(&self.id).unwrap();
+        let changed = if pinned {
+            server_config.pinned.insert(rating_key.to_owned())
+        } else {
+            server_config.pinned.remove(rating_key)
+        };
+
+        if changed {
+            self.inner.persist_config(&config).await?;
+        }
+
+        Ok(changed)
+    }
+
+    /// Lists the media versions the server has for a video.
+    pub async fn media_versions(&self, rating_key: &str) -> Result<Vec<MediaVersion>> {
+        let server = self.connect().await?;
+        let video = match server.item_by_id(rating_key).await? {
+            Item::Movie(movie) => Video::Movie(movie),
+            Item::Episode(episode) => Video::Episode(episode),
+            item => return Err(Error::ItemNotSupported(item.title().to_owned())),
+        };
+
+        let selected = {
+            let state = self.inner.state.read().await;
+            state
+                .servers
+                .get(&self.id)
+                .and_then(|ss| ss.videos.get(rating_key))
+                .map(|vs| vs.media_id.clone())
+        };
+
+        Ok(video
+            .media()
+            .iter()
+            .map(|media| {
+                let id = media.metadata().id.clone().unwrap_or_default();
+                MediaVersion {
+                    selected: selected.as_ref() == Some(&id),
+                    id,
+                    height: media.metadata().height,
+                    container: media.metadata().container.map(|c| c.to_string()),
+                    size: media
+                        .parts()
+                        .iter()
+                        .map(|p| p.metadata().size.unwrap_or_default())
+                        .sum(),
+                }
+            })
+            .collect())
+    }
+
+    /// Chooses the media version to sync for a video, or clears the choice so
+    /// the configured rules apply. The change takes effect on the next sync.
+    pub async fn set_media_version(&self, rating_key: &str, media_id: Option<&str>) -> Result {
+        let mut config = self.inner.config.write().await;
+
+        let server_config = config.servers.get_mut(&self.id).unwrap();
+        match media_id {
+            Some(media_id) => {
+                server_config
+                    .media_versions
+                    .insert(rating_key.to_owned(), media_id.to_owned());
+            }
+            None => {
+                server_config.media_versions.remove(rating_key);
+            }
+        }
+
+        self.inner.persist_config(&config).await
+    }
+
+    /// Sets an explicit path for a video, relative to the server's directory
+    /// and without an extension, or clears it so the naming templates apply.
+    /// Downloaded files are moved on the next sync.
+    pub async fn set_path(&self, rating_key: &str, path: Option<&str>) -> Result {
+        let mut config = self.inner.config.write().await;
+
+        let server_config = config.servers.get_mut(&self.id).unwrap();
+        match path {
+            Some(path) => {
+                if !is_relative_path(path) {
+                    return Err(Error::InvalidConfig(format!(
+                        "{path}: must be relative to the server's directory"
+                    )));
+                }
+
+                server_config
+                    .paths
+                    .insert(rating_key.to_owned(), path.to_owned());
+            }
+            None => {
+                server_config.paths.remove(rating_key);
+            }
+        }
+
+        self.inner.persist_config(&config).await
+    }
+
     /// Updates the state for the synced items
     pub async fn update_state(&self) -> Result {
         info!("Updating item metadata");
+        self.inner.check_media_root().await?;
         let server = self.connect().await?;
 
-        let config = self.inner.config.read().await;
-        let server_config = config.servers.get(&self.id).unwrap();
+        let resolver = self.inner.conflict_resolver.read().await.clone();
+        let events = self.inner.events().await;
+        let mut decisions = HashMap::new();
+
+        let removed_syncs = loop {
+            let (removed_syncs, pending) = {
+                let config = self.inner.config.read().await;
+                let server_config = config.servers.get(&self.id).unwrap();
+
+                let state = self.inner.state.write().await;
+
+                // A copy of the server's state is updated and put back into the
+                // state whenever it is written.
+                let mut server_state = state.servers.get(&self.id).cloned().unwrap_or_default();
+                server_state.name = server.media_container.friendly_name.clone();
+                server_state.machine_id = Some(server.machine_identifier().to_owned());
+                server_state.capabilities = Some(capabilities(&server));
+
+                let (result, mut state) = {
+                    // Scope the write lock on the path.
+                    let path = self.inner.path.write().await;
+                    let root = config.media_root.clone().unwrap_or_else(|| path.clone());
+                    let storage = config
+                        .media_storage
+                        .clone()
+                        .unwrap_or_default()
+                        .backend(&root);
+
+                    let mut state_sync = StateSync {
+                        server_id: &self.id,
+                        config: &config,
+                        server_config,
+                        server_state: &mut server_state,
+                        server: server.clone(),
+                        root: &root,
+                        storage: &*storage,
+                        dry_run: false,
+                        conflicts: Conflicts::new(
+                            &self.id,
+                            resolver.is_some(),
+                            &config.conflict_resolutions,
+                            &decisions,
+                        ),
+                        events: events.clone(),
+                        checkpoint: Some(Checkpoint {
+                            inner: &self.inner,
+                            state,
+                            written: Instant::now(),
+                        }),
+                        seen_items: Default::default(),
+                        seen_libraries: Default::default(),
+                        transcode_profiles: Default::default(),
+                        unskipped: Default::default(),
+                        sources: Default::default(),
+                        removed_syncs: Default::default(),
+                        incomplete: false,
+                    };
 
-        {
-            let mut state = self.inner.state.write().await;
+                    let result = state_sync.sync_items().await;
 
-            let server_state = state.servers.entry(self.id.clone()).or_default();
-            server_state.name = server.media_container.friendly_name.clone();
+                    if result.is_ok() && !state_sync.incomplete {
+                        state_sync.server_state.last_synced = Some(OffsetDateTime::now_utc());
+                    }
 
-            {
-                // Scope the write lock on the path.
-                let root = self.inner.path.write().await;
-
-                let mut state_sync = StateSync {
-                    config: &config,
-                    server_config,
-                    server_state,
-                    server: server.clone(),
-                    root: &root,
-                    seen_items: Default::default(),
-                    seen_libraries: Default::default(),
-                    transcode_profiles: Default::default(),
+                    (
+                        result.map(|_| (state_sync.removed_syncs, state_sync.conflicts.pending())),
+                        state_sync.checkpoint.unwrap().state,
+                    )
                 };
 
-                for item in server_config.syncs.values() {
-                    if let Err(e) = state_sync.add_item_by_key(item, &item.id).await {
-                        warn!(item=item.id, error=?e, "Failed to update item. Aborting update.");
-                    }
-                }
+                // Whatever was updated before a failure is still written.
+                state.servers.insert(self.id.clone(), server_state);
+                self.inner.persist_state(&state).await?;
 
-                state_sync.update_profiles().await?;
+                result?
+            };
 
-                state_sync.prune_unseen().await?;
+            // The resolver may wait for the user so it is only asked once the
+            // store is unlocked, then the state is updated again with the
+            // choices made.
+            let resolver = match resolver {
+                Some(ref resolver) if !pending.is_empty() => resolver,
+                _ => break removed_syncs,
+            };
 
-                state_sync.fetch_collections().await?;
+            let mut resolutions = self.inner.config.read().await.conflict_resolutions.clone();
+            if conflict::ask(&**resolver, pending, &mut decisions, &mut resolutions) {
+                let mut config = self.inner.config.write().await;
+                config.conflict_resolutions = resolutions;
+                self.inner.persist_config(&config).await?;
             }
+        };
 
-            self.inner.persist_state(&state).await?;
+        if !removed_syncs.is_empty() {
+            let mut config = self.inner.config.write().await;
+
+            let server_config = config.servers.get_mut(&self.id).unwrap();
+            for key in removed_syncs {
+                info!(item = key, "Removing missing item from the sync list");
+                server_config.syncs.remove(&key);
+            }
+
+            self.inner.persist_config(&config).await?;
         }
 
         self.update_thumbnails().await?;
@@ -452,122 +1993,568 @@ impl Server {
     pub async fn update_thumbnails(&self) -> Result {
         info!("Updating thumbnails");
 
+        let total = {
+            let state = self.inner.state.read().await;
+            let ss = state.servers.get(&self.id).unwrap();
+            ss.collections.len() + ss.videos.len() + ss.shows.len() + ss.seasons.len()
+        };
+
+        let events = self.inner.events().await;
+        let mut completed = 0;
+        let mut progress = |result: Result| {
+            if let Err(e) = result {
+                warn!(error=?e);
+            }
+
+            completed += 1;
+            events.emit(Event::ArtworkProgress {
+                server: self.id.clone(),
+                completed,
+                total,
+            });
+        };
+
+        events.emit(Event::ArtworkProgress {
+            server: self.id.clone(),
+            completed: 0,
+            total,
+        });
+
         for library in self.libraries().await {
             for collection in library.collections().await {
-                if let Err(e) = collection.update_thumbnail().await {
-                    warn!(error=?e);
-                }
+                progress(collection.update_thumbnail().await);
             }
 
             match library {
                 Library::Movie(l) => {
                     for video in l.movies().await {
-                        if let Err(e) = video.update_thumbnail().await {
-                            warn!(error=?e);
-                        }
+                        progress(video.update_thumbnail().await);
                     }
                 }
                 Library::Show(l) => {
                     for show in l.shows().await {
-                        if let Err(e) = show.update_thumbnail().await {
-                            warn!(error=?e);
+                        progress(show.update_thumbnail().await);
+
+                        for season in show.seasons().await {
+                            progress(season.update_thumbnail().await);
+
+                            for video in season.episodes().await {
+                                progress(video.update_thumbnail().await);
+                            }
                         }
+                    }
+                }
+            }
+        }
+
+        // Items missing from a library are not visited so make sure consumers
+        // see the update finish.
+        if completed < total {
+            events.emit(Event::ArtworkProgress {
+                server: self.id.clone(),
+                completed: total,
+                total,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Verifies the presence of downloads for synced items.
+    #[instrument(level = "trace", skip(self), fields(server = self.id))]
+    pub async fn verify_downloads(&self) -> Result {
+        info!("Verifying downloads");
+
+        let mut parts = Vec::new();
+        for video in self.videos().await {
+            parts.extend(video.parts().await);
+        }
+
+        // Parts that are downloading are left alone by verification so this
+        // is safe to run alongside other work on the store.
+        futures::StreamExt::for_each_concurrent(
+            futures::stream::iter(parts.iter()),
+            VERIFY_CONCURRENCY,
+            |part| async move {
+                if let Err(e) = part.verify_download().await {
+                    warn!(error=?e);
+                }
+            },
+        )
+        .await;
+
+        // Relocating creates and removes shared directories so stays serial.
+        for part in parts {
+            if let Err(e) = part.relocate_download().await {
+                warn!(error=?e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Deletes any files in the server's directory that are no longer needed.
+    /// The store's path is held exclusively throughout so no downloads can
+    /// write to it while directories are pruned concurrently.
+    #[instrument(level = "trace", skip(self), fields(server = self.id))]
+    pub async fn prune(&self) -> Result {
+        info!("Pruning server filesystem");
+
+        self.inner.check_media_root().await?;
+
+        let events = self.inner.events().await;
+        let exporters = self.inner.metadata_exporters().await;
+        let root = self.inner.media_root().await;
+        let storage = self.inner.storage().await;
+        let _path = self.inner.path.write().await;
+
+        let state = self.inner.state.read().await;
+
+        let server_state = match state.servers.get(&self.id) {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+
+        let expected_files = expected_files(server_state, &root, &exporters);
+
+        let server_root = root.join(safe(&self.id));
+        let artwork_root = self.artwork_root(&root).await;
+
+        // Files are deleted one at a time, even when nothing is expected, so
+        // that the media storage removes its copies too.
+        prune_directory(&server_root, &expected_files, &events, &*storage, None).await;
+        if let Some(artwork_root) = artwork_root {
+            prune_directory(&artwork_root, &expected_files, &events, &*storage, None).await;
+        }
+
+        Ok(())
+    }
+
+    /// Uploads completed downloads that earlier syncs failed to upload to the
+    /// configured media storage. Every part is attempted, the last failure is
+    /// returned.
+    #[instrument(level = "trace", skip(self), fields(server = self.id))]
+    pub async fn publish_downloads(&self) -> Result {
+        let mut result = Ok(());
+
+        for video in self.videos().await {
+            for part in video.parts().await {
+                if let Err(e) = part.publish_download().await {
+                    error!(video = video.id(), error=?e, "Failed to upload download");
+                    result = Err(e);
+                }
+            }
+        }
+
+        result
+    }
+
+    /// The directory holding this server's artwork when it is kept apart from
+    /// the media files, if it exists.
+    async fn artwork_root(&self, root: &Path) -> Option<PathBuf> {
+        let artwork_root = self.inner.artwork_path(PathBuf::from(safe(&self.id))).await;
+        if artwork_root == Path::new(&safe(&self.id)) {
+            return None;
+        }
+
+        let artwork_root = root.join(artwork_root);
+        match metadata(&artwork_root).await {
+            Ok(stats) if stats.is_dir() => Some(artwork_root),
+            _ => None,
+        }
+    }
+
+    /// Checks that downloading the outstanding parts will not exceed the
+    /// configured size limits. Where it would, downloaded videos are evicted
+    /// according to the eviction policy and any videos that still do not fit
+    /// are reported as deferred and should not be downloaded.
+    #[instrument(level = "trace", skip(self), fields(server = self.id))]
+    pub async fn enforce_size_limit(&self) -> Result<SizeLimitReport> {
+        let mut report = SizeLimitReport::default();
+
+        let (server_limit, store_limit, policy, pinned) = {
+            let config = self.inner.config.read().await;
+            let server_config = config.servers.get(&self.id).unwrap();
+
+            (
+                server_config.max_size,
+                config.max_size,
+                server_config
+                    .eviction_policy
+                    .or(config.eviction_policy)
+                    .unwrap_or_default(),
+                server_config.pinned.clone(),
+            )
+        };
+
+        let root = self.inner.media_root().await;
+        let state = self.inner.state.read().await.clone();
+
+        let server_state = match state.servers.get(&self.id) {
+            Some(s) => s,
+            None => return Ok(report),
+        };
+
+        if server_limit.is_none() && store_limit.is_none() {
+            // Without limits anything evicted before can be downloaded again.
+            let restored: Vec<String> = server_state
+                .videos
+                .values()
+                .filter(|video| video.evicted)
+                .map(|video| video.id.clone())
+                .collect();
+            self.update_evicted(&root, Vec::new(), restored).await?;
+
+            return Ok(report);
+        }
+
+        let limit = match store_limit {
+            Some(store_limit) => {
+                let mut other_size = 0;
+                for (id, other) in state.servers.iter() {
+                    if id != &self.id {
+                        other_size += local_size(other, &root).await;
+                    }
+                }
+
+                let available = store_limit.saturating_sub(other_size);
+                server_limit.map_or(available, |l| l.min(available))
+            }
+            None => server_limit.unwrap(),
+        };
+
+        let mut used = 0;
+        let mut pending: Vec<(&VideoState, u64)> = Vec::new();
+        let mut downloaded: Vec<(&VideoState, u64)> = Vec::new();
+
+        for video in server_state.videos.values() {
+            let mut size = 0;
+            let mut needed = 0;
+
+            for part in video.parts.iter() {
+                let local = file_size(&root, &part.download).await;
+                size += local;
+
+                if part.download.needs_download() {
+                    needed += part.size.saturating_sub(local);
+                }
+            }
+
+            used += size;
+
+            if video.skipped {
+                continue;
+            }
+
+            if video.parts.iter().any(|p| p.download.needs_download()) {
+                pending.push((video, needed));
+            } else {
+                downloaded.push((video, size));
+            }
+        }
+
+        let priorities = eviction::priorities(policy, server_state, &pinned);
+        let priority = |video: &VideoState| priorities.get(&video.id).unwrap();
+        let can_evict = |candidate: &VideoState, video: &VideoState| {
+            priority(candidate).can_evict_for(priority(video))
+        };
+
+        pending.sort_by(|(a, _), (b, _)| priority(b).compare(priority(a)));
+        downloaded.sort_by(|(a, _), (b, _)| priority(a).compare(priority(b)));
+
+        let mut evicted = Vec::new();
+        let mut restored = Vec::new();
+
+        for (video, needed) in pending {
+            // Evicted videos only return once there is space for them without
+            // evicting anything else.
+            if video.evicted {
+                if used + needed > limit {
+                    report.deferred.push(LimitedVideo {
+                        video: video.id.clone(),
+                        title: video.title.clone(),
+                        size: needed,
+                        reason: "previously evicted, waiting for free space".to_string(),
+                    });
+                } else {
+                    restored.push(video.id.clone());
+                    used += needed;
+                }
+                continue;
+            }
+
+            if used + needed > limit {
+                let evictable: u64 = downloaded
+                    .iter()
+                    .filter(|(candidate, _)| can_evict(candidate, video))
+                    .map(|(_, size)| size)
+                    .sum();
+
+                if used.saturating_sub(evictable) + needed > limit {
+                    report.deferred.push(LimitedVideo {
+                        video: video.id.clone(),
+                        title: video.title.clone(),
+                        size: needed,
+                        reason: format!(
+                            "not enough space could be freed, {}",
+                            priority(video).reason
+                        ),
+                    });
+                    continue;
+                }
 
-                        for season in show.seasons().await {
-                            for video in season.episodes().await {
-                                if let Err(e) = video.update_thumbnail().await {
-                                    warn!(error=?e);
-                                }
-                            }
-                        }
-                    }
+                while used + needed > limit {
+                    let index = downloaded
+                        .iter()
+                        .position(|(candidate, _)| can_evict(candidate, video))
+                        .unwrap();
+                    let (candidate, size) = downloaded.remove(index);
+
+                    used -= size;
+                    evicted.push(candidate.id.clone());
+                    report.evicted.push(LimitedVideo {
+                        video: candidate.id.clone(),
+                        title: candidate.title.clone(),
+                        size,
+                        reason: format!(
+                            "{}, making space for '{}' ({})",
+                            priority(candidate).reason,
+                            video.title,
+                            priority(video).reason
+                        ),
+                    });
                 }
             }
+
+            used += needed;
         }
 
-        Ok(())
+        self.update_evicted(&root, evicted, restored).await?;
+
+        Ok(report)
     }
 
-    /// Verifies the presence of downloads for synced items.
-    #[instrument(level = "trace", skip(self), fields(server = self.id))]
-    pub async fn verify_downloads(&self) -> Result {
-        info!("Verifying downloads");
+    /// Deletes the downloads of videos evicted to free space, marking them so
+    /// they are not downloaded again straight away, and clears the mark from
+    /// videos that there is now space for.
+    async fn update_evicted(
+        &self,
+        root: &Path,
+        evicted: Vec<String>,
+        restored: Vec<String>,
+    ) -> Result {
+        if evicted.is_empty() && restored.is_empty() {
+            return Ok(());
+        }
 
-        for video in self.videos().await {
-            for part in video.parts().await {
-                if let Err(e) = part.verify_download().await {
-                    warn!(error=?e);
+        let server = if evicted.is_empty() {
+            None
+        } else {
+            Some(self.connect().await?)
+        };
+        let events = self.inner.events().await;
+        let storage = self.inner.storage().await;
+        let mut state = self.inner.state.write().await;
+
+        if let Some(server_state) = state.servers.get_mut(&self.id) {
+            for id in restored {
+                if let Some(video) = server_state.videos.get_mut(&id) {
+                    debug!(video = video.id, "Space is available for an evicted video");
+                    video.evicted = false;
+                }
+            }
+
+            if let Some(server) = server {
+                for id in evicted {
+                    if let Some(video) = server_state.videos.get_mut(&id) {
+                        info!(video = video.id, "Evicting download to free space");
+
+                        for part in video.parts.iter_mut() {
+                            part.download.delete(Some(&server), root, &*storage).await;
+                        }
+                        video.evicted = true;
+
+                        events.emit(Event::VideoEvicted {
+                            server: self.id.clone(),
+                            video: video.id.clone(),
+                            title: video.title.clone(),
+                        });
+                    }
                 }
             }
         }
 
-        Ok(())
+        self.inner.persist_state(&state).await
     }
 
-    /// Verifies the presence of downloads for synced items.
+    /// Computes what updating the state, pruning and downloading would do
+    /// without modifying local files or the state on the server.
     #[instrument(level = "trace", skip(self), fields(server = self.id))]
-    pub async fn prune(&self) -> Result {
-        info!("Pruning server filesystem");
+    pub async fn plan(&self) -> Result<SyncPlan> {
+        let server = self.connect().await?;
 
-        let root = self.inner.path.write().await;
+        let current = {
+            let state = self.inner.state.read().await;
+            state.servers.get(&self.id).cloned().unwrap_or_default()
+        };
+        let mut planned = current.clone();
 
-        let mut expected_files: HashSet<PathBuf> = HashSet::new();
+        let root = self.inner.media_root().await;
+        let storage = self.inner.storage().await;
 
-        let state = self.inner.state.read().await;
+        {
+            let config = self.inner.config.read().await;
+            let server_config = config.servers.get(&self.id).unwrap();
+
+            let mut state_sync = StateSync {
+                server_id: &self.id,
+                config: &config,
+                server_config,
+                server_state: &mut planned,
+                server,
+                root: &root,
+                storage: &*storage,
+                dry_run: true,
+                conflicts: Conflicts::new(
+                    &self.id,
+                    false,
+                    &config.conflict_resolutions,
+                    &HashMap::new(),
+                ),
+                events: Default::default(),
+                checkpoint: None,
+                seen_items: Default::default(),
+                seen_libraries: Default::default(),
+                transcode_profiles: Default::default(),
+                unskipped: Default::default(),
+                sources: Default::default(),
+                removed_syncs: Default::default(),
+                incomplete: false,
+            };
 
-        let server_state = match state.servers.get(&self.id) {
-            Some(s) => s,
-            None => return Ok(()),
+            state_sync.sync_items().await?;
+        }
+
+        let mut plan = SyncPlan::default();
+        let server_config = {
+            let config = self.inner.config.read().await;
+            config.servers.get(&self.id).unwrap().clone()
         };
 
-        for collection in server_state.collections.values() {
-            if let Some(file) = collection.thumbnail.file() {
-                expected_files.insert(root.join(file));
+        for (id, video) in current.videos.iter() {
+            if planned.videos.contains_key(id) {
+                continue;
             }
-        }
 
-        for show in server_state.shows.values() {
-            if let Some(file) = show.thumbnail.file() {
-                expected_files.insert(root.join(file));
+            let mut size = 0;
+            for file in video.parts.iter().filter_map(|p| p.download.file()) {
+                if let Ok(stats) = metadata(root.join(file)).await {
+                    size += stats.len();
+                }
             }
+
+            plan.removals.push(PlannedRemoval {
+                video: id.clone(),
+                title: video.title.clone(),
+                size,
+                reason: removal_reason(&server_config, &current, video),
+            });
         }
 
-        for video in server_state.videos.values() {
-            if let Some(file) = video.thumbnail.file() {
-                expected_files.insert(root.join(file));
-            }
+        let server_profile = self.transcode_profile().await;
+        for (id, video) in planned.videos.iter().filter(|(_, v)| !v.skipped) {
+            for (index, part) in video.parts.iter().enumerate() {
+                if part.quarantined {
+                    continue;
+                }
 
-            for part in video.parts.iter() {
-                if let Some(file) = part.download.file() {
-                    expected_files.insert(root.join(file));
+                if !part.download.needs_download() {
+                    plan.kept += file_size(&root, &part.download).await;
+                    continue;
                 }
-            }
-        }
 
-        let server_root = root.join(safe(&self.id));
+                let profile = video
+                    .transcode_profile
+                    .clone()
+                    .or_else(|| server_profile.clone());
+                let options = self.inner.transcode_options(profile).await;
+                let estimated = match options {
+                    // Milliseconds multiplied by kilobits per second gives bits.
+                    Some(ref options) => {
+                        (part.duration * (options.bitrate as u64 + ESTIMATED_AUDIO_BITRATE) / 8)
+                            .min(part.size)
+                    }
+                    None => part.size,
+                };
 
-        if expected_files.is_empty() {
-            debug!("Deleting empty server directory {}", server_root.display());
-            remove_dir_all(&server_root).await?;
-            return Ok(());
+                plan.downloads.push(PlannedDownload {
+                    video: id.clone(),
+                    title: video.title.clone(),
+                    part: index,
+                    size: part.size,
+                    estimated,
+                    transcode: options.is_some(),
+                    group: plan_group(&planned, video),
+                });
+            }
         }
 
-        prune_directory(&server_root, &expected_files).await;
+        let exporters = self.inner.metadata_exporters().await;
+        let expected_files = expected_files(&planned, &root, &exporters);
+        prune_directory(
+            &root.join(safe(&self.id)),
+            &expected_files,
+            &Default::default(),
+            &*storage,
+            Some(&mut plan.deletions),
+        )
+        .await;
+        if let Some(artwork_root) = self.artwork_root(&root).await {
+            prune_directory(
+                &artwork_root,
+                &expected_files,
+                &Default::default(),
+                &*storage,
+                Some(&mut plan.deletions),
+            )
+            .await;
+        }
 
-        Ok(())
+        Ok(plan)
     }
 }
 
+/// Writes the state every so often while item metadata is updated, so that
+/// a crash part way through a large update does not lose everything.
+struct Checkpoint<'a> {
+    inner: &'a Inner,
+    state: RwLockWriteGuard<'a, State>,
+    written: Instant,
+}
+
 struct StateSync<'a> {
+    server_id: &'a str,
     config: &'a Config,
     server_config: &'a ServerConfig,
     server_state: &'a mut ServerState,
     server: plex_api::Server,
     root: &'a Path,
+    storage: &'a dyn StorageBackend,
+    dry_run: bool,
+    conflicts: Conflicts,
+    events: Events,
+    /// Not used when planning as the state is not changed.
+    checkpoint: Option<Checkpoint<'a>>,
 
     seen_items: HashSet<String>,
     seen_libraries: HashSet<String>,
     transcode_profiles: HashMap<String, HashSet<String>>,
+    unskipped: HashSet<String>,
+    sources: HashMap<String, HashSet<String>>,
+    removed_syncs: Vec<String>,
+    /// Set when a sync item or query failed to update, in which case items
+    /// that were not seen are kept rather than removed.
+    incomplete: bool,
 }
 
 macro_rules! return_if_seen {
@@ -580,6 +2567,43 @@ macro_rules! return_if_seen {
 }
 
 impl<'a> StateSync<'a> {
+    async fn sync_items(&mut self) -> Result {
+        let server_config = self.server_config;
+
+        for item in server_config.syncs.values() {
+            if let Err(e) = self.add_item_by_key(item, &item.id).await {
+                if server_config.skipped.contains(&item.id) {
+                    debug!(item=item.id, error=?e, "Failed to update skipped item.");
+                } else {
+                    warn!(item=item.id, error=?e, "Failed to update item.");
+                    self.record_failure(&item.id, e);
+                }
+            }
+
+            self.checkpoint().await;
+        }
+
+        for query in server_config.queries.iter() {
+            if let Err(e) = self.add_query(query).await {
+                warn!(query=query.query, error=?e, "Failed to evaluate sync query.");
+                self.record_failure(&query.query, e);
+            }
+
+            self.checkpoint().await;
+        }
+
+        self.apply_empty_show_policy();
+
+        self.update_skipped();
+        self.update_sources();
+
+        self.update_profiles().await?;
+
+        self.prune_unseen().await?;
+
+        self.fetch_collections().await
+    }
+
     async fn add_video<T: MediaItem + FromMetadata>(&mut self, sync: &SyncItem, video: &T) {
         if sync.only_unplayed && video.metadata().view_count.unwrap_or_default() > 0 {
             return;
@@ -588,17 +2612,46 @@ impl<'a> StateSync<'a> {
         let key = video.rating_key().to_owned();
 
         if !self.seen_items.contains(video.rating_key()) {
+            let media_index = choose_media(
+                video,
+                self.server_config.media_versions.get(&key),
+                &self
+                    .server_config
+                    .media_selection
+                    .clone()
+                    .unwrap_or_default(),
+            );
+
             let video_state = self
                 .server_state
                 .videos
                 .entry(key.clone())
-                .or_insert_with(|| VideoState::from(video));
-
-            video_state.update(video, &self.server, self.root).await;
+                .or_insert_with(|| VideoState::from(video, media_index));
+
+            video_state
+                .update(
+                    video,
+                    media_index,
+                    &self.server,
+                    self.root,
+                    self.storage,
+                    &mut self.conflicts,
+                    self.dry_run,
+                )
+                .await;
 
             self.seen_items.insert(key.clone());
         }
 
+        if !self.is_skipped(sync, &key) {
+            self.unskipped.insert(key.clone());
+        }
+
+        self.sources
+            .entry(key.clone())
+            .or_default()
+            .insert(sync.id.clone());
+
         let transcode_profile = sync
             .transcode_profile
             .clone()
@@ -610,7 +2663,210 @@ impl<'a> StateSync<'a> {
         }
     }
 
+    fn is_skipped(&self, sync: &SyncItem, key: &str) -> bool {
+        let skipped = &self.server_config.skipped;
+        if skipped.contains(&sync.id) || skipped.contains(key) {
+            return true;
+        }
+
+        if let Some(VideoDetail::Episode(ref episode)) =
+            self.server_state.videos.get(key).map(|vs| &vs.detail)
+        {
+            if skipped.contains(&episode.season) {
+                return true;
+            }
+
+            if let Some(season) = self.server_state.seasons.get(&episode.season) {
+                return skipped.contains(&season.show);
+            }
+        }
+
+        false
+    }
+
+    /// Writes the state if it has not been written recently. Only items that
+    /// have been updated are written, those not yet seen are kept as they
+    /// were.
+    async fn checkpoint(&mut self) {
+        let checkpoint = match self.checkpoint {
+            Some(ref mut checkpoint) => checkpoint,
+            None => return,
+        };
+
+        if checkpoint.written.elapsed() < CHECKPOINT_INTERVAL {
+            return;
+        }
+
+        debug!("Writing the partially updated state");
+        checkpoint
+            .state
+            .servers
+            .insert(self.server_id.to_owned(), self.server_state.clone());
+        if let Err(e) = checkpoint.inner.persist_state(&checkpoint.state).await {
+            warn!(error=?e, "Failed to write the partially updated state");
+        }
+
+        checkpoint.written = Instant::now();
+    }
+
+    /// Leaves out the episodes, and depending on the policy the seasons and
+    /// show, of shows with no episodes outside of the specials season.
+    fn apply_empty_show_policy(&mut self) {
+        let policy = match self.config.empty_shows {
+            Some(policy) => policy,
+            None => return,
+        };
+
+        let mut populated_shows = HashSet::new();
+        let mut populated_seasons = HashSet::new();
+        for video in self
+            .server_state
+            .videos
+            .values()
+            .filter(|v| self.seen_items.contains(&v.id))
+        {
+            if let Some(season) = video
+                .episode_state()
+                .and_then(|ep| self.server_state.seasons.get(&ep.season))
+            {
+                populated_seasons.insert(season.id.clone());
+                if season.index != 0 {
+                    populated_shows.insert(season.show.clone());
+                }
+            }
+        }
+
+        let empty_shows: HashSet<String> = self
+            .server_state
+            .shows
+            .values()
+            .filter(|show| {
+                self.seen_items.contains(&show.id) && !populated_shows.contains(&show.id)
+            })
+            .map(|show| show.id.clone())
+            .collect();
+
+        for show in empty_shows
+            .iter()
+            .filter_map(|id| self.server_state.shows.get(id))
+        {
+            if policy == EmptyShowPolicy::Warn {
+                warn!(
+                    show = show.id,
+                    "Skipping '{}' as it has no episodes to sync", show.title
+                );
+            } else {
+                debug!(
+                    show = show.id,
+                    ?policy,
+                    "'{}' has no episodes to sync",
+                    show.title
+                );
+            }
+        }
+
+        let excluded: HashSet<String> = self
+            .server_state
+            .videos
+            .values()
+            .filter(|v| {
+                v.episode_state()
+                    .and_then(|ep| self.server_state.seasons.get(&ep.season))
+                    .map(|season| empty_shows.contains(&season.show))
+                    .unwrap_or_default()
+            })
+            .map(|v| v.id.clone())
+            .collect();
+
+        for id in excluded.iter() {
+            self.seen_items.remove(id);
+            self.unskipped.remove(id);
+            self.sources.remove(id);
+            self.transcode_profiles.remove(id);
+
+            // Nothing needs deleting for videos that were never downloaded so
+            // they are dropped rather than reported as removed.
+            let downloaded = self.server_state.videos[id]
+                .parts
+                .iter()
+                .any(|part| part.download != DownloadState::None);
+            if !downloaded {
+                self.server_state.videos.remove(id);
+            }
+        }
+
+        for playlist in self.server_state.playlists.values_mut() {
+            playlist.videos.retain(|id| !excluded.contains(id));
+        }
+
+        if policy == EmptyShowPolicy::Artwork {
+            return;
+        }
+
+        for season in self.server_state.seasons.values() {
+            if empty_shows.contains(&season.show) || !populated_seasons.contains(&season.id) {
+                self.seen_items.remove(&season.id);
+            }
+        }
+
+        for show in empty_shows {
+            self.seen_items.remove(&show);
+        }
+    }
+
+    fn record_failure(&mut self, item: &str, error: Error) {
+        self.incomplete = true;
+        self.events.emit(Event::SyncItemFailed {
+            server: self.server_id.to_owned(),
+            item: item.to_owned(),
+            error: error.to_string(),
+        });
+    }
+
+    fn update_sources(&mut self) {
+        for video_state in self
+            .server_state
+            .videos
+            .values_mut()
+            .filter(|v| self.seen_items.contains(&v.id))
+        {
+            let mut sources: Vec<String> = self
+                .sources
+                .get(&video_state.id)
+                .map(|sources| sources.iter().cloned().collect())
+                .unwrap_or_default();
+            sources.sort();
+
+            video_state.sources = sources;
+        }
+    }
+
+    fn update_skipped(&mut self) {
+        for video_state in self
+            .server_state
+            .videos
+            .values_mut()
+            .filter(|v| self.seen_items.contains(&v.id))
+        {
+            video_state.skipped = !self.unskipped.contains(&video_state.id);
+        }
+    }
+
     async fn add_movie(&mut self, sync: &SyncItem, movie: &Movie) -> Result {
+        let metadata = movie.metadata();
+        let library = metadata.library_section_id.map(|id| id.to_string());
+        let year = metadata.year.unwrap_or_default();
+        let duplicate = !self.server_state.videos.contains_key(movie.rating_key())
+            && self.server_state.videos.values().any(|vs| {
+                vs.title == movie.title()
+                    && vs
+                        .movie_state()
+                        .is_some_and(|m| Some(&m.library) == library.as_ref() && m.year == year)
+            });
+        if duplicate && !self.add_duplicate(movie) {
+            return Ok(());
+        }
+
         self.add_video(sync, movie).await;
 
         self.add_library(movie)?;
@@ -624,6 +2880,33 @@ impl<'a> StateSync<'a> {
         Ok(())
     }
 
+    /// Adds episodes in order until the sync item's limit on unplayed episodes
+    /// is reached.
+    async fn add_episodes(
+        &mut self,
+        sync: &SyncItem,
+        episodes: Vec<Episode>,
+        remaining: &mut Option<u32>,
+    ) -> Result {
+        for episode in episodes {
+            if let Some(count) = remaining {
+                if *count == 0 {
+                    break;
+                }
+
+                if episode.metadata().view_count.unwrap_or_default() > 0 {
+                    continue;
+                }
+
+                *count -= 1;
+            }
+
+            self.add_episode(sync, &episode).await?;
+        }
+
+        Ok(())
+    }
+
     fn add_season(&mut self, season: &Season) -> Result {
         return_if_seen!(self, season);
 
@@ -636,8 +2919,24 @@ impl<'a> StateSync<'a> {
         Ok(())
     }
 
-    async fn add_show(&mut self, show: &Show) -> Result {
-        return_if_seen!(self, show);
+    /// Adds a show, returning whether it is being synced.
+    async fn add_show(&mut self, show: &Show) -> Result<bool> {
+        if self.seen_items.contains(show.rating_key()) {
+            return Ok(true);
+        }
+
+        let metadata = show.metadata();
+        let library = metadata.library_section_id.map(|id| id.to_string());
+        let year = metadata.year.unwrap_or_default();
+        let duplicate = !self.server_state.shows.contains_key(show.rating_key())
+            && self.server_state.shows.values().any(|ss| {
+                ss.title == show.title() && Some(&ss.library) == library.as_ref() && ss.year == year
+            });
+        if duplicate && !self.add_duplicate(show) {
+            return Ok(false);
+        }
+
+        self.seen_items.insert(show.rating_key().to_owned());
 
         let show_state = self
             .server_state
@@ -645,11 +2944,25 @@ impl<'a> StateSync<'a> {
             .entry(show.rating_key().to_owned())
             .or_insert_with(|| ShowState::from(show));
 
-        show_state.update(show, self.root).await;
+        show_state
+            .update(show, self.root, self.storage, self.dry_run)
+            .await;
 
         self.add_library(show)?;
 
-        Ok(())
+        Ok(true)
+    }
+
+    /// Resolves the conflict for a new movie or show with the same title and
+    /// year as one already synced, returning whether to add it.
+    fn add_duplicate<T: MetadataItem>(&mut self, item: &T) -> bool {
+        let resolution = self.conflicts.resolve(Conflict::DuplicateName {
+            server: self.server_id.to_owned(),
+            item: item.rating_key().to_owned(),
+            title: item.title().to_owned(),
+        });
+
+        resolution == Some(Resolution::Rename)
     }
 
     fn add_library<T>(&mut self, item: &T) -> Result<&mut LibraryState>
@@ -761,7 +3074,13 @@ impl<'a> StateSync<'a> {
                     info!(item=key, old=?video_state.transcode_profile, new=?selected_profile, "Transcode profile changed, deleting existing downloads.");
 
                     for part in video_state.parts.iter_mut() {
-                        part.download.delete(&self.server, self.root).await;
+                        if self.dry_run {
+                            part.download = DownloadState::None;
+                        } else {
+                            part.download
+                                .delete(Some(&self.server), self.root, self.storage)
+                                .await;
+                        }
                     }
                 }
 
@@ -802,7 +3121,9 @@ impl<'a> StateSync<'a> {
                                     .or_insert_with(|| CollectionState::from(&collection));
                                 collection_state.contents = available;
 
-                                collection_state.update(&collection, self.root).await;
+                                collection_state
+                                    .update(&collection, self.root, self.storage, self.dry_run)
+                                    .await;
 
                                 seen_collections.insert(collection_state.id.clone());
                             }
@@ -832,7 +3153,9 @@ impl<'a> StateSync<'a> {
                                     .or_insert_with(|| CollectionState::from(&collection));
                                 collection_state.contents = available;
 
-                                collection_state.update(&collection, self.root).await;
+                                collection_state
+                                    .update(&collection, self.root, self.storage, self.dry_run)
+                                    .await;
 
                                 seen_collections.insert(collection_state.id.clone());
                             }
@@ -849,7 +3172,9 @@ impl<'a> StateSync<'a> {
             .values_mut()
             .filter(|v| !seen_collections.contains(&v.id))
         {
-            collection.delete(self.root).await;
+            if !self.dry_run {
+                collection.delete(self.root, self.storage).await;
+            }
         }
 
         self.server_state
@@ -860,15 +3185,47 @@ impl<'a> StateSync<'a> {
     }
 
     async fn prune_unseen(&mut self) -> Result {
+        if self.incomplete {
+            warn!("Not removing old items as some sync items failed to update");
+            return Ok(());
+        }
+
         info!("Pruning old items");
 
+        let reasons: HashMap<String, String> = self
+            .server_state
+            .videos
+            .values()
+            .filter(|v| !self.seen_items.contains(&v.id))
+            .map(|v| {
+                (
+                    v.id.clone(),
+                    removal_reason(self.server_config, self.server_state, v),
+                )
+            })
+            .collect();
+
         for video in self
             .server_state
             .videos
             .values_mut()
             .filter(|v| !self.seen_items.contains(&v.id))
         {
-            video.delete(&self.server, self.root).await;
+            if self.dry_run {
+                continue;
+            }
+
+            let reason = reasons.get(&video.id).cloned().unwrap_or_default();
+            info!(video = video.id, reason, "Removing '{}'", video.title);
+
+            video.delete(&self.server, self.root, self.storage).await;
+
+            self.events.emit(Event::VideoRemoved {
+                server: self.server_id.to_owned(),
+                video: video.id.clone(),
+                title: video.title.clone(),
+                reason,
+            });
         }
 
         for show in self
@@ -877,7 +3234,20 @@ impl<'a> StateSync<'a> {
             .values_mut()
             .filter(|v| !self.seen_items.contains(&v.id))
         {
-            show.delete(self.root).await;
+            if !self.dry_run {
+                show.delete(self.root, self.storage).await;
+            }
+        }
+
+        for season in self
+            .server_state
+            .seasons
+            .values_mut()
+            .filter(|v| !self.seen_items.contains(&v.id))
+        {
+            if !self.dry_run {
+                season.delete(self.root, self.storage).await;
+            }
         }
 
         self.server_state
@@ -903,11 +3273,80 @@ impl<'a> StateSync<'a> {
         Ok(())
     }
 
+    /// Adds every movie or show that currently matches a query. Items that
+    /// fail are skipped.
+    async fn add_query(&mut self, query: &SyncQuery) -> Result {
+        let filter = Filter::from_str(&query.query)?;
+        let restricted = filter.libraries();
+
+        for library in self.server.libraries() {
+            if let Some(ref names) = restricted {
+                if !names
+                    .iter()
+                    .any(|n| library.id() == *n || library.title().eq_ignore_ascii_case(n))
+                {
+                    continue;
+                }
+            }
+
+            let items = match &library {
+                PlexLibrary::Movie(lib) => lib
+                    .movies()
+                    .await?
+                    .into_iter()
+                    .filter(|movie| {
+                        let height = movie.media().first().and_then(|m| m.metadata().height);
+                        filter.matches(&library, movie.metadata(), height)
+                    })
+                    .map(Item::Movie)
+                    .collect::<Vec<Item>>(),
+                PlexLibrary::TV(lib) => lib
+                    .shows()
+                    .await?
+                    .into_iter()
+                    .filter(|show| filter.matches(&library, show.metadata(), None))
+                    .map(Item::Show)
+                    .collect::<Vec<Item>>(),
+                _ => continue,
+            };
+
+            for item in items {
+                // Videos record the query as their source rather than the item.
+                let sync = SyncItem {
+                    id: query.source(),
+                    transcode_profile: query.transcode_profile.clone(),
+                    only_unplayed: query.only_unplayed,
+                    max_episodes: query.max_episodes,
+                    latest_season: query.latest_season,
+                    added: None,
+                    priority: None,
+                };
+
+                let key = item.rating_key().to_owned();
+                if let Err(e) = self.add_item(&sync, item).await {
+                    warn!(query=query.query, item=key, error=?e, "Failed to update item.");
+                    self.record_failure(&key, e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     async fn add_item_by_key(&mut self, sync: &SyncItem, key: &str) -> Result {
         match self.server.item_by_id(key).await {
             Ok(i) => self.add_item(sync, i).await,
             Err(plex_api::Error::ItemNotFound) => {
                 warn!(item = key, "Sync item no longer appears to exist");
+
+                let resolution = self.conflicts.resolve(Conflict::ItemMissing {
+                    server: self.server_id.to_owned(),
+                    item: key.to_owned(),
+                });
+                if resolution == Some(Resolution::RemoveSync) {
+                    self.removed_syncs.push(key.to_owned());
+                }
+
                 Ok(())
             }
             Err(e) => Err(e.into()),
@@ -921,14 +3360,26 @@ impl<'a> StateSync<'a> {
             Item::Movie(movie) => self.add_movie(sync, &movie).await,
 
             Item::Show(show) => {
-                self.add_show(&show).await?;
+                if !self.add_show(&show).await? {
+                    return Ok(());
+                }
 
-                for season in show.seasons().await? {
-                    self.add_season(&season)?;
+                let mut seasons = show.seasons().await?;
+                if sync.latest_season {
+                    let latest = seasons.iter().filter_map(|s| s.metadata().index).max();
+                    seasons.retain(|s| s.metadata().index == latest);
+                }
 
-                    for episode in season.episodes().await? {
-                        self.add_episode(sync, &episode).await?;
+                let mut remaining = sync.max_episodes;
+                for season in seasons {
+                    if remaining == Some(0) {
+                        break;
                     }
+
+                    self.add_season(&season)?;
+
+                    let episodes = season.episodes().await?;
+                    self.add_episodes(sync, episodes, &mut remaining).await?;
                 }
 
                 Ok(())
@@ -944,14 +3395,16 @@ impl<'a> StateSync<'a> {
                             "show was missing".to_string(),
                         )
                     })?;
-                    self.add_show(&show).await?;
+                    if !self.add_show(&show).await? {
+                        return Ok(());
+                    }
                 }
 
                 self.add_season(&season)?;
 
-                for episode in season.episodes().await? {
-                    self.add_episode(sync, &episode).await?;
-                }
+                let mut remaining = sync.max_episodes;
+                let episodes = season.episodes().await?;
+                self.add_episodes(sync, episodes, &mut remaining).await?;
 
                 Ok(())
             }
@@ -981,7 +3434,9 @@ impl<'a> StateSync<'a> {
                                 "show was missing".to_string(),
                             )
                         })?;
-                        self.add_show(&show).await?;
+                        if !self.add_show(&show).await? {
+                            return Ok(());
+                        }
                     }
 
                     self.add_season(&season)?;
@@ -0,0 +1,82 @@
+use std::path::{Path, PathBuf};
+
+use async_std::{
+    fs::{create_dir_all, read_dir, remove_file, write},
+    stream::StreamExt,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::to_string_pretty;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tracing::warn;
+
+use crate::{
+    config::{SyncItem, SyncQuery},
+    state::ServerState,
+    util::safe,
+    Result,
+};
+
+/// The directory within the store that server snapshots are written to.
+pub const SNAPSHOT_DIR: &str = "flicksync-snapshots";
+/// The version of the snapshot format, increased whenever it changes in a way
+/// that older readers cannot handle.
+pub const SNAPSHOT_VERSION: u32 = 1;
+/// The number of snapshots to keep for each server.
+const SNAPSHOT_LENGTH: usize = 10;
+
+/// The metadata of a server as seen by the most recent sync, along with the
+/// items that were being synced, enough to rebuild the sync elsewhere.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Snapshot {
+    pub(crate) version: u32,
+    pub(crate) server: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub(crate) captured: OffsetDateTime,
+    pub(crate) syncs: Vec<SyncItem>,
+    pub(crate) queries: Vec<SyncQuery>,
+    /// The server's state without its access token.
+    pub(crate) state: ServerState,
+}
+
+impl Snapshot {
+    /// Writes the snapshot beneath the store's snapshot directory and deletes
+    /// the oldest snapshots for the server.
+    pub(crate) async fn write(&self, root: &Path) -> Result<PathBuf> {
+        let directory = root.join(SNAPSHOT_DIR).join(safe(&self.server));
+        create_dir_all(&directory).await?;
+
+        let name = self
+            .captured
+            .replace_nanosecond(0)
+            .unwrap_or(self.captured)
+            .format(&Rfc3339)
+            .unwrap_or_default()
+            .replace(':', "-");
+        let path = directory.join(format!("snapshot-{name}.json"));
+        write(&path, to_string_pretty(self)?).await?;
+
+        let mut snapshots = Vec::new();
+        let mut reader = read_dir(&directory).await?;
+        while let Some(entry) = reader.next().await {
+            let entry = entry?;
+            if let Some(name) = entry.file_name().to_str() {
+                if name.starts_with("snapshot-") && name.ends_with(".json") {
+                    snapshots.push(PathBuf::from(entry.path()));
+                }
+            }
+        }
+
+        // Timestamped names sort oldest first.
+        snapshots.sort();
+        if snapshots.len() > SNAPSHOT_LENGTH {
+            for old in snapshots.drain(0..snapshots.len() - SNAPSHOT_LENGTH) {
+                if let Err(e) = remove_file(&old).await {
+                    warn!(error=?e, path=?old, "Failed to remove old snapshot");
+                }
+            }
+        }
+
+        Ok(path)
+    }
+}
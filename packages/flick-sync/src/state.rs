@@ -1,25 +1,37 @@
 use std::collections::HashMap;
 use std::fmt;
-use std::io::ErrorKind;
+use std::io::{self, ErrorKind};
+use std::net::IpAddr;
 use std::path::{Path, PathBuf};
 
 use async_std::fs;
+use futures::AsyncReadExt;
 use plex_api::{
     library::{Collection, MetadataItem, Part, Playlist, Season, Show},
-    media_container::server::library::{Metadata, MetadataType},
+    media_container::server::library::{Metadata, MetadataType, Stream},
     Server,
 };
 use plex_api::{
     library::{FromMetadata, MediaItem},
     transcode::TranscodeStatus,
 };
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
 use time::{Date, OffsetDateTime};
 use tracing::{debug, error, info, instrument, trace, warn};
 use typeshare::typeshare;
 use uuid::Uuid;
+use xxhash_rust::xxh3::Xxh3;
 
-#[derive(Deserialize, Default, Serialize, Clone, PartialEq)]
+use crate::{
+    backend::StorageBackend,
+    config::{MediaPreference, MediaSelection},
+    conflict::{Conflict, Conflicts, Resolution},
+};
+
+#[derive(Deserialize, Default, Serialize, Clone, PartialEq, JsonSchema)]
 #[serde(tag = "state", rename_all = "camelCase")]
 pub(crate) enum ThumbnailState {
     #[default]
@@ -63,16 +75,14 @@ impl ThumbnailState {
         }
     }
 
-    #[instrument(level = "trace", skip(root))]
-    pub(crate) async fn delete(&mut self, root: &Path) {
+    #[instrument(level = "trace", skip(root, storage))]
+    pub(crate) async fn delete(&mut self, root: &Path, storage: &dyn StorageBackend) {
         if let ThumbnailState::Downloaded { path } = self {
             let file = root.join(&path);
             trace!(?path, "Removing old thumbnail file");
 
-            if let Err(e) = fs::remove_file(&file).await {
-                if e.kind() != ErrorKind::NotFound {
-                    warn!(?path, error=?e, "Failed to remove file");
-                }
+            if let Err(e) = storage.remove(&file).await {
+                warn!(?path, error=?e, "Failed to remove file");
             }
 
             *self = ThumbnailState::None;
@@ -80,6 +90,103 @@ impl ThumbnailState {
     }
 }
 
+/// The kinds of artwork that can be downloaded for an item.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Hash, JsonSchema)]
+#[typeshare]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ArtworkKind {
+    /// The main poster, stored as the item's thumbnail.
+    Poster,
+    /// A wide background image.
+    Fanart,
+    /// A short, wide title banner.
+    Banner,
+}
+
+impl ArtworkKind {
+    pub(crate) const ALL: [ArtworkKind; 3] = [Self::Poster, Self::Fanart, Self::Banner];
+
+    pub(crate) fn file_name(&self) -> &'static str {
+        match self {
+            Self::Poster => "thumb",
+            Self::Fanart => "fanart",
+            Self::Banner => "banner",
+        }
+    }
+
+    /// The name media servers look for when artwork is stored beside media.
+    pub(crate) fn local_name(&self) -> &'static str {
+        match self {
+            Self::Poster => "poster",
+            Self::Fanart => "fanart",
+            Self::Banner => "banner",
+        }
+    }
+
+    pub(crate) fn dimensions(&self) -> (u32, u32) {
+        match self {
+            Self::Poster => (320, 320),
+            Self::Fanart => (1280, 720),
+            Self::Banner => (1000, 185),
+        }
+    }
+
+    pub(crate) fn image<'a>(&self, metadata: &'a Metadata) -> Option<&'a String> {
+        match self {
+            Self::Poster => metadata.thumb.as_ref(),
+            Self::Fanart => metadata.art.as_ref(),
+            Self::Banner => metadata.banner.as_ref(),
+        }
+    }
+}
+
+/// Adds accessors for the artwork of a state type. The poster is kept in the
+/// `thumbnail` field and every other kind in the `artwork` map.
+macro_rules! derive_artwork {
+    ($typ:ident) => {
+        impl $typ {
+            pub(crate) fn artwork(&self, kind: ArtworkKind) -> ThumbnailState {
+                match kind {
+                    ArtworkKind::Poster => self.thumbnail.clone(),
+                    _ => self.artwork.get(&kind).cloned().unwrap_or_default(),
+                }
+            }
+
+            pub(crate) fn set_artwork(&mut self, kind: ArtworkKind, state: ThumbnailState) {
+                match kind {
+                    ArtworkKind::Poster => self.thumbnail = state,
+                    _ if state.is_none() => {
+                        self.artwork.remove(&kind);
+                    }
+                    _ => {
+                        self.artwork.insert(kind, state);
+                    }
+                }
+            }
+
+            pub(crate) fn artwork_files(&self) -> impl Iterator<Item = PathBuf> + '_ {
+                self.thumbnail
+                    .file()
+                    .into_iter()
+                    .chain(self.artwork.values().filter_map(|a| a.file()))
+            }
+
+            pub(crate) async fn delete_artwork(
+                &mut self,
+                root: &Path,
+                storage: &dyn StorageBackend,
+            ) {
+                self.thumbnail.delete(root, storage).await;
+
+                for artwork in self.artwork.values_mut() {
+                    artwork.delete(root, storage).await;
+                }
+                self.artwork.clear();
+            }
+        }
+    };
+}
+
 impl fmt::Debug for ThumbnailState {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -89,7 +196,7 @@ impl fmt::Debug for ThumbnailState {
     }
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
 #[typeshare]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct CollectionState {
@@ -99,8 +206,11 @@ pub(crate) struct CollectionState {
     pub(crate) contents: Vec<String>,
     #[serde(with = "time::serde::timestamp")]
     #[typeshare(serialized_as = "number")]
+    #[schemars(with = "i64")]
     pub(crate) last_updated: OffsetDateTime,
     pub(crate) thumbnail: ThumbnailState,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub(crate) artwork: HashMap<ArtworkKind, ThumbnailState>,
 }
 
 impl CollectionState {
@@ -116,30 +226,35 @@ impl CollectionState {
             contents: Default::default(),
             last_updated: collection.metadata().updated_at.unwrap(),
             thumbnail: Default::default(),
+            artwork: Default::default(),
         }
     }
 
-    pub(crate) async fn update<T>(&mut self, collection: &Collection<T>, root: &Path) {
+    pub(crate) async fn update<T>(
+        &mut self,
+        collection: &Collection<T>,
+        root: &Path,
+        storage: &dyn StorageBackend,
+        dry_run: bool,
+    ) {
         self.title = collection.title().to_owned();
 
         if let Some(updated) = collection.metadata().updated_at {
-            if updated > self.last_updated {
-                self.thumbnail.delete(root).await;
+            if updated > self.last_updated && !dry_run {
+                self.delete_artwork(root, storage).await;
             }
             self.last_updated = updated;
         }
     }
 
-    pub(crate) async fn delete(&mut self, root: &Path) {
-        self.thumbnail.verify(root).await;
-
-        if self.thumbnail != ThumbnailState::None {
-            self.thumbnail.delete(root).await;
-        }
+    pub(crate) async fn delete(&mut self, root: &Path, storage: &dyn StorageBackend) {
+        self.delete_artwork(root, storage).await;
     }
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
+derive_artwork!(CollectionState);
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
 #[typeshare]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct PlaylistState {
@@ -162,7 +277,7 @@ impl PlaylistState {
     }
 }
 
-#[derive(Deserialize, Serialize, Clone, Copy, Debug)]
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, JsonSchema)]
 #[typeshare]
 #[serde(rename_all = "lowercase")]
 pub(crate) enum LibraryType {
@@ -170,7 +285,120 @@ pub(crate) enum LibraryType {
     Show,
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, JsonSchema)]
+#[typeshare]
+#[serde(rename_all = "camelCase")]
+pub enum ItemType {
+    Playlist,
+    MovieCollection,
+    ShowCollection,
+    Show,
+    Season,
+    Episode,
+    Movie,
+    Unknown,
+}
+
+/// How a connection to a server is routed, from most to least preferred.
+#[derive(
+    Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, JsonSchema,
+)]
+#[typeshare]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ConnectionKind {
+    /// An address on the local network.
+    Local,
+    /// A public address of the server.
+    Remote,
+    /// Through a plex.tv relay, which is limited to a low bandwidth.
+    Relay,
+}
+
+impl ConnectionKind {
+    /// Classifies the address of a connection. plex.direct host names encode
+    /// the address they resolve to and relays are always reached on port
+    /// 8443.
+    pub(crate) fn of(url: &str) -> Self {
+        let authority = url
+            .split_once("://")
+            .map_or(url, |(_, rest)| rest)
+            .split('/')
+            .next()
+            .unwrap_or_default();
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (host, port.parse::<u16>().ok()),
+            _ => (authority, None),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+
+        let address = match host.strip_suffix(".plex.direct") {
+            Some(name) => {
+                if port == Some(8443) {
+                    return Self::Relay;
+                }
+                name.split('.').next().unwrap_or_default().replace('-', ".")
+            }
+            None => host.to_owned(),
+        };
+
+        match address.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) if ip.is_private() || ip.is_loopback() || ip.is_link_local() => {
+                Self::Local
+            }
+            Ok(IpAddr::V6(ip)) if ip.is_loopback() => Self::Local,
+            _ if host == "localhost" => Self::Local,
+            _ => Self::Remote,
+        }
+    }
+}
+
+/// The connection last used to reach a server found through a Plex account,
+/// tried first so that local connections are used whenever available.
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[typeshare]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PreferredConnection {
+    pub(crate) url: String,
+    /// The token the server accepts, which differs from the account's token.
+    pub(crate) token: String,
+    pub(crate) kind: ConnectionKind,
+    /// How long connecting took, in milliseconds.
+    #[typeshare(serialized_as = "number")]
+    pub(crate) latency: u64,
+}
+
+/// Details of a sync item fetched from the server, kept so that listing the
+/// sync items does not need to query the server every time.
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[typeshare]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CachedItem {
+    #[serde(rename = "type")]
+    pub(crate) item_type: ItemType,
+    pub(crate) title: String,
+    #[serde(with = "time::serde::timestamp")]
+    #[typeshare(serialized_as = "number")]
+    #[schemars(with = "i64")]
+    pub(crate) fetched: OffsetDateTime,
+}
+
+/// What a server supported when it was last connected to, kept so that it
+/// can be reported without connecting again.
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[typeshare]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CachedCapabilities {
+    pub(crate) claimed: bool,
+    pub(crate) plex_pass: bool,
+    pub(crate) downloads: bool,
+    pub(crate) transcoding: bool,
+    #[serde(with = "time::serde::timestamp")]
+    #[typeshare(serialized_as = "number")]
+    #[schemars(with = "i64")]
+    pub(crate) fetched: OffsetDateTime,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
 #[typeshare]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct LibraryState {
@@ -180,7 +408,7 @@ pub(crate) struct LibraryState {
     pub(crate) library_type: LibraryType,
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
 #[typeshare]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct SeasonState {
@@ -188,6 +416,10 @@ pub(crate) struct SeasonState {
     pub(crate) show: String,
     pub(crate) index: u32,
     pub(crate) title: String,
+    #[serde(default)]
+    pub(crate) thumbnail: ThumbnailState,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub(crate) artwork: HashMap<ArtworkKind, ThumbnailState>,
 }
 
 impl SeasonState {
@@ -199,6 +431,8 @@ impl SeasonState {
             show: metadata.parent.parent_rating_key.clone().unwrap(),
             index: metadata.index.unwrap(),
             title: season.title().to_owned(),
+            thumbnail: Default::default(),
+            artwork: Default::default(),
         }
     }
 
@@ -209,9 +443,15 @@ impl SeasonState {
         self.show = metadata.parent.parent_rating_key.clone().unwrap();
         self.title = season.title().to_owned();
     }
+
+    pub(crate) async fn delete(&mut self, root: &Path, storage: &dyn StorageBackend) {
+        self.delete_artwork(root, storage).await;
+    }
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
+derive_artwork!(SeasonState);
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
 #[typeshare]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct ShowState {
@@ -221,15 +461,18 @@ pub(crate) struct ShowState {
     pub(crate) year: u32,
     #[serde(with = "time::serde::timestamp")]
     #[typeshare(serialized_as = "number")]
+    #[schemars(with = "i64")]
     pub(crate) last_updated: OffsetDateTime,
     pub(crate) thumbnail: ThumbnailState,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub(crate) artwork: HashMap<ArtworkKind, ThumbnailState>,
 }
 
 impl ShowState {
     pub(crate) fn from(show: &Show) -> Self {
         let metadata = show.metadata();
 
-        let year = metadata.year.unwrap();
+        let year = metadata.year.unwrap_or_default();
         let title = show.title().to_owned();
 
         Self {
@@ -239,33 +482,38 @@ impl ShowState {
             year,
             last_updated: metadata.updated_at.unwrap(),
             thumbnail: Default::default(),
+            artwork: Default::default(),
         }
     }
 
-    pub(crate) async fn update(&mut self, show: &Show, root: &Path) {
+    pub(crate) async fn update(
+        &mut self,
+        show: &Show,
+        root: &Path,
+        storage: &dyn StorageBackend,
+        dry_run: bool,
+    ) {
         let metadata = show.metadata();
 
-        self.year = metadata.year.unwrap();
+        self.year = metadata.year.unwrap_or_default();
         self.title = show.title().to_owned();
 
         if let Some(updated) = show.metadata().updated_at {
-            if updated > self.last_updated {
-                self.thumbnail.delete(root).await;
+            if updated > self.last_updated && !dry_run {
+                self.delete_artwork(root, storage).await;
             }
             self.last_updated = updated;
         }
     }
 
-    pub(crate) async fn delete(&mut self, root: &Path) {
-        self.thumbnail.verify(root).await;
-
-        if self.thumbnail != ThumbnailState::None {
-            self.thumbnail.delete(root).await;
-        }
+    pub(crate) async fn delete(&mut self, root: &Path, storage: &dyn StorageBackend) {
+        self.delete_artwork(root, storage).await;
     }
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
+derive_artwork!(ShowState);
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
 #[typeshare]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct MovieDetail {
@@ -277,16 +525,16 @@ impl MovieDetail {
     pub(crate) fn from(metadata: &Metadata) -> Self {
         MovieDetail {
             library: metadata.library_section_id.unwrap().to_string(),
-            year: metadata.year.unwrap(),
+            year: metadata.year.unwrap_or_default(),
         }
     }
 
     pub(crate) fn update(&mut self, metadata: &Metadata) {
-        self.year = metadata.year.unwrap();
+        self.year = metadata.year.unwrap_or_default();
     }
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
 #[typeshare]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct EpisodeDetail {
@@ -308,7 +556,7 @@ impl EpisodeDetail {
     }
 }
 
-#[derive(Deserialize, Default, Serialize, Clone, PartialEq)]
+#[derive(Deserialize, Default, Serialize, Clone, PartialEq, JsonSchema)]
 #[serde(tag = "state", rename_all = "camelCase")]
 pub(crate) enum DownloadState {
     #[default]
@@ -321,6 +569,9 @@ pub(crate) enum DownloadState {
     Downloaded { path: PathBuf },
     #[serde(rename_all = "camelCase")]
     Transcoded { path: PathBuf },
+    /// The original file has downloaded but still needs transcoding locally.
+    #[serde(rename_all = "camelCase")]
+    AwaitingTranscode { path: PathBuf },
 }
 
 impl DownloadState {
@@ -334,6 +585,7 @@ impl DownloadState {
             } => Some(path.clone()),
             Self::Downloaded { path } => Some(path.clone()),
             Self::Transcoded { path } => Some(path.clone()),
+            Self::AwaitingTranscode { path } => Some(path.clone()),
         }
     }
 
@@ -392,6 +644,7 @@ impl DownloadState {
             }
             DownloadState::Downloaded { path } => path,
             DownloadState::Transcoded { path } => path,
+            DownloadState::AwaitingTranscode { path } => path,
         };
 
         let file = root.join(&path);
@@ -414,27 +667,33 @@ impl DownloadState {
         *self = DownloadState::None;
     }
 
-    #[instrument(level = "trace", skip(root, server))]
-    pub(crate) async fn delete(&mut self, server: &Server, root: &Path) {
+    /// Removes the downloaded file. Without a server a transcode in progress
+    /// is left for the server to expire.
+    #[instrument(level = "trace", skip(root, server, storage))]
+    pub(crate) async fn delete(
+        &mut self,
+        server: Option<&Server>,
+        root: &Path,
+        storage: &dyn StorageBackend,
+    ) {
         let (path, session_id) = match self {
             DownloadState::None => return,
             DownloadState::Downloading { path } => (path, None),
             DownloadState::Transcoding { session_id, path } => (path, Some(session_id)),
             DownloadState::Downloaded { path } => (path, None),
             DownloadState::Transcoded { path } => (path, None),
+            DownloadState::AwaitingTranscode { path } => (path, None),
         };
 
         let file = root.join(&path);
 
         trace!(?path, "Removing old video file");
 
-        if let Err(e) = fs::remove_file(&file).await {
-            if e.kind() != ErrorKind::NotFound {
-                warn!(?path, error=?e, "Failed to remove file");
-            }
+        if let Err(e) = storage.remove(&file).await {
+            warn!(?path, error=?e, "Failed to remove file");
         }
 
-        if let Some(session_id) = session_id {
+        if let (Some(session_id), Some(server)) = (session_id, server) {
             if let Ok(session) = server.transcode_session(session_id).await {
                 if let Err(e) = session.cancel().await {
                     warn!(error=?e, "Failed to cancel stale transcode session");
@@ -457,11 +716,12 @@ impl fmt::Debug for DownloadState {
             } => write!(f, "Transcoding({session_id})"),
             Self::Downloaded { path: _ } => write!(f, "Downloaded"),
             Self::Transcoded { path: _ } => write!(f, "Transcoded"),
+            Self::AwaitingTranscode { path: _ } => write!(f, "AwaitingTranscode"),
         }
     }
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
 #[typeshare]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct VideoPartState {
@@ -472,6 +732,235 @@ pub(crate) struct VideoPartState {
     #[typeshare(serialized_as = "number")]
     pub(crate) duration: u64,
     pub(crate) download: DownloadState,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) checksum: Option<FileChecksum>,
+    /// The tracks used by the most recent transcode of this part.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) tracks: Option<TranscodeTracks>,
+    /// The download is complete but has not been uploaded to the configured
+    /// media storage yet.
+    #[serde(default)]
+    pub(crate) unpublished: bool,
+    /// The last download failed verification and was quarantined. It is not
+    /// downloaded again until this is cleared by repairing or redownloading
+    /// the video.
+    #[serde(default)]
+    pub(crate) quarantined: bool,
+}
+
+/// The audio and subtitle tracks selected on the server when a part was
+/// transcoded.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+#[typeshare]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct TranscodeTracks {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) audio_language: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) subtitle_language: Option<String>,
+    #[serde(default)]
+    pub(crate) burn_subtitles: bool,
+    /// The position of the audio track amongst the part's audio tracks, only
+    /// recorded for local transcodes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) audio_track: Option<usize>,
+}
+
+impl TranscodeTracks {
+    /// The stream IDs and languages of a part's audio tracks in order.
+    fn audio_tracks<M: MediaItem>(part: &Part<'_, M>) -> Vec<(String, Option<String>)> {
+        part.metadata()
+            .streams
+            .iter()
+            .filter_map(|stream| match stream {
+                Stream::Audio(audio) => Some((audio.id.to_string(), audio.language_code.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Finds the first audio track in the language, returning its position
+    /// amongst the part's audio tracks and its stream ID.
+    pub(crate) fn preferred_audio<M: MediaItem>(
+        part: &Part<'_, M>,
+        language: &str,
+    ) -> Option<(usize, String)> {
+        Self::audio_tracks(part)
+            .into_iter()
+            .enumerate()
+            .find(|(_, (_, code))| {
+                code.as_ref()
+                    .is_some_and(|code| code.eq_ignore_ascii_case(language))
+            })
+            .map(|(index, (id, _))| (index, id))
+    }
+
+    /// The tracks used when transcoding a part locally, the first audio track
+    /// unless there is one in the preferred language.
+    pub(crate) fn local<M: MediaItem>(part: &Part<'_, M>, language: Option<&str>) -> Self {
+        let index = language
+            .and_then(|language| Self::preferred_audio(part, language))
+            .map(|(index, _)| index)
+            .unwrap_or_default();
+
+        Self {
+            audio_language: Self::audio_tracks(part)
+                .into_iter()
+                .nth(index)
+                .and_then(|(_, code)| code),
+            audio_track: Some(index),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn selected<M: MediaItem>(part: &Part<'_, M>, burn_subtitles: bool) -> Self {
+        let mut tracks = Self {
+            burn_subtitles,
+            ..Default::default()
+        };
+
+        for stream in part.metadata().streams.iter() {
+            match stream {
+                Stream::Audio(audio) if audio.selected == Some(true) => {
+                    tracks.audio_language = audio.language_code.clone();
+                }
+                Stream::Subtitle(subtitle) if subtitle.selected == Some(true) => {
+                    tracks.subtitle_language = subtitle.language_code.clone();
+                }
+                _ => {}
+            }
+        }
+
+        tracks
+    }
+}
+
+/// The algorithms that can be used to hash completed downloads.
+#[derive(Deserialize, Serialize, Default, Clone, Copy, Debug, PartialEq, Eq, JsonSchema)]
+#[typeshare]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum HashAlgorithm {
+    #[default]
+    Sha1,
+    Sha256,
+    Blake3,
+    Xxh3,
+}
+
+/// A hash in progress, fed with a download's data as it is written.
+pub(crate) struct FileHasher {
+    state: HasherState,
+    size: u64,
+}
+
+enum HasherState {
+    Sha1(Sha1),
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+    Xxh3(Box<Xxh3>),
+}
+
+impl FileHasher {
+    pub(crate) fn new(algorithm: HashAlgorithm) -> Self {
+        let state = match algorithm {
+            HashAlgorithm::Sha1 => HasherState::Sha1(Sha1::new()),
+            HashAlgorithm::Sha256 => HasherState::Sha256(Sha256::new()),
+            HashAlgorithm::Blake3 => HasherState::Blake3(Box::default()),
+            HashAlgorithm::Xxh3 => HasherState::Xxh3(Box::default()),
+        };
+
+        Self { state, size: 0 }
+    }
+
+    /// Starts a hash with the contents of a partially downloaded file.
+    pub(crate) async fn resume(algorithm: HashAlgorithm, file: &Path) -> io::Result<Self> {
+        let mut hasher = Self::new(algorithm);
+
+        let mut file = match fs::File::open(file).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(hasher),
+            Err(e) => return Err(e),
+        };
+        let mut buffer = vec![0; 64 * 1024];
+
+        loop {
+            let read = file.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+
+            hasher.update(&buffer[..read]);
+        }
+
+        Ok(hasher)
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        match self.state {
+            HasherState::Sha1(ref mut hasher) => hasher.update(data),
+            HasherState::Sha256(ref mut hasher) => hasher.update(data),
+            HasherState::Blake3(ref mut hasher) => {
+                hasher.update(data);
+            }
+            HasherState::Xxh3(ref mut hasher) => hasher.update(data),
+        }
+        self.size += data.len() as u64;
+    }
+
+    pub(crate) fn finish(self) -> FileChecksum {
+        let (algorithm, hash) = match self.state {
+            HasherState::Sha1(hasher) => (HashAlgorithm::Sha1, format!("{:x}", hasher.finalize())),
+            HasherState::Sha256(hasher) => {
+                (HashAlgorithm::Sha256, format!("{:x}", hasher.finalize()))
+            }
+            HasherState::Blake3(hasher) => (
+                HashAlgorithm::Blake3,
+                hasher.finalize().to_hex().to_string(),
+            ),
+            HasherState::Xxh3(hasher) => {
+                (HashAlgorithm::Xxh3, format!("{:032x}", hasher.digest128()))
+            }
+        };
+
+        FileChecksum {
+            size: self.size,
+            algorithm,
+            hash,
+        }
+    }
+}
+
+/// The size and hash of a completed download.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, JsonSchema)]
+#[typeshare]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct FileChecksum {
+    #[typeshare(serialized_as = "number")]
+    pub(crate) size: u64,
+    /// Older states only recorded SHA-1 hashes.
+    #[serde(default)]
+    pub(crate) algorithm: HashAlgorithm,
+    #[serde(alias = "sha1")]
+    pub(crate) hash: String,
+}
+
+impl FileChecksum {
+    pub(crate) async fn calculate(file: &Path, algorithm: HashAlgorithm) -> io::Result<Self> {
+        let mut file = fs::File::open(file).await?;
+        let mut hasher = FileHasher::new(algorithm);
+        let mut buffer = vec![0; 64 * 1024];
+
+        loop {
+            let read = file.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+
+            hasher.update(&buffer[..read]);
+        }
+
+        Ok(hasher.finish())
+    }
 }
 
 impl<M> From<&Part<'_, M>> for VideoPartState
@@ -486,6 +975,10 @@ where
             size: metadata.size.unwrap(),
             duration: metadata.duration.unwrap(),
             download: Default::default(),
+            checksum: None,
+            tracks: None,
+            unpublished: false,
+            quarantined: false,
         }
     }
 }
@@ -505,14 +998,14 @@ where
     }
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
 #[serde(untagged)]
 pub(crate) enum VideoDetail {
     Movie(MovieDetail),
     Episode(EpisodeDetail),
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(tag = "state", rename_all = "lowercase")]
 pub(crate) enum PlaybackState {
     Unplayed,
@@ -520,7 +1013,7 @@ pub(crate) enum PlaybackState {
     Played,
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
 #[typeshare]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct VideoState {
@@ -528,18 +1021,32 @@ pub(crate) struct VideoState {
     pub(crate) title: String,
     pub(crate) detail: VideoDetail,
     #[typeshare(serialized_as = "string")]
+    #[schemars(with = "String")]
     pub(crate) air_date: Date,
     pub(crate) thumbnail: ThumbnailState,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub(crate) artwork: HashMap<ArtworkKind, ThumbnailState>,
     pub(crate) media_id: String,
     #[serde(with = "time::serde::timestamp")]
     #[typeshare(serialized_as = "number")]
+    #[schemars(with = "i64")]
     pub(crate) last_updated: OffsetDateTime,
     pub(crate) parts: Vec<VideoPartState>,
     pub(crate) transcode_profile: Option<String>,
     pub(crate) playback_state: PlaybackState,
     #[serde(default, with = "time::serde::timestamp::option")]
     #[typeshare(serialized_as = "Option<number>")]
+    #[schemars(with = "Option<i64>")]
     pub(crate) last_viewed_at: Option<OffsetDateTime>,
+    #[serde(default)]
+    pub(crate) skipped: bool,
+    /// The download was deleted to stay within the size limits. It is not
+    /// downloaded again until there is space for it.
+    #[serde(default)]
+    pub(crate) evicted: bool,
+    /// The sync items that include this video.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) sources: Vec<String>,
 }
 
 fn playback_state_from_metadata(metadata: &Metadata) -> PlaybackState {
@@ -552,22 +1059,81 @@ fn playback_state_from_metadata(metadata: &Metadata) -> PlaybackState {
     }
 }
 
+derive_artwork!(VideoState);
+
+/// Picks which of an item's media versions to sync. A version chosen by the
+/// user wins over the configured rules.
+pub(crate) fn choose_media<M: MediaItem>(
+    item: &M,
+    chosen: Option<&String>,
+    selection: &MediaSelection,
+) -> usize {
+    let media = item.media();
+
+    if let Some(chosen) = chosen {
+        if let Some(index) = media
+            .iter()
+            .position(|m| m.metadata().id.as_ref() == Some(chosen))
+        {
+            return index;
+        }
+
+        warn!(
+            video = item.rating_key(),
+            media = chosen,
+            "Chosen media version no longer exists"
+        );
+    }
+
+    let height = |index: &usize| media[*index].metadata().height.unwrap_or_default();
+    let size = |index: &usize| {
+        media[*index]
+            .parts()
+            .iter()
+            .map(|p| p.metadata().size.unwrap_or_default())
+            .sum::<u64>()
+    };
+
+    let mut candidates: Vec<usize> = (0..media.len()).collect();
+    if let Some(max_resolution) = selection.max_resolution {
+        let allowed: Vec<usize> = candidates
+            .iter()
+            .copied()
+            .filter(|index| height(index) <= max_resolution)
+            .collect();
+        if !allowed.is_empty() {
+            candidates = allowed;
+        }
+    }
+
+    let chosen = match selection.prefer {
+        MediaPreference::First => candidates.first().copied(),
+        MediaPreference::Highest => candidates.iter().copied().max_by_key(height),
+        MediaPreference::Lowest => candidates.iter().copied().min_by_key(height),
+        MediaPreference::Smallest => candidates.iter().copied().min_by_key(size),
+    };
+
+    chosen.unwrap_or_default()
+}
+
 impl VideoState {
-    pub(crate) fn movie_state(&self) -> &MovieDetail {
+    /// The movie's details or `None` if this is an episode.
+    pub(crate) fn movie_state(&self) -> Option<&MovieDetail> {
         match self.detail {
-            VideoDetail::Movie(ref m) => m,
-            VideoDetail::Episode(_) => panic!("Unexpected type"),
+            VideoDetail::Movie(ref m) => Some(m),
+            VideoDetail::Episode(_) => None,
         }
     }
 
-    pub(crate) fn episode_state(&self) -> &EpisodeDetail {
+    /// The episode's details or `None` if this is a movie.
+    pub(crate) fn episode_state(&self) -> Option<&EpisodeDetail> {
         match self.detail {
-            VideoDetail::Movie(_) => panic!("Unexpected type"),
-            VideoDetail::Episode(ref e) => e,
+            VideoDetail::Movie(_) => None,
+            VideoDetail::Episode(ref e) => Some(e),
         }
     }
 
-    pub(crate) fn from<M: MediaItem>(item: &M) -> Self {
+    pub(crate) fn from<M: MediaItem>(item: &M, media_index: usize) -> Self {
         let metadata = item.metadata();
         let detail = match metadata.metadata_type {
             Some(MetadataType::Movie) => VideoDetail::Movie(MovieDetail::from(metadata)),
@@ -575,7 +1141,7 @@ impl VideoState {
             _ => panic!("Unexpected video type: {:?}", metadata.metadata_type),
         };
 
-        let media = &item.media()[0];
+        let media = &item.media()[media_index];
         let parts: Vec<VideoPartState> = media.parts().iter().map(VideoPartState::from).collect();
 
         Self {
@@ -584,6 +1150,7 @@ impl VideoState {
             detail,
             air_date: metadata.originally_available_at.unwrap(),
             thumbnail: Default::default(),
+            artwork: Default::default(),
             media_id: media.metadata().id.clone().unwrap(),
             last_updated: metadata.updated_at.unwrap(),
             parts,
@@ -591,20 +1158,30 @@ impl VideoState {
             transcode_profile: None,
             playback_state: playback_state_from_metadata(metadata),
             last_viewed_at: metadata.last_viewed_at,
+            // Determined later
+            skipped: false,
+            evicted: false,
+            sources: Default::default(),
         }
     }
 
     pub(crate) async fn update<M: MediaItem + FromMetadata>(
         &mut self,
         item: &M,
+        media_index: usize,
         server: &Server,
         root: &Path,
+        storage: &dyn StorageBackend,
+        conflicts: &mut Conflicts,
+        dry_run: bool,
     ) {
         let metadata = item.metadata();
         self.title = item.title().to_owned();
 
         let server_state = playback_state_from_metadata(metadata);
-        if self.last_viewed_at == metadata.last_viewed_at {
+        if dry_run {
+            // Never modify the server's state in a dry run.
+        } else if self.last_viewed_at == metadata.last_viewed_at {
             // No server-side views since last sync.
             if server_state != self.playback_state {
                 match self.playback_state {
@@ -651,27 +1228,77 @@ impl VideoState {
         }
 
         if let Some(updated) = metadata.updated_at {
-            if updated > self.last_updated {
-                self.thumbnail.delete(root).await;
+            if updated > self.last_updated && !dry_run {
+                self.delete_artwork(root, storage).await;
             }
             self.last_updated = updated;
         }
 
-        let media = &item.media()[0];
+        let media = &item.media()[media_index];
         let parts = media.parts();
+        let media_id = media.metadata().id.clone().unwrap();
+
+        if media_id != self.media_id {
+            info!(
+                old_media = self.media_id,
+                new_media = media_id,
+                "Media version changed, deleting existing downloads."
+            );
+            if !dry_run {
+                for part in self.parts.iter_mut() {
+                    part.download.delete(Some(server), root, storage).await;
+                }
+            }
 
-        if parts.len() != self.parts.len() {
+            self.media_id = media_id;
+            self.parts = parts.iter().map(VideoPartState::from).collect()
+        } else if parts.len() != self.parts.len() {
             info!("Number of video parts changed, deleting existing downloads.");
-            for part in self.parts.iter_mut() {
-                part.download.delete(server, root).await;
+            if !dry_run {
+                for part in self.parts.iter_mut() {
+                    part.download.delete(Some(server), root, storage).await;
+                }
             }
 
             self.parts = parts.iter().map(VideoPartState::from).collect()
         } else {
+            let mut resolution: Option<Option<Resolution>> = None;
+
             for (part_state, part) in self.parts.iter_mut().zip(parts.iter()) {
                 let metadata = part.metadata();
 
                 if part_state != part {
+                    let resolution = *resolution.get_or_insert_with(|| {
+                        conflicts.resolve(Conflict::MediaReplaced {
+                            server: conflicts.server.clone(),
+                            video: self.id.clone(),
+                            title: self.title.clone(),
+                        })
+                    });
+
+                    let resolution = match resolution {
+                        Some(resolution) => resolution,
+                        // Left alone until the resolver has been asked.
+                        None => continue,
+                    };
+
+                    if resolution == Resolution::KeepExisting {
+                        info!(
+                            part = part_state.id,
+                            "Part changed, keeping existing download."
+                        );
+                        let download = part_state.download.clone();
+                        let checksum = part_state.checksum.take();
+                        let tracks = part_state.tracks.take();
+                        let unpublished = part_state.unpublished;
+                        *part_state = part.into();
+                        part_state.download = download;
+                        part_state.checksum = checksum;
+                        part_state.tracks = tracks;
+                        part_state.unpublished = unpublished;
+                        continue;
+                    }
+
                     info!(
                         old_id = part_state.id,
                         new_id = metadata.id,
@@ -684,27 +1311,35 @@ impl VideoState {
                         part = part_state.id,
                         "Part changed, deleting existing download."
                     );
-                    part_state.download.delete(server, root).await;
+                    if !dry_run {
+                        part_state
+                            .download
+                            .delete(Some(server), root, storage)
+                            .await;
+                    }
                     *part_state = part.into();
                 }
             }
         }
     }
 
-    pub(crate) async fn delete(&mut self, server: &Server, root: &Path) {
-        if self.thumbnail != ThumbnailState::None {
-            self.thumbnail.delete(root).await;
-        }
+    pub(crate) async fn delete(
+        &mut self,
+        server: &Server,
+        root: &Path,
+        storage: &dyn StorageBackend,
+    ) {
+        self.delete_artwork(root, storage).await;
 
         for part in self.parts.iter_mut() {
             if part.download != DownloadState::None {
-                part.download.delete(server, root).await;
+                part.download.delete(Some(server), root, storage).await;
             }
         }
     }
 }
 
-#[derive(Deserialize, Default, Serialize, Clone, Debug)]
+#[derive(Deserialize, Default, Serialize, Clone, Debug, JsonSchema)]
 #[typeshare]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct ServerState {
@@ -723,9 +1358,66 @@ pub(crate) struct ServerState {
     pub(crate) seasons: HashMap<String, SeasonState>,
     #[serde(default)]
     pub(crate) videos: HashMap<String, VideoState>,
+    /// When the server's items were last updated without any failures.
+    #[serde(
+        default,
+        with = "time::serde::timestamp::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[typeshare(serialized_as = "Option<number>")]
+    #[schemars(with = "Option<i64>")]
+    pub(crate) last_synced: Option<OffsetDateTime>,
+    /// The most recent syncs of the server, oldest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) sync_history: Vec<SyncRecord>,
+    /// The server's unique identifier, used to find it through Plex accounts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) machine_id: Option<String>,
+    /// When the server last rejected the stored token.
+    #[serde(
+        default,
+        with = "time::serde::timestamp::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[typeshare(serialized_as = "Option<number>")]
+    #[schemars(with = "Option<i64>")]
+    pub(crate) auth_failed: Option<OffsetDateTime>,
+    /// Details of the sync items as last fetched from the server.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub(crate) item_cache: HashMap<String, CachedItem>,
+    /// The server's capabilities as last seen when connecting.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) capabilities: Option<CachedCapabilities>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) preferred_connection: Option<PreferredConnection>,
+}
+
+/// The number of syncs to keep in each server's history.
+pub(crate) const SYNC_HISTORY_LENGTH: usize = 50;
+
+/// A summary of one sync of a server.
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[typeshare]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SyncRecord {
+    #[serde(with = "time::serde::timestamp")]
+    #[typeshare(serialized_as = "number")]
+    #[schemars(with = "i64")]
+    pub(crate) started: OffsetDateTime,
+    /// The length of the sync in seconds.
+    pub(crate) duration: u64,
+    /// The number of bytes downloaded.
+    pub(crate) bytes: u64,
+    pub(crate) errors: u32,
+    /// The id of the sync run.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) run: Option<String>,
+    /// The space used by the server's downloads once the sync finished.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) used: Option<u64>,
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
 #[typeshare]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct State {
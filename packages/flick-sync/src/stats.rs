@@ -0,0 +1,148 @@
+//! Aggregate statistics about the store, kept up to date as the state is
+//! written so that frontends can read them without walking every video.
+
+use std::collections::HashMap;
+
+use crate::{
+    server::SyncHistoryEntry,
+    state::{ServerState, State, VideoDetail, VideoPartState, VideoState},
+};
+
+/// Whether a part still counts as waiting to download. Parts of skipped videos
+/// are never downloaded so are not pending.
+pub(crate) fn is_pending(video: &VideoState, part: &VideoPartState) -> bool {
+    part.download.needs_download() && !video.skipped
+}
+
+/// Totals for a group of videos.
+#[derive(Default, Clone, Debug)]
+pub struct Totals {
+    pub videos: usize,
+    /// Videos with every part downloaded.
+    pub downloaded: usize,
+    /// Videos with parts still to download.
+    pub pending: usize,
+    /// Videos marked to be skipped.
+    pub skipped: usize,
+    /// The size of the downloaded parts.
+    pub downloaded_bytes: u64,
+    /// The size of the parts still to download, excluding skipped videos.
+    pub pending_bytes: u64,
+}
+
+impl Totals {
+    fn add(&mut self, video: &VideoState) {
+        self.videos += 1;
+
+        let mut complete = true;
+        for part in video.parts.iter() {
+            if part.download.needs_download() {
+                complete = false;
+                if is_pending(video, part) {
+                    self.pending_bytes += part.size;
+                }
+            } else {
+                self.downloaded_bytes += part.size;
+            }
+        }
+
+        if video.skipped {
+            self.skipped += 1;
+        } else if complete {
+            self.downloaded += 1;
+        } else {
+            self.pending += 1;
+        }
+    }
+}
+
+/// Statistics for a single server.
+#[derive(Default, Clone, Debug)]
+pub struct ServerStatistics {
+    pub totals: Totals,
+    /// Totals for each library, by title.
+    pub libraries: HashMap<String, Totals>,
+    /// Totals for each show, by title.
+    pub shows: HashMap<String, Totals>,
+    /// The recent syncs of the server, oldest first.
+    pub history: Vec<SyncHistoryEntry>,
+}
+
+impl ServerStatistics {
+    fn from(server_state: &ServerState) -> Self {
+        let mut statistics = Self {
+            history: server_state
+                .sync_history
+                .iter()
+                .map(SyncHistoryEntry::from)
+                .collect(),
+            ..Default::default()
+        };
+
+        for video in server_state.videos.values() {
+            statistics.totals.add(video);
+
+            let (library, show) = match video.detail {
+                VideoDetail::Movie(ref detail) => (Some(&detail.library), None),
+                VideoDetail::Episode(ref detail) => {
+                    match server_state
+                        .seasons
+                        .get(&detail.season)
+                        .and_then(|season| server_state.shows.get(&season.show))
+                    {
+                        Some(show) => (Some(&show.library), Some(show)),
+                        None => (None, None),
+                    }
+                }
+            };
+
+            if let Some(library) = library.and_then(|id| server_state.libraries.get(id)) {
+                statistics
+                    .libraries
+                    .entry(library.title.clone())
+                    .or_default()
+                    .add(video);
+            }
+
+            if let Some(show) = show {
+                statistics
+                    .shows
+                    .entry(show.title.clone())
+                    .or_default()
+                    .add(video);
+            }
+        }
+
+        statistics
+    }
+}
+
+/// Statistics for the whole store.
+#[derive(Default, Clone, Debug)]
+pub struct StoreStatistics {
+    pub totals: Totals,
+    /// Statistics for each server, by identifier.
+    pub servers: HashMap<String, ServerStatistics>,
+}
+
+impl StoreStatistics {
+    pub(crate) fn from(state: &State) -> Self {
+        let mut statistics = Self::default();
+
+        for (id, server_state) in state.servers.iter() {
+            let server = ServerStatistics::from(server_state);
+
+            let totals = &mut statistics.totals;
+            totals.videos += server.totals.videos;
+            totals.downloaded += server.totals.downloaded;
+            totals.pending += server.totals.pending;
+            totals.skipped += server.totals.skipped;
+            totals.downloaded_bytes += server.totals.downloaded_bytes;
+            totals.pending_bytes += server.totals.pending_bytes;
+
+            statistics.servers.insert(id.clone(), server);
+        }
+
+        statistics
+    }
+}
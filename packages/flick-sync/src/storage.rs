@@ -0,0 +1,455 @@
+//! Where the state is kept. The JSON file is simple to inspect but is
+//! rewritten in full on every change, the SQLite database keeps each video as
+//! a separate record so a change to a single video only serializes and writes
+//! that video. The mobile app only reads the JSON file.
+
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use async_std::{
+    fs::{copy, metadata, read_to_string, remove_file, rename, File},
+    io::WriteExt,
+    task::spawn_blocking,
+};
+use async_trait::async_trait;
+use rusqlite::{params, Connection};
+use serde_json::{from_str, from_value, to_string, to_string_pretty, to_value, Map, Value};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    config::StateBackend, read_or_default, state::State, Error, Result, STATE_BACKUP_FILE,
+    STATE_DATABASE, STATE_FILE, STATE_TEMP_FILE,
+};
+
+/// The journal SQLite writes beside the database during a transaction.
+const STATE_DATABASE_JOURNAL: &str = ".flicksync.state.db-journal";
+
+#[async_trait]
+pub(crate) trait StateStore: Send + Sync {
+    /// Whether the state has been written to this store before.
+    async fn exists(&self, root: &Path) -> bool;
+
+    /// Reads the state, recovering what it can if the store is damaged.
+    async fn load(&self, root: &Path) -> Result<State>;
+
+    /// Reads the state without any recovery.
+    async fn check(&self, root: &Path) -> Result;
+
+    async fn save(&self, root: &Path, state: &State) -> Result;
+
+    /// Saves the state after a change that only touched a single video.
+    async fn save_video(&self, root: &Path, state: &State, _server: &str, _video: &str) -> Result {
+        self.save(root, state).await
+    }
+
+    /// Removes the store once its state has been migrated elsewhere.
+    async fn retire(&self, root: &Path) -> Result;
+
+    /// The files this store keeps in the root of the store, the first is the
+    /// main file that holds the state.
+    fn files(&self) -> &'static [&'static str];
+}
+
+impl StateBackend {
+    fn store(&self) -> Box<dyn StateStore> {
+        match self {
+            Self::Json => Box::new(JsonStore),
+            Self::Sqlite => Box::new(SqliteStore::default()),
+        }
+    }
+}
+
+/// Opens the configured store and reads the state, migrating it from the
+/// other backend if it has only been written there.
+pub(crate) async fn open_store(
+    root: &Path,
+    backend: StateBackend,
+) -> Result<(Box<dyn StateStore>, State)> {
+    let store = backend.store();
+
+    if !store.exists(root).await {
+        for previous_backend in [StateBackend::Json, StateBackend::Sqlite] {
+            if previous_backend == backend {
+                continue;
+            }
+
+            let previous = previous_backend.store();
+            if previous.exists(root).await {
+                info!(from=?previous_backend, to=?backend, "Migrating the state");
+
+                if backend == StateBackend::Sqlite {
+                    warn!("The mobile app cannot read the SQLite state, it will not be able to open this store");
+                }
+
+                let state = previous.load(root).await?;
+                store.save(root, &state).await?;
+                previous.retire(root).await?;
+
+                return Ok((store, state));
+            }
+        }
+    }
+
+    let state = store.load(root).await?;
+    Ok((store, state))
+}
+
+/// Checks that the state can be read from whichever backend holds it without
+/// migrating it. A store with no state yet passes.
+pub(crate) async fn check_state(root: &Path, backend: StateBackend) -> Result {
+    for backend in [backend, StateBackend::Json, StateBackend::Sqlite] {
+        let store = backend.store();
+        if store.exists(root).await {
+            return store.check(root).await;
+        }
+    }
+
+    Ok(())
+}
+
+/// The state in a single JSON file with a backup of the previous version.
+struct JsonStore;
+
+#[async_trait]
+impl StateStore for JsonStore {
+    async fn exists(&self, root: &Path) -> bool {
+        metadata(root.join(STATE_FILE)).await.is_ok()
+    }
+
+    /// Falls back to the backup of the previous state if the state cannot be
+    /// read.
+    async fn load(&self, root: &Path) -> Result<State> {
+        let target = root.join(STATE_FILE);
+
+        let err = match read_to_string(&target).await {
+            Ok(str) => match from_str::<State>(&str) {
+                Ok(state) => return Ok(state),
+                Err(e) => Error::from(e),
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => return read_or_default(&target).await,
+            Err(e) => Error::from(e),
+        };
+
+        error!(error = ?err, "Failed to read the state");
+
+        match read_to_string(root.join(STATE_BACKUP_FILE)).await {
+            Ok(str) => match from_str::<State>(&str) {
+                Ok(state) => {
+                    warn!("Using the backup of the previous state, recent changes may be lost");
+                    Ok(state)
+                }
+                Err(e) => {
+                    error!(error = ?e, "Failed to read the state backup");
+                    Ok(Default::default())
+                }
+            },
+            Err(e) => {
+                if e.kind() != ErrorKind::NotFound {
+                    error!(error = ?e, "Failed to read the state backup");
+                }
+                Ok(Default::default())
+            }
+        }
+    }
+
+    async fn check(&self, root: &Path) -> Result {
+        let str = read_to_string(root.join(STATE_FILE)).await?;
+        from_str::<State>(&str)?;
+
+        Ok(())
+    }
+
+    async fn save(&self, root: &Path, state: &State) -> Result {
+        let str = to_string_pretty(state)?;
+        let temp = root.join(STATE_TEMP_FILE);
+
+        // Write the new state beside the old so a crash part way through
+        // leaves the previous state intact.
+        let mut file = File::create(&temp).await?;
+        file.write_all(str.as_bytes()).await?;
+        file.sync_all().await?;
+        drop(file);
+
+        let target = root.join(STATE_FILE);
+        match copy(&target, root.join(STATE_BACKUP_FILE)).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => warn!(error=?e, "Failed to back up the previous state"),
+        }
+
+        rename(&temp, &target).await?;
+
+        Ok(())
+    }
+
+    /// The state is kept as the backup so it can still be recovered by hand.
+    async fn retire(&self, root: &Path) -> Result {
+        rename(root.join(STATE_FILE), root.join(STATE_BACKUP_FILE)).await?;
+        Ok(())
+    }
+
+    fn files(&self) -> &'static [&'static str] {
+        &[STATE_FILE, STATE_BACKUP_FILE, STATE_TEMP_FILE]
+    }
+}
+
+/// Identifies a record in the database. The top level of the state has an
+/// empty server and video, each server's state has an empty video.
+type RecordKey = (String, String);
+
+/// The state in an SQLite database, split into a record for the top level of
+/// the state, one for each server and one for each video.
+#[derive(Default)]
+struct SqliteStore {
+    /// The records as last read or written, used to only write the records
+    /// that have changed.
+    written: Mutex<HashMap<RecordKey, String>>,
+}
+
+fn connect(root: &Path) -> rusqlite::Result<Connection> {
+    let connection = Connection::open(root.join(STATE_DATABASE))?;
+    connection.execute(
+        "CREATE TABLE IF NOT EXISTS records (
+            server TEXT NOT NULL,
+            video TEXT NOT NULL,
+            value TEXT NOT NULL,
+            PRIMARY KEY (server, video)
+        )",
+        [],
+    )?;
+
+    Ok(connection)
+}
+
+fn read_records(root: PathBuf) -> rusqlite::Result<HashMap<RecordKey, String>> {
+    let connection = connect(&root)?;
+    let mut statement = connection.prepare("SELECT server, video, value FROM records")?;
+    let records = statement
+        .query_map([], |row| Ok(((row.get(0)?, row.get(1)?), row.get(2)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    Ok(records)
+}
+
+fn write_records(
+    root: PathBuf,
+    changed: Vec<(RecordKey, String)>,
+    removed: Vec<RecordKey>,
+) -> rusqlite::Result<()> {
+    let mut connection = connect(&root)?;
+    let transaction = connection.transaction()?;
+
+    {
+        let mut upsert = transaction.prepare(
+            "INSERT INTO records (server, video, value) VALUES (?1, ?2, ?3)
+            ON CONFLICT (server, video) DO UPDATE SET value = excluded.value",
+        )?;
+        for ((server, video), value) in changed.iter() {
+            upsert.execute(params![server, video, value])?;
+        }
+
+        let mut delete =
+            transaction.prepare("DELETE FROM records WHERE server = ?1 AND video = ?2")?;
+        for (server, video) in removed.iter() {
+            delete.execute(params![server, video])?;
+        }
+    }
+
+    transaction.commit()
+}
+
+/// Splits the state into the records stored in the database.
+fn split_state(state: &State) -> Result<HashMap<RecordKey, String>> {
+    let mut records = HashMap::new();
+
+    let mut root = to_value(state)?;
+    let servers = match root.as_object_mut().and_then(|root| root.remove("servers")) {
+        Some(Value::Object(servers)) => servers,
+        _ => Map::new(),
+    };
+
+    for (server_id, mut server) in servers {
+        if let Some(Value::Object(videos)) = server
+            .as_object_mut()
+            .and_then(|server| server.remove("videos"))
+        {
+            for (video_id, video) in videos {
+                records.insert((server_id.clone(), video_id), to_string(&video)?);
+            }
+        }
+
+        records.insert((server_id, String::new()), to_string(&server)?);
+    }
+
+    records.insert((String::new(), String::new()), to_string(&root)?);
+
+    Ok(records)
+}
+
+/// Reassembles the state from the records stored in the database.
+fn join_state(records: &HashMap<RecordKey, String>) -> Result<State> {
+    let mut root = Map::new();
+    let mut servers: HashMap<&str, Map<String, Value>> = HashMap::new();
+    let mut videos: HashMap<&str, Map<String, Value>> = HashMap::new();
+
+    for ((server, video), value) in records.iter() {
+        let value: Value = from_str(value)?;
+
+        if server.is_empty() {
+            if let Value::Object(value) = value {
+                root = value;
+            }
+        } else if video.is_empty() {
+            if let Value::Object(value) = value {
+                servers.insert(server.as_str(), value);
+            }
+        } else {
+            videos
+                .entry(server.as_str())
+                .or_default()
+                .insert(video.clone(), value);
+        }
+    }
+
+    let servers: Map<String, Value> = servers
+        .into_iter()
+        .map(|(id, mut server)| {
+            let server_videos = videos.remove(id).unwrap_or_default();
+            server.insert("videos".to_owned(), Value::Object(server_videos));
+            (id.to_owned(), Value::Object(server))
+        })
+        .collect();
+
+    if !videos.is_empty() {
+        warn!("Ignoring stored videos for servers that no longer exist");
+    }
+
+    root.insert("servers".to_owned(), Value::Object(servers));
+
+    Ok(from_value(Value::Object(root))?)
+}
+
+impl SqliteStore {
+    async fn read(&self, root: &Path) -> Result<(State, HashMap<RecordKey, String>)> {
+        let records = spawn_blocking({
+            let root = root.to_owned();
+            move || read_records(root)
+        })
+        .await?;
+
+        Ok((join_state(&records)?, records))
+    }
+}
+
+#[async_trait]
+impl StateStore for SqliteStore {
+    async fn exists(&self, root: &Path) -> bool {
+        metadata(root.join(STATE_DATABASE)).await.is_ok()
+    }
+
+    /// SQLite writes each change in a transaction so, unlike the JSON file,
+    /// there is no backup to fall back to. A database that cannot be read is
+    /// an error rather than being replaced with an empty state.
+    async fn load(&self, root: &Path) -> Result<State> {
+        if !self.exists(root).await {
+            let state = State::default();
+            self.save(root, &state).await?;
+            return Ok(state);
+        }
+
+        let (state, records) = self.read(root).await?;
+        *self.written.lock().unwrap() = records;
+
+        Ok(state)
+    }
+
+    async fn check(&self, root: &Path) -> Result {
+        self.read(root).await?;
+        Ok(())
+    }
+
+    async fn save(&self, root: &Path, state: &State) -> Result {
+        let records = split_state(state)?;
+
+        let (changed, removed) = {
+            let written = self.written.lock().unwrap();
+
+            let changed: Vec<(RecordKey, String)> = records
+                .iter()
+                .filter(|(key, value)| written.get(*key) != Some(*value))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect();
+            let removed: Vec<RecordKey> = written
+                .keys()
+                .filter(|key| !records.contains_key(*key))
+                .cloned()
+                .collect();
+
+            (changed, removed)
+        };
+
+        if changed.is_empty() && removed.is_empty() {
+            return Ok(());
+        }
+
+        debug!(
+            changed = changed.len(),
+            removed = removed.len(),
+            "Writing state records"
+        );
+
+        spawn_blocking({
+            let root = root.to_owned();
+            move || write_records(root, changed, removed)
+        })
+        .await?;
+
+        *self.written.lock().unwrap() = records;
+
+        Ok(())
+    }
+
+    /// Only the record for the video is serialized and compared with what was
+    /// last written.
+    async fn save_video(&self, root: &Path, state: &State, server: &str, video: &str) -> Result {
+        let Some(video_state) = state
+            .servers
+            .get(server)
+            .and_then(|server_state| server_state.videos.get(video))
+        else {
+            return self.save(root, state).await;
+        };
+
+        let key: RecordKey = (server.to_owned(), video.to_owned());
+        let value = to_string(video_state)?;
+
+        if self.written.lock().unwrap().get(&key) == Some(&value) {
+            return Ok(());
+        }
+
+        spawn_blocking({
+            let root = root.to_owned();
+            let changed = vec![(key.clone(), value.clone())];
+            move || write_records(root, changed, Vec::new())
+        })
+        .await?;
+
+        self.written.lock().unwrap().insert(key, value);
+
+        Ok(())
+    }
+
+    async fn retire(&self, root: &Path) -> Result {
+        remove_file(root.join(STATE_DATABASE)).await?;
+        self.written.lock().unwrap().clear();
+        Ok(())
+    }
+
+    fn files(&self) -> &'static [&'static str] {
+        &[STATE_DATABASE, STATE_DATABASE_JOURNAL]
+    }
+}
@@ -0,0 +1,398 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use tracing::error;
+
+use crate::{util::safe, Error};
+
+pub(crate) const DEFAULT_MOVIE_TEMPLATE: &str =
+    "{library}/{title}[ ({year})]/{title}[ ({year})]{part}.{ext}";
+pub(crate) const DEFAULT_EPISODE_TEMPLATE: &str =
+    "{library}/{show}[ ({show_year})]/S{season:02}E{episode:02} - {title}{part}.{ext}";
+pub(crate) const PLEX_EPISODE_TEMPLATE: &str = "{library}/{show}[ ({show_year})]/Season {season:02}/{show}[ ({show_year})] - s{season:02}e{episode:02} - {title}{part}.{ext}";
+
+pub(crate) enum Value {
+    Text(String),
+    Number(u32),
+    /// A value that is unknown for this item.
+    Missing,
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::Text(value.to_owned())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::Text(value)
+    }
+}
+
+impl From<u32> for Value {
+    fn from(value: u32) -> Self {
+        Value::Number(value)
+    }
+}
+
+/// A year value where zero means the year is unknown.
+pub(crate) fn year(year: u32) -> Value {
+    if year == 0 {
+        Value::Missing
+    } else {
+        Value::Number(year)
+    }
+}
+
+/// The name of a directory for a title, including the year when known.
+pub(crate) fn title_with_year(title: &str, year: u32) -> String {
+    if year == 0 {
+        title.to_owned()
+    } else {
+        format!("{title} ({year})")
+    }
+}
+
+/// Applies a filter such as `slug` or `truncate(40)` to a rendered value.
+fn apply_filter(value: String, filter: &str) -> Result<String, Error> {
+    let (name, argument) = match filter.split_once('(') {
+        Some((name, rest)) => match rest.strip_suffix(')') {
+            Some(argument) => (name.trim(), Some(argument.trim())),
+            None => {
+                return Err(Error::InvalidTemplate(format!(
+                    "Unterminated arguments in '{filter}'"
+                )))
+            }
+        },
+        None => (filter.trim(), None),
+    };
+
+    let length = || {
+        argument
+            .and_then(|a| a.parse::<usize>().ok())
+            .ok_or_else(|| Error::InvalidTemplate(format!("Expected a length for '{name}'")))
+    };
+
+    match name {
+        "lower" => Ok(value.to_lowercase()),
+        "upper" => Ok(value.to_uppercase()),
+        "slug" => Ok(value
+            .to_lowercase()
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .collect::<Vec<&str>>()
+            .join("-")),
+        "truncate" => {
+            let length = length()?;
+            if value.chars().count() <= length {
+                Ok(value)
+            } else {
+                Ok(value
+                    .chars()
+                    .take(length)
+                    .collect::<String>()
+                    .trim_end()
+                    .to_owned())
+            }
+        }
+        "pad" => Ok(format!("{value:0>width$}", width = length()?)),
+        _ => Err(Error::InvalidTemplate(format!("Unknown function '{name}'"))),
+    }
+}
+
+/// Renders a single placeholder, e.g. `year`, `season:02` or
+/// `title|slug|truncate(20)`. Returns `None` when the value is missing, that
+/// is empty or unknown.
+fn render_placeholder(
+    placeholder: &str,
+    values: &HashMap<&str, Value>,
+) -> Result<Option<String>, Error> {
+    let mut filters = placeholder.split('|');
+    let head = filters.next().unwrap_or_default().trim();
+
+    let (name, width) = match head.split_once(':') {
+        Some((name, width)) => (
+            name,
+            width.parse::<usize>().map_err(|_| {
+                Error::InvalidTemplate(format!("Invalid width in '{{{placeholder}}}'"))
+            })?,
+        ),
+        None => (head, 0),
+    };
+    let name = name.trim_end_matches('?');
+
+    let mut rendered = match values.get(name) {
+        Some(Value::Missing) => return Ok(None),
+        Some(Value::Text(text)) if text.is_empty() => return Ok(None),
+        Some(Value::Text(text)) => text.clone(),
+        Some(Value::Number(number)) => format!("{number:0width$}"),
+        None => {
+            return Err(Error::InvalidTemplate(format!(
+                "Unknown placeholder '{name}'"
+            )))
+        }
+    };
+
+    for filter in filters {
+        rendered = apply_filter(rendered, filter)?;
+    }
+
+    Ok(Some(rendered))
+}
+
+/// Reads characters up to the matching `close`, allowing nesting.
+fn take_until(
+    chars: &mut std::str::Chars<'_>,
+    open: char,
+    close: char,
+    component: &str,
+) -> Result<String, Error> {
+    let mut taken = String::new();
+    let mut depth = 0;
+
+    loop {
+        match chars.next() {
+            Some(c) if c == close && depth == 0 => return Ok(taken),
+            Some(c) => {
+                if c == open {
+                    depth += 1;
+                } else if c == close {
+                    depth -= 1;
+                }
+                taken.push(c);
+            }
+            None => {
+                return Err(Error::InvalidTemplate(format!(
+                    "Unterminated '{open}' in '{component}'"
+                )))
+            }
+        }
+    }
+}
+
+/// Renders text containing placeholders and optional `[...]` sections.
+/// Missing values render as nothing, except that within a section they cause
+/// the whole section to be left out, returning `None`, unless the placeholder
+/// is marked with `?`.
+fn render_text(
+    text: &str,
+    values: &HashMap<&str, Value>,
+    in_section: bool,
+) -> Result<Option<String>, Error> {
+    let mut result = String::new();
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' => {
+                let placeholder = take_until(&mut chars, '{', '}', text)?;
+                let optional = placeholder
+                    .split(['|', ':'])
+                    .next()
+                    .unwrap_or_default()
+                    .ends_with('?');
+
+                match render_placeholder(&placeholder, values)? {
+                    Some(value) => result.push_str(&value),
+                    None if in_section && !optional => return Ok(None),
+                    None => {}
+                }
+            }
+            '[' => {
+                let section = take_until(&mut chars, '[', ']', text)?;
+                if let Some(rendered) = render_text(&section, values, true)? {
+                    result.push_str(&rendered);
+                }
+            }
+            c => result.push(c),
+        }
+    }
+
+    Ok(Some(result))
+}
+
+fn render_component(component: &str, values: &HashMap<&str, Value>) -> Result<String, Error> {
+    render_text(component, values, false).map(Option::unwrap_or_default)
+}
+
+/// Renders a naming template such as `{show}/S{season:02}E{episode:02}.{ext}`
+/// into a relative path. Sections in square brackets, e.g. `[ ({year})]`, are
+/// left out when a value within them is missing and values can be passed
+/// through `slug`, `lower`, `upper`, `truncate(n)` or `pad(n)`, e.g.
+/// `{title|truncate(40)}`. Each `/` separated component is made safe for the
+/// filesystem separately.
+pub(crate) fn render(template: &str, values: &HashMap<&str, Value>) -> Result<PathBuf, Error> {
+    let mut path = PathBuf::new();
+
+    for component in template.split('/') {
+        let component = render_component(component, values)?;
+        if !component.is_empty() {
+            path.push(safe(component));
+        }
+    }
+
+    if path.as_os_str().is_empty() {
+        return Err(Error::InvalidTemplate(format!(
+            "'{template}' produces an empty path"
+        )));
+    }
+
+    Ok(path)
+}
+
+/// Renders the configured template, falling back to the default if it is
+/// invalid.
+pub(crate) fn render_or_default(
+    template: Option<&str>,
+    default: &str,
+    values: &HashMap<&str, Value>,
+) -> Result<PathBuf, Error> {
+    if let Some(template) = template {
+        match render(template, values) {
+            Ok(path) => return Ok(path),
+            Err(e) => error!(error=?e, template, "Invalid naming template, using the default"),
+        }
+    }
+
+    render(default, values)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, path::PathBuf};
+
+    use super::{
+        render, render_or_default, year, Value, DEFAULT_EPISODE_TEMPLATE, DEFAULT_MOVIE_TEMPLATE,
+        PLEX_EPISODE_TEMPLATE,
+    };
+
+    fn movie(title: &str, movie_year: u32) -> HashMap<&'static str, Value> {
+        HashMap::from([
+            ("library", "Movies".into()),
+            ("title", title.into()),
+            ("year", year(movie_year)),
+            ("part", "".into()),
+            ("ext", "mkv".into()),
+        ])
+    }
+
+    fn episode() -> HashMap<&'static str, Value> {
+        HashMap::from([
+            ("library", "TV Shows".into()),
+            ("show", "Doctor Who".into()),
+            ("show_year", year(2005)),
+            ("season", 1.into()),
+            ("episode", 3.into()),
+            ("title", "The Unquiet Dead".into()),
+            ("part", " - pt1".into()),
+            ("ext", "mp4".into()),
+        ])
+    }
+
+    fn rendered(template: &str, values: &HashMap<&str, Value>) -> String {
+        render(template, values)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    #[test]
+    fn defaults() {
+        assert_eq!(
+            rendered(DEFAULT_MOVIE_TEMPLATE, &movie("Alien", 1979)),
+            "Movies/Alien (1979)/Alien (1979).mkv"
+        );
+        assert_eq!(
+            rendered(DEFAULT_MOVIE_TEMPLATE, &movie("Alien", 0)),
+            "Movies/Alien/Alien.mkv"
+        );
+        assert_eq!(
+            rendered(DEFAULT_EPISODE_TEMPLATE, &episode()),
+            "TV Shows/Doctor Who (2005)/S01E03 - The Unquiet Dead - pt1.mp4"
+        );
+        assert_eq!(
+            rendered(PLEX_EPISODE_TEMPLATE, &episode()),
+            "TV Shows/Doctor Who (2005)/Season 01/Doctor Who (2005) - s01e03 - The Unquiet Dead - pt1.mp4"
+        );
+    }
+
+    #[test]
+    fn sections() {
+        let values = movie("Alien", 0);
+
+        assert_eq!(rendered("{title}[ - {year}].{ext}", &values), "Alien.mkv");
+        assert_eq!(
+            rendered("{title}[ - {year?}].{ext}", &values),
+            "Alien - .mkv"
+        );
+        assert_eq!(
+            rendered("{title}[ [{year}]{part?}].{ext}", &values),
+            "Alien .mkv"
+        );
+        // Missing values outside of a section render as nothing.
+        assert_eq!(rendered("{title}{year}.{ext}", &values), "Alien.mkv");
+    }
+
+    #[test]
+    fn filters() {
+        let values = movie("The Good, the Bad & the Ugly", 1966);
+
+        assert_eq!(
+            rendered("{title|slug}.{ext}", &values),
+            "the-good-the-bad-the-ugly.mkv"
+        );
+        assert_eq!(
+            rendered("{title|upper|truncate(9)}.{ext}", &values),
+            "THE GOOD,.mkv"
+        );
+        assert_eq!(rendered("{title|lower|truncate(4)}", &values), "the");
+        assert_eq!(rendered("{year|pad(6)}", &values), "001966");
+        assert_eq!(rendered("{year:6}", &values), "001966");
+    }
+
+    #[test]
+    fn components() {
+        let values = movie("AC/DC: Live", 1992);
+
+        // Values cannot introduce new directories and empty components are
+        // dropped.
+        assert_eq!(
+            render("{library}//{part}/{title}.{ext}", &values).unwrap(),
+            PathBuf::from("Movies").join("AC_DC_ Live.mkv")
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        let values = movie("Alien", 1979);
+
+        for template in [
+            "{name}.{ext}",
+            "{title.{ext}",
+            "{title}[ ({year})",
+            "{title|reverse}",
+            "{title|truncate}",
+            "{title|truncate(4}",
+            "{year:wide}",
+            "{part}",
+        ] {
+            assert!(render(template, &values).is_err(), "{template}");
+        }
+    }
+
+    #[test]
+    fn fallback() {
+        let values = movie("Alien", 1979);
+
+        assert_eq!(
+            render_or_default(Some("{title}.{ext}"), DEFAULT_MOVIE_TEMPLATE, &values).unwrap(),
+            PathBuf::from("Alien.mkv")
+        );
+        assert_eq!(
+            render_or_default(Some("{name}.{ext}"), DEFAULT_MOVIE_TEMPLATE, &values).unwrap(),
+            PathBuf::from("Movies/Alien (1979)/Alien (1979).mkv")
+        );
+        assert!(render_or_default(None, "{part}", &values).is_err());
+    }
+}
@@ -0,0 +1,207 @@
+//! Transcoding of downloaded files with a local copy of ffmpeg, used instead
+//! of server transcode sessions for servers with little CPU to spare.
+
+use std::{
+    ffi::OsString,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use async_std::task::spawn_blocking;
+use plex_api::media_container::server::library::{AudioCodec, ContainerFormat};
+use tracing::{debug, trace};
+
+use crate::{
+    config::{H264Profile, HardwareAcceleration, TranscodeProfile},
+    Error, Result,
+};
+
+const DEFAULT_FFMPEG: &str = "ffmpeg";
+const DEFAULT_VAAPI_DEVICE: &str = "/dev/dri/renderD128";
+
+/// Arguments placed before the input to set up hardware decoding.
+fn hardware_arguments(profile: &TranscodeProfile) -> Vec<OsString> {
+    match profile.hardware_acceleration {
+        Some(HardwareAcceleration::Vaapi) => vec![
+            "-vaapi_device".into(),
+            profile
+                .hardware_device
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_VAAPI_DEVICE))
+                .into(),
+        ],
+        Some(HardwareAcceleration::Nvenc) => vec!["-hwaccel".into(), "cuda".into()],
+        Some(HardwareAcceleration::VideoToolbox) => {
+            vec!["-hwaccel".into(), "videotoolbox".into()]
+        }
+        None => Vec::new(),
+    }
+}
+
+fn video_encoder(profile: &TranscodeProfile) -> &'static str {
+    match profile.hardware_acceleration {
+        Some(HardwareAcceleration::Vaapi) => "h264_vaapi",
+        Some(HardwareAcceleration::Nvenc) => "h264_nvenc",
+        Some(HardwareAcceleration::VideoToolbox) => "h264_videotoolbox",
+        None => "libx264",
+    }
+}
+
+/// The container a local transcode produces, the first of the profile's
+/// containers that ffmpeg is asked to write.
+pub(crate) fn container(profile: &TranscodeProfile) -> ContainerFormat {
+    profile
+        .containers
+        .iter()
+        .flatten()
+        .find(|c| matches!(c, ContainerFormat::Mkv | ContainerFormat::Mp4))
+        .cloned()
+        .unwrap_or(ContainerFormat::Mkv)
+}
+
+/// Builds the ffmpeg arguments for a profile. Video is always encoded as
+/// H.264, on the GPU if the profile enables hardware acceleration, along with
+/// the chosen audio track. Subtitles are copied into mkv files and dropped from
+/// mp4 files.
+fn arguments(
+    profile: &TranscodeProfile,
+    audio_track: usize,
+    input: &Path,
+    output: &Path,
+) -> Vec<OsString> {
+    let options = profile.options();
+    let container = container(profile);
+
+    let mut args: Vec<String> = vec![
+        "-map".to_string(),
+        "0:v:0".to_string(),
+        "-map".to_string(),
+        format!("0:a:{audio_track}?"),
+        "-c:v".to_string(),
+        video_encoder(profile).to_string(),
+    ];
+
+    if let Some(ref profiles) = profile.h264_profiles {
+        let h264_profile = [H264Profile::High, H264Profile::Main, H264Profile::Baseline]
+            .into_iter()
+            .find(|p| profiles.contains(p));
+        if let Some(h264_profile) = h264_profile {
+            args.push("-profile:v".to_string());
+            args.push(h264_profile.to_string());
+        }
+    }
+
+    // VAAPI frames are scaled in software and then uploaded to the GPU.
+    let upload = match profile.hardware_acceleration {
+        Some(HardwareAcceleration::Vaapi) => ",format=nv12,hwupload",
+        _ => "",
+    };
+
+    args.extend([
+        "-b:v".to_string(),
+        format!("{}k", options.bitrate),
+        "-maxrate".to_string(),
+        format!("{}k", options.bitrate),
+        "-bufsize".to_string(),
+        format!("{}k", options.bitrate * 2),
+        "-vf".to_string(),
+        format!(
+            "scale=w={}:h={}:force_original_aspect_ratio=decrease:force_divisible_by=2{upload}",
+            options.width, options.height
+        ),
+        "-c:a".to_string(),
+    ]);
+
+    if options
+        .audio_codecs
+        .iter()
+        .any(|codec| matches!(codec, AudioCodec::Aac))
+    {
+        args.push("aac".to_string());
+    } else {
+        args.push("libmp3lame".to_string());
+    }
+    if let Some(channels) = profile.audio_channels {
+        args.push("-ac".to_string());
+        args.push(channels.to_string());
+    }
+
+    let format = if matches!(container, ContainerFormat::Mp4) {
+        ["-sn", "-movflags", "+faststart", "-f", "mp4"].as_slice()
+    } else {
+        ["-map", "0:s?", "-c:s", "copy", "-f", "matroska"].as_slice()
+    };
+    args.extend(format.iter().map(|arg| arg.to_string()));
+
+    let mut command: Vec<OsString> = ["-nostdin", "-y", "-hide_banner", "-loglevel", "error"]
+        .into_iter()
+        .map(OsString::from)
+        .collect();
+    command.extend(hardware_arguments(profile));
+    command.push("-i".into());
+    command.push(input.into());
+    command.extend(args.into_iter().map(OsString::from));
+    command.push(output.into());
+    command
+}
+
+/// Transcodes `input` into `output` according to the profile, waiting for
+/// ffmpeg to complete.
+pub(crate) async fn transcode(
+    ffmpeg: Option<PathBuf>,
+    profile: &TranscodeProfile,
+    audio_track: usize,
+    input: &Path,
+    output: &Path,
+) -> Result {
+    let ffmpeg = ffmpeg.unwrap_or_else(|| PathBuf::from(DEFAULT_FFMPEG));
+    let args = arguments(profile, audio_track, input, output);
+    debug!(?ffmpeg, ?input, ?output, "Starting local transcode");
+    trace!(?args);
+
+    let result = spawn_blocking(move || Command::new(&ffmpeg).args(args).output()).await;
+
+    match result {
+        Ok(output) if output.status.success() => Ok(()),
+        Ok(output) => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let message = stderr
+                .lines()
+                .rev()
+                .find(|line| !line.trim().is_empty())
+                .map(|line| line.trim().to_owned())
+                .unwrap_or_else(|| format!("ffmpeg exited with {}", output.status));
+            Err(Error::LocalTranscodeFailed(message))
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => Err(Error::LocalTranscodeFailed(
+            "ffmpeg could not be found".to_string(),
+        )),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Runs `ffmpeg -version` to check that ffmpeg is available, returning the
+/// first line of its output.
+pub(crate) async fn version(ffmpeg: Option<PathBuf>) -> Result<String> {
+    let ffmpeg = ffmpeg.unwrap_or_else(|| PathBuf::from(DEFAULT_FFMPEG));
+
+    let result = spawn_blocking(move || Command::new(&ffmpeg).arg("-version").output()).await;
+
+    match result {
+        Ok(output) if output.status.success() => Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_owned()),
+        Ok(output) => Err(Error::LocalTranscodeFailed(format!(
+            "ffmpeg exited with {}",
+            output.status
+        ))),
+        Err(e) if e.kind() == ErrorKind::NotFound => Err(Error::LocalTranscodeFailed(
+            "ffmpeg could not be found".to_string(),
+        )),
+        Err(e) => Err(e.into()),
+    }
+}
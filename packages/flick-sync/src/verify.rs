@@ -0,0 +1,79 @@
+//! Verification of completed downloads with an external command, such as a
+//! virus scanner, before they are accepted into the store.
+
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use async_std::{
+    fs::{create_dir_all, rename},
+    task::spawn_blocking,
+};
+use tracing::{debug, warn};
+
+use crate::{Error, Result};
+
+/// The directory within the store that rejected downloads are moved to.
+pub const QUARANTINE_DIR: &str = "flicksync-quarantine";
+
+/// Runs the verification command with the file's path as its final argument,
+/// failing unless the command exits successfully.
+pub(crate) async fn verify(command: &[String], path: &Path) -> Result {
+    let (program, args) = match command.split_first() {
+        Some(parts) => parts,
+        None => return Ok(()),
+    };
+
+    let program = program.clone();
+    let mut args = args.to_vec();
+    args.push(path.to_string_lossy().into_owned());
+    debug!(?program, ?args, "Verifying download");
+
+    let result = spawn_blocking(move || Command::new(&program).args(args).output()).await;
+
+    match result {
+        Ok(output) if output.status.success() => Ok(()),
+        Ok(output) => {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let message = stderr
+                .lines()
+                .chain(stdout.lines())
+                .rev()
+                .find(|line| !line.trim().is_empty())
+                .map(|line| line.trim().to_owned())
+                .unwrap_or_else(|| format!("verification exited with {}", output.status));
+            Err(Error::VerificationFailed {
+                path: path.to_owned(),
+                message,
+            })
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => Err(Error::VerificationFailed {
+            path: path.to_owned(),
+            message: "the verification command could not be found".to_string(),
+        }),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Moves a rejected download into the quarantine directory, keeping its path
+/// relative to the store. Returns the new location of the file.
+pub(crate) async fn quarantine(root: &Path, target: &Path) -> Result<PathBuf> {
+    let relative = target
+        .strip_prefix(root)
+        .unwrap_or_else(|_| Path::new(target.file_name().unwrap_or_default()));
+    let destination = root.join(QUARANTINE_DIR).join(relative);
+
+    if let Some(parent) = destination.parent() {
+        create_dir_all(parent).await?;
+    }
+
+    if let Err(e) = rename(target, &destination).await {
+        warn!(error=?e, path=?target, "Failed to quarantine download");
+        return Err(e.into());
+    }
+
+    Ok(destination)
+}
@@ -0,0 +1,110 @@
+use std::sync::Arc;
+
+use isahc::Request;
+use serde_json::{json, Value};
+
+use crate::{
+    config::{Webhook, WebhookTrigger},
+    events::{Correlation, Event, EventSink},
+    post::Poster,
+};
+
+/// Posts a JSON payload to the configured webhooks for matching events.
+/// Requests are sent in the background, [`Webhooks::flush`] waits for them.
+pub(crate) struct Webhooks {
+    hooks: Vec<Webhook>,
+    poster: Poster,
+}
+
+impl Webhooks {
+    pub(crate) fn new(hooks: Vec<Webhook>) -> Arc<Self> {
+        Arc::new(Self {
+            hooks,
+            poster: Poster::new("webhook"),
+        })
+    }
+
+    /// Waits for all requests sent so far to complete.
+    pub(crate) async fn flush(&self) {
+        self.poster.flush().await
+    }
+}
+
+fn payload(event: &Event) -> Option<(WebhookTrigger, Value)> {
+    match event {
+        Event::DownloadComplete {
+            server,
+            video,
+            part,
+            path,
+        } => Some((
+            WebhookTrigger::Downloaded,
+            json!({
+                "event": "downloaded",
+                "server": server,
+                "video": video,
+                "part": part,
+                "path": path,
+            }),
+        )),
+        Event::SyncItemFailed {
+            server,
+            item,
+            error,
+        } => Some((
+            WebhookTrigger::Failure,
+            json!({
+                "event": "failure",
+                "server": server,
+                "item": item,
+                "error": error,
+            }),
+        )),
+        Event::TransferFailed {
+            server,
+            video,
+            error,
+        } => Some((
+            WebhookTrigger::Failure,
+            json!({
+                "event": "failure",
+                "server": server,
+                "video": video,
+                "error": error,
+            }),
+        )),
+        Event::SyncComplete { failures } => Some((
+            WebhookTrigger::SyncComplete,
+            json!({
+                "event": "syncComplete",
+                "failures": failures,
+            }),
+        )),
+        _ => None,
+    }
+}
+
+impl EventSink for Webhooks {
+    fn event(&self, event: &Event, correlation: &Correlation) {
+        let (trigger, mut payload) = match payload(event) {
+            Some(payload) => payload,
+            None => return,
+        };
+        if let Some(ref run) = correlation.run {
+            payload["run"] = json!(run);
+        }
+        if let Some(ref operation) = correlation.operation {
+            payload["operation"] = json!(operation);
+        }
+        let body = payload.to_string();
+
+        for hook in self.hooks.iter() {
+            if !hook.events.is_empty() && !hook.events.contains(&trigger) {
+                continue;
+            }
+
+            let builder = Request::post(&hook.url).header("content-type", "application/json");
+            self.poster.post(builder, body.clone());
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use std::{
     cmp::{max, min},
+    collections::HashMap,
     fmt,
     io::{ErrorKind, IoSlice},
     ops::{Add, AddAssign},
@@ -8,37 +9,169 @@ use std::{
     result,
     sync::Arc,
     task::{Context, Poll},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use async_std::fs::{create_dir_all, File, OpenOptions};
 use async_std::{
-    fs::{metadata, remove_file},
+    fs::{metadata, remove_file, rename},
     task::sleep,
 };
 use async_trait::async_trait;
-use futures::AsyncWrite;
+use futures::{io::copy, ready, AsyncWrite, Future};
+use isahc::{http::StatusCode, Request};
 use pin_project::pin_project;
 use plex_api::{
     library::{self, Item, MediaItem, MetadataItem},
     media_container::server::library::ContainerFormat,
-    transcode::TranscodeStatus,
+    transcode::{TranscodeStatus, VideoTranscodeOptions},
 };
 use tracing::{debug, error, info, instrument, trace, warn};
 
 use crate::{
+    compress::recompress_artwork,
+    config::{Layout, TranscodeProfile, Transcoder},
+    events::{Event, EventProgress, Events},
+    schedule::RateSchedule,
     state::{
-        CollectionState, DownloadState, LibraryState, PlaylistState, SeasonState, ServerState,
-        ShowState, ThumbnailState, VideoDetail, VideoPartState, VideoState,
+        ArtworkKind, CollectionState, DownloadState, FileChecksum, FileHasher, LibraryState,
+        MovieDetail, PlaylistState, SeasonState, ServerState, ShowState, ThumbnailState,
+        TranscodeTracks, VideoDetail, VideoPartState, VideoState,
     },
+    template::{render_or_default, title_with_year, year, Value},
+    transcode,
     util::safe,
-    Error, Inner, Result, Server,
+    verify, Error, Inner, Result, Server,
 };
 
+/// Fetches an original file from the server's file download endpoint, which
+/// serves the file as is without starting a transcode session, continuing
+/// from `offset`.
+async fn download_file<W>(
+    server: &plex_api::Server,
+    key: &str,
+    offset: u64,
+    mut writer: W,
+) -> Result
+where
+    W: AsyncWrite + Unpin,
+{
+    let client = server.client();
+    let url = format!(
+        "{}{key}?download=1",
+        client.api_url.to_string().trim_end_matches('/')
+    );
+
+    let request = Request::get(url)
+        .header("X-Plex-Token", client.x_plex_token())
+        .header("Range", format!("bytes={offset}-"))
+        .body(())
+        .map_err(|e| Error::Unknown(e.to_string()))?;
+    let response = isahc::send_async(request)
+        .await
+        .map_err(std::io::Error::from)?;
+
+    match response.status() {
+        StatusCode::PARTIAL_CONTENT => {}
+        // A server that ignores the range sends the whole file, which is only
+        // usable when starting from the beginning.
+        StatusCode::OK if offset == 0 => {}
+        status => return Err(Error::DownloadRequestFailed(status.as_u16())),
+    }
+
+    copy(response.into_body(), &mut writer).await?;
+
+    Ok(())
+}
+
+/// Selects the audio stream the server uses for a part, transcode sessions
+/// then use it. This is the same as choosing the audio track in a Plex app so
+/// the server remembers it for the account.
+async fn select_audio_stream(server: &plex_api::Server, part_id: &str, stream_id: &str) -> Result {
+    let client = server.client();
+    let url = format!(
+        "{}/library/parts/{part_id}?audioStreamID={stream_id}&allParts=1",
+        client.api_url.to_string().trim_end_matches('/')
+    );
+
+    let request = Request::put(url)
+        .header("X-Plex-Token", client.x_plex_token())
+        .body(())
+        .map_err(|e| Error::Unknown(e.to_string()))?;
+    let response = isahc::send_async(request)
+        .await
+        .map_err(std::io::Error::from)?;
+
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(Error::AudioSelectionFailed(response.status().as_u16()))
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 enum FileType {
     Video(usize),
-    Thumbnail,
+    Artwork(ArtworkKind),
+}
+
+/// The suffix distinguishing the parts of a video with more than one part.
+fn part_name(state: &VideoState, index: usize) -> String {
+    if state.parts.len() == 1 {
+        "".to_string()
+    } else {
+        format!(" - pt{}", index + 1)
+    }
+}
+
+/// Movies or shows from the same library with the same title and year would
+/// share a directory, so all but the one with the lowest ID have their ID
+/// added to the title used for their files.
+fn unique_title<'a>(title: &str, id: &str, others: impl Iterator<Item = &'a str>) -> String {
+    let order = |id: &str| (id.len(), id.to_owned());
+
+    if others
+        .filter(|other| *other != id)
+        .any(|other| order(other) < order(id))
+    {
+        format!("{title} [{}]", safe(id))
+    } else {
+        title.to_owned()
+    }
+}
+
+fn movie_title(ss: &ServerState, video: &VideoState, movie: &MovieDetail) -> String {
+    let others = ss
+        .videos
+        .values()
+        .filter(|other| {
+            other.title == video.title
+                && other
+                    .movie_state()
+                    .is_some_and(|m| m.library == movie.library && m.year == movie.year)
+        })
+        .map(|other| other.id.as_str());
+
+    unique_title(&video.title, &video.id, others)
+}
+
+fn show_title(ss: &ServerState, show: &ShowState) -> String {
+    let others = ss
+        .shows
+        .values()
+        .filter(|other| {
+            other.title == show.title && other.library == show.library && other.year == show.year
+        })
+        .map(|other| other.id.as_str());
+
+    unique_title(&show.title, &show.id, others)
+}
+
+fn collection_file_name(id: &str, file_type: FileType, extension: &str) -> String {
+    match file_type {
+        FileType::Artwork(ArtworkKind::Poster) => format!(".{id}.{extension}"),
+        FileType::Artwork(kind) => format!(".{id}.{}.{extension}", kind.file_name()),
+        FileType::Video(_) => panic!("Unexpected"),
+    }
 }
 
 #[async_trait]
@@ -57,6 +190,15 @@ trait StateWrapper<S> {
 }
 
 macro_rules! state_wrapper {
+    (@persist $self:ident, $state:ident, videos) => {
+        $self
+            .inner
+            .persist_video(&$state, &$self.server.id, &$self.id)
+            .await
+    };
+    (@persist $self:ident, $state:ident, $prop:ident) => {
+        $self.inner.persist_state(&$state).await
+    };
     ($typ:ident, $st_typ:ident, $prop:ident) => {
         #[async_trait]
         impl StateWrapper<$st_typ> for $typ {
@@ -83,7 +225,7 @@ macro_rules! state_wrapper {
                 let mut state = self.inner.state.write().await;
                 let server_state = state.servers.get_mut(&self.server.id).unwrap();
                 cb(server_state.$prop.get_mut(&self.id).unwrap());
-                self.inner.persist_state(&state).await
+                state_wrapper!(@persist self, state, $prop)
             }
         }
     };
@@ -91,49 +233,93 @@ macro_rules! state_wrapper {
 
 macro_rules! thumbnail_methods {
     () => {
-        pub(crate) async fn thumbnail(&self) -> ThumbnailState {
-            self.with_state(|s| s.thumbnail.clone()).await
-        }
-
+        /// Downloads any missing artwork of the configured kinds and deletes
+        /// artwork of kinds that are no longer wanted.
         #[instrument(level = "trace")]
         pub async fn update_thumbnail(&self) -> Result {
-            let root = self.inner.path.read().await.to_owned();
+            self.inner.check_media_root().await?;
+            let root = self.inner.media_root().await;
+            let storage = self.inner.storage().await;
+            let kinds = self.inner.artwork_kinds().await;
+
+            for kind in ArtworkKind::ALL {
+                let mut artwork = self.with_state(|s| s.artwork(kind)).await;
+
+                if kinds.contains(&kind) {
+                    artwork.verify(&root).await;
+
+                    // Artwork from a different layout is downloaded again.
+                    if let Some(path) = artwork.file() {
+                        let extension = self.inner.artwork_extension().await;
+                        let expected = self
+                            .inner
+                            .artwork_path(self.file_path(FileType::Artwork(kind), extension).await?)
+                            .await;
+                        if path != expected {
+                            artwork.delete(&root, &*storage).await;
+                        }
+                    }
+                } else {
+                    artwork.delete(&root, &*storage).await;
+                }
 
-            let mut thumbnail = self.thumbnail().await;
-            thumbnail.verify(&root).await;
+                self.update_state(|s| s.set_artwork(kind, artwork.clone()))
+                    .await?;
 
-            self.update_state(|s| s.thumbnail = thumbnail.clone())
-                .await?;
+                if artwork.is_none() && kinds.contains(&kind) {
+                    self.download_artwork(&root, kind).await?;
+                }
+            }
+
+            Ok(())
+        }
 
-            if thumbnail.is_none() {
-                let server = self.server.connect().await?;
-                let item = server.item_by_id(&self.id).await?;
-                debug!("Updating thumbnail for {}", item.title());
+        async fn download_artwork(&self, root: &Path, kind: ArtworkKind) -> Result {
+            let server = self.server.connect().await?;
+            let item = server.item_by_id(&self.id).await?;
+            debug!("Updating {kind:?} artwork for {}", item.title());
 
-                let image = if let Some(ref thumb) = item.metadata().thumb {
-                    thumb.clone()
-                } else {
+            let image = if let Some(image) = kind.image(item.metadata()) {
+                image.clone()
+            } else {
+                if kind == ArtworkKind::Poster {
                     warn!("No thumbnail found for {}", item.title());
-                    return Ok(());
-                };
+                }
+                return Ok(());
+            };
 
-                let path = self.file_path(FileType::Thumbnail, "jpg").await;
-                let target = root.join(&path);
+            let extension = self.inner.artwork_extension().await;
+            let path = self
+                .inner
+                .artwork_path(self.file_path(FileType::Artwork(kind), extension).await?)
+                .await;
+            let target = root.join(&path);
 
-                if let Some(parent) = target.parent() {
-                    create_dir_all(parent).await?;
-                }
+            let (width, height) = kind.dimensions();
+            let file = self.inner.storage().await.create(&target, false).await?;
+            server
+                .transcode_artwork(&image, width, height, Default::default(), file)
+                .await?;
 
-                let file = File::create(root.join(&path)).await?;
-                server
-                    .transcode_artwork(&image, 320, 320, Default::default(), file)
+            let compression = self.inner.config.read().await.compression.clone();
+            if let Some(format) = compression.as_ref().and_then(|c| c.artwork_format) {
+                recompress_artwork(&target, format, compression.and_then(|c| c.artwork_quality))
                     .await?;
+            }
+            self.inner.storage().await.publish(&target).await?;
 
-                let state = ThumbnailState::Downloaded { path };
+            let size = metadata(&target).await.map(|m| m.len()).unwrap_or_default();
+            self.inner.events().await.emit(Event::ArtworkDownloaded {
+                server: self.server.id.clone(),
+                item: self.id.clone(),
+                path: path.clone(),
+                size,
+            });
 
-                self.update_state(|s| s.thumbnail = state).await?;
-                trace!("Thumbnail for {} successfully updated", item.title());
-            }
+            let state = ThumbnailState::Downloaded { path };
+
+            self.update_state(|s| s.set_artwork(kind, state)).await?;
+            trace!("{kind:?} artwork for {} successfully updated", item.title());
 
             Ok(())
         }
@@ -197,20 +383,27 @@ impl Show {
     parent!(library, ShowLibrary, library);
     children!(seasons, seasons, Season, show);
 
-    async fn file_path(&self, file_type: FileType, extension: &str) -> PathBuf {
+    async fn file_path(&self, file_type: FileType, extension: &str) -> Result<PathBuf> {
+        let layout = self.inner.layout().await;
+
         self.with_server_state(|ss| {
             let state = ss.shows.get(&self.id).unwrap();
 
-            let name = match file_type {
-                FileType::Video(_) => panic!("Unexpected"),
-                FileType::Thumbnail => format!(".thumb.{extension}"),
+            let name = match (file_type, layout) {
+                (FileType::Video(_), _) => panic!("Unexpected"),
+                (FileType::Artwork(kind), Layout::Default) => {
+                    format!(".{}.{extension}", kind.file_name())
+                }
+                (FileType::Artwork(kind), Layout::Plex) => {
+                    format!("{}.{extension}", kind.local_name())
+                }
             };
 
             let library_title = &ss.libraries.get(&state.library).unwrap().title;
-            PathBuf::from(safe(&self.server.id))
+            Ok(PathBuf::from(safe(&self.server.id))
                 .join(safe(library_title))
-                .join(safe(format!("{} ({})", state.title, state.year)))
-                .join(safe(name))
+                .join(safe(title_with_year(&show_title(ss, state), state.year)))
+                .join(safe(name)))
         })
         .await
     }
@@ -232,8 +425,36 @@ impl fmt::Debug for Season {
 state_wrapper!(Season, SeasonState, seasons);
 
 impl Season {
+    thumbnail_methods!();
     parent!(show, Show, show);
 
+    async fn file_path(&self, file_type: FileType, extension: &str) -> Result<PathBuf> {
+        let layout = self.inner.layout().await;
+
+        self.with_server_state(|ss| {
+            let state = ss.seasons.get(&self.id).unwrap();
+            let show = ss.shows.get(&state.show).unwrap();
+            let show_name = show_title(ss, show);
+            let library_title = &ss.libraries.get(&show.library).unwrap().title;
+
+            let name = match (file_type, layout) {
+                (FileType::Video(_), _) => panic!("Unexpected"),
+                (FileType::Artwork(kind), Layout::Default) => {
+                    format!(".S{:02}.{}.{extension}", state.index, kind.file_name())
+                }
+                (FileType::Artwork(kind), Layout::Plex) => {
+                    format!("season{:02}-{}.{extension}", state.index, kind.local_name())
+                }
+            };
+
+            Ok(PathBuf::from(safe(&self.server.id))
+                .join(safe(library_title))
+                .join(safe(title_with_year(&show_name, show.year)))
+                .join(safe(name)))
+        })
+        .await
+    }
+
     pub async fn episodes(&self) -> Vec<Episode> {
         self.with_server_state(|ss| {
             ss.videos
@@ -270,6 +491,7 @@ struct WriterProgress<'a, W, P> {
     #[pin]
     writer: W,
     progress: &'a mut P,
+    hasher: &'a mut FileHasher,
 }
 
 impl<'a, W, P> AsyncWrite for WriterProgress<'a, W, P>
@@ -286,6 +508,7 @@ where
         let result = this.writer.poll_write(cx, buf);
 
         if let Poll::Ready(Ok(count)) = result {
+            this.hasher.update(&buf[..count]);
             *this.offset += count as u64;
             this.progress.progress(*this.offset, *this.size);
         }
@@ -302,6 +525,16 @@ where
         let result = this.writer.poll_write_vectored(cx, bufs);
 
         if let Poll::Ready(Ok(count)) = result {
+            let mut remaining = count;
+            for buf in bufs {
+                let written = remaining.min(buf.len());
+                this.hasher.update(&buf[..written]);
+                remaining -= written;
+                if remaining == 0 {
+                    break;
+                }
+            }
+
             *this.offset += count as u64;
             this.progress.progress(*this.offset, *this.size);
         }
@@ -326,6 +559,113 @@ where
     }
 }
 
+/// How often a download checks whether the bandwidth schedule has changed.
+const SCHEDULE_CHECK: Duration = Duration::from_secs(60);
+
+/// Delays writes so that data is written no faster than the scheduled rate.
+#[pin_project]
+struct RateLimited<W> {
+    #[pin]
+    writer: W,
+    schedule: RateSchedule,
+    bytes_per_second: Option<u64>,
+    /// When the schedule next needs checking.
+    next_check: Instant,
+    start: Instant,
+    written: u64,
+    delay: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
+}
+
+impl<W> RateLimited<W> {
+    fn new(writer: W, schedule: RateSchedule) -> Self {
+        let now = Instant::now();
+
+        Self {
+            writer,
+            schedule,
+            bytes_per_second: None,
+            next_check: now,
+            start: now,
+            written: 0,
+            delay: None,
+        }
+    }
+}
+
+impl<W> AsyncWrite for RateLimited<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<result::Result<usize, futures::io::Error>> {
+        let this = self.project();
+
+        if this.delay.is_none() {
+            let now = Instant::now();
+            if now >= *this.next_check {
+                let (rate, remaining) = this.schedule.current();
+                let rate = rate.map(|rate| rate * 1024);
+                if rate != *this.bytes_per_second {
+                    *this.bytes_per_second = rate;
+                    *this.start = now;
+                    *this.written = 0;
+                }
+                *this.next_check = now + remaining.min(SCHEDULE_CHECK);
+            }
+
+            match *this.bytes_per_second {
+                // Paused, check again once the window may have ended.
+                Some(0) => {
+                    *this.delay = Some(Box::pin(sleep(*this.next_check - now)));
+                }
+                Some(rate) => {
+                    let allowed = Duration::from_secs_f64(*this.written as f64 / rate as f64);
+                    let elapsed = this.start.elapsed();
+                    if allowed > elapsed {
+                        *this.delay = Some(Box::pin(sleep(allowed - elapsed)));
+                    }
+                }
+                None => {}
+            }
+        }
+
+        if let Some(delay) = this.delay.as_mut() {
+            ready!(delay.as_mut().poll(cx));
+            *this.delay = None;
+
+            if *this.bytes_per_second == Some(0) {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+        }
+
+        let result = this.writer.poll_write(cx, buf);
+
+        if let Poll::Ready(Ok(count)) = result {
+            *this.written += count as u64;
+        }
+
+        result
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<result::Result<(), futures::io::Error>> {
+        self.project().writer.poll_flush(cx)
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<result::Result<(), futures::io::Error>> {
+        self.project().writer.poll_close(cx)
+    }
+}
+
 #[derive(Clone, PartialEq)]
 pub enum TransferState {
     Waiting,
@@ -334,6 +674,38 @@ pub enum TransferState {
     Downloaded,
 }
 
+/// A problem found with a completed download.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DownloadIssue {
+    /// The file no longer exists.
+    Missing,
+    /// The file exists but contains no data.
+    Empty,
+    /// The file is smaller than the size reported by the server.
+    Truncated { expected: u64, actual: u64 },
+    /// The file's contents have changed since it was downloaded.
+    ChecksumMismatch,
+    /// The last download failed verification and was quarantined.
+    Quarantined,
+    /// The file could not be read, with the error that occurred.
+    Unreadable(String),
+}
+
+impl fmt::Display for DownloadIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Missing => f.pad("file is missing"),
+            Self::Empty => f.pad("file is empty"),
+            Self::Truncated { expected, actual } => {
+                write!(f, "file is truncated ({actual} of {expected} bytes)")
+            }
+            Self::ChecksumMismatch => f.pad("file does not match its recorded checksum"),
+            Self::Quarantined => f.pad("download failed verification and was quarantined"),
+            Self::Unreadable(error) => write!(f, "file could not be read: {error}"),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct VideoPart {
     pub(crate) server: Server,
@@ -370,12 +742,18 @@ impl VideoPart {
             .await
     }
 
+    /// The size of the part on the server.
+    pub async fn size(&self) -> u64 {
+        self.with_state(|vs| vs.size).await
+    }
+
     pub async fn transfer_state(&self) -> TransferState {
         let download_state = self.download_state().await;
 
         match download_state {
             DownloadState::None => TransferState::Waiting,
-            DownloadState::Downloading { path: _ } => TransferState::Downloading,
+            DownloadState::Downloading { path: _ }
+            | DownloadState::AwaitingTranscode { path: _ } => TransferState::Downloading,
             DownloadState::Transcoding {
                 session_id: _,
                 path: _,
@@ -386,16 +764,127 @@ impl VideoPart {
 
     #[instrument(level = "trace", skip(self), fields(video=self.id, part=self.index))]
     pub async fn verify_download(&self) -> Result {
+        self.inner.check_media_root().await?;
         let server = self.server.connect().await?;
-        let mut download_state = self.download_state().await;
-        let root = self.inner.path.read().await.clone();
+        let original = self.download_state().await;
+        let root = self.inner.media_root().await;
 
+        let mut download_state = original.clone();
         download_state.verify(&server, &root).await;
 
+        // Writing the state is slow for large stores so skip it when nothing
+        // changed.
+        if download_state == original {
+            return Ok(());
+        }
+
         self.update_state(|state| state.download = download_state)
             .await
     }
 
+    /// Whether the last download failed verification. Syncs do not download
+    /// the part again until [`reset_download`](Self::reset_download) is used.
+    pub async fn is_quarantined(&self) -> bool {
+        self.with_state(|state| state.quarantined).await
+    }
+
+    /// Deletes any existing download, cancelling a transcode in progress, so
+    /// that the next sync fetches the part again. The server is only needed to
+    /// cancel a transcode so this works offline.
+    #[instrument(level = "trace", skip(self), fields(video=self.id, part=self.index))]
+    pub async fn reset_download(&self) -> Result {
+        let mut download_state = self.download_state().await;
+        let root = self.inner.media_root().await;
+        let storage = self.inner.storage().await;
+
+        let server = if matches!(download_state, DownloadState::Transcoding { .. }) {
+            match self.server.connect().await {
+                Ok(server) => Some(server),
+                Err(e) => {
+                    warn!(error=?e, "Unable to cancel the transcode session");
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        download_state
+            .delete(server.as_ref(), &root, &*storage)
+            .await;
+
+        self.update_state(|state| {
+            state.download = download_state;
+            state.checksum = None;
+            state.quarantined = false;
+        })
+        .await
+    }
+
+    /// Checks a completed download against what the server reported and the
+    /// checksum recorded when it completed. Transcodes without a recorded
+    /// checksum cannot be checked for truncation as their size is not known
+    /// in advance.
+    /// A part whose last download was quarantined is also reported.
+    pub async fn check_download(&self) -> Option<DownloadIssue> {
+        let (download_state, size, checksum, quarantined) = self
+            .with_state(|state| {
+                (
+                    state.download.clone(),
+                    state.size,
+                    state.checksum.clone(),
+                    state.quarantined,
+                )
+            })
+            .await;
+
+        if quarantined {
+            return Some(DownloadIssue::Quarantined);
+        }
+
+        let path = match download_state {
+            DownloadState::Downloaded { ref path } | DownloadState::Transcoded { ref path } => {
+                path.clone()
+            }
+            _ => return None,
+        };
+
+        let root = self.inner.media_root().await;
+        let actual = match metadata(root.join(&path)).await {
+            Ok(stats) if stats.is_file() => stats.len(),
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                return Some(DownloadIssue::Unreadable(e.to_string()))
+            }
+            _ => return Some(DownloadIssue::Missing),
+        };
+
+        let expected = match (&checksum, &download_state) {
+            (Some(checksum), _) => Some(checksum.size),
+            (None, DownloadState::Downloaded { .. }) => Some(size),
+            _ => None,
+        };
+
+        if actual == 0 {
+            return Some(DownloadIssue::Empty);
+        }
+
+        if let Some(expected) = expected {
+            if actual < expected {
+                return Some(DownloadIssue::Truncated { expected, actual });
+            }
+        }
+
+        let checksum = checksum?;
+        match FileChecksum::calculate(&root.join(&path), checksum.algorithm).await {
+            Ok(current) if current == checksum => None,
+            Ok(_) => Some(DownloadIssue::ChecksumMismatch),
+            Err(e) => {
+                warn!(error=?e, path=?path, "Failed to calculate checksum");
+                Some(DownloadIssue::Unreadable(e.to_string()))
+            }
+        }
+    }
+
     pub async fn video(&self) -> Video {
         self.with_server_state(|server_state| {
             let video_state = server_state.videos.get(&self.id).unwrap();
@@ -416,15 +905,84 @@ impl VideoPart {
         .await
     }
 
-    async fn file_path(&self, extension: &str) -> PathBuf {
+    async fn file_path(&self, extension: &str) -> Result<PathBuf> {
         let video = self.video().await;
-        video
+        let path = video
             .file_path(FileType::Video(self.index), extension)
+            .await?;
+
+        // Movies and shows with the same title and year already get distinct
+        // directories but custom templates can still render different videos
+        // to the same path. The rating key is added to the name of any video
+        // after the first so that downloads don't overwrite each other, the
+        // path used is recorded in the download state.
+        let taken = self
+            .with_server_state(|ss| {
+                ss.videos
+                    .values()
+                    .filter(|vs| vs.id != self.id)
+                    .flat_map(|vs| vs.parts.iter())
+                    .any(|part| part.download.file().as_ref() == Some(&path))
+            })
+            .await;
+        if !taken {
+            return Ok(path);
+        }
+
+        let stem = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let unique = path.with_file_name(format!("{stem} [{}].{extension}", safe(&self.id)));
+        debug!(path=?path, unique=?unique, "Path is already used by another video");
+
+        Ok(unique)
+    }
+
+    /// Moves a completed download to where the naming templates now place it.
+    pub(crate) async fn relocate_download(&self) -> Result {
+        let (path, transcoded) = match self.download_state().await {
+            DownloadState::Downloaded { path } => (path, false),
+            DownloadState::Transcoded { path } => (path, true),
+            _ => return Ok(()),
+        };
+
+        let extension = match path.extension().and_then(|e| e.to_str()) {
+            Some(extension) => extension.to_owned(),
+            None => return Ok(()),
+        };
+
+        let target = self.file_path(&extension).await?;
+        if target == path {
+            return Ok(());
+        }
+
+        let root = self.inner.media_root().await;
+        let destination = root.join(&target);
+        if metadata(&destination).await.is_ok() {
+            warn!(path=?target, "Unable to move download as the new path already exists");
+            return Ok(());
+        }
+
+        self.inner
+            .storage()
             .await
+            .rename(&root.join(&path), &destination)
+            .await?;
+        info!(old=?path, new=?target, "Moved download to match the naming template");
+
+        self.update_state(|state| {
+            state.download = if transcoded {
+                DownloadState::Transcoded { path: target }
+            } else {
+                DownloadState::Downloaded { path: target }
+            }
+        })
+        .await
     }
 
     pub async fn rebuild_download(&self) -> Result {
-        let root = self.inner.path.read().await;
+        let root = self.inner.media_root().await;
         let title = self.with_video_state(|vs| vs.title.clone()).await;
 
         for container in [
@@ -435,7 +993,7 @@ impl VideoPart {
             ContainerFormat::Mp4,
             ContainerFormat::Mkv,
         ] {
-            let path = self.file_path(&container.to_string()).await;
+            let path = self.file_path(&container.to_string()).await?;
             let target = root.join(&path);
 
             if let Ok(stats) = metadata(target).await {
@@ -443,7 +1001,10 @@ impl VideoPart {
                     info!(path=?path.display(), "Recovered download for {title}");
 
                     return self
-                        .update_state(|state| state.download = DownloadState::Downloaded { path })
+                        .update_state(|state| {
+                            state.download = DownloadState::Downloaded { path };
+                            state.checksum = None;
+                        })
                         .await;
                 }
             }
@@ -459,21 +1020,14 @@ impl VideoPart {
 
     #[instrument(level = "trace", skip(self), fields(session_id, video=self.id, part=self.index))]
     async fn start_transcode(&self) -> Result {
-        let (media_id, profile) = self
-            .with_video_state(|vs| (vs.media_id.clone(), vs.transcode_profile.clone()))
-            .await;
-
-        let server_profile = self.server.transcode_profile().await;
+        let media_id = self.with_video_state(|vs| vs.media_id.clone()).await;
 
-        let options = if let Some(options) = self
-            .inner
-            .transcode_options(profile.or(server_profile))
-            .await
-        {
-            options
+        let profile = if let Some(profile) = self.transcode_profile().await {
+            profile
         } else {
             return Err(Error::TranscodeSkipped);
         };
+        let options = profile.options();
 
         let _permit = self.server.transcode_permit().await;
 
@@ -494,6 +1048,47 @@ impl VideoPart {
         let parts = media.parts();
         let part = parts.get(self.index).ok_or_else(|| Error::MissingItem)?;
 
+        // The transcode session uses the audio track selected on the server so
+        // the preferred language is selected first.
+        let mut tracks = TranscodeTracks::selected(part, options.burn_subtitles);
+        if let Some(ref language) = profile.audio_language {
+            if !tracks
+                .audio_language
+                .as_ref()
+                .is_some_and(|selected| selected.eq_ignore_ascii_case(language))
+            {
+                match TranscodeTracks::preferred_audio(part, language) {
+                    Some((_, stream_id)) => {
+                        let part_id = part.metadata().id.as_deref().ok_or(Error::MissingItem)?;
+                        match select_audio_stream(&server, part_id, &stream_id).await {
+                            Ok(()) => tracks.audio_language = Some(language.clone()),
+                            Err(e) => warn!(
+                                error=?e,
+                                preferred = language,
+                                selected = tracks.audio_language,
+                                "Unable to select the audio track in the preferred language"
+                            ),
+                        }
+                    }
+                    None => warn!(
+                        preferred = language,
+                        selected = tracks.audio_language,
+                        "There is no audio track in the preferred language"
+                    ),
+                }
+            }
+        }
+
+        if let Some(previous) = self.with_state(|state| state.tracks.clone()).await {
+            if previous != tracks {
+                warn!(
+                    ?previous,
+                    current = ?tracks,
+                    "The tracks selected on the server have changed since the last transcode"
+                );
+            }
+        }
+
         trace!("Attempting transcode");
 
         let session = part.create_download_session(options).await?;
@@ -525,14 +1120,15 @@ impl VideoPart {
 
         debug!("Started transcode session");
 
-        let path = self.file_path(&session.container().to_string()).await;
+        let path = self.file_path(&session.container().to_string()).await?;
 
         if let Err(e) = self
             .update_state(|state| {
                 state.download = DownloadState::Transcoding {
                     session_id: session.session_id().to_string(),
                     path,
-                }
+                };
+                state.tracks = Some(tracks);
             })
             .await
         {
@@ -544,6 +1140,12 @@ impl VideoPart {
             return Err(e);
         }
 
+        self.inner.events().await.emit(Event::TranscodeStarted {
+            server: self.server.id.clone(),
+            video: self.id.clone(),
+            part: self.index,
+        });
+
         Ok(())
     }
 
@@ -569,17 +1171,32 @@ impl VideoPart {
 
         let path = self
             .file_path(&part.metadata().container.unwrap().to_string())
-            .await;
+            .await?;
 
-        let target = { self.inner.path.read().await.join(&path) };
+        let target = self.inner.media_root().await.join(&path);
         if let Err(e) = remove_file(target).await {
             if e.kind() != ErrorKind::NotFound {
                 return Err(Error::from(e));
             }
         }
 
-        self.update_state(|state| state.download = DownloadState::Downloading { path })
-            .await?;
+        // ffmpeg cannot see the languages the server knows about so the audio
+        // track for a local transcode is chosen now.
+        let tracks = if self.server.transcoder().await == Transcoder::Local {
+            self.transcode_profile()
+                .await
+                .map(|profile| TranscodeTracks::local(part, profile.audio_language.as_deref()))
+        } else {
+            None
+        };
+
+        self.update_state(|state| {
+            state.download = DownloadState::Downloading { path };
+            if tracks.is_some() {
+                state.tracks = tracks;
+            }
+        })
+        .await?;
 
         Ok(())
     }
@@ -606,10 +1223,21 @@ impl VideoPart {
             }
         };
 
+        let events = self.inner.events().await;
+        let emit_progress = |percent: u64| {
+            events.emit(Event::TranscodeProgress {
+                server: self.server.id.clone(),
+                video: self.id.clone(),
+                part: self.index,
+                percent,
+            })
+        };
+
         loop {
             match session.status().await {
                 Ok(TranscodeStatus::Complete) => {
                     progress.progress(100, 100);
+                    emit_progress(100);
                     break;
                 }
                 Ok(TranscodeStatus::Error) => {
@@ -621,6 +1249,7 @@ impl VideoPart {
                     progress: p,
                 }) => {
                     progress.progress(p as u64, 100);
+                    emit_progress(p as u64);
                     let delay = if let Some(remaining) = remaining {
                         max(2, min(5, remaining))
                     } else {
@@ -646,6 +1275,23 @@ impl VideoPart {
         Ok(())
     }
 
+    /// The options to transcode this part with, or `None` if the selected
+    /// profile downloads the original file.
+    async fn transcode_profile(&self) -> Option<TranscodeProfile> {
+        let profile = self
+            .with_video_state(|vs| vs.transcode_profile.clone())
+            .await;
+        let server_profile = self.server.transcode_profile().await;
+
+        self.inner.resolve_profile(profile.or(server_profile)).await
+    }
+
+    async fn transcode_options(&self) -> Option<VideoTranscodeOptions> {
+        self.transcode_profile()
+            .await
+            .map(|profile| profile.options())
+    }
+
     #[instrument(level = "trace", fields(video=self.id, part=self.index))]
     pub async fn negotiate_transfer_type(&self) -> Result {
         let mut download_state = self.download_state().await;
@@ -657,7 +1303,7 @@ impl VideoPart {
                 path: _
             }
         ) {
-            let root = self.inner.path.read().await;
+            let root = self.inner.media_root().await;
             download_state
                 .verify(&self.server.connect().await?, &root)
                 .await;
@@ -667,6 +1313,18 @@ impl VideoPart {
         }
 
         if matches!(download_state, DownloadState::None) {
+            // Passthrough profiles download the original file directly and
+            // never need a transcode session.
+            if self.transcode_options().await.is_none() {
+                debug!("Profile does not transcode, downloading directly");
+                return self.enter_downloading_state().await;
+            }
+
+            if self.server.transcoder().await == Transcoder::Local {
+                debug!("Transcoding locally, downloading the original file");
+                return self.enter_downloading_state().await;
+            }
+
             match self.start_transcode().await {
                 Err(Error::TranscodeSkipped) => (),
                 Err(Error::PlexError {
@@ -712,8 +1370,8 @@ impl VideoPart {
     }
 
     #[instrument(level = "trace", skip(self, path, progress), fields(video=self.id, part=self.index))]
-    async fn download_direct<P: Progress + Unpin>(&self, path: &Path, mut progress: P) -> Result {
-        let target = { self.inner.path.read().await.join(path) };
+    async fn download_direct<P: Progress + Unpin>(&self, path: &Path, progress: P) -> Result {
+        let target = self.inner.media_root().await.join(path);
         let offset = match metadata(&target).await {
             Ok(stats) => stats.len(),
             Err(e) => {
@@ -743,33 +1401,63 @@ impl VideoPart {
         let parts = media.parts();
         let part = parts.get(self.index).ok_or_else(|| Error::MissingItem)?;
 
-        if let Some(parent) = target.parent() {
-            create_dir_all(parent).await?;
-        }
-
-        let file = OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open(&target)
-            .await?;
+        let file = self.inner.storage().await.create(&target, true).await?;
+
+        let size = part.metadata().size.unwrap();
+        let events = self.inner.events().await;
+        let mut progress = self.event_progress(progress, &events);
+        let (schedule, algorithm) = {
+            let config = self.inner.config.read().await;
+            (
+                RateSchedule::from(&config),
+                config.hash_algorithm.unwrap_or_default(),
+            )
+        };
+        // A resumed download needs the existing data hashed first.
+        let mut hasher = FileHasher::resume(algorithm, &target).await?;
 
         let writer = WriterProgress {
             offset,
-            size: part.metadata().size.unwrap(),
-            writer: file,
+            size,
+            writer: RateLimited::new(file, schedule),
             progress: &mut progress,
+            hasher: &mut hasher,
         };
         info!(path=?path, offset, "Downloading source file");
+        events.emit(Event::DownloadStarted {
+            server: self.server.id.clone(),
+            video: self.id.clone(),
+            part: self.index,
+            path: path.to_owned(),
+            offset,
+            size,
+        });
 
-        part.download(writer, offset..).await?;
+        let key = part.metadata().key.as_deref().ok_or(Error::MissingItem)?;
+        download_file(&server, key, offset, writer).await?;
         info!(path=?path, "Download complete");
 
-        self.update_state(|state| {
-            state.download = DownloadState::Downloaded {
+        // An original that is transcoded locally is not complete, or
+        // published, until the transcode succeeds.
+        let download = if self.transcodes_locally().await {
+            DownloadState::AwaitingTranscode {
                 path: path.to_owned(),
             }
-        })
-        .await?;
+        } else {
+            DownloadState::Downloaded {
+                path: path.to_owned(),
+            }
+        };
+
+        self.complete_download(&target, Some(size), download, Some(hasher.finish()))
+            .await?;
+
+        events.emit(Event::DownloadComplete {
+            server: self.server.id.clone(),
+            video: self.id.clone(),
+            part: self.index,
+            path: path.to_owned(),
+        });
 
         Ok(())
     }
@@ -779,7 +1467,7 @@ impl VideoPart {
         &self,
         session_id: &str,
         path: &Path,
-        mut progress: P,
+        progress: P,
     ) -> Result {
         let server = self.server.connect().await?;
         let session = server.transcode_session(session_id).await?;
@@ -790,37 +1478,59 @@ impl VideoPart {
             return Err(Error::DownloadUnavailable);
         }
 
-        let target = { self.inner.path.read().await.join(path) };
+        let target = self.inner.media_root().await.join(path);
 
-        if let Some(parent) = target.parent() {
-            create_dir_all(parent).await?;
-        }
+        let file = self.inner.storage().await.create(&target, false).await?;
 
-        let file = OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open(&target)
-            .await?;
+        let size = stats.size as u64;
+        let events = self.inner.events().await;
+        let mut progress = self.event_progress(progress, &events);
+        let (schedule, algorithm) = {
+            let config = self.inner.config.read().await;
+            (
+                RateSchedule::from(&config),
+                config.hash_algorithm.unwrap_or_default(),
+            )
+        };
+        let mut hasher = FileHasher::new(algorithm);
 
         let writer = WriterProgress {
             offset: 0,
-            size: stats.size as u64,
-            writer: file,
+            size,
+            writer: RateLimited::new(file, schedule),
             progress: &mut progress,
+            hasher: &mut hasher,
         };
         info!(path=?path, "Downloading transcoded video");
+        events.emit(Event::DownloadStarted {
+            server: self.server.id.clone(),
+            video: self.id.clone(),
+            part: self.index,
+            path: path.to_owned(),
+            offset: 0,
+            size,
+        });
 
         session.download(writer).await?;
         info!(path=?path, "Download complete");
 
-        self.update_state(|state| {
-            state.download = DownloadState::Transcoded {
+        self.complete_download(
+            &target,
+            None,
+            DownloadState::Transcoded {
                 path: path.to_owned(),
-            }
-        })
+            },
+            Some(hasher.finish()),
+        )
         .await?;
 
+        events.emit(Event::DownloadComplete {
+            server: self.server.id.clone(),
+            video: self.id.clone(),
+            part: self.index,
+            path: path.to_owned(),
+        });
+
         if let Err(e) = session.cancel().await {
             warn!(
                 error=?e,
@@ -831,11 +1541,126 @@ impl VideoPart {
         Ok(())
     }
 
+    /// Checks that a newly downloaded file is the expected size and records
+    /// its checksum, calculating it unless it was hashed while downloading. A
+    /// file of the wrong size is deleted so that it will be downloaded again
+    /// and a file that fails the configured verification command is
+    /// quarantined.
+    async fn complete_download(
+        &self,
+        target: &Path,
+        expected: Option<u64>,
+        download: DownloadState,
+        checksum: Option<FileChecksum>,
+    ) -> Result {
+        let checksum = match checksum {
+            Some(checksum) => checksum,
+            None => {
+                let algorithm = self.inner.config.read().await.hash_algorithm;
+                FileChecksum::calculate(target, algorithm.unwrap_or_default()).await?
+            }
+        };
+
+        if checksum.size == 0 || expected.is_some_and(|expected| expected != checksum.size) {
+            error!(
+                path=?target,
+                expected,
+                actual = checksum.size,
+                "Downloaded file is the wrong size"
+            );
+
+            if let Err(e) = remove_file(target).await {
+                warn!(error=?e, path=?target, "Failed to remove bad download");
+            }
+
+            self.update_state(|state| {
+                state.download = DownloadState::None;
+                state.checksum = None;
+            })
+            .await?;
+
+            return Err(Error::DownloadMismatch {
+                path: target.to_owned(),
+                expected: expected.unwrap_or_default(),
+                actual: checksum.size,
+            });
+        }
+
+        let verify_command = self.inner.config.read().await.verify_command.clone();
+        if let Some(command) = verify_command {
+            if let Err(e) = verify::verify(&command, target).await {
+                error!(error=%e, path=?target, "Downloaded file failed verification");
+
+                let root = self.inner.media_root().await;
+                match verify::quarantine(&root, target).await {
+                    Ok(destination) => {
+                        warn!(path=?destination, "Quarantined download");
+                    }
+                    Err(_) => {
+                        if let Err(e) = remove_file(target).await {
+                            warn!(error=?e, path=?target, "Failed to remove rejected download");
+                        }
+                    }
+                }
+
+                self.update_state(|state| {
+                    state.download = DownloadState::None;
+                    state.checksum = None;
+                    state.quarantined = true;
+                })
+                .await?;
+
+                return Err(e);
+            }
+        }
+
+        trace!(path=?target, hash=checksum.hash, "Recorded download checksum");
+
+        self.update_state(|state| {
+            state.download = download;
+            state.checksum = Some(checksum);
+            state.unpublished = true;
+        })
+        .await?;
+
+        self.publish_download().await
+    }
+
+    /// Uploads a completed download to the configured media storage if that
+    /// has not succeeded yet.
+    #[instrument(level = "trace", skip(self), fields(video=self.id, part=self.index))]
+    pub async fn publish_download(&self) -> Result {
+        let path = match self
+            .with_state(|state| state.unpublished.then(|| state.download.clone()))
+            .await
+        {
+            Some(DownloadState::Downloaded { path }) | Some(DownloadState::Transcoded { path }) => {
+                path
+            }
+            _ => return Ok(()),
+        };
+
+        let root = self.inner.media_root().await;
+        self.inner.storage().await.publish(&root.join(path)).await?;
+
+        self.update_state(|state| state.unpublished = false).await
+    }
+
     pub async fn download<P: Progress + Unpin>(&self, progress: P) -> Result {
         let download_state = self.download_state().await;
         match download_state {
             DownloadState::None => Err(Error::DownloadUnavailable),
-            DownloadState::Downloading { path } => self.download_direct(&path, progress).await,
+            DownloadState::Downloading { path } => {
+                self.download_direct(&path, progress).await?;
+
+                match self.download_state().await {
+                    DownloadState::AwaitingTranscode { path } => {
+                        self.transcode_locally(&path).await
+                    }
+                    _ => Ok(()),
+                }
+            }
+            DownloadState::AwaitingTranscode { path } => self.transcode_locally(&path).await,
             DownloadState::Transcoding { session_id, path } => {
                 self.download_transcode(&session_id, &path, progress).await
             }
@@ -843,6 +1668,85 @@ impl VideoPart {
         }
     }
 
+    /// Whether a downloaded original is transcoded with ffmpeg before it is
+    /// complete.
+    async fn transcodes_locally(&self) -> bool {
+        self.server.transcoder().await == Transcoder::Local
+            && self.transcode_profile().await.is_some()
+    }
+
+    /// Transcodes a downloaded original file with ffmpeg for servers that
+    /// transcode locally. If the transcode fails the original is kept, the
+    /// part stays waiting for the transcode so the next sync tries again and
+    /// the error is returned. The original is only removed once the
+    /// transcoded file is in place.
+    #[instrument(level = "trace", skip(self, source), fields(video=self.id, part=self.index))]
+    async fn transcode_locally(&self, source: &Path) -> Result {
+        let profile = match self.transcode_profile().await {
+            Some(profile) if self.server.transcoder().await == Transcoder::Local => profile,
+            // The config changed since the original downloaded so keep it.
+            _ => {
+                let path = source.to_owned();
+                self.update_state(|state| state.download = DownloadState::Downloaded { path })
+                    .await?;
+                return self.publish_download().await;
+            }
+        };
+
+        let path = self
+            .file_path(&transcode::container(&profile).to_string())
+            .await?;
+        let (source, target) = {
+            let root = self.inner.media_root().await;
+            (root.join(source), root.join(&path))
+        };
+        let temp = target.with_extension("transcoding");
+        let ffmpeg = self.inner.config.read().await.ffmpeg.clone();
+        let audio_track = self
+            .with_state(|state| state.tracks.as_ref().and_then(|tracks| tracks.audio_track))
+            .await
+            .unwrap_or_default();
+
+        info!(path=?path, "Transcoding downloaded file");
+        if let Err(e) = transcode::transcode(ffmpeg, &profile, audio_track, &source, &temp).await {
+            warn!(error=?e, "Local transcode failed, keeping the original file");
+
+            if let Err(e) = remove_file(&temp).await {
+                if e.kind() != ErrorKind::NotFound {
+                    warn!(error=?e, path=?temp, "Failed to remove partial transcode");
+                }
+            }
+
+            return Err(e);
+        }
+
+        rename(&temp, &target).await?;
+        info!(path=?path, "Transcode complete");
+
+        self.complete_download(&target, None, DownloadState::Transcoded { path }, None)
+            .await?;
+
+        // Originals downloaded by older versions were published to the media
+        // storage so are removed from there too.
+        if source != target {
+            if let Err(e) = self.inner.storage().await.remove(&source).await {
+                warn!(error=?e, path=?source, "Failed to remove original file");
+            }
+        }
+
+        Ok(())
+    }
+
+    fn event_progress<P: Progress>(&self, progress: P, events: &Events) -> EventProgress<P> {
+        EventProgress {
+            progress,
+            events: events.clone(),
+            server: self.server.id.clone(),
+            video: self.id.clone(),
+            part: self.index,
+        }
+    }
+
     async fn download_state(&self) -> DownloadState {
         self.with_state(|part_state| part_state.download.clone())
             .await
@@ -880,7 +1784,9 @@ impl StateWrapper<VideoPartState> for VideoPart {
             .parts
             .get_mut(self.index)
             .unwrap());
-        self.inner.persist_state(&state).await
+        self.inner
+            .persist_video(&state, &self.server.id, &self.id)
+            .await
     }
 }
 
@@ -924,9 +1830,16 @@ impl AddAssign for VideoStats {
 }
 
 impl VideoStats {
-    async fn try_from<M: MediaItem>(item: M, parts: Vec<VideoPart>) -> Result<Self> {
+    async fn try_from<M: MediaItem>(
+        item: M,
+        media_id: String,
+        parts: Vec<VideoPart>,
+    ) -> Result<Self> {
         let media = item.media();
-        let media = &media[0];
+        let media = media
+            .iter()
+            .find(|m| m.metadata().id.as_ref() == Some(&media_id))
+            .ok_or_else(|| Error::MissingItem)?;
 
         let mut stats = VideoStats::default();
 
@@ -942,7 +1855,7 @@ impl VideoStats {
             }
 
             if let Some(path) = state.file() {
-                let path = local_part.inner.path.read().await.join(path);
+                let path = local_part.inner.media_root().await.join(path);
                 if let Ok(file_stats) = metadata(path).await {
                     stats.local_bytes += file_stats.len();
                 }
@@ -977,26 +1890,43 @@ state_wrapper!(Episode, VideoState, videos);
 
 impl Episode {
     thumbnail_methods!();
-    parent!(season, Season, episode_state().season);
+
+    pub async fn season(&self) -> Result<Season> {
+        let id = self
+            .with_state(|vs| vs.episode_state().map(|ep| ep.season.clone()))
+            .await
+            .ok_or_else(|| Error::UnexpectedVideoType(self.id.clone()))?;
+
+        Ok(Season {
+            server: self.server.clone(),
+            id,
+            inner: self.inner.clone(),
+        })
+    }
 
     pub async fn stats(&self) -> Result<VideoStats> {
         let server = self.server.connect().await?;
         let item = server.item_by_id(&self.id).await?;
-        VideoStats::try_from(item, self.parts().await).await
+        let media_id = self.with_state(|s| s.media_id.clone()).await;
+        VideoStats::try_from(item, media_id, self.parts().await).await
     }
 
-    pub async fn show(&self) -> Show {
-        self.season().await.show().await
+    pub async fn show(&self) -> Result<Show> {
+        Ok(self.season().await?.show().await)
     }
 
-    pub async fn library(&self) -> ShowLibrary {
-        self.show().await.library().await
+    pub async fn library(&self) -> Result<ShowLibrary> {
+        Ok(self.show().await?.library().await)
     }
 
     pub async fn title(&self) -> String {
         self.with_state(|s| s.title.clone()).await
     }
 
+    pub async fn is_skipped(&self) -> bool {
+        self.with_state(|s| s.skipped).await
+    }
+
     pub async fn parts(&self) -> Vec<VideoPart> {
         self.with_state(|vs| {
             vs.parts
@@ -1013,37 +1943,76 @@ impl Episode {
         .await
     }
 
-    async fn file_path(&self, file_type: FileType, extension: &str) -> PathBuf {
+    async fn file_path(&self, file_type: FileType, extension: &str) -> Result<PathBuf> {
+        let (template, path) = {
+            let config = self.inner.config.read().await;
+            (
+                config.episode_template.clone(),
+                config
+                    .servers
+                    .get(&self.server.id)
+                    .and_then(|sc| sc.paths.get(&self.id))
+                    .cloned(),
+            )
+        };
+        let layout = self.inner.layout().await;
+
         self.with_server_state(|ss| {
             let state = ss.videos.get(&self.id).unwrap();
-            let ep_state = state.episode_state();
+            let ep_state = state
+                .episode_state()
+                .ok_or_else(|| Error::UnexpectedVideoType(self.id.clone()))?;
             let season = ss.seasons.get(&ep_state.season).unwrap();
             let show = ss.shows.get(&season.show).unwrap();
+            let show_name = show_title(ss, show);
             let library_title = &ss.libraries.get(&show.library).unwrap().title;
 
-            let name = match file_type {
-                FileType::Video(index) => {
-                    let part_name = if state.parts.len() == 1 {
-                        "".to_string()
-                    } else {
-                        format!(" - pt{}", index + 1)
-                    };
+            let render = |part: String, extension: &str| -> Result<PathBuf> {
+                // An explicit path bypasses the template entirely.
+                if let Some(ref path) = path {
+                    return Ok(PathBuf::from(safe(&self.server.id))
+                        .join(format!("{path}{part}.{extension}")));
+                }
 
-                    format!(
-                        "S{:02}E{:02} - {}{part_name}.{extension}",
-                        season.index, ep_state.index, state.title
-                    )
+                let values: HashMap<&str, Value> = HashMap::from([
+                    ("library", library_title.as_str().into()),
+                    ("show", show_name.as_str().into()),
+                    ("show_year", year(show.year)),
+                    ("season", season.index.into()),
+                    ("episode", ep_state.index.into()),
+                    ("title", state.title.as_str().into()),
+                    ("part", part.into()),
+                    ("ext", extension.into()),
+                ]);
+
+                Ok(PathBuf::from(safe(&self.server.id)).join(render_or_default(
+                    template.as_deref(),
+                    layout.episode_template(),
+                    &values,
+                )?))
+            };
+
+            let name = match (file_type, layout) {
+                (FileType::Video(index), _) => return render(part_name(state, index), extension),
+                // Episode artwork is named after the video file.
+                (FileType::Artwork(ArtworkKind::Poster), Layout::Plex) => {
+                    return render(String::new(), extension)
+                }
+                (FileType::Artwork(kind), Layout::Plex) => {
+                    return render(format!("-{}", kind.local_name()), extension)
                 }
-                FileType::Thumbnail => format!(
-                    ".S{:02}E{:02}.thumb.{extension}",
-                    season.index, ep_state.index
+                (FileType::Artwork(kind), Layout::Default) => format!(
+                    ".S{:02}E{:02}.{}.{extension}",
+                    season.index,
+                    ep_state.index,
+                    kind.file_name()
                 ),
             };
 
-            PathBuf::from(safe(&self.server.id))
+            Ok(PathBuf::from(safe(&self.server.id))
                 .join(safe(library_title))
-                .join(safe(format!("{} ({})", show.title, show.year)))
-                .join(safe(name))
+                .join(safe(title_with_year(&show_name, show.year)))
+                .join(safe(name)))
         })
         .await
     }
@@ -1066,18 +2035,35 @@ state_wrapper!(Movie, VideoState, videos);
 
 impl Movie {
     thumbnail_methods!();
-    parent!(library, MovieLibrary, movie_state().library);
+
+    pub async fn library(&self) -> Result<MovieLibrary> {
+        let id = self
+            .with_state(|vs| vs.movie_state().map(|m| m.library.clone()))
+            .await
+            .ok_or_else(|| Error::UnexpectedVideoType(self.id.clone()))?;
+
+        Ok(MovieLibrary {
+            server: self.server.clone(),
+            id,
+            inner: self.inner.clone(),
+        })
+    }
 
     pub async fn stats(&self) -> Result<VideoStats> {
         let server = self.server.connect().await?;
         let item = server.item_by_id(&self.id).await?;
-        VideoStats::try_from(item, self.parts().await).await
+        let media_id = self.with_state(|s| s.media_id.clone()).await;
+        VideoStats::try_from(item, media_id, self.parts().await).await
     }
 
     pub async fn title(&self) -> String {
         self.with_state(|s| s.title.clone()).await
     }
 
+    pub async fn is_skipped(&self) -> bool {
+        self.with_state(|s| s.skipped).await
+    }
+
     pub async fn parts(&self) -> Vec<VideoPart> {
         self.with_state(|vs| {
             vs.parts
@@ -1094,29 +2080,70 @@ impl Movie {
         .await
     }
 
-    async fn file_path(&self, file_type: FileType, extension: &str) -> PathBuf {
+    async fn file_path(&self, file_type: FileType, extension: &str) -> Result<PathBuf> {
+        let (template, path) = {
+            let config = self.inner.config.read().await;
+            (
+                config.movie_template.clone(),
+                config
+                    .servers
+                    .get(&self.server.id)
+                    .and_then(|sc| sc.paths.get(&self.id))
+                    .cloned(),
+            )
+        };
+        let layout = self.inner.layout().await;
+
         self.with_server_state(|ss| {
             let state = ss.videos.get(&self.id).unwrap();
-            let m_state = state.movie_state();
+            let m_state = state
+                .movie_state()
+                .ok_or_else(|| Error::UnexpectedVideoType(self.id.clone()))?;
             let library_title = &ss.libraries.get(&m_state.library).unwrap().title;
+            let title = movie_title(ss, state, m_state);
 
-            let name = match file_type {
-                FileType::Video(index) => {
-                    let part_name = if state.parts.len() == 1 {
-                        "".to_string()
-                    } else {
-                        format!(" - pt{}", index + 1)
-                    };
+            let render = |part: String, extension: &str| -> Result<PathBuf> {
+                // An explicit path bypasses the template entirely.
+                if let Some(ref path) = path {
+                    return Ok(PathBuf::from(safe(&self.server.id))
+                        .join(format!("{path}{part}.{extension}")));
+                }
+
+                let values: HashMap<&str, Value> = HashMap::from([
+                    ("library", library_title.as_str().into()),
+                    ("title", title.as_str().into()),
+                    ("year", year(m_state.year)),
+                    ("part", part.into()),
+                    ("ext", extension.into()),
+                ]);
+
+                Ok(PathBuf::from(safe(&self.server.id)).join(render_or_default(
+                    template.as_deref(),
+                    layout.movie_template(),
+                    &values,
+                )?))
+            };
 
-                    format!("{} ({}){part_name}.{extension}", state.title, m_state.year)
+            let name = match (file_type, layout) {
+                (FileType::Video(index), _) => return render(part_name(state, index), extension),
+                // Artwork goes in the same directory as the video.
+                (FileType::Artwork(kind), Layout::Plex) => {
+                    let video = render(String::new(), extension)?;
+                    let name = format!("{}.{extension}", kind.local_name());
+                    return Ok(match video.parent() {
+                        Some(parent) => parent.join(name),
+                        None => PathBuf::from(name),
+                    });
+                }
+                (FileType::Artwork(kind), Layout::Default) => {
+                    format!(".{}.{extension}", kind.file_name())
                 }
-                FileType::Thumbnail => format!(".thumb.{extension}",),
             };
 
-            PathBuf::from(safe(&self.server.id))
+            Ok(PathBuf::from(safe(&self.server.id))
                 .join(safe(library_title))
-                .join(safe(format!("{} ({})", state.title, m_state.year)))
-                .join(safe(name))
+                .join(safe(title_with_year(&title, m_state.year)))
+                .join(safe(name)))
         })
         .await
     }
@@ -1129,13 +2156,20 @@ pub enum Video {
 }
 
 impl Video {
-    pub async fn library(&self) -> Library {
+    pub fn id(&self) -> &str {
         match self {
-            Self::Movie(v) => Library::Movie(v.library().await),
-            Self::Episode(v) => Library::Show(v.library().await),
+            Self::Movie(v) => &v.id,
+            Self::Episode(v) => &v.id,
         }
     }
 
+    pub async fn library(&self) -> Result<Library> {
+        Ok(match self {
+            Self::Movie(v) => Library::Movie(v.library().await?),
+            Self::Episode(v) => Library::Show(v.library().await?),
+        })
+    }
+
     pub async fn stats(&self) -> Result<VideoStats> {
         match self {
             Self::Movie(v) => v.stats().await,
@@ -1150,6 +2184,13 @@ impl Video {
         }
     }
 
+    pub async fn is_skipped(&self) -> bool {
+        match self {
+            Self::Movie(v) => v.is_skipped().await,
+            Self::Episode(v) => v.is_skipped().await,
+        }
+    }
+
     pub async fn parts(&self) -> Vec<VideoPart> {
         match self {
             Self::Movie(v) => v.parts().await,
@@ -1157,7 +2198,7 @@ impl Video {
         }
     }
 
-    async fn file_path(&self, file_type: FileType, extension: &str) -> PathBuf {
+    async fn file_path(&self, file_type: FileType, extension: &str) -> Result<PathBuf> {
         match self {
             Self::Movie(v) => v.file_path(file_type, extension).await,
             Self::Episode(v) => v.file_path(file_type, extension).await,
@@ -1244,14 +2285,14 @@ impl MovieCollection {
         .await
     }
 
-    async fn file_path(&self, _file_type: FileType, extension: &str) -> PathBuf {
+    async fn file_path(&self, file_type: FileType, extension: &str) -> Result<PathBuf> {
         self.with_server_state(|ss| {
             let state = ss.collections.get(&self.id).unwrap();
             let library_title = &ss.libraries.get(&state.library).unwrap().title;
 
-            PathBuf::from(safe(&self.server.id))
+            Ok(PathBuf::from(safe(&self.server.id))
                 .join(safe(library_title))
-                .join(safe(format!(".{}.{extension}", state.id)))
+                .join(safe(collection_file_name(&state.id, file_type, extension))))
         })
         .await
     }
@@ -1290,14 +2331,14 @@ impl ShowCollection {
         .await
     }
 
-    async fn file_path(&self, _file_type: FileType, extension: &str) -> PathBuf {
+    async fn file_path(&self, file_type: FileType, extension: &str) -> Result<PathBuf> {
         self.with_server_state(|ss| {
             let state = ss.collections.get(&self.id).unwrap();
             let library_title = &ss.libraries.get(&state.library).unwrap().title;
 
-            PathBuf::from(safe(&self.server.id))
+            Ok(PathBuf::from(safe(&self.server.id))
                 .join(safe(library_title))
-                .join(safe(format!(".{}.{extension}", state.id)))
+                .join(safe(collection_file_name(&state.id, file_type, extension))))
         })
         .await
     }
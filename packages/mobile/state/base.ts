@@ -7,7 +7,8 @@ export type DownloadState =
   | { state: "downloading"; path: string }
   | { state: "transcoding"; path: string }
   | { state: "downloaded"; path: string }
-  | { state: "transcoded"; path: string };
+  | { state: "transcoded"; path: string }
+  | { state: "awaitingTranscode"; path: string };
 
 export type PlaybackState =
   | { state: "unplayed" }
@@ -28,6 +29,7 @@ export interface CollectionState {
   contents: string[];
   lastUpdated: number;
   thumbnail: ThumbnailState;
+  artwork?: Partial<Record<ArtworkKind, ThumbnailState>>;
 }
 
 export interface PlaylistState {
@@ -36,6 +38,12 @@ export interface PlaylistState {
   videos: string[];
 }
 
+export enum ArtworkKind {
+  Poster = "poster",
+  Fanart = "fanart",
+  Banner = "banner",
+}
+
 export enum LibraryType {
   Movie = "movie",
   Show = "show",
@@ -52,6 +60,8 @@ export interface SeasonState {
   show: string;
   index: number;
   title: string;
+  thumbnail: ThumbnailState;
+  artwork?: Partial<Record<ArtworkKind, ThumbnailState>>;
 }
 
 export interface ShowState {
@@ -61,6 +71,7 @@ export interface ShowState {
   year: number;
   lastUpdated: number;
   thumbnail: ThumbnailState;
+  artwork?: Partial<Record<ArtworkKind, ThumbnailState>>;
 }
 
 export interface MovieDetail {
@@ -73,12 +84,36 @@ export interface EpisodeDetail {
   index: number;
 }
 
+export enum HashAlgorithm {
+  Sha1 = "sha1",
+  Sha256 = "sha256",
+  Blake3 = "blake3",
+  Xxh3 = "xxh3",
+}
+
+export interface FileChecksum {
+  size: number;
+  algorithm: HashAlgorithm;
+  hash: string;
+}
+
+export interface TranscodeTracks {
+  audioLanguage?: string;
+  subtitleLanguage?: string;
+  burnSubtitles?: boolean;
+  audioTrack?: number;
+}
+
 export interface VideoPartState {
   id: string;
   key: string;
   size: number;
   duration: number;
   download: DownloadState;
+  checksum?: FileChecksum;
+  tracks?: TranscodeTracks;
+  unpublished: boolean;
+  quarantined: boolean;
 }
 
 export interface VideoState {
@@ -87,12 +122,63 @@ export interface VideoState {
   detail: VideoDetail;
   airDate: string;
   thumbnail: ThumbnailState;
+  artwork?: Partial<Record<ArtworkKind, ThumbnailState>>;
   mediaId: string;
   lastUpdated: number;
   parts: VideoPartState[];
   transcodeProfile?: string;
   playbackState: PlaybackState;
   lastViewedAt?: number;
+  skipped: boolean;
+  evicted: boolean;
+  sources?: string[];
+}
+
+export enum ItemType {
+  Playlist = "playlist",
+  MovieCollection = "movieCollection",
+  ShowCollection = "showCollection",
+  Show = "show",
+  Season = "season",
+  Episode = "episode",
+  Movie = "movie",
+  Unknown = "unknown",
+}
+
+export interface CachedItem {
+  type: ItemType;
+  title: string;
+  fetched: number;
+}
+
+export interface CachedCapabilities {
+  claimed: boolean;
+  plexPass: boolean;
+  downloads: boolean;
+  transcoding: boolean;
+  fetched: number;
+}
+
+export enum ConnectionKind {
+  Local = "local",
+  Remote = "remote",
+  Relay = "relay",
+}
+
+export interface PreferredConnection {
+  url: string;
+  token: string;
+  kind: ConnectionKind;
+  latency: number;
+}
+
+export interface SyncRecord {
+  started: number;
+  duration: number;
+  bytes: number;
+  errors: number;
+  run?: string;
+  used?: number;
 }
 
 export interface ServerState {
@@ -104,6 +190,13 @@ export interface ServerState {
   shows?: Record<string, ShowState>;
   seasons?: Record<string, SeasonState>;
   videos?: Record<string, VideoState>;
+  lastSynced?: number;
+  syncHistory?: SyncRecord[];
+  machineId?: string;
+  authFailed?: number;
+  itemCache?: Record<string, CachedItem>;
+  capabilities?: CachedCapabilities;
+  preferredConnection?: PreferredConnection;
 }
 
 export interface State {
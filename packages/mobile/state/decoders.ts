@@ -73,6 +73,13 @@ const DownloadStateDecoder = JsonDecoder.oneOf<DownloadState>(
       },
       "transcoded",
     ),
+    JsonDecoder.object(
+      {
+        state: JsonDecoder.isExactly("awaitingTranscode"),
+        path: JsonDecoder.string,
+      },
+      "awaitingTranscode",
+    ),
   ],
   "DownloadState",
 );